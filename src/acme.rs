@@ -0,0 +1,186 @@
+use instant_acme::{
+    Account, AccountCredentials, AuthorizationStatus, ChallengeType, Identifier, NewAccount, NewOrder, OrderStatus,
+};
+use log::{info, warn};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509ReqBuilder, X509};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::config::AcmeConfig;
+
+pub const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+const ACCOUNT_PATH: &str = "ssl/acme_account.json";
+const CERT_PATH: &str = "ssl/server.pem";
+const KEY_PATH: &str = "ssl/server.key";
+
+// HTTP-01 tokens currently being validated, answered by the proxy listener.
+#[derive(Default)]
+pub struct AcmeChallenges {
+    tokens: Mutex<HashMap<String, String>>,
+}
+
+impl AcmeChallenges {
+    pub fn key_authorization(&self, token: &str) -> Option<String> {
+        self.tokens.lock().unwrap().get(token).cloned()
+    }
+}
+
+pub struct AcmeClient {
+    pub config: AcmeConfig,
+    pub challenges: Arc<AcmeChallenges>,
+}
+
+impl AcmeClient {
+    // The self-signed bootstrap certificate doesn't name our domains, so it's replaced on the
+    // first pass of the cert watcher.
+    pub fn has_certificate(&self) -> bool {
+        let Some(cert) = fs::read(CERT_PATH).ok().and_then(|pem| X509::from_pem(&pem).ok()) else {
+            return false;
+        };
+        let names: Vec<String> = cert
+            .subject_alt_names()
+            .map(|sans| sans.iter().filter_map(|san| san.dnsname().map(str::to_lowercase)).collect())
+            .unwrap_or_default();
+        self.config.domains.iter().all(|domain| names.contains(&domain.to_lowercase()))
+    }
+
+    // Runs a full HTTP-01 order and writes the issued chain and key over ssl/server.pem and
+    // ssl/server.key. Blocking; meant for the cert watcher thread.
+    pub fn provision(&self) -> Result<(), String> {
+        info!("🔏 Requesting a certificate for {} from {}", self.config.domains.join(", "), self.config.directory_url);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(|e| format!("Failed to start ACME runtime: {}", e))?;
+        let result = runtime.block_on(self.order_certificate());
+        self.challenges.tokens.lock().unwrap().clear();
+
+        match result {
+            Ok(()) => {
+                info!("🔏 Certificate for {} issued", self.config.domains.join(", "));
+                Ok(())
+            }
+            Err(e) => Err(format!("ACME order for {} failed: {}", self.config.domains.join(", "), e)),
+        }
+    }
+
+    async fn account(&self) -> Result<Account, Box<dyn Error + Send + Sync>> {
+        if let Ok(saved) = fs::read_to_string(ACCOUNT_PATH) {
+            let credentials: AccountCredentials = serde_json::from_str(&saved)?;
+            return Ok(Account::from_credentials(credentials).await?);
+        }
+
+        let contact = format!("mailto:{}", self.config.email);
+        let (account, credentials) = Account::create(
+            &NewAccount {
+                contact: &[&contact],
+                terms_of_service_agreed: true,
+                only_return_existing: false,
+            },
+            &self.config.directory_url,
+            None,
+        )
+        .await?;
+        fs::create_dir_all("ssl")?;
+        fs::write(ACCOUNT_PATH, serde_json::to_string(&credentials)?)?;
+        restrict_permissions(ACCOUNT_PATH);
+        info!("🔏 Registered ACME account for {}", self.config.email);
+        Ok(account)
+    }
+
+    async fn order_certificate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let account = self.account().await?;
+        let identifiers: Vec<Identifier> = self.config.domains.iter().cloned().map(Identifier::Dns).collect();
+        let mut order = account.new_order(&NewOrder { identifiers: &identifiers }).await?;
+
+        let mut ready = Vec::new();
+        for authz in order.authorizations().await? {
+            match authz.status {
+                AuthorizationStatus::Pending => {}
+                AuthorizationStatus::Valid => continue,
+                status => return Err(format!("authorization for {:?} is {:?}", authz.identifier, status).into()),
+            }
+            let challenge = authz
+                .challenges
+                .iter()
+                .find(|c| c.r#type == ChallengeType::Http01)
+                .ok_or_else(|| format!("no http-01 challenge offered for {:?}", authz.identifier))?;
+            let key_authorization = order.key_authorization(challenge).as_str().to_string();
+            self.challenges.tokens.lock().unwrap().insert(challenge.token.clone(), key_authorization);
+            ready.push(challenge.url.clone());
+        }
+        for url in &ready {
+            order.set_challenge_ready(url).await?;
+        }
+
+        // Validation usually takes a few seconds; back off up to roughly two minutes.
+        let mut delay = Duration::from_millis(500);
+        loop {
+            tokio::time::sleep(delay).await;
+            let state = order.refresh().await?;
+            match state.status {
+                OrderStatus::Ready | OrderStatus::Valid => break,
+                OrderStatus::Invalid => return Err(format!("order became invalid: {:?}", state.error).into()),
+                _ if delay >= Duration::from_secs(32) => return Err("timed out waiting for validation".into()),
+                _ => delay *= 2,
+            }
+        }
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+        let key = PKey::from_ec_key(EcKey::generate(&group)?)?;
+        order.finalize(&self.csr(&key)?).await?;
+
+        let mut chain = None;
+        for _ in 0..30 {
+            if let Some(pem) = order.certificate().await? {
+                chain = Some(pem);
+                break;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        let chain = chain.ok_or("certificate was not issued in time")?;
+
+        fs::create_dir_all("ssl")?;
+        fs::write(KEY_PATH, key.private_key_to_pem_pkcs8()?)?;
+        restrict_permissions(KEY_PATH);
+        fs::write(CERT_PATH, chain)?;
+        Ok(())
+    }
+
+    fn csr(&self, key: &PKey<openssl::pkey::Private>) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &self.config.domains[0])?;
+        let name = name.build();
+
+        let mut req = X509ReqBuilder::new()?;
+        req.set_subject_name(&name)?;
+        req.set_pubkey(key)?;
+        let mut san = SubjectAlternativeName::new();
+        for domain in &self.config.domains {
+            san.dns(domain);
+        }
+        let mut extensions = Stack::new()?;
+        extensions.push(san.build(&req.x509v3_context(None))?)?;
+        req.add_extensions(&extensions)?;
+        req.sign(key, MessageDigest::sha256())?;
+        Ok(req.build().to_der()?)
+    }
+}
+
+fn restrict_permissions(path: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            warn!("Failed to set permissions on {}: {}", path, e);
+        }
+    }
+}
@@ -0,0 +1,40 @@
+use log::info;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::backend::Backend;
+use crate::config::AdaptiveWeightsConfig;
+
+// Every interval, scales each backend's weight by how its latency average compares with its pool's
+// mean: twice as slow as the mean targets half the weight, twice as fast double, clamped to the
+// configured bounds so a slow backend keeps getting enough traffic to show when it recovers. The
+// factor moves halfway to its target each round so one noisy interval doesn't swing the split.
+pub async fn adjust_loop(pools: Vec<Arc<RwLock<Vec<Backend>>>>, config: AdaptiveWeightsConfig) {
+    loop {
+        tokio::time::sleep(Duration::from_secs(config.interval_secs)).await;
+        for backends in &pools {
+            adjust(&backends.read().unwrap(), &config);
+        }
+    }
+}
+
+fn adjust(backends: &[Backend], config: &AdaptiveWeightsConfig) {
+    // Backends without a sample yet (or out of rotation) keep their factor until they have one.
+    let sampled: Vec<&Backend> = backends.iter().filter(|b| b.healthy && b.latency_ewma() > 0.0).collect();
+    if sampled.len() < 2 {
+        return;
+    }
+    let mean = sampled.iter().map(|b| b.latency_ewma()).sum::<f64>() / sampled.len() as f64;
+
+    for backend in sampled {
+        let target = (mean / backend.latency_ewma() * 100.0).clamp(config.min_percent as f64, config.max_percent as f64);
+        let current = backend.latency_factor.load(Ordering::Relaxed) as f64;
+        let next = ((current + target) / 2.0).round() as u32;
+        if next != current as u32 {
+            backend.latency_factor.store(next, Ordering::Relaxed);
+            if next.abs_diff(current as u32) >= 10 {
+                info!("📈 {} now at {}% of its weight ({:.1}ms vs {:.1}ms pool mean)", backend.address(), next, backend.latency_ewma() / 1000.0, mean / 1000.0);
+            }
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use async_trait::async_trait;
+use http::{Method, Response, StatusCode};
+use log::{info, warn};
+use pingora_core::apps::http_app::ServeHttp;
+use pingora_core::protocols::http::ServerSession;
+use serde_json::json;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use crate::backend::Backend;
+use crate::config::{parse_backend, HealthCheckConfig};
+use crate::load_balancer::LoadBalanceStrategy;
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::routing::{Pool, Router};
+
+// Request bodies on the admin listener are a backend spec or a weight; anything longer is refused.
+const MAX_BODY_BYTES: usize = 4096;
+
+// Admin endpoints, served on their own listener so they never share the proxy port. GETs are
+// open; anything that changes state needs `Authorization: Bearer <ADMIN_TOKEN>`.
+pub struct AdminService {
+    pub router: Arc<Router>,
+    pub metrics: Arc<Metrics>,
+    pub maintenance: Arc<Maintenance>,
+    pub token: Option<String>,
+    pub drain_timeout_secs: u64,
+    pub ssl_enabled: bool,
+    pub health_check: HealthCheckConfig,
+}
+
+impl AdminService {
+    fn status(&self) -> Response<Vec<u8>> {
+        // `backends` is the default pool; host-routed pools are listed under `pools`.
+        let body = json!({
+            "strategy": format!("{:?}", self.router.default_pool.load_balancer.strategy()),
+            "backends": AdminService::backends_json(&self.router.default_pool.backends.read().unwrap()),
+            "pools": self.router.pools.iter().map(|pool| json!({
+                "name": pool.name,
+                "strategy": format!("{:?}", pool.load_balancer.strategy()),
+                "hosts": pool.hosts,
+                "path_prefixes": pool.path_prefixes,
+                "backends": AdminService::backends_json(&pool.backends.read().unwrap()),
+            })).collect::<Vec<_>>(),
+        });
+
+        AdminService::respond(StatusCode::OK, "application/json", body.to_string().into_bytes())
+    }
+
+    // How this instance is built and configured. Health check header values are left out, since
+    // they often carry credentials for the backends.
+    fn info(&self) -> Response<Vec<u8>> {
+        let hc = &self.health_check;
+        let body = json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_hash": env!("GIT_HASH"),
+            "strategy": format!("{:?}", self.router.default_pool.load_balancer.strategy()),
+            "ssl": self.ssl_enabled,
+            "backends": self.router.all_pools().map(|pool| pool.backends.read().unwrap().len()).sum::<usize>(),
+            "pools": self.router.all_pools().map(|pool| json!({
+                "name": pool.name,
+                "strategy": format!("{:?}", pool.load_balancer.strategy()),
+                "backends": pool.backends.read().unwrap().len(),
+            })).collect::<Vec<_>>(),
+            "health_check": {
+                "enabled": hc.enabled,
+                "mode": format!("{:?}", hc.mode),
+                "path": hc.path,
+                "host": hc.host,
+                "header_names": hc.headers.keys().collect::<Vec<_>>(),
+                "interval_secs": hc.interval_secs,
+                "jitter_percent": hc.jitter_percent,
+                "timeout_secs": hc.timeout_secs,
+                "retries": hc.retries,
+                "success_codes": hc.success_codes,
+                "expects_body": hc.expect_body.is_some(),
+                "unhealthy_threshold": hc.unhealthy_threshold,
+                "healthy_threshold": hc.healthy_threshold,
+                "passive_failure_threshold": hc.passive_failure_threshold,
+            },
+        });
+
+        AdminService::respond(StatusCode::OK, "application/json", body.to_string().into_bytes())
+    }
+
+    // Ready while some pool could pick a backend: healthy, circuit closed and not ejected.
+    fn readiness(&self) -> Response<Vec<u8>> {
+        let ready = self
+            .router
+            .all_pools()
+            .any(|pool| pool.load_balancer.has_available(&pool.backends.read().unwrap()));
+        if ready {
+            AdminService::respond(StatusCode::OK, "text/plain", b"ready\n".to_vec())
+        } else {
+            AdminService::respond(StatusCode::SERVICE_UNAVAILABLE, "text/plain", b"no healthy backend\n".to_vec())
+        }
+    }
+
+    // Rebuilt on every scrape, so backends removed at runtime drop out of the series.
+    fn refresh_connection_gauges(&self) {
+        let gauge = &self.metrics.backend_active_connections;
+        gauge.reset();
+        for pool in self.router.all_pools() {
+            for backend in pool.backends.read().unwrap().iter() {
+                gauge.with_label_values(&[pool.name.as_str(), backend.address().as_str()]).set(backend.active_connections() as i64);
+            }
+        }
+    }
+
+    // GET reports the flag; POST /maintenance?enabled=true|false flips it.
+    fn maintenance(&self, method: &Method, query: Option<&str>) -> Response<Vec<u8>> {
+        if *method == Method::POST {
+            let enabled = query_param(query, "enabled").and_then(|value| match value {
+                "true" | "on" | "1" => Some(true),
+                "false" | "off" | "0" => Some(false),
+                _ => None,
+            });
+            let Some(enabled) = enabled else {
+                return AdminService::respond(StatusCode::BAD_REQUEST, "text/plain", b"Expected ?enabled=true or ?enabled=false\n".to_vec());
+            };
+            self.maintenance.set_enabled(enabled);
+        } else if *method != Method::GET {
+            return AdminService::respond(StatusCode::METHOD_NOT_ALLOWED, "text/plain", b"Method Not Allowed\n".to_vec());
+        }
+
+        let body = json!({ "enabled": self.maintenance.is_enabled() });
+        AdminService::respond(StatusCode::OK, "application/json", body.to_string().into_bytes())
+    }
+
+    fn is_authorized(&self, session: &ServerSession) -> bool {
+        let Some(token) = &self.token else {
+            return false;
+        };
+        session
+            .req_header()
+            .headers
+            .get("Authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .is_some_and(|presented| bool::from(presented.trim().as_bytes().ct_eq(token.as_bytes())))
+    }
+
+    // `?pool=name` picks a host-routed pool; without it the default pool is edited.
+    fn pool(&self, query: Option<&str>) -> Option<&Arc<Pool>> {
+        let name = query_param(query, "pool").unwrap_or(crate::routing::DEFAULT_POOL);
+        self.router.all_pools().find(|pool| pool.name == name)
+    }
+
+    // POST /backends with a BACKENDS-style entry (host:port[:weight[:tls[:sni[:health_path]]]]) as
+    // the body. The health checker and `upstream_peer` read the same list, so it is live at once.
+    fn add_backend(&self, pool: &Pool, body: &str) -> Response<Vec<u8>> {
+        let Some(backend) = parse_backend(body.trim()) else {
+            return AdminService::respond(StatusCode::BAD_REQUEST, "text/plain", b"Expected host:port[:weight[:tls[:sni[:health_path]]]]\n".to_vec());
+        };
+        let mut backends = pool.backends.write().unwrap();
+        if backends.iter().any(|b| b.address() == backend.address()) {
+            return AdminService::respond(StatusCode::CONFLICT, "text/plain", b"Backend already exists\n".to_vec());
+        }
+        info!("🛠️ Added backend {} to pool '{}' (weight {})", backend.address(), pool.name, backend.weight);
+        let body = json!(AdminService::backends_json(std::slice::from_ref(&backend))[0]);
+        backends.push(backend);
+        AdminService::respond(StatusCode::CREATED, "application/json", body.to_string().into_bytes())
+    }
+
+    // Drains the backend first: it stops getting new traffic but keeps its sticky sessions and
+    // in-flight requests until they finish or the drain timeout passes. `?now=true` removes it at
+    // once, dropping the sessions pinned to it.
+    fn remove_backend(&self, pool: &Pool, address: &str, now: bool) -> Response<Vec<u8>> {
+        let mut backends = pool.backends.write().unwrap();
+        let Some(index) = backends.iter().position(|b| b.address() == address) else {
+            return AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"No such backend\n".to_vec());
+        };
+        if !now {
+            let backend = &mut backends[index];
+            backend.drain_started.get_or_insert_with(std::time::Instant::now);
+            info!("🛠️ Draining backend {} in pool '{}' before removing it (at most {}s)", address, pool.name, self.drain_timeout_secs);
+            let body = json!(AdminService::backends_json(std::slice::from_ref(backend))[0]);
+            return AdminService::respond(StatusCode::ACCEPTED, "application/json", body.to_string().into_bytes());
+        }
+        backends.remove(index);
+        pool.load_balancer.retain_sessions(&backends);
+        warn!("🛠️ Removed backend {} from pool '{}'", address, pool.name);
+        AdminService::respond(StatusCode::NO_CONTENT, "text/plain", Vec::new())
+    }
+
+    // PUT /backends/<host:port>/weight with the new weight as the body; 0 drains the backend.
+    fn set_weight(&self, pool: &Pool, address: &str, body: &str) -> Response<Vec<u8>> {
+        let Ok(weight) = body.trim().parse::<usize>() else {
+            return AdminService::respond(StatusCode::BAD_REQUEST, "text/plain", b"Expected a non-negative integer weight\n".to_vec());
+        };
+        let mut backends = pool.backends.write().unwrap();
+        let Some(backend) = backends.iter_mut().find(|b| b.address() == address) else {
+            return AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"No such backend\n".to_vec());
+        };
+        backend.weight = weight;
+        info!("🛠️ Set weight of {} in pool '{}' to {}", address, pool.name, weight);
+        let body = json!(AdminService::backends_json(std::slice::from_ref(backend))[0]);
+        AdminService::respond(StatusCode::OK, "application/json", body.to_string().into_bytes())
+    }
+
+    // GET reports the pool's strategy; PUT with a LOAD_BALANCE_STRATEGY name as the body switches it
+    // for every request from then on.
+    fn strategy(&self, pool: &Pool, requested: Option<&str>) -> Response<Vec<u8>> {
+        if let Some(name) = requested.map(str::trim) {
+            let Some(strategy) = LoadBalanceStrategy::_from_str(name) else {
+                return AdminService::respond(StatusCode::BAD_REQUEST, "text/plain", format!("Unknown strategy '{}'\n", name).into_bytes());
+            };
+            pool.load_balancer.set_strategy(strategy);
+        }
+
+        let body = json!({ "pool": pool.name, "strategy": format!("{:?}", pool.load_balancer.strategy()) });
+        AdminService::respond(StatusCode::OK, "application/json", body.to_string().into_bytes())
+    }
+
+    async fn read_body(session: &mut ServerSession) -> Option<String> {
+        let mut body = Vec::new();
+        while let Ok(Some(chunk)) = session.read_request_body().await {
+            body.extend_from_slice(&chunk);
+            if body.len() > MAX_BODY_BYTES {
+                return None;
+            }
+        }
+        String::from_utf8(body).ok()
+    }
+
+    fn backends_json(backends: &[Backend]) -> Vec<serde_json::Value> {
+        backends.iter().map(|b| json!({
+            "host": b.host,
+            "port": b.port,
+            "weight": b.weight,
+            "healthy": b.healthy,
+            "draining": b.is_draining(),
+            "drain_secs": b.drain_started.map(|since| since.elapsed().as_secs()),
+            "last_checked_secs_ago": b.last_checked.map(|t| t.elapsed().as_secs()),
+            "last_error": b.last_error,
+            "consecutive_failures": b.consecutive_failures,
+            "consecutive_successes": b.consecutive_successes,
+            "active_connections": b.active_connections(),
+        })).collect()
+    }
+
+    fn respond(status: StatusCode, content_type: &str, body: Vec<u8>) -> Response<Vec<u8>> {
+        Response::builder()
+            .status(status)
+            .header("Content-Type", content_type)
+            .header("Content-Length", body.len())
+            .body(body)
+            .unwrap()
+    }
+}
+
+#[async_trait]
+impl ServeHttp for AdminService {
+    async fn response(&self, http_session: &mut ServerSession) -> Response<Vec<u8>> {
+        let req = http_session.req_header();
+        let method = req.method.clone();
+        let path = req.uri.path().to_string();
+        let query = req.uri.query().map(str::to_string);
+
+        if method != Method::GET && method != Method::HEAD && !self.is_authorized(http_session) {
+            let message: &[u8] = if self.token.is_some() { b"Unauthorized\n" } else { b"Set ADMIN_TOKEN to enable admin changes\n" };
+            return AdminService::respond(StatusCode::UNAUTHORIZED, "text/plain", message.to_vec());
+        }
+
+        match (&method, path.as_str()) {
+            (_, "/status") => self.status(),
+            (_, "/info") => self.info(),
+            (_, "/livez") => AdminService::respond(StatusCode::OK, "text/plain", b"ok\n".to_vec()),
+            (_, "/readyz") => self.readiness(),
+            (_, "/metrics") => {
+                self.refresh_connection_gauges();
+                AdminService::respond(StatusCode::OK, "text/plain; version=0.0.4", self.metrics.render())
+            }
+            (_, "/maintenance") => self.maintenance(&method, query.as_deref()),
+            (_, "/strategy") => {
+                let Some(pool) = self.pool(query.as_deref()) else {
+                    return AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"No such pool\n".to_vec());
+                };
+                match method {
+                    Method::GET | Method::HEAD => self.strategy(pool, None),
+                    Method::PUT => match AdminService::read_body(http_session).await {
+                        Some(body) => self.strategy(pool, Some(&body)),
+                        None => AdminService::respond(StatusCode::PAYLOAD_TOO_LARGE, "text/plain", b"Body too large\n".to_vec()),
+                    },
+                    _ => AdminService::respond(StatusCode::METHOD_NOT_ALLOWED, "text/plain", b"Method Not Allowed\n".to_vec()),
+                }
+            }
+            (_, target) if target == "/backends" || target.starts_with("/backends/") => {
+                let Some(pool) = self.pool(query.as_deref()) else {
+                    return AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"No such pool\n".to_vec());
+                };
+                let target = path.trim_start_matches("/backends").trim_start_matches('/');
+                match (&method, target.strip_suffix("/weight")) {
+                    (&Method::POST, _) if target.is_empty() => match AdminService::read_body(http_session).await {
+                        Some(body) => self.add_backend(pool, &body),
+                        None => AdminService::respond(StatusCode::PAYLOAD_TOO_LARGE, "text/plain", b"Body too large\n".to_vec()),
+                    },
+                    (&Method::PUT, Some(address)) => match AdminService::read_body(http_session).await {
+                        Some(body) => self.set_weight(pool, address, &body),
+                        None => AdminService::respond(StatusCode::PAYLOAD_TOO_LARGE, "text/plain", b"Body too large\n".to_vec()),
+                    },
+                    (&Method::DELETE, None) if !target.is_empty() => {
+                        let now = matches!(query_param(query.as_deref(), "now"), Some("true" | "1"));
+                        self.remove_backend(pool, target, now)
+                    }
+                    _ => AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"Not Found\n".to_vec()),
+                }
+            }
+            _ => AdminService::respond(StatusCode::NOT_FOUND, "text/plain", b"Not Found\n".to_vec()),
+        }
+    }
+}
+
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query?.split('&').find_map(|pair| pair.strip_prefix(name)?.strip_prefix('='))
+}
@@ -0,0 +1,109 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use log::info;
+use subtle::ConstantTimeEq;
+use crate::config::BasicAuthConfig;
+use crate::routing::matches_prefix_normalized;
+
+pub struct BasicAuth {
+    users: Vec<(String, String)>,
+    bypass_paths: Vec<String>,
+    // Verified against when the user is unknown, so a bad username costs as much as a bad password.
+    dummy_hash: String,
+}
+
+impl BasicAuth {
+    pub fn new(config: BasicAuthConfig) -> Self {
+        let cost = config
+            .users
+            .first()
+            .and_then(|(_, hash)| hash.split('$').nth(2))
+            .and_then(|cost| cost.parse().ok())
+            .unwrap_or(bcrypt::DEFAULT_COST);
+        let dummy_hash = bcrypt::hash("pingora-proxy", cost).expect("Failed to hash basic auth placeholder");
+
+        info!("🔐 Basic auth enabled for {} user(s), bypassed for {:?}", config.users.len(), config.bypass_paths);
+        Self { users: config.users, bypass_paths: config.bypass_paths, dummy_hash }
+    }
+
+    pub fn is_bypassed(&self, path: &str) -> bool {
+        self.bypass_paths.iter().any(|prefix| matches_prefix_normalized(prefix, path))
+    }
+
+    // Checks an `Authorization: Basic ...` value. bcrypt is deliberately slow, so the hash check
+    // runs on the blocking pool rather than stalling the proxy's worker threads.
+    pub async fn verify(&self, authorization: Option<&str>) -> bool {
+        let Some((user, password)) = authorization.and_then(decode_credentials) else {
+            return false;
+        };
+
+        // Every entry is compared so the position of a match doesn't show up in the timing.
+        let mut matched = None;
+        for (name, hash) in &self.users {
+            if bool::from(name.as_bytes().ct_eq(user.as_bytes())) {
+                matched = Some(hash.clone());
+            }
+        }
+        let known = matched.is_some();
+        let hash = matched.unwrap_or_else(|| self.dummy_hash.clone());
+
+        let valid = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        known && valid
+    }
+}
+
+fn decode_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> BasicAuth {
+        BasicAuth::new(BasicAuthConfig {
+            users: vec![("alice".to_string(), bcrypt::hash("secret", 4).unwrap())],
+            bypass_paths: vec!["/health".to_string()],
+        })
+    }
+
+    fn basic(credentials: &str) -> String {
+        format!("Basic {}", STANDARD.encode(credentials))
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_credentials() {
+        assert!(auth().verify(Some(&basic("alice:secret"))).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_bad_credentials() {
+        let auth = auth();
+        assert!(!auth.verify(Some(&basic("alice:wrong"))).await);
+        assert!(!auth.verify(Some(&basic("bob:secret"))).await);
+        assert!(!auth.verify(Some("Bearer abc")).await);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_credentials() {
+        assert!(!auth().verify(None).await);
+    }
+
+    #[test]
+    fn bypass_ignores_dot_segments() {
+        let auth = auth();
+        assert!(auth.is_bypassed("/health"));
+        assert!(auth.is_bypassed("/health/live"));
+        assert!(!auth.is_bypassed("/healthz"));
+        assert!(!auth.is_bypassed("/health/../admin"));
+        assert!(!auth.is_bypassed("/health/%2e%2e/admin"));
+    }
+}
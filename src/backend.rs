@@ -1,4 +1,38 @@
-use std::time::Instant;
+use pingora_core::protocols::tls::CaType;
+use pingora_core::tls::x509::X509;
+use std::collections::HashMap;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+// Weight of the newest sample in the response latency average.
+const LATENCY_EWMA_ALPHA: f64 = 0.3;
+// Share of its weight a backend starts with at the beginning of its slow-start window.
+const SLOW_START_MIN_FACTOR: f64 = 0.1;
+// Effective weights are in thousandths of a configured weight unit, so the ramp stays smooth for
+// small raw weights like 1 or 2.
+pub const WEIGHT_SCALE: usize = 1000;
+
+// CA certificates a TLS backend's chain is verified against instead of the system roots.
+#[derive(Clone, Debug)]
+pub struct CaBundle {
+    pub path: String,
+    pub certs: Arc<CaType>,
+}
+
+impl CaBundle {
+    // Errors are the reason only; callers name the path.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let pem = fs::read(path).map_err(|e| e.to_string())?;
+        let certs = X509::stack_from_pem(&pem).map_err(|e| format!("not a PEM certificate bundle ({})", e))?;
+        if certs.is_empty() {
+            return Err("no certificate found".to_string());
+        }
+        Ok(Self { path: path.to_string(), certs: Arc::new(certs.into_boxed_slice()) })
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Backend {
@@ -7,4 +41,177 @@ pub struct Backend {
     pub weight: usize,
     pub healthy: bool,
     pub last_checked: Option<Instant>,
-}
\ No newline at end of file
+    pub consecutive_failures: u32,
+    pub consecutive_successes: u32,
+    // Why the latest probe (or passive ejection) failed; kept until the backend is healthy again.
+    pub last_error: Option<String>,
+    // When the health checker last brought this backend back; drives the slow-start ramp.
+    pub healthy_since: Option<Instant>,
+    pub active_connections: Arc<AtomicUsize>,
+    pub passive_failures: Arc<AtomicU32>,
+    // EWMA of response latency in microseconds, stored as f64 bits; 0 until the first response.
+    pub latency_ewma: Arc<AtomicU64>,
+    // Percentage of the weight granted by ADAPTIVE_WEIGHTS from the latency average; 100 otherwise.
+    pub latency_factor: Arc<AtomicU32>,
+    pub tls: bool,
+    pub sni: String,
+    // Certificate checks for TLS backends; unset ones fall back to BACKEND_TLS_VERIFY and
+    // BACKEND_CA_BUNDLE. verify_name is accepted as the certificate's name besides the SNI.
+    pub tls_verify: Option<bool>,
+    pub ca_bundle: Option<CaBundle>,
+    pub verify_name: Option<String>,
+    pub health_path: Option<String>,
+    pub health_success_codes: Option<Vec<u16>>,
+    // Probe cadence for this backend; the health check interval when unset.
+    pub health_interval_secs: Option<u64>,
+    // Added to every request proxied to this backend, e.g. an auth token only it expects.
+    pub request_headers: HashMap<String, String>,
+    // Where a hostname currently resolves, refreshed in the background and shared by every copy of
+    // the backend; an IP literal resolves to itself. Connections take the addresses in turn.
+    pub resolved: Arc<RwLock<Vec<SocketAddr>>>,
+    pub next_resolved: Arc<AtomicUsize>,
+    // Set when the backend is removed through the admin API: it takes no new traffic and isn't
+    // probed, and is dropped once its sessions and requests are done or the drain timeout passes.
+    pub drain_started: Option<Instant>,
+}
+
+impl Backend {
+    pub fn new(host: String, port: u16, weight: usize) -> Self {
+        let resolved = match host.parse::<IpAddr>() {
+            Ok(ip) => vec![SocketAddr::new(ip, port)],
+            Err(_) => Vec::new(),
+        };
+        Self {
+            host,
+            port,
+            weight,
+            healthy: true,
+            last_checked: None,
+            consecutive_failures: 0,
+            consecutive_successes: 0,
+            last_error: None,
+            healthy_since: None,
+            active_connections: Arc::new(AtomicUsize::new(0)),
+            passive_failures: Arc::new(AtomicU32::new(0)),
+            latency_ewma: Arc::new(AtomicU64::new(0)),
+            latency_factor: Arc::new(AtomicU32::new(100)),
+            tls: false,
+            sni: String::new(),
+            tls_verify: None,
+            ca_bundle: None,
+            verify_name: None,
+            health_path: None,
+            health_success_codes: None,
+            health_interval_secs: None,
+            request_headers: HashMap::new(),
+            resolved: Arc::new(RwLock::new(resolved)),
+            next_resolved: Arc::new(AtomicUsize::new(0)),
+            drain_started: None,
+        }
+    }
+
+    pub fn is_hostname(&self) -> bool {
+        self.host.parse::<IpAddr>().is_err()
+    }
+
+    // The next resolved address, rotating through all of a hostname's records.
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        let resolved = self.resolved.read().unwrap();
+        if resolved.is_empty() {
+            return None;
+        }
+        Some(resolved[self.next_resolved.fetch_add(1, Ordering::Relaxed) % resolved.len()])
+    }
+
+    // Like socket_addr, but a hostname that hasn't been resolved yet (just added or reloaded) is
+    // looked up on the runtime's resolver rather than waiting for the next DNS refresh.
+    pub async fn resolve(&self) -> Option<SocketAddr> {
+        if let Some(addr) = self.socket_addr() {
+            return Some(addr);
+        }
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((self.host.as_str(), self.port)).await.ok()?.collect();
+        let first = addrs.first().copied();
+        *self.resolved.write().unwrap() = addrs;
+        first
+    }
+
+    // Authority form of the backend; IPv6 literals are bracketed so the port stays unambiguous.
+    pub fn address(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    // Carries runtime state over from the previous incarnation of this backend (same address)
+    // when the backend list is reloaded, so health and live connection counts survive.
+    pub fn inherit_state(&mut self, previous: &Backend) {
+        self.healthy = previous.healthy;
+        self.last_checked = previous.last_checked;
+        self.consecutive_failures = previous.consecutive_failures;
+        self.consecutive_successes = previous.consecutive_successes;
+        self.last_error = previous.last_error.clone();
+        self.healthy_since = previous.healthy_since;
+        self.active_connections = previous.active_connections.clone();
+        self.passive_failures = previous.passive_failures.clone();
+        self.latency_ewma = previous.latency_ewma.clone();
+        self.latency_factor = previous.latency_factor.clone();
+        self.resolved = previous.resolved.clone();
+        self.next_resolved = previous.next_resolved.clone();
+    }
+
+    // A weight of 0 or a pending removal drains the backend: it keeps serving sticky sessions
+    // already pinned to it but receives no new selections.
+    pub fn is_draining(&self) -> bool {
+        self.weight == 0 || self.drain_started.is_some()
+    }
+
+    // Ramps linearly from SLOW_START_MIN_FACTOR of the configured weight to the full weight over
+    // `slow_start` after the backend recovers. Backends healthy since startup are never ramped.
+    // The full weight is the configured one scaled by the latency factor.
+    pub fn effective_weight(&self, slow_start: Duration) -> usize {
+        let full = self.weight * WEIGHT_SCALE * self.latency_factor.load(Ordering::Relaxed) as usize / 100;
+        let elapsed = match self.healthy_since {
+            Some(since) if !slow_start.is_zero() => since.elapsed(),
+            _ => return full,
+        };
+        if elapsed >= slow_start {
+            return full;
+        }
+        let factor = (elapsed.as_secs_f64() / slow_start.as_secs_f64()).max(SLOW_START_MIN_FACTOR);
+        ((full as f64 * factor).round() as usize).max(1)
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::Relaxed)
+    }
+
+    pub fn latency_ewma(&self) -> f64 {
+        f64::from_bits(self.latency_ewma.load(Ordering::Relaxed))
+    }
+
+    pub fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros() as f64;
+        let _ = self.latency_ewma.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+            let current = f64::from_bits(bits);
+            let next = if current == 0.0 { sample } else { current + LATENCY_EWMA_ALPHA * (sample - current) };
+            Some(next.to_bits())
+        });
+    }
+
+    pub fn track_connection(&self) -> ConnectionGuard {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.active_connections.clone())
+    }
+}
+
+// Decrements the backend's active connection count when dropped, so the count is released on
+// every request exit path (success, error, client disconnect) without explicit bookkeeping.
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
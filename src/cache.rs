@@ -0,0 +1,136 @@
+use bytes::Bytes;
+use log::info;
+use lru::LruCache;
+use pingora_http::{Method, RequestHeader, ResponseHeader};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::CacheConfig;
+
+#[derive(Clone)]
+pub struct CachedResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+    expires_at: Instant,
+}
+
+// A response being buffered on its way to the client, stored once the body completes.
+pub struct CacheFill {
+    pub key: String,
+    pub header: ResponseHeader,
+    pub ttl: Duration,
+    pub body: Vec<u8>,
+}
+
+pub struct ResponseCache {
+    entries: Mutex<LruCache<String, CachedResponse>>,
+    pub max_body_bytes: usize,
+}
+
+impl ResponseCache {
+    pub fn new(config: CacheConfig) -> Self {
+        info!("🗄️ Response cache enabled (max entries: {}, max body: {} bytes)", config.max_entries, config.max_body_bytes);
+        let capacity = NonZeroUsize::new(config.max_entries).unwrap_or(NonZeroUsize::MIN);
+        Self {
+            entries: Mutex::new(LruCache::new(capacity)),
+            max_body_bytes: config.max_body_bytes,
+        }
+    }
+
+    // Only plain GETs are cacheable; anything carrying credentials always goes to the backend.
+    pub fn key(req: &RequestHeader) -> Option<String> {
+        if req.method != Method::GET || req.headers.contains_key("Authorization") {
+            return None;
+        }
+        let host = req
+            .headers
+            .get("Host")
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| req.uri.host())
+            .unwrap_or("");
+        let path = req.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        Some(format!("GET {}{}", host, path))
+    }
+
+    // How long a response may be cached: 200s with a positive `max-age`, unless the backend
+    // opted out or the response sets cookies. The key holds no request headers, so a response
+    // that depends on them (any Vary, or an encoding picked from Accept-Encoding) isn't cached.
+    pub fn ttl(resp: &ResponseHeader) -> Option<Duration> {
+        if resp.status.as_u16() != 200
+            || resp.headers.contains_key("Set-Cookie")
+            || resp.headers.contains_key("Vary")
+            || resp.headers.contains_key("Content-Encoding")
+        {
+            return None;
+        }
+        let cache_control = resp.headers.get("Cache-Control")?.to_str().ok()?.to_lowercase();
+        let mut max_age = None;
+        for directive in cache_control.split(',').map(str::trim) {
+            match directive {
+                "no-store" | "no-cache" | "private" => return None,
+                _ => {
+                    if let Some(secs) = directive.strip_prefix("max-age=") {
+                        max_age = secs.trim_matches('"').parse::<u64>().ok();
+                    }
+                }
+            }
+        }
+        max_age.filter(|secs| *secs > 0).map(Duration::from_secs)
+    }
+
+    pub fn get(&self, key: &str) -> Option<CachedResponse> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get(key) {
+            Some(entry) if entry.expires_at > Instant::now() => Some(entry.clone()),
+            Some(_) => {
+                entries.pop(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, fill: CacheFill) {
+        let mut header = fill.header;
+        // The whole body is stored, so it's replayed with an exact length rather than chunked.
+        header.remove_header("Transfer-Encoding");
+        header.remove_header("Set-Cookie");
+        let _ = header.insert_header("Content-Length", fill.body.len().to_string());
+
+        self.entries.lock().unwrap().put(
+            fill.key,
+            CachedResponse {
+                header,
+                body: Bytes::from(fill.body),
+                expires_at: Instant::now() + fill.ttl,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(headers: &[(&str, &str)]) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in headers {
+            resp.append_header(name.to_string(), *value).unwrap();
+        }
+        resp
+    }
+
+    #[test]
+    fn caches_for_max_age() {
+        let resp = response(&[("Cache-Control", "public, max-age=60")]);
+        assert_eq!(ResponseCache::ttl(&resp), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn skips_responses_that_vary_by_request_headers() {
+        for extra in [("Vary", "Accept-Language"), ("Vary", "*"), ("Content-Encoding", "gzip")] {
+            let resp = response(&[("Cache-Control", "max-age=60"), extra]);
+            assert_eq!(ResponseCache::ttl(&resp), None, "{:?}", extra);
+        }
+    }
+}
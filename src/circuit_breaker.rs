@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::config::CircuitBreakerConfig;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CircuitState {
+    Closed { window_start: Instant, requests: u32, failures: u32 },
+    Open { opened_at: Instant },
+    HalfOpen { probe_started: Instant },
+}
+
+// Per-backend breaker keyed by host:port. Closed counts outcomes over a fixed window and trips to
+// Open once the error rate crosses the threshold; Open blocks selection for the cooldown; HalfOpen
+// lets a single probe request through, whose outcome either closes or re-opens the circuit.
+pub struct CircuitBreaker {
+    config: CircuitBreakerConfig,
+    states: Mutex<HashMap<String, CircuitState>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(config: CircuitBreakerConfig) -> Self {
+        info!(
+            "🔌 Circuit breaker enabled (error threshold: {:.0}%, cooldown: {}s)",
+            config.error_threshold * 100.0,
+            config.cooldown_secs
+        );
+        Self { config, states: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_available(&self, address: &str) -> bool {
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        match self.states.lock().unwrap().get(address) {
+            None | Some(CircuitState::Closed { .. }) => true,
+            Some(CircuitState::Open { opened_at }) => opened_at.elapsed() >= cooldown,
+            // A probe that never reported back (e.g. the client went away) shouldn't wedge the
+            // circuit, so another one is allowed after a further cooldown.
+            Some(CircuitState::HalfOpen { probe_started }) => probe_started.elapsed() >= cooldown,
+        }
+    }
+
+    // Called once a backend has actually been picked; an expired Open circuit becomes HalfOpen
+    // and this request is its probe.
+    pub fn on_selected(&self, address: &str) {
+        let cooldown = Duration::from_secs(self.config.cooldown_secs);
+        let mut states = self.states.lock().unwrap();
+        if let Some(state) = states.get_mut(address) {
+            let probe_due = match state {
+                CircuitState::Open { opened_at } => opened_at.elapsed() >= cooldown,
+                CircuitState::HalfOpen { probe_started } => probe_started.elapsed() >= cooldown,
+                CircuitState::Closed { .. } => false,
+            };
+            if probe_due {
+                info!("🔌 Circuit for {} half-open, sending probe request", address);
+                *state = CircuitState::HalfOpen { probe_started: Instant::now() };
+            }
+        }
+    }
+
+    pub fn record_success(&self, address: &str) {
+        self.record(address, false);
+    }
+
+    pub fn record_failure(&self, address: &str) {
+        self.record(address, true);
+    }
+
+    fn record(&self, address: &str, failed: bool) {
+        let window = Duration::from_secs(self.config.window_secs);
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(address.to_string()).or_insert_with(|| CircuitState::Closed {
+            window_start: Instant::now(),
+            requests: 0,
+            failures: 0,
+        });
+
+        match *state {
+            CircuitState::Closed { window_start, requests, failures } => {
+                let (requests, failures) = if window_start.elapsed() >= window { (0, 0) } else { (requests, failures) };
+                let window_start = if requests == 0 { Instant::now() } else { window_start };
+                let requests = requests + 1;
+                let failures = failures + failed as u32;
+
+                if requests >= self.config.min_requests
+                    && failures as f64 / requests as f64 >= self.config.error_threshold
+                {
+                    warn!("🔌 Circuit for {} opened after {}/{} failed requests", address, failures, requests);
+                    *state = CircuitState::Open { opened_at: Instant::now() };
+                } else {
+                    *state = CircuitState::Closed { window_start, requests, failures };
+                }
+            }
+            CircuitState::HalfOpen { .. } if failed => {
+                warn!("🔌 Probe to {} failed, circuit re-opened", address);
+                *state = CircuitState::Open { opened_at: Instant::now() };
+            }
+            CircuitState::HalfOpen { .. } => {
+                info!("🔌 Probe to {} succeeded, circuit closed", address);
+                *state = CircuitState::Closed { window_start: Instant::now(), requests: 0, failures: 0 };
+            }
+            // Stragglers from requests that started before the circuit opened don't extend it.
+            CircuitState::Open { .. } => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "10.0.0.1:8080";
+
+    fn breaker(cooldown_secs: u64) -> CircuitBreaker {
+        CircuitBreaker::new(CircuitBreakerConfig { error_threshold: 0.5, min_requests: 4, window_secs: 60, cooldown_secs })
+    }
+
+    #[test]
+    fn opens_once_the_error_rate_crosses_the_threshold() {
+        let cb = breaker(60);
+        cb.record_failure(BACKEND);
+        cb.record_failure(BACKEND);
+        cb.record_success(BACKEND);
+        // Below min_requests nothing trips, whatever the rate.
+        assert!(cb.is_available(BACKEND));
+        cb.record_failure(BACKEND);
+        assert!(!cb.is_available(BACKEND));
+    }
+
+    #[test]
+    fn stays_closed_below_the_threshold() {
+        let cb = breaker(60);
+        for _ in 0..3 {
+            cb.record_success(BACKEND);
+        }
+        cb.record_failure(BACKEND);
+        assert!(cb.is_available(BACKEND));
+    }
+
+    #[test]
+    fn probe_outcome_closes_or_reopens_the_circuit() {
+        let cb = breaker(0);
+        for _ in 0..4 {
+            cb.record_failure(BACKEND);
+        }
+        // A zero cooldown makes the probe due straight away.
+        assert!(cb.is_available(BACKEND));
+        cb.on_selected(BACKEND);
+        cb.record_failure(BACKEND);
+        assert!(matches!(cb.states.lock().unwrap()[BACKEND], CircuitState::Open { .. }));
+
+        cb.on_selected(BACKEND);
+        assert!(matches!(cb.states.lock().unwrap()[BACKEND], CircuitState::HalfOpen { .. }));
+        cb.record_success(BACKEND);
+        assert!(matches!(cb.states.lock().unwrap()[BACKEND], CircuitState::Closed { requests: 0, .. }));
+    }
+}
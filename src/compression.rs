@@ -0,0 +1,144 @@
+use brotli::CompressorWriter;
+use flate2::write::{GzDecoder, GzEncoder, ZlibDecoder};
+use flate2::Compression;
+use pingora_http::ResponseHeader;
+use std::io::Write;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    // Prefers brotli, then gzip, among the codings the client accepts (q=0 means refused).
+    pub fn negotiate(accept_encoding: &str) -> Option<Self> {
+        let accepted: Vec<String> = accept_encoding
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';').map(str::trim);
+                let coding = params.next()?.to_lowercase();
+                let refused = params.any(|p| p.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) == Some(0.0));
+                (!refused).then_some(coding)
+            })
+            .collect();
+
+        if accepted.iter().any(|c| c == "br") {
+            Some(Self::Brotli)
+        } else if accepted.iter().any(|c| c == "gzip") {
+            Some(Self::Gzip)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+}
+
+// Text-like bodies of a worthwhile size that the backend hasn't already encoded.
+pub fn should_compress(resp: &ResponseHeader, min_size: usize) -> bool {
+    if resp.headers.contains_key("Content-Encoding") || matches!(resp.status.as_u16(), 100..=199 | 204 | 304) {
+        return false;
+    }
+
+    let content_length = resp
+        .headers
+        .get("Content-Length")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok());
+    if content_length.is_some_and(|len| len < min_size) {
+        return false;
+    }
+
+    let content_type = match resp.headers.get("Content-Type").and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type.to_lowercase(),
+        None => return false,
+    };
+    if content_type.contains("zip") || content_type.contains("compressed") {
+        return false;
+    }
+    content_type.starts_with("text/")
+        || content_type.contains("json")
+        || content_type.contains("javascript")
+        || content_type.contains("xml")
+        || content_type.starts_with("image/svg")
+}
+
+pub enum Compressor {
+    Brotli(Box<CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub fn new(encoding: Encoding) -> Self {
+        match encoding {
+            Encoding::Brotli => Self::Brotli(Box::new(CompressorWriter::new(Vec::new(), 4096, 5, 22))),
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+        }
+    }
+
+    // Feeds a chunk in and hands back whatever compressed output is ready so far.
+    pub fn compress(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let output = match self {
+            Self::Brotli(writer) => {
+                writer.write_all(chunk)?;
+                writer.get_mut()
+            }
+            Self::Gzip(encoder) => {
+                encoder.write_all(chunk)?;
+                encoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli(writer) => Ok(writer.into_inner()),
+            Self::Gzip(encoder) => encoder.finish(),
+        }
+    }
+}
+
+// Undoes a request body's gzip or deflate (zlib) coding on the way to the backend.
+pub enum Decompressor {
+    Gzip(GzDecoder<Vec<u8>>),
+    Deflate(ZlibDecoder<Vec<u8>>),
+}
+
+impl Decompressor {
+    // None for any other coding, including stacked ones like `gzip, br`, which are forwarded as-is.
+    pub fn for_encoding(content_encoding: &str) -> Option<Self> {
+        match content_encoding.trim().to_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(Self::Gzip(GzDecoder::new(Vec::new()))),
+            "deflate" => Some(Self::Deflate(ZlibDecoder::new(Vec::new()))),
+            _ => None,
+        }
+    }
+
+    pub fn decompress(&mut self, chunk: &[u8]) -> std::io::Result<Vec<u8>> {
+        let output = match self {
+            Self::Gzip(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+            Self::Deflate(decoder) => {
+                decoder.write_all(chunk)?;
+                decoder.get_mut()
+            }
+        };
+        Ok(std::mem::take(output))
+    }
+
+    pub fn finish(self) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Gzip(decoder) => decoder.finish(),
+            Self::Deflate(decoder) => decoder.finish(),
+        }
+    }
+}
@@ -1,155 +1,2605 @@
 use std::env;
+use std::fs;
 use std::path::Path;
 use std::process::{self};
 use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+use http::Method;
+use ipnet::IpNet;
 use log::{self, info, warn};
+use regex::Regex;
+use serde::Deserialize;
 
-use crate::backend::Backend;
+use crate::backend::{Backend, CaBundle};
 use crate::generate_ssl::generate_cert;
+use crate::health_check::HealthCheckMode;
 use crate::load_balancer::LoadBalanceStrategy;
+use crate::routing::{PathRewrite, UpstreamHost, DEFAULT_POOL};
+
+// What's wrong with the configuration, reported as a single line at startup (or when a SIGHUP
+// reload is rejected) instead of a panic.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    // A required setting is unset or empty.
+    Missing(String),
+    // A setting whose value doesn't parse or is out of range.
+    Invalid { name: String, value: String, expected: String },
+    // An entry of a backend list that isn't host:port[:weight[:tls[:sni[:health_path]]]].
+    Backend { source: String, entry: String },
+    // A file the configuration points at (the TOML file, certificates, pages) can't be read or parsed.
+    File { path: String, reason: String },
+    // Settings that are each valid but don't go together.
+    Conflict(String),
+}
+
+impl ConfigError {
+    fn invalid(name: impl Into<String>, value: impl Into<String>, expected: impl Into<String>) -> Self {
+        ConfigError::Invalid { name: name.into(), value: value.into(), expected: expected.into() }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Missing(name) => write!(f, "{} must be set and not empty", name),
+            ConfigError::Invalid { name, value, expected } => {
+                write!(f, "Invalid {} '{}', expected {}", name, value, expected)
+            }
+            ConfigError::Backend { source, entry } => write!(
+                f,
+                "Invalid backend '{}' in {}, expected host:port[:weight[:tls[:sni[:health_path]]]]",
+                entry, source
+            ),
+            ConfigError::File { path, reason } => write!(f, "Failed to read {}: {}", path, reason),
+            ConfigError::Conflict(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+// Reads an optional env var that must parse when set, e.g. env_parsed("ADMIN_PORT", "a port number").
+fn env_parsed<T: std::str::FromStr>(name: &str, expected: &str) -> Result<Option<T>, ConfigError> {
+    match env::var(name) {
+        Ok(value) => value.trim().parse().map(Some).map_err(|_| ConfigError::invalid(name, value, expected)),
+        Err(_) => Ok(None),
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct HealthCheckConfig {
     pub enabled: bool,
+    pub mode: HealthCheckMode,
     pub path: String,
+    pub host: Option<String>,
+    pub headers: HashMap<String, String>,
+    pub tls_verify: bool,
     pub interval_secs: u64,
+    // Each cycle sleeps interval_secs ± this percentage, so pools don't probe in lockstep.
+    pub jitter_percent: u32,
     pub timeout_secs: u64,
+    // Per-phase probe timeouts; each falls back to timeout_secs.
+    pub connect_timeout_ms: Option<u64>,
+    pub read_timeout_ms: Option<u64>,
+    // Idle probe connections kept per backend between cycles (0 disables reuse) and how long they
+    // may sit idle; the timeout defaults to twice the interval.
+    pub pool_max_idle: usize,
+    pub pool_idle_timeout_secs: Option<u64>,
+    // Extra attempts within one probe when it errors (not on a bad status), doubling the backoff
+    // each time, before the cycle counts a failure.
+    pub retries: u32,
+    pub retry_backoff_ms: u64,
     pub success_codes: Vec<u16>,
+    // Checked on top of the status code, against at most the first 64 KiB of the body.
+    pub expect_body: Option<BodyMatch>,
+    pub passive_failure_threshold: u32,
+    pub unhealthy_threshold: u32,
+    pub healthy_threshold: u32,
+}
+
+// A plain substring, or a regex when written as `regex:<pattern>`.
+#[derive(Debug, Clone)]
+pub enum BodyMatch {
+    Contains(String),
+    Pattern(Regex),
+}
+
+impl BodyMatch {
+    pub fn is_match(&self, body: &str) -> bool {
+        match self {
+            BodyMatch::Contains(needle) => body.contains(needle.as_str()),
+            BodyMatch::Pattern(pattern) => pattern.is_match(body),
+        }
+    }
+}
+
+// Bounds are percentages of each backend's configured weight.
+#[derive(Debug, Clone)]
+pub struct AdaptiveWeightsConfig {
+    pub interval_secs: u64,
+    pub min_percent: u32,
+    pub max_percent: u32,
+}
+
+// Unset caps are unlimited.
+#[derive(Debug, Clone)]
+pub struct ConnectionLimitConfig {
+    pub max: Option<usize>,
+    pub per_ip: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct CircuitBreakerConfig {
+    pub error_threshold: f64,
+    pub min_requests: u32,
+    pub window_secs: u64,
+    pub cooldown_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct OutlierConfig {
+    pub error_pct: f64,
+    pub min_requests: u32,
+    pub window_secs: u64,
+    pub base_ejection_secs: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MirrorConfig {
+    pub backends: Vec<Backend>,
+    pub percent: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct MaintenanceConfig {
+    pub enabled: bool,
+    pub page_path: Option<String>,
+    pub retry_after_secs: u64,
+    pub allow_ips: Vec<IpNet>,
+    pub bypass_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamTimeouts {
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration,
+    // How long a pooled keep-alive connection may sit unused; None keeps it until the backend closes it.
+    pub idle: Option<Duration>,
+}
+
+// Certificate checks for TLS backends that don't set their own.
+#[derive(Debug, Clone)]
+pub struct BackendTlsConfig {
+    pub verify: bool,
+    pub ca_bundle: Option<CaBundle>,
+}
+
+// A plain-HTTP listener on a Unix socket, for sidecars on the same host.
+#[derive(Debug, Clone)]
+pub struct UdsListenerConfig {
+    pub path: String,
+    pub mode: u32,
+    // Skips the TCP proxy listeners, leaving the socket as the only way in.
+    pub only: bool,
+}
+
+// TCP keepalive probes on client and upstream sockets, to notice peers that vanished without a FIN.
+#[derive(Debug, Clone, Copy)]
+pub struct TcpKeepaliveConfig {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub probes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CacheConfig {
+    pub max_entries: usize,
+    pub max_body_bytes: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    pub min_size: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct HstsConfig {
+    pub max_age_secs: u64,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct SecurityHeadersConfig {
+    // Only sent when SSL is on.
+    pub hsts: Option<HstsConfig>,
+    pub content_type_options: bool,
+    pub frame_options: Option<String>,
+    pub referrer_policy: Option<String>,
+    // Replace values the backend already set instead of keeping them.
+    pub override_backend: bool,
+}
+
+// Rewrites every value of `header`, on requests to the backends and on responses to clients.
+#[derive(Debug, Clone)]
+pub struct HeaderRewriteConfig {
+    pub header: String,
+    pub pattern: Regex,
+    // May refer to capture groups as $1 or ${name}.
+    pub replacement: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PathAction {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+pub struct PathRuleConfig {
+    pub action: PathAction,
+    pub pattern: Regex,
+    // Sent when a deny rule matches: 403 or 404.
+    pub status: u16,
+}
+
+#[derive(Debug, Clone)]
+pub struct BasicAuthConfig {
+    pub users: Vec<(String, String)>,
+    pub bypass_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct JwtConfig {
+    pub secret: Option<String>,
+    pub jwks_url: Option<String>,
+    pub jwks_refresh_secs: u64,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // (claim, header) pairs copied onto the upstream request.
+    pub forward_claims: Vec<(String, String)>,
+    pub bypass_paths: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct IpFilterConfig {
+    pub allow: Vec<IpNet>,
+    pub deny: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogRotation {
+    Size(u64),
+    Daily,
+    Hourly,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessLogConfig {
+    pub path: String,
+    pub rotation: LogRotation,
+    pub max_files: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct AcmeConfig {
+    pub domains: Vec<String>,
+    pub email: String,
+    pub directory_url: String,
+    pub http_port: u16,
+    pub renew_before_days: i32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ListenerConfig {
+    pub port: u16,
+    // None follows SSL, so a bare port behaves as the single PROXY_PORT always has.
+    pub tls: Option<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct SniCertConfig {
+    // Exact hostname, or `*.example.com` for one level of subdomains.
+    pub domain: String,
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CertKeyAlg {
+    EcdsaP256,
+    Rsa2048,
+    Rsa4096,
+}
+
+#[derive(Debug, Clone)]
+pub struct SelfSignedConfig {
+    pub common_name: String,
+    pub country: String,
+    pub state: String,
+    pub locality: String,
+    pub organization: String,
+    // DNS names and IP addresses; entries that parse as an IP become IP SANs.
+    pub sans: Vec<String>,
+    pub validity_days: i64,
+    pub key_alg: CertKeyAlg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MtlsMode {
+    Optional,
+    Require,
+}
+
+#[derive(Debug, Clone)]
+pub struct MtlsConfig {
+    pub ca_path: String,
+    pub mode: MtlsMode,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsVersion {
+    Tls12,
+    Tls13,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsProfile {
+    // Mozilla intermediate: TLS 1.2 and 1.3 with forward-secret AEAD ciphers.
+    Intermediate,
+    // Mozilla modern: TLS 1.3 only.
+    Modern,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TlsPolicy {
+    pub profile: TlsProfile,
+    pub min_version: TlsVersion,
+}
+
+#[derive(Debug, Clone)]
+pub struct RedirectConfig {
+    pub status: u16,
+    pub http_port: Option<u16>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SameSite {
+    Lax,
+    Strict,
+    None,
+}
+
+impl SameSite {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SameSite::Lax => "Lax",
+            SameSite::Strict => "Strict",
+            SameSite::None => "None",
+        }
+    }
+}
+
+// Attributes of the cookies the proxy sets itself (sticky session, A/B variant).
+#[derive(Debug, Clone)]
+pub struct CookieConfig {
+    // None follows SSL. SameSite=None always sends Secure, since browsers drop it otherwise.
+    pub secure: Option<bool>,
+    pub same_site: SameSite,
+    pub domain: Option<String>,
+    pub path: String,
+}
+
+// Body of the errors the proxy generates itself, for statuses without an ERROR_PAGE_<code> file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    // {"error": "...", "code": N}
+    Json,
+    Plain,
+    Html,
+}
+
+// Hash behind ip_hash, consistent_hash and hashed sticky sessions. Changing it remaps every key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgo {
+    Fnv,
+    // SipHash-2-4 with fixed keys, so positions are the same in every process.
+    SipHash,
+    // For matching the affinity of another load balancer that hashes with CRC-32.
+    Crc32,
+}
+
+// Where a sticky session's backend is remembered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StickyMode {
+    // A server-side session -> backend map, optionally persisted to STICKY_SESSION_STORE.
+    Stateful,
+    // The session id is hashed onto the consistent-hash ring: no state, and affinity survives
+    // restarts as long as the backend set doesn't change.
+    Hashed,
+}
+
+// What a sticky session gets when its backend is still in the pool but unhealthy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StickyFailover {
+    // Pin the session to another healthy backend.
+    Reassign,
+    // Answer 503 and keep the pin, for stateful apps where another backend can't serve the session.
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnmatchedHost {
+    Default,
+    NotFound,
+    BadGateway,
+}
+
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub path_prefixes: Vec<String>,
+    pub path_rewrite: Option<PathRewrite>,
+    pub backends: Vec<Backend>,
+    pub load_balance_strategy: LoadBalanceStrategy,
+    // Set when the pool overrides any upstream timeout; fields it leaves out keep the global value.
+    pub upstream_timeouts: Option<UpstreamTimeouts>,
+    pub upstream_host: Option<UpstreamHost>,
+    pub max_concurrency: Option<usize>,
+}
+
+// One arm of an A/B split: `percent` of clients are sent to `pool`.
+#[derive(Debug, Clone)]
+pub struct SplitConfig {
+    pub pool: String,
+    pub percent: u8,
+}
+
+// Sends requests carrying `cookie` with the secret as its value to the split arm `pool`, whatever
+// its percentage, so testers can opt in while random users can't.
+#[derive(Debug, Clone)]
+pub struct CanaryOverrideConfig {
+    pub cookie: String,
+    pub secret: String,
+    pub pool: String,
+}
+
+// Pools chosen by the client's location in a MaxMind database. Country codes (ISO 3166, e.g. DE)
+// are checked before continent codes (e.g. EU); clients matching neither stay on the default pool.
+#[derive(Debug, Clone)]
+pub struct GeoIpConfig {
+    pub db_path: String,
+    pub countries: HashMap<String, String>,
+    pub continents: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct AppConfig {
+    pub backends: Vec<Backend>,
+    pub pools: Vec<PoolConfig>,
+    pub unmatched_host: UnmatchedHost,
+    pub split: Vec<SplitConfig>,
+    pub geoip: Option<GeoIpConfig>,
+    pub load_balance_strategy: LoadBalanceStrategy,
+    pub consistent_hash_vnodes: usize,
+    pub hash_algo: HashAlgo,
+    pub health_check: HealthCheckConfig,
+    pub sticky_cookie_name: String,
+    // Request header that carries the session id for clients without cookies; checked before the cookie.
+    pub sticky_header: Option<String>,
+    pub sticky_cookie: CookieConfig,
+    pub sticky_session_ttl: u64,
+    pub sticky_session_store: Option<String>,
+    pub sticky_failover: StickyFailover,
+    pub sticky_mode: StickyMode,
+    pub custom_headers: HashMap<String, String>,
+    pub remove_headers: Vec<String>,
+    pub custom_request_headers: HashMap<String, String>,
+    pub remove_request_headers: Vec<String>,
+    pub header_rewrites: Vec<HeaderRewriteConfig>,
+    // Checked in order against the request path; the first match decides.
+    pub path_rules: Vec<PathRuleConfig>,
+    pub cors: Option<CorsConfig>,
+    // Status code -> file served as the body of proxy-generated errors.
+    pub error_pages: HashMap<u16, String>,
+    // Extra certificates selected by SNI; ssl/server.pem stays the fallback.
+    pub certificates: Vec<SniCertConfig>,
+    pub listeners: Vec<ListenerConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlConfig {
+    load_balance_strategy: Option<String>,
+    consistent_hash_vnodes: Option<usize>,
+    hash_algo: Option<String>,
+    backends: Option<Vec<TomlBackend>>,
+    health_check: Option<TomlHealthCheck>,
+    sticky: Option<TomlSticky>,
+    headers: Option<TomlHeaders>,
+    routing: Option<TomlRouting>,
+    geoip: Option<TomlGeoIp>,
+    path_rules: Option<Vec<TomlPathRule>>,
+    pools: Option<Vec<TomlPool>>,
+    cors: Option<TomlCors>,
+    error_pages: Option<HashMap<String, String>>,
+    certificates: Option<Vec<TomlCertificate>>,
+    listeners: Option<Vec<TomlListener>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlRouting {
+    unmatched: Option<String>,
+    split: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlGeoIp {
+    db: String,
+    #[serde(default)]
+    countries: HashMap<String, String>,
+    #[serde(default)]
+    continents: HashMap<String, String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPool {
+    name: String,
+    #[serde(default)]
+    hosts: Vec<String>,
+    #[serde(default)]
+    path_prefixes: Vec<String>,
+    strip_prefix: Option<bool>,
+    replace_prefix: Option<String>,
+    load_balance_strategy: Option<String>,
+    connect_timeout_ms: Option<u64>,
+    read_timeout_ms: Option<u64>,
+    write_timeout_ms: Option<u64>,
+    upstream_host: Option<String>,
+    max_concurrency: Option<usize>,
+    backends: Vec<TomlBackend>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlBackend {
+    host: String,
+    port: u16,
+    weight: Option<usize>,
+    tls: Option<bool>,
+    sni: Option<String>,
+    health_path: Option<String>,
+    success_codes: Option<Vec<u16>>,
+    health_interval_secs: Option<u64>,
+    headers: Option<HashMap<String, String>>,
+    tls_verify: Option<bool>,
+    ca_bundle: Option<String>,
+    verify_name: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlHealthCheck {
+    enabled: Option<bool>,
+    mode: Option<String>,
+    path: Option<String>,
+    host: Option<String>,
+    headers: Option<HashMap<String, String>>,
+    tls_verify: Option<bool>,
+    interval_secs: Option<u64>,
+    jitter_percent: Option<u32>,
+    timeout_secs: Option<u64>,
+    connect_timeout_ms: Option<u64>,
+    read_timeout_ms: Option<u64>,
+    pool_max_idle: Option<usize>,
+    pool_idle_timeout_secs: Option<u64>,
+    retries: Option<u32>,
+    retry_backoff_ms: Option<u64>,
+    success_codes: Option<Vec<u16>>,
+    expect_body: Option<String>,
+    passive_failure_threshold: Option<u32>,
+    unhealthy_threshold: Option<u32>,
+    healthy_threshold: Option<u32>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlSticky {
+    cookie_name: Option<String>,
+    header: Option<String>,
+    secure: Option<bool>,
+    same_site: Option<String>,
+    domain: Option<String>,
+    path: Option<String>,
+    ttl_secs: Option<u64>,
+    store: Option<String>,
+    failover: Option<String>,
+    mode: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlHeaders {
+    custom: Option<HashMap<String, String>>,
+    remove: Option<Vec<String>>,
+    request_custom: Option<HashMap<String, String>>,
+    request_remove: Option<Vec<String>>,
+    rewrite: Option<Vec<TomlHeaderRewrite>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlHeaderRewrite {
+    header: String,
+    pattern: String,
+    replacement: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlPathRule {
+    action: String,
+    pattern: String,
+    status: Option<u16>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlListener {
+    port: u16,
+    tls: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlCertificate {
+    domain: String,
+    cert_path: String,
+    key_path: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlCors {
+    allowed_origins: Vec<String>,
+    allowed_methods: Option<Vec<String>>,
+    allowed_headers: Option<Vec<String>>,
+    allow_credentials: Option<bool>,
+    max_age_secs: Option<u64>,
+}
+
+pub fn load_from_env() -> Result<AppConfig, ConfigError> {
+    Ok(AppConfig {
+        backends: load_backends()?,
+        pools: Vec::new(),
+        unmatched_host: UnmatchedHost::Default,
+        split: load_traffic_split()?,
+        geoip: load_geoip_config()?,
+        load_balance_strategy: load_balance_strategy(),
+        consistent_hash_vnodes: load_consistent_hash_vnodes(),
+        hash_algo: load_hash_algo()?,
+        health_check: load_health_check_config()?,
+        sticky_cookie_name: load_sticky_cookie_name(),
+        sticky_header: load_sticky_header(),
+        sticky_cookie: load_sticky_cookie_config()?,
+        sticky_session_ttl: load_sticky_session_ttl(),
+        sticky_session_store: load_sticky_session_store(),
+        sticky_failover: load_sticky_failover()?,
+        sticky_mode: load_sticky_mode()?,
+        custom_headers: load_custom_headers(),
+        remove_headers: load_remove_headers(),
+        custom_request_headers: load_custom_request_headers(),
+        remove_request_headers: load_remove_request_headers(),
+        header_rewrites: load_header_rewrites()?,
+        path_rules: load_path_rules()?,
+        cors: load_cors_config(),
+        error_pages: load_error_pages(),
+        certificates: load_sni_certs()?,
+        listeners: load_listeners()?,
+    })
+}
+
+// Values present in the file take precedence; anything omitted falls back to the env loaders,
+// so a TOML file can override just part of an env-based deployment.
+pub fn load_from_toml(path: &str) -> Result<AppConfig, ConfigError> {
+    let file_error = |reason: String| ConfigError::File { path: path.to_string(), reason };
+    let content = fs::read_to_string(path).map_err(|e| file_error(e.to_string()))?;
+    let file: TomlConfig = toml::from_str(&content).map_err(|e| file_error(e.to_string()))?;
+
+    let backends = match file.backends {
+        Some(entries) => toml_backends(entries, path, "backends")?,
+        None => load_backends()?,
+    };
+
+    let load_balance_strategy = match file.load_balance_strategy {
+        Some(name) => LoadBalanceStrategy::_from_str(&name)
+            .ok_or_else(|| ConfigError::invalid(format!("load_balance_strategy in {}", path), name, "a LOAD_BALANCE_STRATEGY name"))?,
+        None => load_balance_strategy(),
+    };
+
+    let mut health_check = load_health_check_config()?;
+    if let Some(hc) = file.health_check {
+        if let Some(enabled) = hc.enabled { health_check.enabled = enabled; }
+        if let Some(mode) = hc.mode {
+            health_check.mode = HealthCheckMode::_from_str(&mode)
+                .ok_or_else(|| ConfigError::invalid(format!("health_check.mode in {}", path), mode, "http or tcp"))?;
+        }
+        if let Some(path) = hc.path { health_check.path = path; }
+        if let Some(host) = hc.host { health_check.host = Some(host); }
+        if let Some(headers) = hc.headers { health_check.headers = headers; }
+        if let Some(tls_verify) = hc.tls_verify { health_check.tls_verify = tls_verify; }
+        if let Some(interval_secs) = hc.interval_secs { health_check.interval_secs = interval_secs; }
+        if let Some(jitter_percent) = hc.jitter_percent { health_check.jitter_percent = jitter_percent.min(100); }
+        if let Some(timeout_secs) = hc.timeout_secs { health_check.timeout_secs = timeout_secs; }
+        if let Some(timeout_ms) = hc.connect_timeout_ms { health_check.connect_timeout_ms = Some(timeout_ms); }
+        if let Some(timeout_ms) = hc.read_timeout_ms { health_check.read_timeout_ms = Some(timeout_ms); }
+        if let Some(pool_max_idle) = hc.pool_max_idle { health_check.pool_max_idle = pool_max_idle; }
+        if let Some(timeout_secs) = hc.pool_idle_timeout_secs { health_check.pool_idle_timeout_secs = Some(timeout_secs); }
+        if let Some(retries) = hc.retries { health_check.retries = retries; }
+        if let Some(backoff_ms) = hc.retry_backoff_ms { health_check.retry_backoff_ms = backoff_ms; }
+        if let Some(success_codes) = hc.success_codes.filter(|codes| !codes.is_empty()) {
+            health_check.success_codes = success_codes;
+        }
+        if let Some(expect_body) = hc.expect_body {
+            health_check.expect_body = parse_body_match(&expect_body, &format!("health_check.expect_body in {}", path))?;
+        }
+        if let Some(threshold) = hc.passive_failure_threshold { health_check.passive_failure_threshold = threshold; }
+        if let Some(threshold) = hc.unhealthy_threshold { health_check.unhealthy_threshold = threshold.max(1); }
+        if let Some(threshold) = hc.healthy_threshold { health_check.healthy_threshold = threshold.max(1); }
+    }
+
+    let mut pools: Vec<PoolConfig> = Vec::new();
+    for pool in file.pools.unwrap_or_default() {
+        if pool.name == DEFAULT_POOL || pools.iter().any(|p| p.name == pool.name) {
+            return Err(ConfigError::Conflict(format!("Duplicate or reserved pool name '{}' in {}", pool.name, path)));
+        }
+        let strategy = match pool.load_balance_strategy {
+            Some(name) => LoadBalanceStrategy::_from_str(&name).ok_or_else(|| {
+                ConfigError::invalid(format!("load_balance_strategy for pool '{}' in {}", pool.name, path), name, "a LOAD_BALANCE_STRATEGY name")
+            })?,
+            None => load_balance_strategy,
+        };
+        if pool.hosts.is_empty() && pool.path_prefixes.is_empty() {
+            return Err(ConfigError::Missing(format!("hosts or path_prefixes of pool '{}' in {}", pool.name, path)));
+        }
+        let path_rewrite = match (pool.strip_prefix.unwrap_or(false), pool.replace_prefix) {
+            (true, Some(_)) => {
+                return Err(ConfigError::Conflict(format!("Pool '{}' in {} sets both strip_prefix and replace_prefix", pool.name, path)))
+            }
+            (true, None) => Some(PathRewrite::Strip),
+            (false, Some(replacement)) => Some(PathRewrite::Replace(replacement)),
+            (false, None) => None,
+        };
+        let upstream_timeouts = match (pool.connect_timeout_ms, pool.read_timeout_ms, pool.write_timeout_ms) {
+            (None, None, None) => None,
+            (connect, read, write) => {
+                let global = load_upstream_timeouts();
+                Some(UpstreamTimeouts {
+                    connect: connect.map(Duration::from_millis).unwrap_or(global.connect),
+                    read: read.map(Duration::from_millis).unwrap_or(global.read),
+                    write: write.map(Duration::from_millis).unwrap_or(global.write),
+                    idle: global.idle,
+                })
+            }
+        };
+        let upstream_host = match pool.upstream_host {
+            Some(value) => Some(parse_upstream_host(&value, &format!("upstream_host for pool '{}' in {}", pool.name, path))?),
+            None => load_upstream_host()?,
+        };
+        let max_concurrency = match pool.max_concurrency {
+            Some(0) => return Err(ConfigError::invalid(format!("max_concurrency of pool '{}' in {}", pool.name, path), "0", "a positive number")),
+            Some(max) => Some(max),
+            None => load_backend_max_concurrency()?,
+        };
+        pools.push(PoolConfig {
+            upstream_timeouts,
+            upstream_host,
+            max_concurrency,
+            path_rewrite,
+            backends: toml_backends(pool.backends, path, &format!("pools.{}.backends", pool.name))?,
+            hosts: pool.hosts.iter().map(|h| h.trim().to_lowercase()).collect(),
+            // `/api/*` and `/api` mean the same thing.
+            path_prefixes: pool
+                .path_prefixes
+                .iter()
+                .map(|p| p.trim().trim_end_matches('*').to_string())
+                .map(|p| if p.len() > 1 { p.trim_end_matches('/').to_string() } else { p })
+                .collect(),
+            name: pool.name,
+            load_balance_strategy: strategy,
+        });
+    }
+
+    let routing = file.routing.unwrap_or_default();
+    let unmatched_host = match routing.unmatched.as_deref() {
+        None | Some("default") => UnmatchedHost::Default,
+        Some("404") => UnmatchedHost::NotFound,
+        Some("502") => UnmatchedHost::BadGateway,
+        Some(other) => {
+            return Err(ConfigError::invalid(format!("routing.unmatched in {}", path), other, "\"default\", \"404\" or \"502\""))
+        }
+    };
+
+    let mut error_pages = load_error_pages();
+    for (code, page) in file.error_pages.unwrap_or_default() {
+        let code = code
+            .parse::<u16>()
+            .ok()
+            .filter(|code| (400..600).contains(code))
+            .ok_or_else(|| ConfigError::invalid(format!("error_pages key in {}", path), code, "a 4xx/5xx status code"))?;
+        error_pages.insert(code, page);
+    }
+
+    let sticky = file.sticky.unwrap_or_default();
+    let mut sticky_cookie = load_sticky_cookie_config()?;
+    if let Some(secure) = sticky.secure { sticky_cookie.secure = Some(secure); }
+    if let Some(same_site) = &sticky.same_site {
+        sticky_cookie.same_site = parse_same_site(same_site, &format!("sticky.same_site in {}", path))?;
+    }
+    if let Some(domain) = sticky.domain.clone().filter(|d| !d.is_empty()) { sticky_cookie.domain = Some(domain); }
+    if let Some(cookie_path) = sticky.path.clone().filter(|p| !p.is_empty()) { sticky_cookie.path = cookie_path; }
+    let headers = file.headers.unwrap_or_default();
+
+    info!("📄 Loaded configuration from {}", path);
+
+    let split = match routing.split {
+        Some(entries) => parse_traffic_split(&entries, &format!("routing.split in {}", path))?,
+        None => load_traffic_split()?,
+    };
+    if let Some(arm) = split.iter().find(|arm| arm.pool != DEFAULT_POOL && !pools.iter().any(|p| p.name == arm.pool)) {
+        return Err(ConfigError::Conflict(format!("routing.split in {} names unknown pool '{}'", path, arm.pool)));
+    }
+
+    let geoip = match file.geoip {
+        Some(geoip) => Some(GeoIpConfig {
+            db_path: geoip.db,
+            countries: region_codes(geoip.countries),
+            continents: region_codes(geoip.continents),
+        }),
+        None => load_geoip_config()?,
+    };
+    if let Some(geoip) = &geoip {
+        let unknown = geoip.countries.values().chain(geoip.continents.values())
+            .find(|pool| *pool != DEFAULT_POOL && !pools.iter().any(|p| &p.name == *pool));
+        if let Some(pool) = unknown {
+            return Err(ConfigError::Conflict(format!("geoip in {} names unknown pool '{}'", path, pool)));
+        }
+    }
+
+    Ok(AppConfig {
+        backends,
+        pools,
+        unmatched_host,
+        split,
+        geoip,
+        load_balance_strategy,
+        consistent_hash_vnodes: file.consistent_hash_vnodes.filter(|v| *v > 0).unwrap_or_else(load_consistent_hash_vnodes),
+        hash_algo: match &file.hash_algo {
+            Some(algo) => parse_hash_algo(algo, &format!("hash_algo in {}", path))?,
+            None => load_hash_algo()?,
+        },
+        health_check,
+        sticky_cookie_name: sticky.cookie_name.unwrap_or_else(load_sticky_cookie_name),
+        sticky_header: sticky.header.filter(|h| !h.trim().is_empty()).or_else(load_sticky_header),
+        sticky_cookie,
+        sticky_session_ttl: sticky.ttl_secs.unwrap_or_else(load_sticky_session_ttl),
+        sticky_session_store: sticky.store.or_else(load_sticky_session_store),
+        sticky_failover: match &sticky.failover {
+            Some(failover) => parse_sticky_failover(failover, &format!("sticky.failover in {}", path))?,
+            None => load_sticky_failover()?,
+        },
+        sticky_mode: match &sticky.mode {
+            Some(mode) => parse_sticky_mode(mode, &format!("sticky.mode in {}", path))?,
+            None => load_sticky_mode()?,
+        },
+        custom_headers: headers.custom.unwrap_or_else(load_custom_headers),
+        remove_headers: headers.remove.unwrap_or_else(load_remove_headers),
+        custom_request_headers: headers.request_custom.unwrap_or_else(load_custom_request_headers),
+        remove_request_headers: headers.request_remove.unwrap_or_else(load_remove_request_headers),
+        header_rewrites: match headers.rewrite {
+            Some(rules) => header_rewrites(rules, &format!("headers.rewrite in {}", path))?,
+            None => load_header_rewrites()?,
+        },
+        path_rules: match file.path_rules {
+            Some(rules) => path_rules(rules, &format!("path_rules in {}", path))?,
+            None => load_path_rules()?,
+        },
+        cors: match file.cors {
+            Some(cors) if cors.allowed_origins.is_empty() => {
+                return Err(ConfigError::Missing(format!("cors.allowed_origins in {}", path)))
+            }
+            Some(cors) => Some(CorsConfig {
+                allowed_origins: cors.allowed_origins,
+                allowed_methods: cors.allowed_methods.unwrap_or_else(default_cors_methods),
+                allowed_headers: cors.allowed_headers.unwrap_or_else(default_cors_headers),
+                allow_credentials: cors.allow_credentials.unwrap_or(false),
+                max_age_secs: cors.max_age_secs.or(Some(600)),
+            }),
+            None => load_cors_config(),
+        },
+        error_pages,
+        certificates: match file.certificates {
+            Some(entries) => entries
+                .into_iter()
+                .map(|entry| SniCertConfig {
+                    domain: entry.domain.trim().to_lowercase(),
+                    cert_path: entry.cert_path,
+                    key_path: entry.key_path,
+                })
+                .collect(),
+            None => load_sni_certs()?,
+        },
+        listeners: match file.listeners {
+            Some(entries) if entries.is_empty() => return Err(ConfigError::Missing(format!("listeners in {}", path))),
+            Some(entries) => unique_ports(
+                entries.into_iter().map(|entry| ListenerConfig { port: entry.port, tls: entry.tls }).collect(),
+                &format!("listeners in {}", path),
+            )?,
+            None => load_listeners()?,
+        },
+    })
+}
+
+fn toml_backends(entries: Vec<TomlBackend>, path: &str, section: &str) -> Result<Vec<Backend>, ConfigError> {
+    let backends: Vec<Backend> = entries
+        .into_iter()
+        .map(|entry| {
+            let ca_bundle = match entry.ca_bundle {
+                Some(ca_path) => Some(CaBundle::load(&ca_path).map_err(|reason| ConfigError::File { path: ca_path.clone(), reason })?),
+                None => None,
+            };
+            let mut backend = Backend::new(entry.host, entry.port, entry.weight.unwrap_or(1));
+            backend.tls = entry.tls.unwrap_or(false);
+            backend.sni = match entry.sni {
+                Some(sni) => sni,
+                None if backend.tls && backend.host.parse::<std::net::IpAddr>().is_err() => backend.host.clone(),
+                None => String::new(),
+            };
+            backend.health_path = entry.health_path;
+            backend.health_success_codes = entry.success_codes.filter(|codes| !codes.is_empty());
+            backend.health_interval_secs = entry.health_interval_secs;
+            backend.request_headers = entry.headers.unwrap_or_default();
+            backend.tls_verify = entry.tls_verify;
+            backend.ca_bundle = ca_bundle;
+            backend.verify_name = entry.verify_name.filter(|name| !name.is_empty());
+            Ok(backend)
+        })
+        .collect::<Result<_, ConfigError>>()?;
+    if backends.is_empty() {
+        return Err(ConfigError::Missing(format!("{} in {}", section, path)));
+    }
+    warn_if_all_drained(&backends);
+    Ok(backends)
+}
+
+// Re-reads the backend lists from the TOML file when one is in use, otherwise from `.env`
+// (overriding the process env) and the environment. Keyed by pool name.
+pub fn reload_backends(app_conf: Option<&str>) -> Result<HashMap<String, Vec<Backend>>, ConfigError> {
+    match app_conf {
+        Some(path) => {
+            let config = load_from_toml(path)?;
+            let mut pools: HashMap<String, Vec<Backend>> =
+                config.pools.into_iter().map(|pool| (pool.name, pool.backends)).collect();
+            pools.insert(DEFAULT_POOL.to_string(), config.backends);
+            Ok(pools)
+        }
+        None => {
+            dotenvy::dotenv_override().ok();
+            Ok(HashMap::from([(DEFAULT_POOL.to_string(), load_backends()?)]))
+        }
+    }
+}
+
+// Checks every setting up front and returns all the problems at once, so a broken deployment is
+// fixed in one pass instead of one restart per error. Startup doesn't go on unless this is empty.
+pub fn validate(app_conf: Option<&str>) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+    let ssl = ssl_requested();
+
+    let listeners = match app_conf {
+        Some(path) => {
+            let config = collect(load_from_toml(path), &mut errors);
+            if let Some(config) = &config {
+                check_backends(&config.backends, &format!("backends in {}", path), &mut errors);
+                for pool in &config.pools {
+                    check_backends(&pool.backends, &format!("pools.{}.backends in {}", pool.name, path), &mut errors);
+                }
+                check_health_check(&config.health_check, &mut errors);
+                check_certificates(&config.certificates, ssl, &mut errors);
+                for page in config.error_pages.values() {
+                    check_readable(page, &mut errors);
+                }
+                if let Some(geoip) = &config.geoip {
+                    check_readable(&geoip.db_path, &mut errors);
+                }
+            }
+            config.map(|config| config.listeners)
+        }
+        None => {
+            // Every bad entry is reported, not just the first one load_backends runs into.
+            let entries = split_list(&env::var("BACKENDS").unwrap_or_default());
+            if entries.is_empty() {
+                errors.push(ConfigError::Missing("BACKENDS".to_string()));
+            }
+            let mut backends = Vec::new();
+            for entry in entries {
+                match parse_backend(&entry) {
+                    Some(backend) => backends.push(backend),
+                    None => errors.push(ConfigError::Backend { source: "BACKENDS".to_string(), entry }),
+                }
+            }
+            check_backends(&backends, "BACKENDS", &mut errors);
+            if let Ok(name) = env::var("LOAD_BALANCE_STRATEGY") {
+                if LoadBalanceStrategy::_from_str(&name).is_none() {
+                    errors.push(ConfigError::invalid("LOAD_BALANCE_STRATEGY", name, "a strategy listed in .env_example"));
+                }
+            }
+            collect(load_traffic_split(), &mut errors);
+            if let Some(geoip) = collect(load_geoip_config(), &mut errors).flatten() {
+                check_readable(&geoip.db_path, &mut errors);
+                if let Some(pool) = geoip.countries.values().chain(geoip.continents.values()).find(|p| *p != DEFAULT_POOL) {
+                    errors.push(ConfigError::Conflict(format!(
+                        "GEOIP pools name '{}', but only the default pool exists without a TOML config",
+                        pool
+                    )));
+                }
+            }
+            collect(load_sticky_cookie_config(), &mut errors);
+            collect(load_sticky_failover(), &mut errors);
+            collect(load_sticky_mode(), &mut errors);
+            collect(load_hash_algo(), &mut errors);
+            collect(load_path_rules(), &mut errors);
+            if let Some(health_check) = collect(load_health_check_config(), &mut errors) {
+                check_health_check(&health_check, &mut errors);
+            }
+            if let Some(certificates) = collect(load_sni_certs(), &mut errors) {
+                check_certificates(&certificates, ssl, &mut errors);
+            }
+            for page in load_error_pages().values() {
+                check_readable(page, &mut errors);
+            }
+            collect(load_listeners(), &mut errors)
+        }
+    };
+    if let Ok(mode) = env::var("HEALTH_CHECK_MODE") {
+        if HealthCheckMode::_from_str(&mode).is_none() {
+            errors.push(ConfigError::invalid("HEALTH_CHECK_MODE", mode, "http or tcp"));
+        }
+    }
+    if let Some(listeners) = listeners {
+        for listener in &listeners {
+            check_port("PROXY_PORT", listener.port, &mut errors);
+        }
+        let ports: Vec<u16> = listeners.iter().map(|listener| listener.port).collect();
+        if let Some(internal_ports) = collect(load_proxy_protocol_ports(&ports), &mut errors).flatten() {
+            for port in internal_ports {
+                check_port("PROXY_PROTOCOL_INTERNAL_PORT", port, &mut errors);
+            }
+        }
+    }
+
+    if ssl {
+        let autogen = collect(load_ssl_autogen(), &mut errors).unwrap_or(true);
+        for path in ["ssl/server.pem", "ssl/server.key"] {
+            // Missing ones are generated at startup unless SSL_AUTOGEN=off; existing ones have to be usable.
+            if Path::new(path).exists() {
+                check_readable(path, &mut errors);
+            } else if !autogen {
+                errors.push(ConfigError::File {
+                    path: path.to_string(),
+                    reason: "not found, and SSL_AUTOGEN=off forbids generating a self-signed one".to_string(),
+                });
+            }
+        }
+        collect(load_self_signed_config(), &mut errors);
+    }
+
+    collect(get_bind_address(), &mut errors);
+    collect(load_circuit_breaker_config(), &mut errors);
+    collect(load_outlier_config(), &mut errors);
+    collect(load_mirror_config(), &mut errors);
+    collect(load_max_header_bytes(), &mut errors);
+    collect(load_max_body_bytes(), &mut errors);
+    collect(load_max_response_bytes(), &mut errors);
+    collect(load_connection_limits(), &mut errors);
+    collect(load_retry_buffer_bytes(), &mut errors);
+    collect(load_startup_probe_timeout(), &mut errors);
+    collect(load_tcp_keepalive(), &mut errors);
+    collect(load_uds_listener(), &mut errors);
+    collect(load_backend_tls(), &mut errors);
+    collect(load_error_format(), &mut errors);
+    collect(load_allowed_methods(), &mut errors);
+    collect(load_unavailable_retry_after(), &mut errors);
+    collect(load_connect_cooldown(), &mut errors);
+    collect(load_request_deadline(), &mut errors);
+    collect(load_backend_max_concurrency(), &mut errors);
+    collect(load_forwarded_by(), &mut errors);
+    collect(load_via(), &mut errors);
+    collect(load_concurrency_queue_timeout(), &mut errors);
+    collect(load_adaptive_weights(), &mut errors);
+    collect(load_sticky_cookie_secret(), &mut errors);
+    collect(load_retry_methods(), &mut errors);
+    collect(load_dns_refresh_interval(), &mut errors);
+    collect(load_upstream_pool_size(), &mut errors);
+    collect(load_upstream_host(), &mut errors);
+    collect(load_drain_timeout(), &mut errors);
+    collect(load_basic_auth_config(), &mut errors);
+    collect(load_jwt_config(), &mut errors);
+    collect(load_ip_filter_config(), &mut errors);
+    collect(load_trusted_proxies(), &mut errors);
+    collect(load_debug_upstream_ips(), &mut errors);
+    collect(load_access_log_config(), &mut errors);
+    collect(load_mtls_config(), &mut errors);
+    collect(load_tls_policy(), &mut errors);
+    collect(load_tls_alpn(), &mut errors);
+    collect(load_canary_override(), &mut errors);
+    collect(load_security_headers_config(), &mut errors);
+    collect(load_acme_config(), &mut errors);
+    collect(load_ocsp_refresh_interval(), &mut errors);
+    collect(load_shutdown_grace_secs(), &mut errors);
+    collect(load_shutdown_thread_timeout(), &mut errors);
+    if let Some(page) = collect(load_maintenance_config(), &mut errors).and_then(|config| config.page_path) {
+        check_readable(&page, &mut errors);
+    }
+    if let Some(port) = collect(load_redirect_config(), &mut errors).flatten().and_then(|config| config.http_port) {
+        check_port("REDIRECT_HTTP_PORT", port, &mut errors);
+    }
+    if let Ok(Some(port)) = env_parsed::<u16>("ADMIN_PORT", "a port number") {
+        check_port("ADMIN_PORT", port, &mut errors);
+    }
+    collect(get_admin_addr(), &mut errors);
+
+    errors
+}
+
+fn collect<T>(result: Result<T, ConfigError>, errors: &mut Vec<ConfigError>) -> Option<T> {
+    result.map_err(|e| errors.push(e)).ok()
+}
+
+fn check_backends(backends: &[Backend], source: &str, errors: &mut Vec<ConfigError>) {
+    if !backends.is_empty() && backends.iter().all(|b| b.weight == 0) {
+        errors.push(ConfigError::Conflict(format!("Every backend in {} has weight 0, nothing would receive traffic", source)));
+    }
+    for backend in backends {
+        if backend.port == 0 {
+            errors.push(ConfigError::Backend { source: source.to_string(), entry: backend.address() });
+        }
+        if let Some(path) = backend.health_path.as_ref().filter(|path| !path.starts_with('/')) {
+            errors.push(ConfigError::invalid(format!("health path of {}", backend.address()), path, "a path starting with /"));
+        }
+        if backend.health_interval_secs == Some(0) {
+            errors.push(ConfigError::invalid(format!("health interval of {}", backend.address()), "0", "a number of seconds above 0"));
+        }
+    }
+}
+
+fn check_health_check(config: &HealthCheckConfig, errors: &mut Vec<ConfigError>) {
+    if config.mode == HealthCheckMode::Http && !config.path.starts_with('/') {
+        errors.push(ConfigError::invalid("HEALTH_CHECK_PATH", &config.path, "a path starting with /"));
+    }
+}
+
+fn check_certificates(certificates: &[SniCertConfig], ssl: bool, errors: &mut Vec<ConfigError>) {
+    if !ssl {
+        return;
+    }
+    for certificate in certificates {
+        check_readable(&certificate.cert_path, errors);
+        check_readable(&certificate.key_path, errors);
+    }
+}
+
+fn check_port(name: &str, port: u16, errors: &mut Vec<ConfigError>) {
+    if port == 0 {
+        errors.push(ConfigError::invalid(name, "0", "a port between 1 and 65535"));
+    }
+}
+
+fn check_readable(path: &str, errors: &mut Vec<ConfigError>) {
+    if let Err(e) = fs::File::open(path) {
+        errors.push(ConfigError::File { path: path.to_string(), reason: e.to_string() });
+    }
+}
+
+// TRAFFIC_SPLIT=default:95,canary:5 — percentages must add up to 100.
+pub fn load_traffic_split() -> Result<Vec<SplitConfig>, ConfigError> {
+    match env::var("TRAFFIC_SPLIT") {
+        Ok(value) => parse_traffic_split(&split_list(&value), "TRAFFIC_SPLIT"),
+        Err(_) => Ok(Vec::new()),
+    }
+}
+
+fn parse_traffic_split(entries: &[String], source: &str) -> Result<Vec<SplitConfig>, ConfigError> {
+    let split = entries
+        .iter()
+        .map(|entry| {
+            let (pool, percent) = entry
+                .rsplit_once(':')
+                .and_then(|(pool, percent)| Some((pool.trim(), percent.trim().parse::<u8>().ok()?)))
+                .filter(|(pool, percent)| !pool.is_empty() && *percent <= 100)
+                .ok_or_else(|| ConfigError::invalid(format!("{} entry", source), entry, "pool:percent"))?;
+            Ok(SplitConfig { pool: pool.to_string(), percent })
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+
+    let total: u32 = split.iter().map(|arm| arm.percent as u32).sum();
+    if !split.is_empty() && total != 100 {
+        return Err(ConfigError::invalid(format!("{} total", source), total.to_string(), "percentages adding up to 100"));
+    }
+    Ok(split)
+}
+
+// CANARY_OVERRIDE_COOKIE=x-canary=<secret> forces matching requests onto CANARY_OVERRIDE_POOL
+// (canary by default). The secret must be long enough that it can't be guessed.
+pub fn load_canary_override() -> Result<Option<CanaryOverrideConfig>, ConfigError> {
+    let value = match env::var("CANARY_OVERRIDE_COOKIE") {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => return Ok(None),
+    };
+    let (cookie, secret) = value
+        .split_once('=')
+        .map(|(cookie, secret)| (cookie.trim(), secret.trim()))
+        .filter(|(cookie, _)| !cookie.is_empty())
+        .ok_or_else(|| ConfigError::invalid("CANARY_OVERRIDE_COOKIE", &value, "cookie=secret"))?;
+    // Reported by length only, so the secret doesn't end up in logs.
+    if secret.len() < 16 {
+        return Err(ConfigError::invalid(
+            "CANARY_OVERRIDE_COOKIE",
+            format!("{}=<{} characters>", cookie, secret.len()),
+            "a secret of at least 16 characters",
+        ));
+    }
+    let pool = env::var("CANARY_OVERRIDE_POOL").ok().filter(|p| !p.trim().is_empty()).unwrap_or_else(|| "canary".to_string());
+    Ok(Some(CanaryOverrideConfig { cookie: cookie.to_string(), secret: secret.to_string(), pool: pool.trim().to_string() }))
+}
+
+// GEOIP_DB=GeoLite2-Country.mmdb with GEOIP_COUNTRY_POOLS=DE:eu,US:us and/or
+// GEOIP_CONTINENT_POOLS=EU:eu,NA:us. Pools other than default only exist in the TOML config.
+pub fn load_geoip_config() -> Result<Option<GeoIpConfig>, ConfigError> {
+    let db_path = match env::var("GEOIP_DB") {
+        Ok(path) if !path.trim().is_empty() => path.trim().to_string(),
+        _ => return Ok(None),
+    };
+    Ok(Some(GeoIpConfig {
+        db_path,
+        countries: parse_region_pools("GEOIP_COUNTRY_POOLS")?,
+        continents: parse_region_pools("GEOIP_CONTINENT_POOLS")?,
+    }))
+}
+
+fn parse_region_pools(name: &str) -> Result<HashMap<String, String>, ConfigError> {
+    let entries = split_list(&env::var(name).unwrap_or_default())
+        .into_iter()
+        .map(|entry| {
+            entry
+                .split_once(':')
+                .map(|(code, pool)| (code.trim().to_string(), pool.trim().to_string()))
+                .filter(|(code, pool)| !code.is_empty() && !pool.is_empty())
+                .ok_or_else(|| ConfigError::invalid(format!("{} entry", name), &entry, "code:pool"))
+        })
+        .collect::<Result<HashMap<_, _>, ConfigError>>()?;
+    Ok(region_codes(entries))
+}
+
+// The database stores codes in upper case.
+fn region_codes(entries: HashMap<String, String>) -> HashMap<String, String> {
+    entries.into_iter().map(|(code, pool)| (code.to_uppercase(), pool)).collect()
+}
+
+pub fn load_balance_strategy() -> LoadBalanceStrategy {
+    let strategy_str = env::var("LOAD_BALANCE_STRATEGY")
+        .unwrap_or_else(|_| "weighted".to_string())
+        .to_lowercase();
+    
+    match strategy_str.as_str() {
+        "round_robin" | "round-robin" | "roundrobin" => LoadBalanceStrategy::RoundRobin,
+        "weighted" => LoadBalanceStrategy::Weighted,
+        "least_connections" | "least-connections" | "leastconnections" => LoadBalanceStrategy::LeastConnections,
+        "weighted_least_connections" | "weighted-least-connections" | "weightedleastconnections" => {
+            LoadBalanceStrategy::WeightedLeastConnections
+        }
+        "sticky_session" | "sticky-session" | "stickysession" => LoadBalanceStrategy::StickySession,
+        "random" => LoadBalanceStrategy::Random,
+        "ip_hash" | "ip-hash" | "iphash" => LoadBalanceStrategy::IpHash,
+        "consistent_hash" | "consistent-hash" | "consistenthash" => LoadBalanceStrategy::ConsistentHash,
+        "p2c" | "power_of_two" | "power-of-two" | "poweroftwo" => LoadBalanceStrategy::P2C,
+        "least_response_time" | "least-response-time" | "leastresponsetime" => LoadBalanceStrategy::LeastResponseTime,
+        _ => {
+            warn!("⚠️ Unknown load balance strategy '{}', defaulting to 'weighted'", strategy_str);
+            LoadBalanceStrategy::Weighted
+        }
+    }
+}
+
+pub fn load_sticky_cookie_name() -> String {
+    env::var("STICKY_COOKIE_NAME")
+        .unwrap_or_else(|_| "PINGORA_SESSION".to_string())
+}
+
+pub fn load_sticky_header() -> Option<String> {
+    env::var("STICKY_HEADER")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+}
+
+// STICKY_COOKIE_SECURE defaults to following SSL; STICKY_COOKIE_SAMESITE is lax, strict or none.
+pub fn load_sticky_cookie_config() -> Result<CookieConfig, ConfigError> {
+    let secure = env::var("STICKY_COOKIE_SECURE")
+        .ok()
+        .filter(|v| !v.trim().is_empty() && !v.eq_ignore_ascii_case("auto"))
+        .map(|v| v.to_lowercase() == "true");
+    let same_site = match env::var("STICKY_COOKIE_SAMESITE") {
+        Ok(value) => parse_same_site(&value, "STICKY_COOKIE_SAMESITE")?,
+        Err(_) => SameSite::Lax,
+    };
+
+    Ok(CookieConfig {
+        secure,
+        same_site,
+        domain: env::var("STICKY_COOKIE_DOMAIN").ok().filter(|v| !v.trim().is_empty()),
+        path: env::var("STICKY_COOKIE_PATH").ok().filter(|v| !v.trim().is_empty()).unwrap_or_else(|| "/".to_string()),
+    })
+}
+
+fn parse_same_site(value: &str, name: &str) -> Result<SameSite, ConfigError> {
+    match value.trim().to_lowercase().as_str() {
+        "lax" => Ok(SameSite::Lax),
+        "strict" => Ok(SameSite::Strict),
+        "none" => Ok(SameSite::None),
+        _ => Err(ConfigError::invalid(name, value, "lax, strict or none")),
+    }
+}
+
+// Unset leaves sticky session ids unsigned. Short secrets make the HMAC guessable, so 16 bytes is the minimum.
+pub fn load_sticky_cookie_secret() -> Result<Option<String>, ConfigError> {
+    match env::var("STICKY_COOKIE_SECRET") {
+        Ok(secret) if secret.trim().is_empty() => Ok(None),
+        Ok(secret) if secret.len() < 16 => Err(ConfigError::invalid("STICKY_COOKIE_SECRET", "<redacted>", "at least 16 bytes")),
+        Ok(secret) => Ok(Some(secret)),
+        Err(_) => Ok(None),
+    }
+}
+
+pub fn load_sticky_session_ttl() -> u64 {
+    std::env::var("STICKY_SESSION_TTL")
+        .ok()
+        .and_then(|v| v.parse::<u64>().ok())
+        .unwrap_or(3600)
+}
+
+pub fn load_sticky_session_store() -> Option<String> {
+    env::var("STICKY_SESSION_STORE")
+        .ok()
+        .filter(|v| !v.trim().is_empty())
+}
+
+pub fn load_sticky_failover() -> Result<StickyFailover, ConfigError> {
+    match env::var("STICKY_FAILOVER") {
+        Ok(value) if !value.trim().is_empty() => parse_sticky_failover(&value, "STICKY_FAILOVER"),
+        _ => Ok(StickyFailover::Reassign),
+    }
+}
+
+fn parse_sticky_failover(value: &str, name: &str) -> Result<StickyFailover, ConfigError> {
+    match value.trim().to_lowercase().as_str() {
+        "reassign" => Ok(StickyFailover::Reassign),
+        "503" => Ok(StickyFailover::Unavailable),
+        _ => Err(ConfigError::invalid(name, value, "reassign or 503")),
+    }
+}
+
+pub fn load_sticky_mode() -> Result<StickyMode, ConfigError> {
+    match env::var("STICKY_MODE") {
+        Ok(value) if !value.trim().is_empty() => parse_sticky_mode(&value, "STICKY_MODE"),
+        _ => Ok(StickyMode::Stateful),
+    }
+}
+
+fn parse_sticky_mode(value: &str, name: &str) -> Result<StickyMode, ConfigError> {
+    match value.trim().to_lowercase().as_str() {
+        "stateful" => Ok(StickyMode::Stateful),
+        "hashed" => Ok(StickyMode::Hashed),
+        _ => Err(ConfigError::invalid(name, value, "stateful or hashed")),
+    }
+}
+
+pub fn load_consistent_hash_vnodes() -> usize {
+    env::var("CONSISTENT_HASH_VNODES")
+        .ok()
+        .and_then(|v| v.parse::<usize>().ok())
+        .filter(|v| *v > 0)
+        .unwrap_or(100)
+}
+
+pub fn load_hash_algo() -> Result<HashAlgo, ConfigError> {
+    match env::var("HASH_ALGO") {
+        Ok(value) if !value.trim().is_empty() => parse_hash_algo(&value, "HASH_ALGO"),
+        _ => Ok(HashAlgo::Fnv),
+    }
+}
+
+fn parse_hash_algo(value: &str, name: &str) -> Result<HashAlgo, ConfigError> {
+    match value.trim().to_lowercase().as_str() {
+        "fnv" => Ok(HashAlgo::Fnv),
+        "siphash" => Ok(HashAlgo::SipHash),
+        "crc32" => Ok(HashAlgo::Crc32),
+        _ => Err(ConfigError::invalid(name, value, "fnv, siphash or crc32")),
+    }
+}
+
+// Parses `host:port[:weight[:tls[:sni[:health_path]]]]`, where host may be a bracketed IPv6
+// literal such as `[2001:db8::1]:443`. Omitted fields keep the plaintext defaults; with TLS on and
+// no SNI given, a hostname is used as the SNI.
+pub fn parse_backend(entry: &str) -> Option<Backend> {
+    let (host, rest) = if let Some(bracketed) = entry.strip_prefix('[') {
+        let (host, rest) = bracketed.split_once(']')?;
+        (host, rest.strip_prefix(':')?)
+    } else {
+        entry.split_once(':')?
+    };
+
+    if host.is_empty() || (host.contains(':') && host.parse::<std::net::Ipv6Addr>().is_err()) {
+        return None;
+    }
+
+    let parts: Vec<&str> = rest.split(':').collect();
+    let port = parts[0].parse::<u16>().ok()?;
+    let weight = match parts.get(1) {
+        Some(weight) if !weight.is_empty() => weight.parse::<usize>().ok()?,
+        _ => 1, // Default weight
+    };
+
+    let mut backend = Backend::new(host.to_string(), port, weight);
+    backend.tls = parts
+        .get(2)
+        .map(|tls| matches!(tls.to_lowercase().as_str(), "tls" | "https" | "true" | "on" | "1"))
+        .unwrap_or(false);
+    backend.sni = match parts.get(3) {
+        Some(sni) => sni.to_string(),
+        None if backend.tls && backend.host.parse::<std::net::IpAddr>().is_err() => backend.host.clone(),
+        None => String::new(),
+    };
+    backend.health_path = parts
+        .get(4)
+        .filter(|path| !path.is_empty())
+        .map(|path| path.to_string());
+
+    Some(backend)
+}
+
+pub fn load_backends() -> Result<Vec<Backend>, ConfigError> {
+    let mut backends = Vec::new();
+    let mut unparseable = None;
+    // The SRV record supplies them instead; main resolves it before anything needs a backend.
+    if env::var("BACKENDS").is_err() && load_backends_srv().is_some() {
+        return Ok(backends);
+    }
+    
+    if let Ok(val) = env::var("BACKENDS") {
+        for entry in split_list(&val) {
+            match parse_backend(&entry) {
+                Some(backend) => backends.push(backend),
+                None => {
+                    warn!("⚠️ Ignoring unparseable backend entry '{}'", entry);
+                    unparseable.get_or_insert(entry);
+                }
+            }
+        }
+    }
+    
+    if backends.is_empty() {
+        // With nothing usable left, name the bad entry rather than claiming BACKENDS is unset.
+        return Err(match unparseable {
+            Some(entry) => ConfigError::Backend { source: "BACKENDS".to_string(), entry },
+            None => ConfigError::Missing("BACKENDS".to_string()),
+        });
+    }
+    
+    warn_if_all_drained(&backends);
+    Ok(backends)
+}
+
+// Weights are used as given, relative to each other; 0 marks a draining backend.
+fn warn_if_all_drained(backends: &[Backend]) {
+    if backends.iter().all(|b| b.weight == 0) {
+        warn!("⚠️ All backends have weight 0, no backend will receive new traffic");
+    }
+}
+
+pub fn load_health_check_config() -> Result<HealthCheckConfig, ConfigError> {
+    let enabled = env::var("HEALTH_CHECK_ENABLED").unwrap_or_else(|_| "true".to_string()).to_lowercase() == "true";
+    let mode_str = env::var("HEALTH_CHECK_MODE").unwrap_or_else(|_| "http".to_string());
+    let mode = HealthCheckMode::_from_str(&mode_str).unwrap_or_else(|| {
+        warn!("⚠️ Unknown HEALTH_CHECK_MODE '{}', falling back to http", mode_str);
+        HealthCheckMode::Http
+    });
+    let path = env::var("HEALTH_CHECK_PATH").unwrap_or_else(|_| "/health".to_string());
+    let host = env::var("HEALTH_CHECK_HOST").ok().filter(|h| !h.trim().is_empty());
+    let headers = match env::var("HEALTH_CHECK_HEADERS") {
+        Ok(json) if !json.trim().is_empty() => serde_json::from_str(&json).unwrap_or_else(|e| {
+            warn!("⚠️ Ignoring HEALTH_CHECK_HEADERS, expected a JSON object of strings: {}", e);
+            HashMap::new()
+        }),
+        _ => HashMap::new(),
+    };
+    let tls_verify = env::var("HEALTH_CHECK_TLS_VERIFY").unwrap_or_else(|_| "true".to_string()).to_lowercase() == "true";
+    let interval_secs: u64 = env_parsed("HEALTH_CHECK_INTERVAL", "a number of seconds")?.unwrap_or(30);
+    let jitter_percent = env::var("HEALTH_CHECK_JITTER").unwrap_or_else(|_| "10".to_string()).parse::<u32>().unwrap_or(10).min(100);
+    let timeout_secs = env::var("HEALTH_CHECK_TIMEOUT").unwrap_or_else(|_| "5".to_string()).parse().unwrap_or(5);
+    let connect_timeout_ms = env_parsed("HEALTH_CHECK_CONNECT_TIMEOUT", "a number of milliseconds")?;
+    let read_timeout_ms = env_parsed("HEALTH_CHECK_READ_TIMEOUT", "a number of milliseconds")?;
+    let pool_max_idle = env_parsed("HEALTH_CHECK_POOL_MAX_IDLE", "a number of connections")?.unwrap_or(1);
+    let pool_idle_timeout_secs = env_parsed("HEALTH_CHECK_POOL_IDLE_TIMEOUT", "a number of seconds")?;
+    let retries = env_parsed("HEALTH_CHECK_RETRIES", "a number of attempts")?.unwrap_or(0);
+    let retry_backoff_ms = env_parsed("HEALTH_CHECK_RETRY_BACKOFF", "a number of milliseconds")?.unwrap_or(100);
+    let success_codes_str = env::var("HEALTH_CHECK_SUCCESS_CODES").unwrap_or_else(|_| "200".to_string());
+    let (success_codes, dropped) = parse_success_codes(&success_codes_str);
+    if !dropped.is_empty() {
+        warn!("⚠️ Ignoring invalid HEALTH_CHECK_SUCCESS_CODES entries {:?}, expected codes between 100 and 599 or ranges like 200-299", dropped);
+    }
+    if success_codes.is_empty() {
+        warn!("⚠️ No valid HEALTH_CHECK_SUCCESS_CODES, accepting 200 only");
+    }
+    let expect_body = match env::var("HEALTH_CHECK_EXPECT_BODY") {
+        Ok(value) => parse_body_match(&value, "HEALTH_CHECK_EXPECT_BODY")?,
+        Err(_) => None,
+    };
+    let passive_failure_threshold = env::var("PASSIVE_HEALTH_FAILURE_THRESHOLD").unwrap_or_else(|_| "3".to_string()).parse().unwrap_or(3);
+    let unhealthy_threshold = env::var("HEALTH_CHECK_UNHEALTHY_THRESHOLD").unwrap_or_else(|_| "3".to_string()).parse::<u32>().unwrap_or(3).max(1);
+    let healthy_threshold = env::var("HEALTH_CHECK_HEALTHY_THRESHOLD").unwrap_or_else(|_| "2".to_string()).parse::<u32>().unwrap_or(2).max(1);
+
+    Ok(HealthCheckConfig {
+        enabled,
+        mode,
+        path,
+        host,
+        headers,
+        tls_verify,
+        interval_secs,
+        jitter_percent,
+        timeout_secs,
+        connect_timeout_ms,
+        read_timeout_ms,
+        pool_max_idle,
+        pool_idle_timeout_secs,
+        retries,
+        retry_backoff_ms,
+        success_codes: if success_codes.is_empty() { vec![200] } else { success_codes },
+        expect_body,
+        passive_failure_threshold,
+        unhealthy_threshold,
+        healthy_threshold,
+    })
+}
+
+// HEALTH_CHECK_SUCCESS_CODES=200,204,300-399. Tokens that aren't a status code between 100 and 599
+// (or a range of them) are returned separately so the caller can warn about them.
+fn parse_success_codes(value: &str) -> (Vec<u16>, Vec<&str>) {
+    let mut codes = Vec::new();
+    let mut dropped = Vec::new();
+    for token in value.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+        let range = match token.split_once('-') {
+            Some((start, end)) => start.trim().parse::<u16>().ok().zip(end.trim().parse::<u16>().ok()),
+            None => token.parse::<u16>().ok().map(|code| (code, code)),
+        };
+        match range.filter(|(start, end)| (100..=599).contains(start) && (100..=599).contains(end) && start <= end) {
+            Some((start, end)) => {
+                for code in start..=end {
+                    if !codes.contains(&code) {
+                        codes.push(code);
+                    }
+                }
+            }
+            None => dropped.push(token),
+        }
+    }
+    (codes, dropped)
+}
+
+fn parse_body_match(value: &str, name: &str) -> Result<Option<BodyMatch>, ConfigError> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    match value.strip_prefix("regex:") {
+        Some(pattern) => Regex::new(pattern)
+            .map(|pattern| Some(BodyMatch::Pattern(pattern)))
+            .map_err(|e| ConfigError::invalid(name, value, format!("a valid regex after regex: ({})", e))),
+        None => Ok(Some(BodyMatch::Contains(value.to_string()))),
+    }
+}
+
+pub fn load_custom_headers() -> HashMap<String, String> {
+    parse_header_map("CUSTOM_HEADER")
+}
+
+pub fn load_remove_headers() -> Vec<String> {
+    parse_header_list("REMOVE_HEADER")
+}
+
+// Same formats as CUSTOM_HEADER / REMOVE_HEADER, applied to the request sent to the backend.
+pub fn load_custom_request_headers() -> HashMap<String, String> {
+    parse_header_map("CUSTOM_REQUEST_HEADER")
+}
+
+pub fn load_remove_request_headers() -> Vec<String> {
+    parse_header_list("REMOVE_REQUEST_HEADER")
+}
+
+fn parse_header_map(name: &str) -> HashMap<String, String> {
+    let mut headers = HashMap::new();
+    if let Ok(val) = env::var(name) {
+        let trimmed_val = val.trim_matches('"');
+        match serde_json::from_str::<HashMap<String, String>>(trimmed_val) {
+            Ok(map) => headers = map,
+            Err(e) => {
+                log::warn!("⚠️ Failed to parse {} env: {} (value={})", name, e, val);
+                if let Some(colon_pos) = trimmed_val.find(':') {
+                    let key = trimmed_val[..colon_pos].trim().to_string();
+                    let value = trimmed_val[colon_pos+1..].trim().to_string();
+                    headers.insert(key, value);
+                }
+            }
+        }
+    }
+    headers
+}
+
+fn parse_header_list(name: &str) -> Vec<String> {
+    if let Ok(val) = env::var(name) {
+        let trimmed_val = val.trim_matches('"');
+        match serde_json::from_str::<Vec<String>>(trimmed_val) {
+            Ok(list) => list,
+            Err(e) => {
+                log::warn!("⚠️ Failed to parse {} env: {} (value={})", name, e, val);
+                trimmed_val.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            }
+        }
+    } else {
+        Vec::new()
+    }
+}
+
+// HEADER_REWRITE is a JSON array of {"header", "pattern", "replacement"} objects, e.g.
+// [{"header": "Location", "pattern": "^http://internal:8080/", "replacement": "https://public/"}]
+pub fn load_header_rewrites() -> Result<Vec<HeaderRewriteConfig>, ConfigError> {
+    let Some(json) = env::var("HEADER_REWRITE").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(Vec::new());
+    };
+    let rules: Vec<TomlHeaderRewrite> = serde_json::from_str(&json)
+        .map_err(|e| ConfigError::invalid("HEADER_REWRITE", json.clone(), format!("a JSON array of rewrite rules ({})", e)))?;
+    header_rewrites(rules, "HEADER_REWRITE")
+}
+
+fn header_rewrites(rules: Vec<TomlHeaderRewrite>, source: &str) -> Result<Vec<HeaderRewriteConfig>, ConfigError> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            let pattern = Regex::new(&rule.pattern)
+                .map_err(|e| ConfigError::invalid(format!("pattern for {} in {}", rule.header, source), &rule.pattern, format!("a valid regex ({})", e)))?;
+            Ok(HeaderRewriteConfig { header: rule.header, pattern, replacement: rule.replacement })
+        })
+        .collect()
+}
+
+// PATH_RULES='[{"action": "deny", "pattern": "^/admin(/|$)", "status": 404}, {"action": "allow", "pattern": "^/api/"}]'
+pub fn load_path_rules() -> Result<Vec<PathRuleConfig>, ConfigError> {
+    let Some(json) = env::var("PATH_RULES").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(Vec::new());
+    };
+    let rules: Vec<TomlPathRule> = serde_json::from_str(&json)
+        .map_err(|e| ConfigError::invalid("PATH_RULES", json.clone(), format!("a JSON array of path rules ({})", e)))?;
+    path_rules(rules, "PATH_RULES")
+}
+
+fn path_rules(rules: Vec<TomlPathRule>, source: &str) -> Result<Vec<PathRuleConfig>, ConfigError> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            let action = match rule.action.to_lowercase().as_str() {
+                "allow" => PathAction::Allow,
+                "deny" => PathAction::Deny,
+                _ => return Err(ConfigError::invalid(format!("action in {}", source), rule.action, "allow or deny")),
+            };
+            let pattern = Regex::new(&rule.pattern)
+                .map_err(|e| ConfigError::invalid(format!("pattern in {}", source), &rule.pattern, format!("a valid regex ({})", e)))?;
+            let status = match rule.status {
+                None => 403,
+                Some(status @ (403 | 404)) => status,
+                Some(status) => return Err(ConfigError::invalid(format!("status in {}", source), status.to_string(), "403 or 404")),
+            };
+            Ok(PathRuleConfig { action, pattern, status })
+        })
+        .collect()
+}
+
+// PROXY_PORT is one port or a comma-separated list. `port:tls` and `port:plain` fix the protocol
+// for that port; a bare port is TLS exactly when SSL=ON. e.g. PROXY_PORT=80:plain,443:tls
+pub fn load_listeners() -> Result<Vec<ListenerConfig>, ConfigError> {
+    let value = env::var("PROXY_PORT").unwrap_or_else(|_| "3000".to_string());
+    let listeners = split_list(&value)
+        .iter()
+        .map(|entry| {
+            let invalid = || ConfigError::invalid("PROXY_PORT entry", entry, "port, port:tls or port:plain");
+            let (port, tls) = match entry.split_once(':') {
+                Some((port, "tls")) => (port, Some(true)),
+                Some((port, "plain")) => (port, Some(false)),
+                Some(_) => return Err(invalid()),
+                None => (entry.as_str(), None),
+            };
+            let port = port.parse().map_err(|_| invalid())?;
+            Ok(ListenerConfig { port, tls })
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    if listeners.is_empty() {
+        return Err(ConfigError::Missing("PROXY_PORT".to_string()));
+    }
+    unique_ports(listeners, "PROXY_PORT")
+}
+
+// Two listeners on one port would only fail later, when the second one binds.
+fn unique_ports(listeners: Vec<ListenerConfig>, source: &str) -> Result<Vec<ListenerConfig>, ConfigError> {
+    for (i, listener) in listeners.iter().enumerate() {
+        if listeners[..i].iter().any(|other| other.port == listener.port) {
+            return Err(ConfigError::Conflict(format!("port {} is listed more than once in {}", listener.port, source)));
+        }
+    }
+    Ok(listeners)
+}
+
+// The breaker is off unless CB_ERROR_THRESHOLD (an error rate between 0 and 1) is set.
+pub fn load_circuit_breaker_config() -> Result<Option<CircuitBreakerConfig>, ConfigError> {
+    let Some(error_threshold) = env_parsed::<f64>("CB_ERROR_THRESHOLD", "a number between 0 and 1")? else {
+        return Ok(None);
+    };
+    let min_requests = env::var("CB_MIN_REQUESTS").unwrap_or_else(|_| "10".to_string()).parse::<u32>().unwrap_or(10).max(1);
+    let window_secs = env::var("CB_WINDOW_SECS").unwrap_or_else(|_| "10".to_string()).parse().unwrap_or(10);
+    let cooldown_secs = env::var("CB_COOLDOWN_SECS").unwrap_or_else(|_| "30".to_string()).parse().unwrap_or(30);
+
+    Ok(Some(CircuitBreakerConfig {
+        error_threshold: error_threshold.clamp(0.0, 1.0),
+        min_requests,
+        window_secs,
+        cooldown_secs,
+    }))
+}
+
+// Outlier detection is off unless OUTLIER_ERROR_PCT (an error percentage between 0 and 100) is set.
+pub fn load_outlier_config() -> Result<Option<OutlierConfig>, ConfigError> {
+    let Some(error_pct) = env_parsed::<f64>("OUTLIER_ERROR_PCT", "a number between 0 and 100")? else {
+        return Ok(None);
+    };
+    let min_requests = env::var("OUTLIER_MIN_REQUESTS").unwrap_or_else(|_| "20".to_string()).parse::<u32>().unwrap_or(20).max(1);
+    let window_secs = env::var("OUTLIER_WINDOW_SECS").unwrap_or_else(|_| "10".to_string()).parse::<u64>().unwrap_or(10).max(1);
+    let base_ejection_secs = env::var("OUTLIER_BASE_EJECTION_SECS").unwrap_or_else(|_| "30".to_string()).parse().unwrap_or(30);
+
+    Ok(Some(OutlierConfig {
+        error_pct: error_pct.clamp(0.0, 100.0),
+        min_requests,
+        window_secs,
+        base_ejection_secs,
+    }))
+}
+
+// Shadow traffic is off unless MIRROR_BACKENDS (same format as BACKENDS, weights ignored) is set.
+pub fn load_mirror_config() -> Result<Option<MirrorConfig>, ConfigError> {
+    let Some(entries) = env::var("MIRROR_BACKENDS").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let backends = split_list(&entries)
+        .into_iter()
+        .map(|entry| {
+            parse_backend(&entry).ok_or_else(|| ConfigError::Backend { source: "MIRROR_BACKENDS".to_string(), entry })
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    let percent = env_parsed::<f64>("MIRROR_PERCENT", "a number between 0 and 100")?.unwrap_or(100.0);
+
+    Ok(Some(MirrorConfig { backends, percent: percent.clamp(0.0, 100.0) }))
+}
+
+// Seconds over which a backend that recovers from unhealthy ramps up to its full weight (0 = off).
+pub fn load_slow_start() -> Duration {
+    Duration::from_secs(env::var("SLOW_START_SECS").ok().and_then(|v| v.parse().ok()).unwrap_or(0))
+}
+
+// How long a backend that refused a connection is skipped while HEALTH_CHECK_ENABLED=false (0 = never).
+pub fn load_connect_cooldown() -> Result<Duration, ConfigError> {
+    Ok(Duration::from_secs(env_parsed("CONNECT_FAILURE_COOLDOWN", "a number of seconds")?.unwrap_or(10)))
+}
+
+// Host header policy for the default pool and any pool that doesn't set `upstream_host`.
+pub fn load_upstream_host() -> Result<Option<UpstreamHost>, ConfigError> {
+    match env::var("UPSTREAM_HOST") {
+        Ok(value) if !value.trim().is_empty() => parse_upstream_host(&value, "UPSTREAM_HOST").map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn parse_upstream_host(value: &str, name: &str) -> Result<UpstreamHost, ConfigError> {
+    match value.trim() {
+        "preserve" => Ok(UpstreamHost::Preserve),
+        "backend" => Ok(UpstreamHost::Backend),
+        host if !host.is_empty() && http::HeaderValue::from_str(host).is_ok() && !host.contains(char::is_whitespace) => {
+            Ok(UpstreamHost::Fixed(host.to_string()))
+        }
+        _ => Err(ConfigError::invalid(name, value, "preserve, backend or a host name")),
+    }
+}
+
+// Millisecond timeouts applied to every upstream peer; a hung backend yields a 504 instead of
+// holding the client connection open.
+pub fn load_upstream_timeouts() -> UpstreamTimeouts {
+    let millis = |name: &str, default: u64| {
+        Duration::from_millis(env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default))
+    };
+
+    UpstreamTimeouts {
+        connect: millis("UPSTREAM_CONNECT_TIMEOUT", 5_000),
+        read: millis("UPSTREAM_READ_TIMEOUT", 60_000),
+        write: millis("UPSTREAM_WRITE_TIMEOUT", 60_000),
+        idle: env::var("UPSTREAM_IDLE_TIMEOUT").ok().and_then(|v| v.parse().ok()).map(Duration::from_millis),
+    }
+}
+
+pub fn load_backend_tls() -> Result<BackendTlsConfig, ConfigError> {
+    let verify = env::var("BACKEND_TLS_VERIFY").unwrap_or_else(|_| "true".to_string()).to_lowercase() == "true";
+    let ca_bundle = match env::var("BACKEND_CA_BUNDLE").ok().filter(|path| !path.trim().is_empty()) {
+        Some(path) => Some(CaBundle::load(path.trim()).map_err(|reason| ConfigError::File { path: path.trim().to_string(), reason })?),
+        None => None,
+    };
+    Ok(BackendTlsConfig { verify, ca_bundle })
+}
+
+pub fn load_uds_listener() -> Result<Option<UdsListenerConfig>, ConfigError> {
+    let Some(path) = env::var("LISTEN_UDS").ok().filter(|path| !path.trim().is_empty()) else {
+        return Ok(None);
+    };
+    let mode = match env::var("LISTEN_UDS_MODE") {
+        Ok(value) => u32::from_str_radix(value.trim().trim_start_matches("0o"), 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+            .ok_or_else(|| ConfigError::invalid("LISTEN_UDS_MODE", value, "octal permissions like 660"))?,
+        Err(_) => 0o660,
+    };
+    let only = env::var("LISTEN_UDS_ONLY").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    Ok(Some(UdsListenerConfig { path: path.trim().to_string(), mode, only }))
+}
+
+// Off unless TCP_KEEPALIVE=true, matching Pingora's own default.
+pub fn load_tcp_keepalive() -> Result<Option<TcpKeepaliveConfig>, ConfigError> {
+    if env::var("TCP_KEEPALIVE").unwrap_or_else(|_| "false".to_string()).to_lowercase() != "true" {
+        return Ok(None);
+    }
+    let idle = env_parsed("TCP_KEEPALIVE_IDLE", "a number of seconds")?.unwrap_or(60);
+    let interval = env_parsed("TCP_KEEPALIVE_INTERVAL", "a number of seconds")?.unwrap_or(10);
+    let probes = env_parsed("TCP_KEEPALIVE_PROBES", "a number of probes")?.unwrap_or(6);
+    for (name, value) in [("TCP_KEEPALIVE_IDLE", idle), ("TCP_KEEPALIVE_INTERVAL", interval), ("TCP_KEEPALIVE_PROBES", probes as u64)] {
+        if value == 0 {
+            return Err(ConfigError::invalid(name, "0", "at least 1"));
+        }
+    }
+    Ok(Some(TcpKeepaliveConfig { idle: Duration::from_secs(idle), interval: Duration::from_secs(interval), probes }))
+}
+
+// Pingora disables Nagle's algorithm on every socket it opens; false turns it back on for backend
+// connections. Client sockets can't be changed, as Pingora doesn't expose them.
+pub fn load_upstream_tcp_nodelay() -> bool {
+    env::var("UPSTREAM_TCP_NODELAY").unwrap_or_else(|_| "true".to_string()).to_lowercase() == "true"
+}
+
+// Per-backend connect timeout of the reachability probe run once at startup.
+pub fn load_startup_probe_timeout() -> Result<Duration, ConfigError> {
+    match env_parsed("STARTUP_PROBE_TIMEOUT", "a number of milliseconds")? {
+        Some(0) => Err(ConfigError::invalid("STARTUP_PROBE_TIMEOUT", "0", "at least 1 millisecond")),
+        millis => Ok(Duration::from_millis(millis.unwrap_or(2_000))),
+    }
+}
+
+// Idle keep-alive connections kept across all backends for reuse; Pingora's default when unset.
+pub fn load_upstream_pool_size() -> Result<Option<usize>, ConfigError> {
+    match env_parsed("UPSTREAM_KEEPALIVE_POOL_SIZE", "a number of connections")? {
+        Some(0) => Err(ConfigError::invalid("UPSTREAM_KEEPALIVE_POOL_SIZE", "0", "at least 1 connection")),
+        size => Ok(size),
+    }
+}
+
+pub fn load_cache_config() -> Option<CacheConfig> {
+    let enabled = env::var("CACHE_ENABLED").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    if !enabled {
+        return None;
+    }
+
+    Some(CacheConfig {
+        max_entries: env::var("CACHE_MAX_ENTRIES").unwrap_or_else(|_| "1000".to_string()).parse().unwrap_or(1000),
+        max_body_bytes: env::var("CACHE_MAX_BODY_BYTES").unwrap_or_else(|_| "1048576".to_string()).parse().unwrap_or(1_048_576),
+    })
+}
+
+pub fn load_compression_config() -> Option<CompressionConfig> {
+    let enabled = env::var("COMPRESSION_ENABLED").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    if !enabled {
+        return None;
+    }
+
+    Some(CompressionConfig {
+        min_size: env::var("COMPRESSION_MIN_SIZE").unwrap_or_else(|_| "1024".to_string()).parse().unwrap_or(1024),
+    })
+}
+
+pub fn load_max_retries() -> usize {
+    env::var("MAX_RETRIES").unwrap_or_else(|_| "1".to_string()).parse().unwrap_or(1)
+}
+
+// Bounds all attempts of a request together, retries included (0 or unset = per-attempt timeouts only).
+pub fn load_request_deadline() -> Result<Option<Duration>, ConfigError> {
+    Ok(env_parsed::<u64>("REQUEST_DEADLINE_MS", "a number of milliseconds")?.filter(|ms| *ms > 0).map(Duration::from_millis))
+}
+
+// Requests in flight to any one backend, for the default pool and pools without `max_concurrency`
+// (unset = unlimited).
+pub fn load_backend_max_concurrency() -> Result<Option<usize>, ConfigError> {
+    match env_parsed::<usize>("BACKEND_MAX_CONCURRENCY", "a positive number")? {
+        Some(0) => Err(ConfigError::invalid("BACKEND_MAX_CONCURRENCY", "0", "a positive number")),
+        max => Ok(max),
+    }
 }
 
-pub fn load_balance_strategy() -> LoadBalanceStrategy {
-    let strategy_str = env::var("LOAD_BALANCE_STRATEGY")
-        .unwrap_or_else(|_| "weighted".to_string())
-        .to_lowercase();
-    
-    match strategy_str.as_str() {
-        "round_robin" | "round-robin" | "roundrobin" => LoadBalanceStrategy::RoundRobin,
-        "weighted" => LoadBalanceStrategy::Weighted,
-        "least_connections" | "least-connections" | "leastconnections" => LoadBalanceStrategy::LeastConnections,
-        "sticky_session" | "sticky-session" | "stickysession" => LoadBalanceStrategy::StickySession,
-        "random" => LoadBalanceStrategy::Random,
-        _ => {
-            warn!("⚠️ Unknown load balance strategy '{}', defaulting to 'weighted'", strategy_str);
-            LoadBalanceStrategy::Weighted
+// How long a request waits for a backend below its concurrency limit before the 503 (0 = none).
+pub fn load_concurrency_queue_timeout() -> Result<Duration, ConfigError> {
+    Ok(Duration::from_millis(env_parsed("CONCURRENCY_QUEUE_TIMEOUT_MS", "a number of milliseconds")?.unwrap_or(0)))
+}
+
+// Off unless ADAPTIVE_WEIGHTS=true. The minimum is at least 1% so no healthy backend is starved.
+pub fn load_adaptive_weights() -> Result<Option<AdaptiveWeightsConfig>, ConfigError> {
+    if env::var("ADAPTIVE_WEIGHTS").unwrap_or_default().to_lowercase() != "true" {
+        return Ok(None);
+    }
+    let interval_secs = env_parsed("ADAPTIVE_WEIGHTS_INTERVAL", "a number of seconds")?.unwrap_or(10).max(1);
+    let min_percent = env_parsed("ADAPTIVE_WEIGHTS_MIN_PERCENT", "a percentage")?.unwrap_or(25);
+    let max_percent = env_parsed("ADAPTIVE_WEIGHTS_MAX_PERCENT", "a percentage")?.unwrap_or(200);
+    if min_percent == 0 {
+        return Err(ConfigError::invalid("ADAPTIVE_WEIGHTS_MIN_PERCENT", "0", "at least 1"));
+    }
+    if min_percent > max_percent {
+        return Err(ConfigError::Conflict(format!(
+            "ADAPTIVE_WEIGHTS_MIN_PERCENT ({}) is above ADAPTIVE_WEIGHTS_MAX_PERCENT ({})",
+            min_percent, max_percent
+        )));
+    }
+    Ok(Some(AdaptiveWeightsConfig { interval_secs, min_percent, max_percent }))
+}
+
+// Off unless MAX_CONNECTIONS or MAX_CONNECTIONS_PER_IP is set; 0 isn't a usable cap.
+pub fn load_connection_limits() -> Result<Option<ConnectionLimitConfig>, ConfigError> {
+    let max = env_parsed::<usize>("MAX_CONNECTIONS", "a number of connections")?;
+    let per_ip = env_parsed::<usize>("MAX_CONNECTIONS_PER_IP", "a number of connections")?;
+    for (name, value) in [("MAX_CONNECTIONS", max), ("MAX_CONNECTIONS_PER_IP", per_ip)] {
+        if value == Some(0) {
+            return Err(ConfigError::invalid(name, "0", "at least 1 connection"));
         }
     }
+    Ok(Some(ConnectionLimitConfig { max, per_ip }).filter(|_| max.is_some() || per_ip.is_some()))
 }
 
-pub fn load_sticky_cookie_name() -> String {
-    env::var("STICKY_COOKIE_NAME")
-        .unwrap_or_else(|_| "PINGORA_SESSION".to_string())
+// Counted as on the wire: request line plus every `name: value` line.
+pub fn load_max_header_bytes() -> Result<Option<usize>, ConfigError> {
+    env_parsed("MAX_HEADER_BYTES", "a number of bytes")
 }
 
-pub fn load_sticky_session_ttl() -> u64 {
-    std::env::var("STICKY_SESSION_TTL")
-        .ok()
-        .and_then(|v| v.parse::<u64>().ok())
-        .unwrap_or(3600)
+// Unset means request bodies of any size are forwarded.
+pub fn load_max_body_bytes() -> Result<Option<usize>, ConfigError> {
+    env_parsed("MAX_BODY_BYTES", "a number of bytes")
 }
 
-pub fn load_backends() -> Vec<Backend> {
-    let mut backends = Vec::new();
-    
-    if let Ok(val) = env::var("BACKENDS") {
-        for entry in val.split(',') {
-            let parts: Vec<&str> = entry.split(':').collect();
-            
-            if parts.len() >= 2 {
-                if let Ok(port) = parts[1].parse::<u16>() {
-                    let weight = if parts.len() == 3 {
-                        parts[2].parse::<usize>().unwrap_or(1)
-                    } else {
-                        1 // Default weight
-                    };
-                    
-                    backends.push(Backend {
-                        host: parts[0].to_string(),
-                        port,
-                        weight,
-                        healthy: true,
-                        last_checked: None,
-                    });
-                }
-            }
+// Announced larger responses become a 502; ones that only grow past it while streaming are cut off.
+pub fn load_max_response_bytes() -> Result<Option<usize>, ConfigError> {
+    env_parsed("MAX_RESPONSE_BYTES", "a number of bytes")
+}
+
+// ALLOWED_METHODS=GET,HEAD,OPTIONS; anything else is answered with 405. Unset allows every method.
+pub fn load_allowed_methods() -> Result<Option<Vec<Method>>, ConfigError> {
+    let value = match env::var("ALLOWED_METHODS") {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => return Ok(None),
+    };
+    let methods = split_list(&value)
+        .into_iter()
+        .map(|name| {
+            Method::from_bytes(name.to_uppercase().as_bytes())
+                .map_err(|_| ConfigError::invalid("ALLOWED_METHODS entry", name, "an HTTP method"))
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    Ok(Some(methods))
+}
+
+// Off by default: decoding costs CPU and the decompressed size is only bounded by MAX_BODY_BYTES.
+pub fn load_decompress_request() -> bool {
+    env::var("DECOMPRESS_REQUEST").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true"
+}
+
+// When no backend is healthy, try them all anyway instead of answering 503.
+pub fn load_fail_open() -> bool {
+    env::var("FAIL_OPEN").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true"
+}
+
+// SRV record (e.g. `_http._tcp.service.consul`) the default pool's backends are read from instead of
+// BACKENDS, re-queried as its TTL expires.
+pub fn load_backends_srv() -> Option<String> {
+    env::var("BACKENDS_SRV").ok().map(|v| v.trim().to_string()).filter(|v| !v.is_empty())
+}
+
+// How often hostname backends are re-resolved; 0 resolves them once and keeps the result.
+pub fn load_dns_refresh_interval() -> Result<u64, ConfigError> {
+    Ok(env_parsed("DNS_REFRESH_INTERVAL", "a number of seconds")?.unwrap_or(30))
+}
+
+// How long a backend removed through the admin API may keep serving its sticky sessions.
+pub fn load_drain_timeout() -> Result<u64, ConfigError> {
+    Ok(env_parsed("BACKEND_DRAIN_TIMEOUT", "a number of seconds")?.unwrap_or(300))
+}
+
+// Retry-After sent with the 503 for a pool without healthy backends.
+pub fn load_unavailable_retry_after() -> Result<u64, ConfigError> {
+    Ok(env_parsed("UNAVAILABLE_RETRY_AFTER", "a number of seconds")?.unwrap_or(5))
+}
+
+// The ALPN protocols TLS listeners accept, most preferred first; empty leaves ALPN unanswered so
+// every client speaks HTTP/1.1. TLS_ALPN takes an explicit list, and ENABLE_H2=true is shorthand
+// for "h2,http/1.1". Plain listeners stay HTTP/1.1 either way.
+pub fn load_tls_alpn() -> Result<Vec<String>, ConfigError> {
+    let enable_h2 = env::var("ENABLE_H2").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    let Some(value) = env::var("TLS_ALPN").ok().filter(|v| !v.trim().is_empty()) else {
+        return Ok(if enable_h2 { vec!["h2".to_string(), "http/1.1".to_string()] } else { Vec::new() });
+    };
+    let protocols = split_list(&value);
+    for protocol in &protocols {
+        if protocol != "h2" && protocol != "http/1.1" {
+            return Err(ConfigError::invalid("TLS_ALPN", protocol, "a list of h2 and http/1.1"));
         }
     }
-    
-    if backends.is_empty() {
-        panic!("❌ BACKENDS must be set and not empty!");
+    if protocols.len() == 2 && protocols[0] == protocols[1] {
+        return Err(ConfigError::invalid("TLS_ALPN", value, "each protocol at most once"));
     }
-    
-    // Normalize weights to sum to 100
-    let total: usize = backends.iter().map(|b| b.weight).sum();
-    if total != 100 {
-        let factor = 100.0 / (total as f64);
-        for b in backends.iter_mut() {
-            b.weight = ((b.weight as f64) * factor).round() as usize;
+    if enable_h2 && !protocols.iter().any(|p| p == "h2") {
+        return Err(ConfigError::Conflict("ENABLE_H2=true, but TLS_ALPN doesn't include h2".to_string()));
+    }
+    Ok(protocols)
+}
+
+// Pingora keeps at most 64 KiB of each request body for replaying it on a retry, so that's the
+// ceiling. Unset, a request is only retried when connecting fails.
+pub fn load_retry_buffer_bytes() -> Result<Option<usize>, ConfigError> {
+    const RETRY_BUFFER_LIMIT: usize = 64 * 1024;
+    match env_parsed("RETRY_BUFFER_BYTES", "a number of bytes")? {
+        Some(bytes) if !(1..=RETRY_BUFFER_LIMIT).contains(&bytes) => {
+            Err(ConfigError::invalid("RETRY_BUFFER_BYTES", bytes.to_string(), format!("between 1 and {}", RETRY_BUFFER_LIMIT)))
         }
+        bytes => Ok(bytes),
     }
-    
-    backends
 }
 
-pub fn load_health_check_config() -> HealthCheckConfig {
-    let enabled = env::var("HEALTH_CHECK_ENABLED").unwrap_or_else(|_| "true".to_string()).to_lowercase() == "true";
-    let path = env::var("HEALTH_CHECK_PATH").unwrap_or_else(|_| "/health".to_string());
-    let interval_secs: u64 = env::var("HEALTH_CHECK_INTERVAL").unwrap_or_else(|_| "30".to_string()).parse::<u64>().expect("HEALTH_CHECK_INTERVAL must be a valid u64 number");
-    let timeout_secs = env::var("HEALTH_CHECK_TIMEOUT").unwrap_or_else(|_| "5".to_string()).parse().unwrap_or(5);
-    let success_codes_str = env::var("HEALTH_CHECK_SUCCESS_CODES").unwrap_or_else(|_| "200".to_string());
-    let success_codes: Vec<u16> = success_codes_str.split(',').filter_map(|s| s.trim().parse().ok()).collect();
+// Methods retried on another backend after a failure; defaults to the ones without side effects.
+pub fn load_retry_methods() -> Result<Vec<Method>, ConfigError> {
+    let value = match env::var("RETRY_METHODS") {
+        Ok(value) if !value.trim().is_empty() => value,
+        _ => return Ok(vec![Method::GET, Method::HEAD, Method::OPTIONS]),
+    };
+    split_list(&value)
+        .into_iter()
+        .map(|name| {
+            Method::from_bytes(name.to_uppercase().as_bytes())
+                .map_err(|_| ConfigError::invalid("RETRY_METHODS entry", name, "an HTTP method"))
+        })
+        .collect()
+}
 
-    HealthCheckConfig {
-        enabled,
-        path,
-        interval_secs,
-        timeout_secs,
-        success_codes: if success_codes.is_empty() { vec![200] } else { success_codes },
+pub fn load_retry_non_idempotent() -> bool {
+    env::var("RETRY_NON_IDEMPOTENT").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true"
+}
+
+// Names the serving backend in an X-Upstream response header; off by default so production
+// doesn't reveal internal addresses.
+pub fn load_expose_upstream() -> bool {
+    env::var("EXPOSE_UPSTREAM_HEADER").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true"
+}
+
+pub fn load_emit_forwarded() -> bool {
+    env::var("EMIT_FORWARDED").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true"
+}
+
+// On unless STRIP_FINGERPRINT_HEADERS=false: drops the backend's Server, X-Powered-By and similar
+// version headers from responses.
+pub fn load_strip_fingerprint_headers() -> bool {
+    env::var("STRIP_FINGERPRINT_HEADERS").unwrap_or_else(|_| "true".to_string()).to_lowercase() != "false"
+}
+
+// The marker header added to requests sent upstream, `X-Forwarded-By: Pingora-Proxy` by default.
+// FORWARDED_BY_HEADER renames it, or drops it when set to `off`.
+pub fn load_forwarded_by() -> Result<Option<(String, String)>, ConfigError> {
+    let name = env::var("FORWARDED_BY_HEADER").map(|v| v.trim().to_string()).unwrap_or_else(|_| "X-Forwarded-By".to_string());
+    if name.is_empty() || name.eq_ignore_ascii_case("off") {
+        return Ok(None);
+    }
+    if http::HeaderName::from_bytes(name.as_bytes()).is_err() {
+        return Err(ConfigError::invalid("FORWARDED_BY_HEADER", name, "a header name or off"));
     }
+    let value = env::var("FORWARDED_BY_VALUE").unwrap_or_else(|_| "Pingora-Proxy".to_string());
+    if value.trim().is_empty() || http::HeaderValue::from_str(&value).is_err() {
+        return Err(ConfigError::invalid("FORWARDED_BY_VALUE", value, "a header value"));
+    }
+    Ok(Some((name, value)))
 }
 
-pub fn load_custom_headers() -> HashMap<String, String> {
-    let mut headers = HashMap::new();
-    if let Ok(val) = env::var("CUSTOM_HEADER") {
-        let trimmed_val = val.trim_matches('"');
-        match serde_json::from_str::<HashMap<String, String>>(trimmed_val) {
-            Ok(map) => headers = map,
-            Err(e) => {
-                log::warn!("⚠️ Failed to parse CUSTOM_HEADER env: {} (value={})", e, val);
-                if let Some(colon_pos) = trimmed_val.find(':') {
-                    let key = trimmed_val[..colon_pos].trim().to_string();
-                    let value = trimmed_val[colon_pos+1..].trim().to_string();
-                    headers.insert(key, value);
-                }
+// A `Via` entry such as `1.1 edge` appended to requests and responses; unset adds none.
+pub fn load_via() -> Result<Option<String>, ConfigError> {
+    match env::var("VIA_HEADER") {
+        Ok(value) if !value.trim().is_empty() => match http::HeaderValue::from_str(value.trim()) {
+            Ok(_) => Ok(Some(value.trim().to_string())),
+            Err(_) => Err(ConfigError::invalid("VIA_HEADER", value, "a header value like `1.1 edge`")),
+        },
+        _ => Ok(None),
+    }
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|v| !v.is_empty()).map(str::to_string).collect()
+}
+
+fn default_cors_methods() -> Vec<String> {
+    split_list("GET, POST, PUT, PATCH, DELETE, OPTIONS")
+}
+
+fn default_cors_headers() -> Vec<String> {
+    split_list("Content-Type, Authorization")
+}
+
+// ERROR_PAGE_<status>=path, e.g. ERROR_PAGE_503=errors/503.html.
+pub fn load_error_pages() -> HashMap<u16, String> {
+    env::vars()
+        .filter_map(|(key, value)| {
+            let code = key.strip_prefix("ERROR_PAGE_")?.parse::<u16>().ok()?;
+            (400..600).contains(&code).then_some((code, value))
+        })
+        .collect()
+}
+
+pub fn load_error_format() -> Result<ErrorFormat, ConfigError> {
+    match env::var("ERROR_FORMAT").unwrap_or_default().trim().to_lowercase().as_str() {
+        "" | "json" => Ok(ErrorFormat::Json),
+        "plain" | "text" => Ok(ErrorFormat::Plain),
+        "html" => Ok(ErrorFormat::Html),
+        other => Err(ConfigError::invalid("ERROR_FORMAT", other, "json, plain or html")),
+    }
+}
+
+// SNI_CERTS is a comma-separated list of `domain:cert_path:key_path`, e.g.
+// `api.example.com:ssl/api.pem:ssl/api.key,*.example.org:ssl/org.pem:ssl/org.key`.
+pub fn load_sni_certs() -> Result<Vec<SniCertConfig>, ConfigError> {
+    split_list(&env::var("SNI_CERTS").unwrap_or_default())
+        .iter()
+        .map(|entry| match entry.split(':').collect::<Vec<_>>().as_slice() {
+            [domain, cert_path, key_path] if !domain.is_empty() => Ok(SniCertConfig {
+                domain: domain.to_lowercase(),
+                cert_path: cert_path.to_string(),
+                key_path: key_path.to_string(),
+            }),
+            _ => Err(ConfigError::invalid("SNI_CERTS entry", entry, "domain:cert_path:key_path")),
+        })
+        .collect()
+}
+
+// SECURITY_HEADERS=true adds HSTS (with SSL), nosniff, X-Frame-Options and Referrer-Policy; each
+// can be tuned or turned `off` on its own.
+pub fn load_security_headers_config() -> Result<Option<SecurityHeadersConfig>, ConfigError> {
+    if env::var("SECURITY_HEADERS").unwrap_or_else(|_| "false".to_string()).to_lowercase() != "true" {
+        return Ok(None);
+    }
+    let flag = |name: &str, default: bool| {
+        env::var(name).map(|v| v.trim().to_lowercase() == "true").unwrap_or(default)
+    };
+    let setting = |name: &str, default: &str| {
+        let value = env::var(name).unwrap_or_else(|_| default.to_string()).trim().to_string();
+        Some(value).filter(|v| !v.is_empty() && !v.eq_ignore_ascii_case("off"))
+    };
+
+    let hsts = match setting("HSTS_MAX_AGE", "31536000") {
+        Some(max_age) => {
+            let max_age_secs = max_age
+                .parse()
+                .map_err(|_| ConfigError::invalid("HSTS_MAX_AGE", &max_age, "a number of seconds or off"))?;
+            let hsts = HstsConfig {
+                max_age_secs,
+                include_subdomains: flag("HSTS_INCLUDE_SUBDOMAINS", true),
+                preload: flag("HSTS_PRELOAD", false),
+            };
+            // What the browser preload lists require before accepting a domain.
+            if hsts.preload && (!hsts.include_subdomains || hsts.max_age_secs < 31536000) {
+                return Err(ConfigError::Conflict(
+                    "HSTS_PRELOAD needs HSTS_INCLUDE_SUBDOMAINS=true and HSTS_MAX_AGE of at least 31536000".to_string(),
+                ));
             }
+            Some(hsts)
         }
+        None => None,
+    };
+
+    let frame_options = setting("X_FRAME_OPTIONS", "DENY").map(|v| v.to_uppercase());
+    if let Some(value) = frame_options.as_ref().filter(|v| !matches!(v.as_str(), "DENY" | "SAMEORIGIN")) {
+        return Err(ConfigError::invalid("X_FRAME_OPTIONS", value, "DENY, SAMEORIGIN or off"));
     }
-    headers
+
+    let referrer_policy = setting("REFERRER_POLICY", "strict-origin-when-cross-origin").map(|v| v.to_lowercase());
+    const REFERRER_POLICIES: [&str; 8] = [
+        "no-referrer",
+        "no-referrer-when-downgrade",
+        "origin",
+        "origin-when-cross-origin",
+        "same-origin",
+        "strict-origin",
+        "strict-origin-when-cross-origin",
+        "unsafe-url",
+    ];
+    if let Some(value) = referrer_policy.as_ref().filter(|v| !REFERRER_POLICIES.contains(&v.as_str())) {
+        return Err(ConfigError::invalid("REFERRER_POLICY", value, "a Referrer-Policy value or off"));
+    }
+
+    Ok(Some(SecurityHeadersConfig {
+        hsts,
+        content_type_options: flag("X_CONTENT_TYPE_OPTIONS", true),
+        frame_options,
+        referrer_policy,
+        override_backend: flag("SECURITY_HEADERS_OVERRIDE", false),
+    }))
 }
 
-pub fn load_remove_headers() -> Vec<String> {
-    if let Ok(val) = env::var("REMOVE_HEADER") {
-        let trimmed_val = val.trim_matches('"');
-        match serde_json::from_str::<Vec<String>>(trimmed_val) {
-            Ok(list) => list,
-            Err(e) => {
-                log::warn!("⚠️ Failed to parse REMOVE_HEADER env: {} (value={})", e, val);
-                trimmed_val.split(',')
-                    .map(|s| s.trim().to_string())
-                    .filter(|s| !s.is_empty())
-                    .collect()
-            }
+// CORS is handled by the proxy only when CORS_ALLOWED_ORIGINS is set (a comma-separated list, or `*`).
+pub fn load_cors_config() -> Option<CorsConfig> {
+    let allowed_origins = split_list(&env::var("CORS_ALLOWED_ORIGINS").ok()?);
+    if allowed_origins.is_empty() {
+        return None;
+    }
+
+    Some(CorsConfig {
+        allowed_origins,
+        allowed_methods: env::var("CORS_ALLOWED_METHODS").map(|v| split_list(&v)).unwrap_or_else(|_| default_cors_methods()),
+        allowed_headers: env::var("CORS_ALLOWED_HEADERS").map(|v| split_list(&v)).unwrap_or_else(|_| default_cors_headers()),
+        allow_credentials: env::var("CORS_ALLOW_CREDENTIALS").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true",
+        max_age_secs: Some(env::var("CORS_MAX_AGE").ok().and_then(|v| v.parse().ok()).unwrap_or(600)),
+    })
+}
+
+// BASIC_AUTH_USERS is a comma-separated list of `user:bcrypt-hash` pairs. Hashes contain `$`, so
+// quote the value with single quotes in `.env` to keep it from being expanded.
+pub fn load_basic_auth_config() -> Result<Option<BasicAuthConfig>, ConfigError> {
+    let entries = split_list(&env::var("BASIC_AUTH_USERS").unwrap_or_default());
+    if entries.is_empty() {
+        return Ok(None);
+    }
+
+    let users = entries
+        .iter()
+        .map(|entry| match entry.split_once(':') {
+            Some((user, hash)) if !user.is_empty() && hash.starts_with("$2") => Ok((user.to_string(), hash.to_string())),
+            _ => Err(ConfigError::invalid("BASIC_AUTH_USERS entry", entry, "user:$2b$...")),
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+    let bypass_paths = env::var("BASIC_AUTH_BYPASS_PATHS").map(|v| split_list(&v)).unwrap_or_default();
+
+    Ok(Some(BasicAuthConfig { users, bypass_paths }))
+}
+
+// Bearer token validation is on when either JWT_SECRET (HMAC) or JWT_JWKS_URL is set.
+// JWT_FORWARD_CLAIMS maps claims to upstream headers, e.g. `sub=X-User-Id,email=X-User-Email`.
+pub fn load_jwt_config() -> Result<Option<JwtConfig>, ConfigError> {
+    let secret = env::var("JWT_SECRET").ok().filter(|v| !v.is_empty());
+    let jwks_url = env::var("JWT_JWKS_URL").ok().filter(|v| !v.is_empty());
+    if secret.is_some() && jwks_url.is_some() {
+        return Err(ConfigError::Conflict("Set only one of JWT_SECRET and JWT_JWKS_URL".to_string()));
+    }
+    if secret.is_none() && jwks_url.is_none() {
+        return Ok(None);
+    }
+
+    let forward_claims = env::var("JWT_FORWARD_CLAIMS")
+        .map(|v| split_list(&v))
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| match entry.split_once('=') {
+            Some((claim, header)) => Ok((claim.trim().to_string(), header.trim().to_string())),
+            None => Err(ConfigError::invalid("JWT_FORWARD_CLAIMS entry", entry, "claim=Header-Name")),
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()?;
+
+    Ok(Some(JwtConfig {
+        secret,
+        jwks_url,
+        jwks_refresh_secs: env::var("JWT_JWKS_REFRESH_SECS").unwrap_or_else(|_| "300".to_string()).parse().unwrap_or(300),
+        issuer: env::var("JWT_ISSUER").ok().filter(|v| !v.is_empty()),
+        audience: env::var("JWT_AUDIENCE").ok().filter(|v| !v.is_empty()),
+        forward_claims,
+        bypass_paths: env::var("JWT_BYPASS_PATHS").map(|v| split_list(&v)).unwrap_or_default(),
+    }))
+}
+
+// ALLOW_IPS / DENY_IPS take comma-separated CIDR ranges or bare addresses; filtering is off
+// unless at least one is set.
+pub fn load_ip_filter_config() -> Result<Option<IpFilterConfig>, ConfigError> {
+    let allow = load_ip_nets("ALLOW_IPS")?;
+    let deny = load_ip_nets("DENY_IPS")?;
+    if allow.is_empty() && deny.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(IpFilterConfig { allow, deny }))
+}
+
+// Always loaded, even when off at startup, so the admin listener can switch it on later.
+pub fn load_maintenance_config() -> Result<MaintenanceConfig, ConfigError> {
+    let enabled = matches!(
+        env::var("MAINTENANCE_MODE").unwrap_or_default().to_lowercase().as_str(),
+        "true" | "on" | "1"
+    );
+    Ok(MaintenanceConfig {
+        enabled,
+        page_path: env::var("MAINTENANCE_PAGE").ok().filter(|v| !v.trim().is_empty()),
+        retry_after_secs: env::var("MAINTENANCE_RETRY_AFTER").ok().and_then(|v| v.parse().ok()).unwrap_or(300),
+        allow_ips: load_ip_nets("MAINTENANCE_ALLOW_IPS")?,
+        bypass_paths: env::var("MAINTENANCE_BYPASS_PATHS").map(|v| split_list(&v)).unwrap_or_default(),
+    })
+}
+
+// Peers whose X-Forwarded-For and Forwarded are kept and appended to; anyone else's are replaced.
+pub fn load_trusted_proxies() -> Result<Vec<IpNet>, ConfigError> {
+    load_ip_nets("TRUSTED_PROXIES")
+}
+
+// Clients allowed to pin a request to a backend with X-Debug-Upstream; unset disables the header.
+pub fn load_debug_upstream_ips() -> Result<Vec<IpNet>, ConfigError> {
+    load_ip_nets("DEBUG_UPSTREAM_ALLOW_IPS")
+}
+
+fn load_ip_nets(name: &str) -> Result<Vec<IpNet>, ConfigError> {
+    env::var(name)
+        .map(|v| split_list(&v))
+        .unwrap_or_default()
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| ConfigError::invalid(name, entry, "IP addresses or CIDR ranges"))
+        })
+        .collect()
+}
+
+// With ACCEPT_PROXY_PROTOCOL=true every connection on the proxy ports must start with a PROXY v1/v2
+// header. The proxy itself then listens on loopback ports behind the header-stripping relays, one
+// per proxy port (returned in the same order). PROXY_PROTOCOL_INTERNAL_PORT only fits a single port.
+pub fn load_proxy_protocol_ports(proxy_ports: &[u16]) -> Result<Option<Vec<u16>>, ConfigError> {
+    let enabled = env::var("ACCEPT_PROXY_PROTOCOL").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    if !enabled {
+        return Ok(None);
+    }
+
+    if let Some(port) = env_parsed("PROXY_PROTOCOL_INTERNAL_PORT", "a port number")? {
+        if proxy_ports.len() > 1 {
+            return Err(ConfigError::Conflict("PROXY_PROTOCOL_INTERNAL_PORT can't be used with several proxy ports".to_string()));
         }
+        return Ok(Some(vec![port]));
+    }
+    proxy_ports
+        .iter()
+        .map(|port| {
+            port.checked_add(10_000).ok_or_else(|| {
+                ConfigError::Missing(format!("PROXY_PROTOCOL_INTERNAL_PORT (proxy port {} is above 55535)", port))
+            })
+        })
+        .collect::<Result<Vec<_>, ConfigError>>()
+        .map(Some)
+}
+
+// ACCESS_LOG_ROTATION is "size" (at ACCESS_LOG_MAX_BYTES), "daily" or "hourly".
+pub fn load_access_log_config() -> Result<Option<AccessLogConfig>, ConfigError> {
+    let Some(path) = env::var("ACCESS_LOG_FILE").ok().filter(|v| !v.is_empty()) else {
+        return Ok(None);
+    };
+    let rotation = match env::var("ACCESS_LOG_ROTATION").unwrap_or_else(|_| "size".to_string()).to_lowercase().as_str() {
+        "size" => LogRotation::Size(
+            env::var("ACCESS_LOG_MAX_BYTES").unwrap_or_else(|_| "104857600".to_string()).parse().unwrap_or(104_857_600),
+        ),
+        "daily" => LogRotation::Daily,
+        "hourly" => LogRotation::Hourly,
+        other => return Err(ConfigError::invalid("ACCESS_LOG_ROTATION", other, "size, daily or hourly")),
+    };
+
+    Ok(Some(AccessLogConfig {
+        path,
+        rotation,
+        max_files: env::var("ACCESS_LOG_MAX_FILES").unwrap_or_else(|_| "5".to_string()).parse().unwrap_or(5),
+    }))
+}
+
+// Client certificates are checked against the MTLS_CA bundle. `optional` lets clients without a
+// certificate through (backends see no X-Client-Cert-* headers); a presented but untrusted
+// certificate fails the handshake in both modes.
+pub fn load_mtls_config() -> Result<Option<MtlsConfig>, ConfigError> {
+    let mode = match env::var("MTLS_MODE").unwrap_or_else(|_| "off".to_string()).to_lowercase().as_str() {
+        "off" => return Ok(None),
+        "optional" => MtlsMode::Optional,
+        "require" => MtlsMode::Require,
+        other => return Err(ConfigError::invalid("MTLS_MODE", other, "off, optional or require")),
+    };
+    let ca_path = env::var("MTLS_CA")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| ConfigError::Missing("MTLS_CA (required by MTLS_MODE)".to_string()))?;
+    if !Path::new(&ca_path).exists() {
+        return Err(ConfigError::invalid("MTLS_CA", ca_path, "an existing CA bundle file"));
+    }
+
+    Ok(Some(MtlsConfig { ca_path, mode }))
+}
+
+// TLS_PROFILE=intermediate|modern and TLS_MIN_VERSION=1.2|1.3. Neither profile offers TLS 1.0 or
+// 1.1, and modern can't be combined with a 1.2 minimum.
+pub fn load_tls_policy() -> Result<TlsPolicy, ConfigError> {
+    let profile = match env::var("TLS_PROFILE").unwrap_or_else(|_| "intermediate".to_string()).trim().to_lowercase().as_str() {
+        "intermediate" => TlsProfile::Intermediate,
+        "modern" => TlsProfile::Modern,
+        other => return Err(ConfigError::invalid("TLS_PROFILE", other, "intermediate or modern")),
+    };
+    let min_version = match env::var("TLS_MIN_VERSION").ok().filter(|v| !v.trim().is_empty()) {
+        None if profile == TlsProfile::Modern => TlsVersion::Tls13,
+        None => TlsVersion::Tls12,
+        Some(value) => match value.trim() {
+            "1.2" => TlsVersion::Tls12,
+            "1.3" => TlsVersion::Tls13,
+            _ => return Err(ConfigError::invalid("TLS_MIN_VERSION", value, "1.2 or 1.3")),
+        },
+    };
+    if profile == TlsProfile::Modern && min_version == TlsVersion::Tls12 {
+        return Err(ConfigError::Conflict("TLS_PROFILE=modern only allows TLS 1.3, but TLS_MIN_VERSION is 1.2".to_string()));
+    }
+    Ok(TlsPolicy { profile, min_version })
+}
+
+// Let's Encrypt (or any ACME directory) via HTTP-01; needs SSL=ON and the domains pointing here
+// with ACME_HTTP_PORT reachable as port 80.
+pub fn load_acme_config() -> Result<Option<AcmeConfig>, ConfigError> {
+    let enabled = matches!(env::var("ACME_ENABLED").unwrap_or_default().to_lowercase().as_str(), "on" | "true");
+    if !enabled {
+        return Ok(None);
+    }
+
+    let domains = split_list(&env::var("ACME_DOMAINS").unwrap_or_default());
+    if domains.is_empty() {
+        return Err(ConfigError::Missing("ACME_DOMAINS (required by ACME_ENABLED)".to_string()));
+    }
+    let email = env::var("ACME_EMAIL")
+        .ok()
+        .filter(|v| !v.is_empty())
+        .ok_or_else(|| ConfigError::Missing("ACME_EMAIL (required by ACME_ENABLED)".to_string()))?;
+    let staging = env::var("ACME_STAGING").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    let default_directory = if staging {
+        instant_acme::LetsEncrypt::Staging.url()
     } else {
-        Vec::new()
+        instant_acme::LetsEncrypt::Production.url()
+    };
+
+    Ok(Some(AcmeConfig {
+        domains,
+        email,
+        directory_url: env::var("ACME_DIRECTORY_URL").unwrap_or_else(|_| default_directory.to_string()),
+        http_port: env::var("ACME_HTTP_PORT").ok().and_then(|v| v.parse().ok()).unwrap_or(80),
+        renew_before_days: env::var("ACME_RENEW_BEFORE_DAYS").ok().and_then(|v| v.parse().ok()).unwrap_or(30),
+    }))
+}
+
+// How often the stapled OCSP responses are refreshed; None when OCSP_STAPLING is off.
+pub fn load_ocsp_refresh_interval() -> Result<Option<Duration>, ConfigError> {
+    let enabled = env::var("OCSP_STAPLING").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    if !enabled {
+        return Ok(None);
+    }
+    match env_parsed("OCSP_REFRESH_INTERVAL", "a number of seconds")? {
+        Some(0) => Err(ConfigError::invalid("OCSP_REFRESH_INTERVAL", "0", "at least 1 second")),
+        secs => Ok(Some(Duration::from_secs(secs.unwrap_or(60 * 60 * 6)))),
     }
 }
 
-pub fn get_proxy_port(args_proxy_port: Option<u16>) -> u16 {
-    args_proxy_port.unwrap_or_else(|| {
-        env::var("PROXY_PORT")
-            .unwrap_or_else(|_| "3000".to_string())
-            .parse()
-            .unwrap()
+// Only meaningful with SSL=ON. Plain requests (from the REDIRECT_HTTP_PORT listener, or marked
+// `X-Forwarded-Proto: http` by a load balancer in front) are answered with a redirect to https.
+pub fn load_redirect_config() -> Result<Option<RedirectConfig>, ConfigError> {
+    let enabled = env::var("REDIRECT_HTTP_TO_HTTPS").unwrap_or_else(|_| "false".to_string()).to_lowercase() == "true";
+    if !enabled {
+        return Ok(None);
+    }
+
+    let status = env::var("REDIRECT_STATUS").ok().and_then(|v| v.parse().ok()).unwrap_or(301);
+    if status != 301 && status != 308 {
+        return Err(ConfigError::invalid("REDIRECT_STATUS", status.to_string(), "301 or 308"));
+    }
+    let http_port = env_parsed("REDIRECT_HTTP_PORT", "a port number")?;
+
+    Ok(Some(RedirectConfig { status, http_port }))
+}
+
+// How long a SIGTERM waits for in-flight requests before the process exits regardless. Unset keeps
+// the grace_period_seconds of the Pingora conf file (or Pingora's 300s default).
+pub fn load_shutdown_grace_secs() -> Result<Option<u64>, ConfigError> {
+    env_parsed("SHUTDOWN_GRACE_SECS", "a number of seconds")
+}
+
+// How long the health checker, cert watcher and SIGHUP handlers get to stop after the drain.
+pub fn load_shutdown_thread_timeout() -> Result<Duration, ConfigError> {
+    Ok(Duration::from_secs(env_parsed("SHUTDOWN_THREAD_TIMEOUT", "a number of seconds")?.unwrap_or(5)))
+}
+
+// Interface the public listeners bind to; 0.0.0.0 (all IPv4 interfaces) unless BIND_ADDRESS is set.
+pub fn get_bind_address() -> Result<IpAddr, ConfigError> {
+    Ok(env_parsed("BIND_ADDRESS", "an IP address")?.unwrap_or(IpAddr::from([0, 0, 0, 0])))
+}
+
+// `ip:port`, with IPv6 addresses bracketed.
+pub fn listen_addr(ip: IpAddr, port: u16) -> String {
+    std::net::SocketAddr::new(ip, port).to_string()
+}
+
+// The admin listener is off unless ADMIN_PORT is set, and binds to localhost unless told otherwise.
+pub fn get_admin_addr() -> Result<Option<String>, ConfigError> {
+    let Some(port) = env_parsed::<u16>("ADMIN_PORT", "a port number")? else {
+        return Ok(None);
+    };
+    let host = env::var("ADMIN_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+    Ok(Some(format!("{}:{}", host, port)))
+}
+
+// Bearer token for the admin listener's mutating calls; without one they are refused.
+pub fn get_admin_token() -> Option<String> {
+    env::var("ADMIN_TOKEN").ok().filter(|v| !v.trim().is_empty())
+}
+
+// Subject and SANs of the generated self-signed certificate. SELF_SIGNED_SANS defaults to the
+// common name; set it to the hostnames (and IPs) clients actually connect to.
+pub fn load_self_signed_config() -> Result<SelfSignedConfig, ConfigError> {
+    let common_name = env::var("SELF_SIGNED_CN").ok().filter(|v| !v.is_empty()).unwrap_or_else(|| "localhost".to_string());
+    let sans = match env::var("SELF_SIGNED_SANS").map(|v| split_list(&v)) {
+        Ok(sans) if !sans.is_empty() => sans,
+        _ => vec![common_name.clone()],
+    };
+    let key_alg = match env::var("CERT_KEY_ALG").unwrap_or_else(|_| "ecdsa_p256".to_string()).to_lowercase().as_str() {
+        "ecdsa_p256" => CertKeyAlg::EcdsaP256,
+        "rsa2048" => CertKeyAlg::Rsa2048,
+        "rsa4096" => CertKeyAlg::Rsa4096,
+        other => return Err(ConfigError::invalid("CERT_KEY_ALG", other, "ecdsa_p256, rsa2048 or rsa4096")),
+    };
+    let validity_days = env::var("SELF_SIGNED_DAYS").ok().and_then(|v| v.parse().ok()).filter(|days| *days > 0).unwrap_or(365);
+
+    Ok(SelfSignedConfig {
+        common_name,
+        country: env::var("SELF_SIGNED_COUNTRY").unwrap_or_else(|_| "ID".to_string()),
+        state: env::var("SELF_SIGNED_STATE").unwrap_or_else(|_| "NorthSumatera".to_string()),
+        locality: env::var("SELF_SIGNED_LOCALITY").unwrap_or_else(|_| "Medan".to_string()),
+        organization: env::var("SELF_SIGNED_ORG").unwrap_or_else(|_| "Organization".to_string()),
+        sans,
+        validity_days,
+        key_alg,
     })
 }
 
@@ -157,26 +2607,41 @@ pub struct SslEnabled {
     pub status: bool,
     pub cert_loc: String,
     pub key_loc: String,
+    // Whether a missing, unloadable or expiring certificate may be replaced by a self-signed one.
+    pub autogen: bool,
+}
+
+fn ssl_requested() -> bool {
+    env::var("SSL").unwrap_or_else(|_| "OFF".to_string()).to_uppercase() == "ON"
+}
+
+// SSL_AUTOGEN=off makes a missing certificate a startup error instead of a freshly generated
+// self-signed one, so production never ends up quietly serving an untrusted certificate.
+pub fn load_ssl_autogen() -> Result<bool, ConfigError> {
+    match env::var("SSL_AUTOGEN").unwrap_or_default().to_lowercase().as_str() {
+        "" | "on" | "true" => Ok(true),
+        "off" | "false" => Ok(false),
+        other => Err(ConfigError::invalid("SSL_AUTOGEN", other, "on or off")),
+    }
 }
 
 pub fn is_ssl_enabled() -> SslEnabled {
-    let ssl = env::var("SSL").unwrap_or_else(|_| "OFF".to_string()).to_uppercase() == "ON";
+    let ssl = ssl_requested();
+    let autogen = load_ssl_autogen().unwrap_or(true);
     let cert_loc = "ssl/server.pem".to_string();
     let key_loc = "ssl/server.key".to_string();
     let cert = Path::new(&cert_loc);
     let key = Path::new(&key_loc);
 
-    if ssl {
-        if !cert.exists() || !key.exists() {
-            let gen_ssl = generate_cert();
-
-            if gen_ssl.status != "Success".to_string() {
-                warn!("{}", gen_ssl.error);
-                process::exit(1);
-            }
+    if ssl && autogen && (!cert.exists() || !key.exists()) {
+        let gen_ssl = generate_cert();
 
-            info!("SSL Generated !!!");
+        if gen_ssl.status != "Success" {
+            warn!("{}", gen_ssl.error);
+            process::exit(1);
         }
+
+        info!("SSL Generated !!!");
     }
 
     if !cert.exists() {
@@ -193,82 +2658,214 @@ pub fn is_ssl_enabled() -> SslEnabled {
         status: ssl,
         cert_loc: "ssl/server.pem".to_string(),
         key_loc: "ssl/server.key".to_string(),
+        autogen,
     }
 }
 
-// pub struct GenerateSslStatus {
-//     pub status: String,
-//     pub error: String,
-// }
-
-// pub fn generate_ssl() -> GenerateSslStatus {
-//     match Command::new("mkdir").args(&["-p", "ssl"]).status() {
-//         Ok(status) if status.success() => {
-//             info!("Created ssl directory.");
-//         }
-//         Ok(status) => {
-//             return GenerateSslStatus {status: "Error".to_string(), error: format!("mkdir failed with status: {}", status)};
-//         }
-//         Err(err) => {
-//             return GenerateSslStatus {status: "Error".to_string(), error: format!("Failed to create ssl directory: {}", err)};
-//         }
-//     }
-
-//     match Command::new("openssl")
-//         .args(&[
-//             "req", "-x509", "-newkey", "rsa:2048",
-//             "-keyout", "ssl/server.key",
-//             "-out", "ssl/server.pem",
-//             "-days", "365",
-//             "-nodes",
-//             "-subj", "/C=ID/ST=NorthSumatera/L=Medan/O=Organization/CN=localhost",
-//         ])
-//         .status()
-//     {
-//         Ok(status) if status.success() => {
-//             info!("Generated private key and certificate.");
-//         }
-//         Ok(status) => {
-//             return GenerateSslStatus {status: "Error".to_string(), error: format!("openssl req failed with status: {}", status)};
-//         }
-//         Err(err) => {
-//             return GenerateSslStatus {status: "Error".to_string(), error: format!("Failed to run openssl req: {}", err)};
-//         }
-//     }
-
-//     #[cfg(unix)]
-//     {
-//         use std::os::unix::fs::PermissionsExt;
-//         if let Err(e) = std::fs::set_permissions("ssl/server.key", std::fs::Permissions::from_mode(0o600)) {
-//             warn!("Failed to set permissions on private key: {}", e);
-//         }
-//     }
-
-//     if let Err(e) = verify_ssl_files() {
-//         return GenerateSslStatus {status: "Error".to_string(), error: format!("Generated SSL files are invalid: {}", e)};
-//     }
-
-//     GenerateSslStatus {status: "Success".to_string(), error: "".to_string()}
-// }
-
-// fn verify_ssl_files() -> Result<(), String> {
-//     let key_output = Command::new("openssl")
-//         .args(&["rsa", "-in", "ssl/server.key", "-check", "-noout"])
-//         .output()
-//         .map_err(|e| format!("Failed to verify private key: {}", e))?;
-    
-//     if !key_output.status.success() {
-//         return Err(format!("Private key is invalid: {}", String::from_utf8_lossy(&key_output.stderr)));
-//     }
-
-//     let cert_output = Command::new("openssl")
-//         .args(&["x509", "-in", "ssl/server.pem", "-noout"])
-//         .output()
-//         .map_err(|e| format!("Failed to verify certificate: {}", e))?;
-    
-//     if !cert_output.status.success() {
-//         return Err(format!("Certificate is invalid: {}", String::from_utf8_lossy(&cert_output.stderr)));
-//     }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Held by tests that set environment variables or read many of them, since tests share the
+    // process environment.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn env_lock() -> std::sync::MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write_config(name: &str, content: &str) -> String {
+        let path = env::temp_dir().join(format!("pingora-proxy-{}-{}.toml", process::id(), name));
+        fs::write(&path, content).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    // Backend tables are deny_unknown_fields, so a stray key in the shipped example stops startup.
+    #[test]
+    fn example_config_loads() {
+        let _env = env_lock();
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/config.example.toml");
+        let config = load_from_toml(path).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(config.backends.len(), 3);
+        assert_eq!(config.pools.len(), 2);
+    }
+
+    #[test]
+    fn parse_backend_tls_defaults_sni_to_the_hostname() {
+        let backend = parse_backend("api.internal:443:2:tls").unwrap();
+        assert!(backend.tls);
+        assert_eq!(backend.weight, 2);
+        assert_eq!(backend.sni, "api.internal");
+    }
+
+    #[test]
+    fn parse_backend_tls_to_an_ip_leaves_sni_empty() {
+        let backend = parse_backend("10.0.0.5:443:1:tls").unwrap();
+        assert!(backend.tls);
+        assert_eq!(backend.sni, "");
+    }
+
+    #[test]
+    fn parse_backend_takes_an_explicit_sni_and_health_path() {
+        let backend = parse_backend("10.0.0.5:443:1:tls:api.example.com:/healthz").unwrap();
+        assert_eq!(backend.sni, "api.example.com");
+        assert_eq!(backend.health_path.as_deref(), Some("/healthz"));
+        assert_eq!(parse_backend("10.0.0.5:80:1:plain::/ready").unwrap().health_path.as_deref(), Some("/ready"));
+    }
+
+    #[test]
+    fn parse_backend_accepts_bracketed_ipv6() {
+        let backend = parse_backend("[2001:db8::1]:443:5").unwrap();
+        assert_eq!((backend.host.as_str(), backend.port, backend.weight), ("2001:db8::1", 443, 5));
+        assert_eq!(backend.address(), "[2001:db8::1]:443");
+    }
+
+    #[test]
+    fn parse_backend_accepts_hostnames_and_ipv4() {
+        let backend = parse_backend("example.com:8080").unwrap();
+        assert_eq!((backend.host.as_str(), backend.port, backend.weight), ("example.com", 8080, 1));
+        let backend = parse_backend("127.0.0.1:80").unwrap();
+        assert_eq!((backend.host.as_str(), backend.port, backend.weight), ("127.0.0.1", 80, 1));
+    }
+
+    #[test]
+    fn parse_backend_rejects_unbracketed_ipv6() {
+        assert!(parse_backend("2001:db8::1:443").is_none());
+        assert!(parse_backend("[2001:db8::1]").is_none());
+    }
+
+    #[test]
+    fn toml_config_overrides_strategy_and_backends() {
+        let _env = env_lock();
+        let path = write_config(
+            "overrides",
+            "load_balance_strategy = \"least_connections\"\n\n[[backends]]\nhost = \"10.0.0.1\"\nport = 8080\nweight = 3\n",
+        );
+        let config = load_from_toml(&path).unwrap_or_else(|e| panic!("{}", e));
+        assert_eq!(config.load_balance_strategy, LoadBalanceStrategy::LeastConnections);
+        assert_eq!(config.backends.len(), 1);
+        assert_eq!((config.backends[0].address(), config.backends[0].weight), ("10.0.0.1:8080".to_string(), 3));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn toml_config_rejects_unknown_keys_and_strategies() {
+        let _env = env_lock();
+        let path = write_config("unknown-key", "[[backends]]\nhost = \"10.0.0.1\"\nport = 8080\nunhealthy_threshold = 3\n");
+        assert!(matches!(load_from_toml(&path), Err(ConfigError::File { .. })));
+        fs::remove_file(path).unwrap();
+
+        let path = write_config("bad-strategy", "load_balance_strategy = \"fastest\"\n\n[[backends]]\nhost = \"10.0.0.1\"\nport = 8080\n");
+        assert!(matches!(load_from_toml(&path), Err(ConfigError::Invalid { .. })));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn listen_addr_brackets_ipv6() {
+        assert_eq!(listen_addr(IpAddr::from([0, 0, 0, 0]), 3000), "0.0.0.0:3000");
+        assert_eq!(listen_addr("::1".parse().unwrap(), 3000), "[::1]:3000");
+    }
+
+    #[test]
+    fn bind_address_defaults_to_every_interface() {
+        let _env = env_lock();
+        env::remove_var("BIND_ADDRESS");
+        assert_eq!(get_bind_address().unwrap(), IpAddr::from([0, 0, 0, 0]));
+        env::set_var("BIND_ADDRESS", "::1");
+        assert_eq!(get_bind_address().unwrap(), "::1".parse::<IpAddr>().unwrap());
+        env::set_var("BIND_ADDRESS", "localhost");
+        assert!(get_bind_address().is_err());
+        env::remove_var("BIND_ADDRESS");
+    }
+
+    fn listeners(value: &str) -> Result<Vec<ListenerConfig>, ConfigError> {
+        let _env = env_lock();
+        env::set_var("PROXY_PORT", value);
+        let listeners = load_listeners();
+        env::remove_var("PROXY_PORT");
+        listeners
+    }
+
+    #[test]
+    fn listeners_take_several_ports() {
+        assert_eq!(
+            listeners("80:plain, 443:tls,8080").unwrap(),
+            vec![
+                ListenerConfig { port: 80, tls: Some(false) },
+                ListenerConfig { port: 443, tls: Some(true) },
+                ListenerConfig { port: 8080, tls: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn listeners_reject_duplicate_ports() {
+        assert!(matches!(listeners("443:tls,443:plain"), Err(ConfigError::Conflict(_))));
+    }
 
-//     Ok(())
-// }
\ No newline at end of file
+    #[test]
+    fn listeners_reject_invalid_ports() {
+        for value in ["http", "70000", "443:quic", ","] {
+            assert!(listeners(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn config_errors_read_as_one_line_each() {
+        assert_eq!(ConfigError::Missing("BACKENDS".to_string()).to_string(), "BACKENDS must be set and not empty");
+        assert_eq!(
+            ConfigError::invalid("ADMIN_PORT", "abc", "a port number").to_string(),
+            "Invalid ADMIN_PORT 'abc', expected a port number"
+        );
+        assert_eq!(
+            ConfigError::Backend { source: "BACKENDS".to_string(), entry: "nope".to_string() }.to_string(),
+            "Invalid backend 'nope' in BACKENDS, expected host:port[:weight[:tls[:sni[:health_path]]]]"
+        );
+        assert_eq!(
+            ConfigError::File { path: "ssl/server.pem".to_string(), reason: "not found".to_string() }.to_string(),
+            "Failed to read ssl/server.pem: not found"
+        );
+        assert_eq!(ConfigError::Conflict("SSL is off".to_string()).to_string(), "SSL is off");
+    }
+
+    #[test]
+    fn validate_reports_every_error_at_once() {
+        let _env = env_lock();
+        let vars = [("BACKENDS", "127.0.0.1:8080,not-a-backend"), ("LOAD_BALANCE_STRATEGY", "fastest"), ("PROXY_PORT", "0")];
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let errors: Vec<String> = validate(None).iter().map(ConfigError::to_string).collect();
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+
+        assert!(errors.contains(&"Invalid backend 'not-a-backend' in BACKENDS, expected host:port[:weight[:tls[:sni[:health_path]]]]".to_string()), "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("Invalid LOAD_BALANCE_STRATEGY 'fastest'")), "{:?}", errors);
+        assert!(errors.iter().any(|e| e.starts_with("Invalid PROXY_PORT '0'")), "{:?}", errors);
+    }
+
+    #[test]
+    fn success_codes_accept_single_codes_and_ranges() {
+        let (codes, dropped) = parse_success_codes("200, 204,301-303,204");
+        assert_eq!(codes, vec![200, 204, 301, 302, 303]);
+        assert!(dropped.is_empty());
+    }
+
+    #[test]
+    fn success_codes_drop_entries_outside_100_to_599() {
+        let (codes, dropped) = parse_success_codes("99,200,600,300-299,500-600,abc,2xx");
+        assert_eq!(codes, vec![200]);
+        assert_eq!(dropped, vec!["99", "600", "300-299", "500-600", "abc", "2xx"]);
+    }
+
+    #[test]
+    fn health_check_falls_back_to_200_when_no_success_code_is_valid() {
+        let _env = env_lock();
+        env::set_var("HEALTH_CHECK_SUCCESS_CODES", "0,700");
+        let config = load_health_check_config();
+        env::remove_var("HEALTH_CHECK_SUCCESS_CODES");
+        assert_eq!(config.unwrap().success_codes, vec![200]);
+    }
+}
@@ -0,0 +1,114 @@
+use log::info;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use crate::config::ConnectionLimitConfig;
+
+// Caps how many requests the proxy handles at once, overall and per client IP. Pingora has no hook
+// at accept time, so a slot is taken when a request arrives and given back once it has been logged;
+// idle keep-alive connections don't hold one, but a tunnelled upgrade does for as long as it's open.
+pub struct ConnectionLimiter {
+    max: Option<usize>,
+    per_ip: Option<usize>,
+    active: Mutex<Active>,
+}
+
+#[derive(Default)]
+struct Active {
+    total: usize,
+    by_ip: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(config: ConnectionLimitConfig) -> Self {
+        info!(
+            "🚧 Connection limits: {} in total, {} per client IP",
+            config.max.map_or("unlimited".to_string(), |max| max.to_string()),
+            config.per_ip.map_or("unlimited".to_string(), |max| max.to_string())
+        );
+        Self { max: config.max, per_ip: config.per_ip, active: Mutex::new(Active::default()) }
+    }
+
+    // None once either cap is reached; the slot is released when the returned guard is dropped.
+    pub fn acquire(self: &Arc<Self>, ip: Option<IpAddr>) -> Option<ConnectionSlot> {
+        let ip = ip.map(|ip| ip.to_canonical());
+        let mut active = self.active.lock().unwrap();
+        if self.max.is_some_and(|max| active.total >= max) {
+            return None;
+        }
+        if let (Some(per_ip), Some(ip)) = (self.per_ip, ip) {
+            if active.by_ip.get(&ip).is_some_and(|count| *count >= per_ip) {
+                return None;
+            }
+            *active.by_ip.entry(ip).or_insert(0) += 1;
+        }
+        active.total += 1;
+        Some(ConnectionSlot { limiter: self.clone(), ip: ip.filter(|_| self.per_ip.is_some()) })
+    }
+
+    fn release(&self, ip: Option<IpAddr>) {
+        let mut active = self.active.lock().unwrap();
+        active.total -= 1;
+        if let Some(ip) = ip {
+            if let Some(count) = active.by_ip.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    active.by_ip.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+pub struct ConnectionSlot {
+    limiter: Arc<ConnectionLimiter>,
+    ip: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.limiter.release(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limiter(max: Option<usize>, per_ip: Option<usize>) -> Arc<ConnectionLimiter> {
+        Arc::new(ConnectionLimiter::new(ConnectionLimitConfig { max, per_ip }))
+    }
+
+    fn ip(last: u8) -> Option<IpAddr> {
+        Some(IpAddr::from([10, 0, 0, last]))
+    }
+
+    #[test]
+    fn total_cap_rejects_one_past_the_limit() {
+        let limiter = limiter(Some(3), None);
+        let slots: Vec<ConnectionSlot> = (1..=3).map(|i| limiter.acquire(ip(i)).unwrap()).collect();
+        assert!(limiter.acquire(ip(4)).is_none());
+        assert!(limiter.acquire(None).is_none());
+        drop(slots);
+    }
+
+    #[test]
+    fn per_ip_cap_only_limits_that_client() {
+        let limiter = limiter(None, Some(2));
+        let _first = limiter.acquire(ip(1)).unwrap();
+        let _second = limiter.acquire(ip(1)).unwrap();
+        assert!(limiter.acquire(ip(1)).is_none());
+        assert!(limiter.acquire(ip(2)).is_some());
+        assert!(limiter.acquire("::ffff:10.0.0.1".parse().ok()).is_none());
+    }
+
+    #[test]
+    fn dropping_a_slot_frees_it() {
+        let limiter = limiter(Some(1), Some(1));
+        let slot = limiter.acquire(ip(1)).unwrap();
+        assert!(limiter.acquire(ip(1)).is_none());
+        drop(slot);
+        let _again = limiter.acquire(ip(1)).unwrap();
+        assert_eq!(limiter.active.lock().unwrap().by_ip.get(&IpAddr::from([10, 0, 0, 1])), Some(&1));
+    }
+}
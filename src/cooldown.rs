@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+
+// Minimal resilience for when active health checks are off and nothing else would ever take a
+// dead backend out of rotation: a failed connect hides the backend for a fixed cooldown, after
+// which it is optimistically tried again. Keyed by host:port like the circuit breaker.
+pub struct ConnectCooldown {
+    duration: Duration,
+    until: Mutex<HashMap<String, Instant>>,
+}
+
+impl ConnectCooldown {
+    pub fn new(duration: Duration) -> Self {
+        info!("🧊 Health checks disabled, backends that refuse connections are skipped for {}s", duration.as_secs());
+        Self { duration, until: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn start(&self, address: &str) {
+        let mut until = self.until.lock().unwrap();
+        if until.get(address).is_none_or(|until| Instant::now() >= *until) {
+            warn!("🧊 Connect to {} failed, skipping it for {}s", address, self.duration.as_secs());
+        }
+        until.insert(address.to_string(), Instant::now() + self.duration);
+    }
+
+    pub fn is_cooling(&self, address: &str) -> bool {
+        let mut until = self.until.lock().unwrap();
+        match until.get(address) {
+            Some(deadline) if Instant::now() < *deadline => true,
+            Some(_) => {
+                info!("🧊 Cooldown for {} expired, trying it again", address);
+                until.remove(address);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_backend_cools_down_until_the_duration_passes() {
+        let cooldown = ConnectCooldown::new(Duration::from_millis(20));
+        assert!(!cooldown.is_cooling("10.0.0.1:80"));
+        cooldown.start("10.0.0.1:80");
+        assert!(cooldown.is_cooling("10.0.0.1:80"));
+        assert!(!cooldown.is_cooling("10.0.0.2:80"));
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(!cooldown.is_cooling("10.0.0.1:80"));
+    }
+
+    #[test]
+    fn another_failure_extends_the_cooldown() {
+        let cooldown = ConnectCooldown::new(Duration::from_millis(40));
+        cooldown.start("10.0.0.1:80");
+        std::thread::sleep(Duration::from_millis(25));
+        cooldown.start("10.0.0.1:80");
+        std::thread::sleep(Duration::from_millis(25));
+        assert!(cooldown.is_cooling("10.0.0.1:80"));
+    }
+}
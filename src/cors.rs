@@ -0,0 +1,58 @@
+use log::info;
+use pingora_core::Result;
+use pingora_http::{Method, RequestHeader, ResponseHeader};
+use crate::config::CorsConfig;
+
+// Answers preflights and decorates responses on behalf of every backend. Allowed origins are
+// always echoed back (never `*`) so credentialed requests work and caches see `Vary: Origin`.
+pub struct Cors {
+    config: CorsConfig,
+}
+
+impl Cors {
+    pub fn new(config: CorsConfig) -> Self {
+        info!("🌐 CORS enabled for origins: {}", config.allowed_origins.join(", "));
+        Self { config }
+    }
+
+    pub fn is_allowed(&self, origin: &str) -> bool {
+        self.config.allowed_origins.iter().any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(origin))
+    }
+
+    pub fn is_preflight(req: &RequestHeader) -> bool {
+        req.method == Method::OPTIONS
+            && req.headers.contains_key("Origin")
+            && req.headers.contains_key("Access-Control-Request-Method")
+    }
+
+    // The 204 sent in place of forwarding a preflight for an allowed origin.
+    pub fn preflight_response(&self, req: &RequestHeader, origin: &str) -> Result<ResponseHeader> {
+        let mut header = ResponseHeader::build(204, Some(6))?;
+        self.apply(&mut header, origin)?;
+        header.insert_header("Access-Control-Allow-Methods", self.config.allowed_methods.join(", "))?;
+
+        // `*` in allowed_headers means whatever the browser asks for.
+        let requested = req.headers.get("Access-Control-Request-Headers").and_then(|v| v.to_str().ok());
+        let allow_headers = match requested {
+            Some(requested) if self.config.allowed_headers.iter().any(|h| h == "*") => requested.to_string(),
+            _ => self.config.allowed_headers.join(", "),
+        };
+        if !allow_headers.is_empty() {
+            header.insert_header("Access-Control-Allow-Headers", allow_headers)?;
+        }
+        if let Some(max_age) = self.config.max_age_secs {
+            header.insert_header("Access-Control-Max-Age", max_age.to_string())?;
+        }
+        header.insert_header("Content-Length", "0")?;
+        Ok(header)
+    }
+
+    pub fn apply(&self, resp: &mut ResponseHeader, origin: &str) -> Result<()> {
+        resp.insert_header("Access-Control-Allow-Origin", origin)?;
+        if self.config.allow_credentials {
+            resp.insert_header("Access-Control-Allow-Credentials", "true")?;
+        }
+        resp.append_header("Vary", "Origin")?;
+        Ok(())
+    }
+}
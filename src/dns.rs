@@ -0,0 +1,48 @@
+use futures::future::join_all;
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use crate::backend::Backend;
+
+// Re-resolves hostname backends (a Kubernetes service, a DNS-balanced fleet) every `interval`, so
+// rolling IP changes are picked up without a restart. The system resolver doesn't expose record
+// TTLs, so the interval stands in for them.
+pub async fn refresh_loop(pools: Vec<Arc<RwLock<Vec<Backend>>>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        // Backends share their address list with the pool's copy, so updating the snapshot is enough.
+        let hostnames: Vec<Backend> = pools
+            .iter()
+            .flat_map(|backends| backends.read().unwrap().clone())
+            .filter(|backend| backend.is_hostname())
+            .collect();
+        join_all(hostnames.iter().map(refresh)).await;
+    }
+}
+
+// A failed lookup keeps the last known addresses rather than leaving the backend unreachable.
+async fn refresh(backend: &Backend) {
+    let mut addrs: Vec<SocketAddr> = match tokio::net::lookup_host((backend.host.as_str(), backend.port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            warn!("⚠️ Failed to re-resolve {}, keeping its previous addresses: {}", backend.address(), e);
+            return;
+        }
+    };
+    if addrs.is_empty() {
+        warn!("⚠️ {} resolved to no addresses, keeping its previous ones", backend.address());
+        return;
+    }
+    addrs.sort();
+    addrs.dedup();
+
+    let mut resolved = backend.resolved.write().unwrap();
+    let mut previous = resolved.clone();
+    previous.sort();
+    if previous != addrs {
+        let list: Vec<String> = addrs.iter().map(|addr| addr.ip().to_string()).collect();
+        info!("🌐 {} now resolves to {}", backend.address(), list.join(", "));
+        *resolved = addrs;
+    }
+}
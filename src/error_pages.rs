@@ -0,0 +1,110 @@
+use bytes::Bytes;
+use http::StatusCode;
+use log::info;
+use pingora_core::Result;
+use pingora_http::{Method, ResponseHeader};
+use pingora_proxy::Session;
+use serde_json::json;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use crate::config::ErrorFormat;
+
+struct ErrorPage {
+    content_type: &'static str,
+    body: Bytes,
+}
+
+// Every error the proxy generates itself goes through here, so they share one shape: an
+// ERROR_PAGE_<code> file when one is configured, otherwise a body in ERROR_FORMAT carrying the
+// status and a short reason, e.g. {"error": "no healthy backend", "code": 503}.
+pub struct ErrorPages {
+    pages: HashMap<u16, ErrorPage>,
+    format: ErrorFormat,
+}
+
+impl ErrorPages {
+    pub fn new(configured: &HashMap<u16, String>, format: ErrorFormat) -> Self {
+        let mut pages = HashMap::new();
+        for (code, path) in configured {
+            let body = fs::read(path).unwrap_or_else(|e| panic!("❌ Failed to read error page {} for {}: {}", path, code, e));
+            info!("📄 Serving {} for {} responses", path, code);
+            pages.insert(*code, ErrorPage { content_type: content_type(path), body: Bytes::from(body) });
+        }
+        Self { pages, format }
+    }
+
+    pub async fn respond(&self, session: &mut Session, code: u16) -> Result<()> {
+        self.send(session, code, None, &[]).await
+    }
+
+    // With a reason more specific than the status text and any headers the status calls for,
+    // like Allow on a 405 or WWW-Authenticate on a 401.
+    pub async fn respond_with(&self, session: &mut Session, code: u16, reason: &str, headers: &[(&'static str, String)]) -> Result<()> {
+        self.send(session, code, Some(reason), headers).await
+    }
+
+    // 503 for a condition expected to clear up, e.g. no healthy backend, with a hint on when to retry.
+    pub async fn respond_unavailable(&self, session: &mut Session, reason: &str, retry_after_secs: u64) -> Result<()> {
+        self.send(session, 503, Some(reason), &[("Retry-After", retry_after_secs.to_string())]).await
+    }
+
+    async fn send(&self, session: &mut Session, code: u16, reason: Option<&str>, headers: &[(&'static str, String)]) -> Result<()> {
+        let (content_type, body) = match self.pages.get(&code) {
+            Some(page) => (page.content_type, page.body.clone()),
+            None => {
+                let reason = reason.unwrap_or_else(|| status_text(code));
+                render(self.format, code, reason)
+            }
+        };
+
+        let mut header = ResponseHeader::build(code, Some(4 + headers.len()))?;
+        header.insert_header("Content-Type", content_type)?;
+        header.insert_header("Content-Length", body.len().to_string())?;
+        header.insert_header("Cache-Control", "no-store")?;
+        for (name, value) in headers {
+            header.insert_header(*name, value.as_str())?;
+        }
+        if session.req_header().method == Method::HEAD {
+            session.write_response_header(Box::new(header), true).await
+        } else {
+            session.write_response_header(Box::new(header), false).await?;
+            session.write_response_body(Some(body), true).await
+        }
+    }
+}
+
+fn status_text(code: u16) -> &'static str {
+    StatusCode::from_u16(code).ok().and_then(|s| s.canonical_reason()).unwrap_or("Error")
+}
+
+fn render(format: ErrorFormat, code: u16, reason: &str) -> (&'static str, Bytes) {
+    match format {
+        ErrorFormat::Json => ("application/json", Bytes::from(format!("{}\n", json!({ "error": reason, "code": code })))),
+        ErrorFormat::Plain => ("text/plain; charset=utf-8", Bytes::from(format!("{} {}: {}\n", code, status_text(code), reason))),
+        ErrorFormat::Html => {
+            let status = status_text(code);
+            let reason = html_escape(reason);
+            (
+                "text/html; charset=utf-8",
+                Bytes::from(format!(
+                    "<!DOCTYPE html>\n<html><head><title>{code} {status}</title></head>\
+                     <body><h1>{code} {status}</h1><p>{reason}</p></body></html>\n"
+                )),
+            )
+        }
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
+}
+
+fn content_type(path: &str) -> &'static str {
+    match Path::new(path).extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+        Some("json") => "application/json",
+        Some("txt") => "text/plain; charset=utf-8",
+        Some("xml") => "application/xml",
+        _ => "text/html; charset=utf-8",
+    }
+}
@@ -1,6 +1,11 @@
-use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, SanType};
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::x509::X509;
+use rcgen::{Certificate, CertificateParams, DistinguishedName, DnType, KeyPair, SanType, PKCS_RSA_SHA256};
 use std::fs;
+use std::net::IpAddr;
 use time::{OffsetDateTime, Duration};
+use crate::config::{load_self_signed_config, CertKeyAlg};
 
 pub struct GenerateSslStatus {
     pub status: String,
@@ -15,24 +20,61 @@ pub fn generate_cert() -> GenerateSslStatus {
         };
     }
 
-    // Get current time and add 365 days
+    let config = match load_self_signed_config() {
+        Ok(config) => config,
+        Err(e) => {
+            return GenerateSslStatus {
+                status: "Error".to_string(),
+                error: e.to_string(),
+            };
+        }
+    };
+
+    // Valid from now for the configured number of days
     let now = OffsetDateTime::now_utc();
-    let one_year_later = now + Duration::days(365);
 
     // Configure certificate parameters
     let mut params = CertificateParams::default();
     params.not_before = now;
-    params.not_after = one_year_later;
+    params.not_after = now + Duration::days(config.validity_days);
     params.distinguished_name = {
         let mut dn = DistinguishedName::new();
-        dn.push(DnType::CountryName, "ID");
-        dn.push(DnType::StateOrProvinceName, "NorthSumatera");
-        dn.push(DnType::LocalityName, "Medan");
-        dn.push(DnType::OrganizationName, "Organization");
-        dn.push(DnType::CommonName, "localhost");
+        dn.push(DnType::CountryName, config.country.as_str());
+        dn.push(DnType::StateOrProvinceName, config.state.as_str());
+        dn.push(DnType::LocalityName, config.locality.as_str());
+        dn.push(DnType::OrganizationName, config.organization.as_str());
+        dn.push(DnType::CommonName, config.common_name.as_str());
         dn
     };
-    params.subject_alt_names = vec![SanType::DnsName("localhost".to_string())];
+    params.subject_alt_names = config
+        .sans
+        .iter()
+        .map(|san| match san.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(san.clone()),
+        })
+        .collect();
+
+    // rcgen (ring) can only generate ECDSA keys itself; RSA keys come from openssl.
+    let rsa_bits = match config.key_alg {
+        CertKeyAlg::EcdsaP256 => None,
+        CertKeyAlg::Rsa2048 => Some(2048),
+        CertKeyAlg::Rsa4096 => Some(4096),
+    };
+    if let Some(bits) = rsa_bits {
+        match rsa_key_pair(bits) {
+            Ok(key_pair) => {
+                params.alg = &PKCS_RSA_SHA256;
+                params.key_pair = Some(key_pair);
+            }
+            Err(e) => {
+                return GenerateSslStatus {
+                    status: "Error".to_string(),
+                    error: format!("Failed to generate RSA key: {}", e),
+                };
+            }
+        }
+    }
 
     // Generate the certificate
     let cert = match Certificate::from_params(params) {
@@ -72,8 +114,38 @@ pub fn generate_cert() -> GenerateSslStatus {
         }
     }
 
+    if let Err(e) = verify_ssl_files() {
+        return GenerateSslStatus {
+            status: "Error".to_string(),
+            error: format!("Generated SSL files are invalid: {}", e),
+        };
+    }
+
     GenerateSslStatus {
         status: "Success".to_string(),
         error: "".to_string(),
     }
-}
\ No newline at end of file
+}
+
+fn rsa_key_pair(bits: u32) -> Result<KeyPair, String> {
+    let rsa = Rsa::generate(bits).map_err(|e| e.to_string())?;
+    let pem = PKey::from_rsa(rsa).and_then(|key| key.private_key_to_pem_pkcs8()).map_err(|e| e.to_string())?;
+    let pem = String::from_utf8(pem).map_err(|e| e.to_string())?;
+    KeyPair::from_pem(&pem).map_err(|e| e.to_string())
+}
+
+// Reads the written files back so a bad write is caught here rather than at the TLS handshake.
+fn verify_ssl_files() -> Result<(), String> {
+    let key_pem = fs::read("ssl/server.key").map_err(|e| format!("Failed to read private key: {}", e))?;
+    let key = PKey::private_key_from_pem(&key_pem).map_err(|e| format!("Private key is invalid: {}", e))?;
+
+    let cert_pem = fs::read("ssl/server.pem").map_err(|e| format!("Failed to read certificate: {}", e))?;
+    let cert = X509::from_pem(&cert_pem).map_err(|e| format!("Certificate is invalid: {}", e))?;
+
+    let cert_key = cert.public_key().map_err(|e| format!("Certificate public key is invalid: {}", e))?;
+    if !cert_key.public_eq(&key) {
+        return Err("Certificate does not match the private key".to_string());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use log::{debug, info};
+use maxminddb::{geoip2, Reader};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use crate::config::{ConfigError, GeoIpConfig};
+
+// Looks clients up in a MaxMind country (or city) database and names the pool mapped to their
+// country, else their continent.
+pub struct GeoIp {
+    reader: Reader<Vec<u8>>,
+    countries: HashMap<String, String>,
+    continents: HashMap<String, String>,
+}
+
+impl GeoIp {
+    pub fn open(config: GeoIpConfig) -> Result<Self, ConfigError> {
+        let reader = Reader::open_readfile(&config.db_path)
+            .map_err(|e| ConfigError::File { path: config.db_path.clone(), reason: e.to_string() })?;
+        info!(
+            "🌍 GeoIP routing with {} ({} countries, {} continents mapped)",
+            reader.metadata.database_type,
+            config.countries.len(),
+            config.continents.len()
+        );
+        Ok(Self { reader, countries: config.countries, continents: config.continents })
+    }
+
+    // None for addresses the database can't place (private, loopback, unlisted) and for regions
+    // without a mapping, so those clients keep the pool they were routed to.
+    pub fn pool_for(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        if !is_public(ip) {
+            return None;
+        }
+        let record: geoip2::Country = match self.reader.lookup(ip) {
+            Ok(record) => record,
+            Err(e) => {
+                debug!("🌍 No GeoIP record for {}: {}", ip, e);
+                return None;
+            }
+        };
+        let country = record.country.and_then(|c| c.iso_code).and_then(|code| self.countries.get(code));
+        let continent = || record.continent.and_then(|c| c.code).and_then(|code| self.continents.get(code));
+        country.or_else(continent).map(String::as_str)
+    }
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+        // fc00::/7 (unique local) and fe80::/10 (link local).
+        IpAddr::V6(ip) => {
+            !(ip.is_loopback() || ip.is_unspecified() || (ip.segments()[0] & 0xfe00) == 0xfc00 || (ip.segments()[0] & 0xffc0) == 0xfe80)
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use pingora_core::Result;
+use pingora_http::{RequestHeader, ResponseHeader};
+use crate::config::HeaderRewriteConfig;
+
+// Regex rewrites of existing header values, e.g. turning an internal `Location` or `Set-Cookie`
+// domain into the public one. Every value of a multi-valued header is rewritten on its own.
+pub struct HeaderRewriter {
+    rules: Vec<HeaderRewriteConfig>,
+}
+
+impl HeaderRewriter {
+    pub fn new(rules: Vec<HeaderRewriteConfig>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rewrite_request(&self, req: &mut RequestHeader) -> Result<()> {
+        for rule in &self.rules {
+            if let Some(values) = rewritten(rule, req.headers.get_all(rule.header.as_str()).iter()) {
+                req.remove_header(rule.header.as_str());
+                for value in values {
+                    req.append_header(rule.header.clone(), value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rewrite_response(&self, resp: &mut ResponseHeader) -> Result<()> {
+        for rule in &self.rules {
+            if let Some(values) = rewritten(rule, resp.headers.get_all(rule.header.as_str()).iter()) {
+                resp.remove_header(rule.header.as_str());
+                for value in values {
+                    resp.append_header(rule.header.clone(), value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// The header's values after applying `rule`, or None when none of them match, so untouched headers
+// keep their original bytes and order. Headers with a non-UTF-8 value are left alone entirely.
+fn rewritten<'a>(rule: &HeaderRewriteConfig, values: impl Iterator<Item = &'a http::HeaderValue>) -> Option<Vec<String>> {
+    let values: Vec<&str> = values.map(|v| v.to_str().ok()).collect::<Option<_>>()?;
+    if !values.iter().any(|v| rule.pattern.is_match(v)) {
+        return None;
+    }
+    Some(values.iter().map(|v| rule.pattern.replace_all(v, rule.replacement.as_str()).into_owned()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn rewriter(header: &str, pattern: &str, replacement: &str) -> HeaderRewriter {
+        HeaderRewriter::new(vec![HeaderRewriteConfig {
+            header: header.to_string(),
+            pattern: Regex::new(pattern).unwrap(),
+            replacement: replacement.to_string(),
+        }])
+    }
+
+    #[test]
+    fn rewrites_an_internal_location() {
+        let rewriter = rewriter("Location", "^http://internal:8080/", "https://public/");
+        let mut resp = ResponseHeader::build(302, None).unwrap();
+        resp.insert_header("Location", "http://internal:8080/x").unwrap();
+        rewriter.rewrite_response(&mut resp).unwrap();
+        assert_eq!(resp.headers.get("Location").unwrap(), "https://public/x");
+    }
+
+    #[test]
+    fn rewrites_every_value_of_a_multi_valued_header() {
+        let rewriter = rewriter("Set-Cookie", "Domain=internal", "Domain=public");
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.append_header("Set-Cookie", "a=1; Domain=internal").unwrap();
+        resp.append_header("Set-Cookie", "b=2").unwrap();
+        resp.append_header("Set-Cookie", "c=3; Domain=internal").unwrap();
+        rewriter.rewrite_response(&mut resp).unwrap();
+        let cookies: Vec<&str> = resp.headers.get_all("Set-Cookie").iter().map(|v| v.to_str().unwrap()).collect();
+        assert_eq!(cookies, ["a=1; Domain=public", "b=2", "c=3; Domain=public"]);
+    }
+
+    #[test]
+    fn leaves_requests_without_a_match_alone() {
+        let rewriter = rewriter("Referer", "^http://internal", "https://public");
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("Referer", "https://elsewhere/").unwrap();
+        rewriter.rewrite_request(&mut req).unwrap();
+        assert_eq!(req.headers.get("Referer").unwrap(), "https://elsewhere/");
+    }
+}
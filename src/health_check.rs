@@ -1,58 +1,542 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use log::{info, warn};
+use std::time::{Duration, Instant};
+use futures::future::join_all;
+use log::{debug, info, warn};
+use rand::Rng;
 use reqwest::Client;
-use crate::backend::Backend;
-use crate::config::HealthCheckConfig;
+use tokio::net::TcpStream;
+use crate::backend::{Backend, CaBundle};
+use crate::config::{BackendTlsConfig, HealthCheckConfig};
+
+// How much of a probe's body HEALTH_CHECK_EXPECT_BODY is matched against.
+const HEALTH_BODY_LIMIT: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthCheckMode {
+    Http,
+    Tcp,
+}
+
+impl HealthCheckMode {
+    pub fn _from_str(s: &str) -> Option<Self> {
+        match s.to_lowercase().as_str() {
+            "http" => Some(Self::Http),
+            "tcp" => Some(Self::Tcp),
+            _ => None,
+        }
+    }
+}
 
 pub struct HealthChecker;
 
+// How a probe client treats TLS backends; backends sharing these share a client.
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ProbeTls {
+    verify: bool,
+    ca_path: Option<String>,
+    check_hostname: bool,
+}
+
 impl HealthChecker {
     pub async fn health_check_loop(
         backends: Arc<RwLock<Vec<Backend>>>,
         config: HealthCheckConfig,
+        backend_tls: BackendTlsConfig,
     ) {
         if !config.enabled {
             info!("🩺 Health check service is disabled");
             return;
         }
         
-        let client = Client::new();
-        let mut interval = tokio::time::interval(Duration::from_secs(config.interval_secs));
+        // Clients live for the whole loop, one per TLS setup in use (usually just one). Their pools
+        // keep each backend's probe connection open across cycles, so steady-state probes skip the
+        // TCP/TLS handshake.
+        let longest_interval = backends.read().unwrap().iter().filter_map(|b| b.health_interval_secs).fold(config.interval_secs, u64::max);
+        let idle_timeout = Duration::from_secs(config.pool_idle_timeout_secs.unwrap_or(longest_interval * 2));
+        let mut clients: HashMap<ProbeTls, Client> = HashMap::new();
         
-        info!("🩺 Starting health check service (interval: {}s)", config.interval_secs);
+        info!(
+            "🩺 Starting health check service (mode: {:?}, interval: {}s ±{}%)",
+            config.mode, config.interval_secs, config.jitter_percent
+        );
+        if config.mode == HealthCheckMode::Http && config.pool_max_idle > 0 && idle_timeout.as_secs() <= longest_interval {
+            warn!(
+                "⚠️ Health check idle timeout ({}s) is not longer than the interval ({}s), probe connections won't be reused",
+                idle_timeout.as_secs(), longest_interval
+            );
+        }
         
+        // When each backend (by address) is next due; one not listed yet is due at once.
+        let mut next_due: HashMap<String, Instant> = HashMap::new();
         loop {
-            interval.tick().await;
-            
-            let mut backends_write = backends.write().unwrap();
-            for backend in backends_write.iter_mut() {
-                match HealthChecker::check_backend(&client, backend, &config).await {
-                    Ok(healthy) => {
-                        backend.healthy = healthy;
-                        backend.last_checked = Some(std::time::Instant::now());
-                    }
-                    Err(e) => {
-                        warn!("Health check failed for {}:{}: {}", backend.host, backend.port, e);
-                        backend.healthy = false;
+            // Probe a snapshot so the lock isn't held while waiting on the network; `upstream_peer`
+            // keeps reading the live list in the meantime.
+            let snapshot = backends.read().unwrap().clone();
+            next_due.retain(|address, _| snapshot.iter().any(|b| &b.address() == address));
+            let now = Instant::now();
+            let due: Vec<&Backend> = snapshot
+                .iter()
+                .filter(|b| b.drain_started.is_none() && next_due.get(&b.address()).is_none_or(|at| *at <= now))
+                .collect();
+            for backend in &due {
+                let tls = HealthChecker::probe_tls(backend, &config, &backend_tls);
+                let ca = backend.ca_bundle.as_ref().or(backend_tls.ca_bundle.as_ref()).filter(|_| tls.ca_path.is_some());
+                clients.entry(tls.clone()).or_insert_with(|| HealthChecker::build_client(&config, &tls, ca, idle_timeout));
+            }
+            // Probes run concurrently, each bounded by its own timeout, so a slow backend only
+            // costs one timeout per cycle instead of delaying everyone queued behind it.
+            let probes = due.iter().map(|backend| {
+                let client = &clients[&HealthChecker::probe_tls(backend, &config, &backend_tls)];
+                let config = &config;
+                async move {
+                    let failure = match HealthChecker::check_with_retries(client, backend, config).await {
+                        Ok(rejection) => rejection,
+                        Err(e) => {
+                            warn!("Health check failed for {}: {}", backend.address(), e);
+                            Some(e.to_string())
+                        }
+                    };
+                    (backend.address(), failure)
+                }
+            });
+            let results = join_all(probes).await;
+
+            // Apply by address: the list may have been reloaded while probes were in flight, and
+            // backends that disappeared are simply skipped.
+            let checked_at = std::time::Instant::now();
+            {
+                let mut backends_write = backends.write().unwrap();
+                for (address, failure) in results {
+                    if let Some(backend) = backends_write.iter_mut().find(|b| b.address() == address) {
+                        backend.last_checked = Some(checked_at);
+                        HealthChecker::apply_probe_result(backend, failure, &config);
                     }
                 }
             }
+
+            for backend in due {
+                let interval = HealthChecker::probe_interval(backend, &config);
+                next_due.insert(backend.address(), checked_at + HealthChecker::jittered_interval(interval, &config));
+            }
+
+            // Sleep until the next backend is due, but no longer than the default interval so
+            // backends added in the meantime get their first probe in reasonable time.
+            let cap = checked_at + Duration::from_secs(config.interval_secs);
+            let wake = next_due.values().min().map_or(cap, |at| (*at).min(cap));
+            tokio::time::sleep_until(wake.into()).await;
+        }
+    }
+
+    // Probes verify like the proxy does, unless HEALTH_CHECK_TLS_VERIFY turns it off for all of
+    // them. The URL names the backend's host, so the name is only checked when that's what the
+    // proxy checks too; otherwise the chain is still verified.
+    fn probe_tls(backend: &Backend, config: &HealthCheckConfig, backend_tls: &BackendTlsConfig) -> ProbeTls {
+        if !backend.tls {
+            return ProbeTls { verify: true, ca_path: None, check_hostname: true };
+        }
+        let verify = config.tls_verify && backend.tls_verify.unwrap_or(backend_tls.verify);
+        let ca_path = backend.ca_bundle.as_ref().or(backend_tls.ca_bundle.as_ref()).map(|ca| ca.path.clone());
+        let check_hostname = backend.verify_name.is_none() && backend.sni == backend.host;
+        ProbeTls { verify, ca_path: ca_path.filter(|_| verify), check_hostname }
+    }
+
+    fn build_client(config: &HealthCheckConfig, tls: &ProbeTls, ca: Option<&CaBundle>, idle_timeout: Duration) -> Client {
+        let mut builder = Client::builder()
+            .danger_accept_invalid_certs(!tls.verify)
+            .danger_accept_invalid_hostnames(!tls.check_hostname)
+            .connect_timeout(HealthChecker::connect_timeout(config))
+            .pool_max_idle_per_host(config.pool_max_idle)
+            .pool_idle_timeout(idle_timeout)
+            .tcp_keepalive(Duration::from_secs(config.interval_secs.max(1)));
+        if let Some(ca) = ca {
+            // The bundle replaces the system roots, as it does for proxied requests.
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in ca.certs.iter() {
+                match cert.to_der().map_err(|e| e.to_string()).and_then(|der| reqwest::Certificate::from_der(&der).map_err(|e| e.to_string())) {
+                    Ok(cert) => builder = builder.add_root_certificate(cert),
+                    Err(e) => warn!("⚠️ Skipping a certificate of {} for health checks: {}", ca.path, e),
+                }
+            }
+        }
+        builder.build().expect("Failed to build health check HTTP client")
+    }
+
+    fn probe_interval(backend: &Backend, config: &HealthCheckConfig) -> Duration {
+        Duration::from_secs(backend.health_interval_secs.unwrap_or(config.interval_secs))
+    }
+
+    // Uniform within ±jitter_percent of the interval, so the mean stays at the interval.
+    fn jittered_interval(interval: Duration, config: &HealthCheckConfig) -> Duration {
+        if config.jitter_percent == 0 {
+            return interval;
+        }
+        let spread = config.jitter_percent as f64 / 100.0;
+        interval.mul_f64(1.0 + rand::thread_rng().gen_range(-spread..=spread))
+    }
+    
+    // Only flips a backend unhealthy after `unhealthy_threshold` consecutive failed probes, and
+    // only brings it back after `healthy_threshold` consecutive passing ones, so neither a single
+    // transient failure nor a single lucky probe changes its state.
+    fn apply_probe_result(backend: &mut Backend, failure: Option<String>, config: &HealthCheckConfig) {
+        let Some(failure) = failure else {
+            backend.consecutive_failures = 0;
+            backend.consecutive_successes = backend.consecutive_successes.saturating_add(1);
+            if !backend.healthy && backend.consecutive_successes >= config.healthy_threshold {
+                info!(
+                    "✅ {} marked healthy after {} consecutive successful health checks",
+                    backend.address(),
+                    backend.consecutive_successes
+                );
+                // Recovery also revives a backend that was ejected passively.
+                backend.passive_failures.store(0, Ordering::Relaxed);
+                backend.healthy = true;
+                backend.healthy_since = Some(std::time::Instant::now());
+                backend.last_error = None;
+            }
+            return;
+        };
+
+        backend.last_error = Some(failure);
+        backend.consecutive_successes = 0;
+        backend.consecutive_failures = backend.consecutive_failures.saturating_add(1);
+        if backend.healthy && backend.consecutive_failures >= config.unhealthy_threshold {
+            warn!(
+                "⚠️ {} marked unhealthy after {} consecutive failed health checks",
+                backend.address(),
+                backend.consecutive_failures
+            );
+            backend.healthy = false;
         }
     }
     
+    fn connect_timeout(config: &HealthCheckConfig) -> Duration {
+        config.connect_timeout_ms.map(Duration::from_millis).unwrap_or(Duration::from_secs(config.timeout_secs))
+    }
+
+    fn read_timeout(config: &HealthCheckConfig) -> Duration {
+        config.read_timeout_ms.map(Duration::from_millis).unwrap_or(Duration::from_secs(config.timeout_secs))
+    }
+
+    // Ok(None) when the probe passed and Ok(Some(reason)) when the backend answered but failed it;
+    // errors mean no usable answer at all, and only those are retried.
     async fn check_backend(
         client: &Client,
         backend: &Backend,
         config: &HealthCheckConfig,
-    ) -> Result<bool, reqwest::Error> {
-        let url = format!("http://{}:{}{}", backend.host, backend.port, config.path);
-        let response = client
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        match config.mode {
+            HealthCheckMode::Http => Ok(HealthChecker::check_http(client, backend, config).await?),
+            HealthCheckMode::Tcp => Ok(HealthChecker::check_tcp(backend, config).await?),
+        }
+    }
+
+    // Retries errored attempts (resets, DNS blips, timeouts) so a single flaky attempt doesn't
+    // count against the backend; a bad status is a real answer and is returned as is.
+    async fn check_with_retries(
+        client: &Client,
+        backend: &Backend,
+        config: &HealthCheckConfig,
+    ) -> Result<Option<String>, Box<dyn std::error::Error + Send + Sync>> {
+        let mut backoff = Duration::from_millis(config.retry_backoff_ms);
+        let mut attempt = 0;
+        loop {
+            match HealthChecker::check_backend(client, backend, config).await {
+                Err(e) if attempt < config.retries => {
+                    attempt += 1;
+                    debug!("🩺 Probe of {} errored ({}), retry {}/{} in {:?}", backend.address(), e, attempt, config.retries, backoff);
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    // Names the phase that failed, so a refused or unreachable port reads differently from a
+    // backend that accepted the connection and then stalled.
+    fn describe_failure(e: reqwest::Error, config: &HealthCheckConfig) -> String {
+        if e.is_connect() && e.is_timeout() {
+            format!("connect timed out after {}ms", HealthChecker::connect_timeout(config).as_millis())
+        } else if e.is_connect() {
+            format!("connect failed: {}", e)
+        } else if e.is_timeout() {
+            format!("read timed out, no response within {}ms", HealthChecker::read_timeout(config).as_millis())
+        } else {
+            e.to_string()
+        }
+    }
+
+    async fn check_http(
+        client: &Client,
+        backend: &Backend,
+        config: &HealthCheckConfig,
+    ) -> Result<Option<String>, String> {
+        let path = backend.health_path.as_deref().unwrap_or(&config.path);
+        let success_codes = backend.health_success_codes.as_ref().unwrap_or(&config.success_codes);
+        let scheme = if backend.tls { "https" } else { "http" };
+        let url = format!("{}://{}{}", scheme, backend.address(), path);
+        // The client enforces the connect timeout; this overall one leaves the read its full budget
+        // however long connecting took.
+        let mut request = client
             .get(&url)
-            .timeout(Duration::from_secs(config.timeout_secs))
-            .send()
-            .await?;
+            .timeout(HealthChecker::connect_timeout(config) + HealthChecker::read_timeout(config));
+        // Backends that route by Host or guard the endpoint with auth need these to answer 2xx.
+        if let Some(host) = &config.host {
+            request = request.header("Host", host);
+        }
+        for (name, value) in &config.headers {
+            request = request.header(name, value);
+        }
+        let mut response = request.send().await.map_err(|e| HealthChecker::describe_failure(e, config))?;
         
-        Ok(config.success_codes.contains(&response.status().as_u16()))
+        let status = response.status().as_u16();
+        if !success_codes.contains(&status) {
+            return Ok(Some(format!("unexpected status {}", status)));
+        }
+        let Some(expect_body) = &config.expect_body else {
+            return Ok(None);
+        };
+        // Only the start of the body is read, so a huge status page can't stall the checker.
+        let mut body = Vec::new();
+        while body.len() < HEALTH_BODY_LIMIT {
+            match response.chunk().await.map_err(|e| HealthChecker::describe_failure(e, config))? {
+                Some(chunk) => body.extend_from_slice(&chunk),
+                None => break,
+            }
+        }
+        body.truncate(HEALTH_BODY_LIMIT);
+        let matched = expect_body.is_match(&String::from_utf8_lossy(&body));
+        Ok(Some(format!("status {} but the body did not match", status)).filter(|_| !matched))
+    }
+    
+    // For non-HTTP backends: a completed TCP handshake within the timeout counts as healthy.
+    async fn check_tcp(backend: &Backend, config: &HealthCheckConfig) -> std::io::Result<Option<String>> {
+        let timeout = HealthChecker::connect_timeout(config);
+        match tokio::time::timeout(timeout, TcpStream::connect(backend.address())).await {
+            Ok(stream) => stream.map(|_| None),
+            Err(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!("connect timed out after {}ms", timeout.as_millis()),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{load_health_check_config, BodyMatch};
+
+    fn config(unhealthy_threshold: u32, healthy_threshold: u32) -> HealthCheckConfig {
+        HealthCheckConfig { unhealthy_threshold, healthy_threshold, ..load_health_check_config().unwrap() }
+    }
+
+    fn client(config: &HealthCheckConfig) -> Client {
+        let tls = ProbeTls { verify: true, ca_path: None, check_hostname: true };
+        HealthChecker::build_client(config, &tls, None, Duration::from_secs(1))
+    }
+
+    // A backend on a local port that answers one connection per entry of `responses`, in turn,
+    // after `delay`; an empty response closes the connection unanswered. The handle yields the
+    // request heads it received.
+    async fn serve(responses: Vec<String>, delay: Duration) -> (Backend, tokio::task::JoinHandle<Vec<String>>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let backend = Backend::new("127.0.0.1".to_string(), listener.local_addr().unwrap().port(), 1);
+        let handle = tokio::spawn(async move {
+            let mut requests = Vec::new();
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    match stream.read(&mut buf).await {
+                        Ok(0) | Err(_) => break,
+                        Ok(n) => head.extend_from_slice(&buf[..n]),
+                    }
+                }
+                requests.push(String::from_utf8_lossy(&head).into_owned());
+                tokio::time::sleep(delay).await;
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+            requests
+        });
+        (backend, handle)
+    }
+
+    // A local port with nothing listening on it.
+    async fn closed_port() -> Backend {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        Backend::new("127.0.0.1".to_string(), listener.local_addr().unwrap().port(), 1)
+    }
+
+    fn response(status: &str, body: &str) -> String {
+        format!("HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, body.len(), body)
+    }
+
+    #[test]
+    fn stays_healthy_until_the_failure_threshold() {
+        let config = config(3, 1);
+        let mut backend = Backend::new("127.0.0.1".to_string(), 8080, 1);
+        HealthChecker::apply_probe_result(&mut backend, Some("refused".to_string()), &config);
+        HealthChecker::apply_probe_result(&mut backend, Some("refused".to_string()), &config);
+        assert!(backend.healthy);
+        HealthChecker::apply_probe_result(&mut backend, Some("refused".to_string()), &config);
+        assert!(!backend.healthy);
+        assert_eq!(backend.last_error.as_deref(), Some("refused"));
+    }
+
+    #[test]
+    fn a_passing_probe_resets_the_failure_count() {
+        let config = config(2, 1);
+        let mut backend = Backend::new("127.0.0.1".to_string(), 8080, 1);
+        HealthChecker::apply_probe_result(&mut backend, Some("timeout".to_string()), &config);
+        HealthChecker::apply_probe_result(&mut backend, None, &config);
+        HealthChecker::apply_probe_result(&mut backend, Some("timeout".to_string()), &config);
+        assert!(backend.healthy);
+        assert_eq!(backend.consecutive_failures, 1);
+    }
+
+    #[test]
+    fn recovers_only_after_the_success_threshold() {
+        let config = config(1, 2);
+        let mut backend = Backend::new("127.0.0.1".to_string(), 8080, 1);
+        HealthChecker::apply_probe_result(&mut backend, Some("refused".to_string()), &config);
+        assert!(!backend.healthy);
+        HealthChecker::apply_probe_result(&mut backend, None, &config);
+        assert!(!backend.healthy);
+        HealthChecker::apply_probe_result(&mut backend, None, &config);
+        assert!(backend.healthy);
+        assert!(backend.healthy_since.is_some());
+        assert_eq!(backend.last_error, None);
+    }
+
+    #[tokio::test]
+    async fn probes_the_backends_own_path_and_success_codes() {
+        let config = config(1, 1);
+        let (mut backend, requests) = serve(vec![response("204 No Content", ""), response("200 OK", "")], Duration::ZERO).await;
+        backend.health_path = Some("/ready".to_string());
+        backend.health_success_codes = Some(vec![204]);
+        let client = client(&config);
+        assert_eq!(HealthChecker::check_http(&client, &backend, &config).await, Ok(None));
+        // 200 passes the default codes but not this backend's.
+        assert_eq!(
+            HealthChecker::check_http(&client, &backend, &config).await,
+            Ok(Some("unexpected status 200".to_string()))
+        );
+        assert!(requests.await.unwrap()[0].starts_with("GET /ready HTTP/1.1"));
+    }
+
+    #[tokio::test]
+    async fn tcp_mode_passes_on_a_completed_connect() {
+        let config = HealthCheckConfig { mode: HealthCheckMode::Tcp, ..config(1, 1) };
+        let (backend, _) = serve(vec![String::new()], Duration::ZERO).await;
+        assert!(HealthChecker::check_backend(&client(&config), &backend, &config).await.unwrap().is_none());
+        assert!(HealthChecker::check_backend(&client(&config), &closed_port().await, &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn http_mode_needs_an_http_answer() {
+        let config = config(1, 1);
+        let (backend, _) = serve(vec![response("200 OK", "")], Duration::ZERO).await;
+        assert!(HealthChecker::check_backend(&client(&config), &backend, &config).await.unwrap().is_none());
+        assert!(HealthChecker::check_backend(&client(&config), &closed_port().await, &config).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn probe_carries_the_configured_host_and_headers() {
+        let config = HealthCheckConfig {
+            host: Some("api.example.com".to_string()),
+            headers: HashMap::from([("Authorization".to_string(), "Bearer probe".to_string())]),
+            ..config(1, 1)
+        };
+        let (backend, requests) = serve(vec![response("200 OK", "")], Duration::ZERO).await;
+        assert_eq!(HealthChecker::check_http(&client(&config), &backend, &config).await, Ok(None));
+        let request = requests.await.unwrap().remove(0).to_lowercase();
+        assert!(request.contains("\r\nhost: api.example.com\r\n"), "{}", request);
+        assert!(request.contains("\r\nauthorization: bearer probe\r\n"), "{}", request);
+    }
+
+    #[test]
+    fn jitter_stays_within_the_configured_spread() {
+        let interval = Duration::from_secs(10);
+        let config = HealthCheckConfig { jitter_percent: 20, ..config(1, 1) };
+        let samples: Vec<Duration> = (0..200).map(|_| HealthChecker::jittered_interval(interval, &config)).collect();
+        assert!(samples.iter().all(|d| (Duration::from_secs(8)..=Duration::from_secs(12)).contains(d)));
+        assert!(samples.iter().any(|d| *d != interval));
+
+        let config = HealthCheckConfig { jitter_percent: 0, ..config };
+        assert_eq!(HealthChecker::jittered_interval(interval, &config), interval);
+    }
+
+    #[tokio::test]
+    async fn refused_connect_fails_fast_as_a_connect_failure() {
+        let config = HealthCheckConfig { connect_timeout_ms: Some(1000), read_timeout_ms: Some(5000), ..config(1, 1) };
+        let started = Instant::now();
+        let failure = HealthChecker::check_http(&client(&config), &closed_port().await, &config).await.unwrap_err();
+        assert!(failure.starts_with("connect failed"), "{}", failure);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn slow_answer_is_a_read_timeout() {
+        let config = HealthCheckConfig { connect_timeout_ms: Some(50), read_timeout_ms: Some(100), ..config(1, 1) };
+        let (backend, _) = serve(vec![response("200 OK", "")], Duration::from_millis(500)).await;
+        let failure = HealthChecker::check_http(&client(&config), &backend, &config).await.unwrap_err();
+        assert!(failure.starts_with("read timed out"), "{}", failure);
+    }
+
+    #[test]
+    fn backend_interval_overrides_the_default() {
+        let config = HealthCheckConfig { interval_secs: 10, ..config(1, 1) };
+        let mut backend = Backend::new("127.0.0.1".to_string(), 8080, 1);
+        assert_eq!(HealthChecker::probe_interval(&backend, &config), Duration::from_secs(10));
+        backend.health_interval_secs = Some(2);
+        assert_eq!(HealthChecker::probe_interval(&backend, &config), Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn errored_attempt_is_retried_within_the_probe() {
+        let config = HealthCheckConfig { retries: 1, retry_backoff_ms: 10, ..config(1, 1) };
+        // The first connection is dropped unanswered, the retry gets a 200.
+        let (mut backend, requests) = serve(vec![String::new(), response("200 OK", "")], Duration::ZERO).await;
+        let result = HealthChecker::check_with_retries(&client(&config), &backend, &config).await.unwrap();
+        HealthChecker::apply_probe_result(&mut backend, result, &config);
+        assert!(backend.healthy);
+        assert_eq!(requests.await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn bad_status_is_not_retried() {
+        let config = HealthCheckConfig { retries: 2, retry_backoff_ms: 10, ..config(1, 1) };
+        let (backend, _) = serve(vec![response("503 Service Unavailable", "")], Duration::ZERO).await;
+        let result = HealthChecker::check_with_retries(&client(&config), &backend, &config).await.unwrap();
+        assert_eq!(result.as_deref(), Some("unexpected status 503"));
+    }
+
+    #[tokio::test]
+    async fn body_must_match_on_top_of_the_status() {
+        let config = HealthCheckConfig { expect_body: Some(BodyMatch::Contains("\"status\":\"ok\"".to_string())), ..config(1, 1) };
+        let (backend, _) = serve(
+            vec![response("200 OK", r#"{"status":"ok"}"#), response("200 OK", r#"{"status":"degraded"}"#)],
+            Duration::ZERO,
+        )
+        .await;
+        let client = client(&config);
+        assert_eq!(HealthChecker::check_http(&client, &backend, &config).await, Ok(None));
+        assert_eq!(
+            HealthChecker::check_http(&client, &backend, &config).await,
+            Ok(Some("status 200 but the body did not match".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn body_can_match_a_regex() {
+        let pattern = regex::Regex::new("\"status\":\\s*\"(ok|up)\"").unwrap();
+        let config = HealthCheckConfig { expect_body: Some(BodyMatch::Pattern(pattern)), ..config(1, 1) };
+        let (backend, _) = serve(vec![response("200 OK", r#"{"status": "up"}"#)], Duration::ZERO).await;
+        assert_eq!(HealthChecker::check_http(&client(&config), &backend, &config).await, Ok(None));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,121 @@
+use ipnet::IpNet;
+use log::info;
+use std::net::IpAddr;
+use crate::config::IpFilterConfig;
+
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+}
+
+impl IpFilter {
+    pub fn new(config: IpFilterConfig) -> Self {
+        info!("🛡️ IP filtering enabled ({} allowed, {} denied ranges)", config.allow.len(), config.deny.len());
+        Self { allow: config.allow, deny: config.deny }
+    }
+
+    // Deny wins over allow; an empty allow list admits everyone not denied.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 clients on a dual-stack listener show up as ::ffff:a.b.c.d.
+        let ip = ip.to_canonical();
+        if self.deny.iter().any(|net| net.contains(&ip)) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|net| net.contains(&ip))
+    }
+}
+
+// Peers allowed to tell us who the client is via X-Forwarded-For or Forwarded, e.g. a load balancer in front.
+pub struct TrustedProxies {
+    nets: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new(nets: Vec<IpNet>) -> Self {
+        if !nets.is_empty() {
+            info!("🤝 Trusting X-Forwarded-For and Forwarded from {:?}", nets);
+        }
+        Self { nets }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.nets.iter().any(|net| net.contains(&ip))
+    }
+
+    // Walks the forwarded chain right to left past our own trusted hops; the first address not in
+    // the trusted set is the furthest one that can't have been forged by the client.
+    pub fn real_client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        if !self.contains(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        for hop in forwarded_for.unwrap_or("").rsplit(',').map(str::trim) {
+            match hop.parse::<IpAddr>() {
+                Ok(ip) => {
+                    client = ip;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nets(nets: &[&str]) -> Vec<IpNet> {
+        nets.iter().map(|net| net.parse().unwrap()).collect()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let filter = IpFilter::new(IpFilterConfig { allow: nets(&["10.0.0.0/8"]), deny: nets(&["10.1.0.0/16"]) });
+        assert!(filter.is_allowed(ip("10.2.3.4")));
+        assert!(!filter.is_allowed(ip("10.1.2.3")));
+        assert!(!filter.is_allowed(ip("192.168.1.1")));
+    }
+
+    #[test]
+    fn empty_allow_list_admits_everyone_not_denied() {
+        let filter = IpFilter::new(IpFilterConfig { allow: Vec::new(), deny: nets(&["203.0.113.0/24"]) });
+        assert!(filter.is_allowed(ip("198.51.100.1")));
+        assert!(!filter.is_allowed(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses() {
+        let filter = IpFilter::new(IpFilterConfig { allow: Vec::new(), deny: nets(&["203.0.113.0/24"]) });
+        assert!(!filter.is_allowed(ip("::ffff:203.0.113.9")));
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let trusted = TrustedProxies::new(nets(&["10.0.0.0/8"]));
+        assert_eq!(trusted.real_client_ip(ip("198.51.100.7"), Some("1.2.3.4")), ip("198.51.100.7"));
+    }
+
+    #[test]
+    fn walks_the_chain_past_trusted_hops() {
+        let trusted = TrustedProxies::new(nets(&["10.0.0.0/8"]));
+        // The leftmost entry is client supplied and can't be trusted.
+        let chain = Some("6.6.6.6, 198.51.100.7, 10.0.0.2");
+        assert_eq!(trusted.real_client_ip(ip("10.0.0.1"), chain), ip("198.51.100.7"));
+        assert_eq!(trusted.real_client_ip(ip("10.0.0.1"), None), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn stops_at_a_malformed_hop() {
+        let trusted = TrustedProxies::new(nets(&["10.0.0.0/8"]));
+        assert_eq!(trusted.real_client_ip(ip("10.0.0.1"), Some("1.2.3.4, garbage, 10.0.0.2")), ip("10.0.0.2"));
+    }
+}
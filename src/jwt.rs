@@ -0,0 +1,183 @@
+use async_trait::async_trait;
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use log::{info, warn};
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde_json::{Map, Value};
+use std::sync::RwLock;
+use std::time::Duration;
+use crate::config::JwtConfig;
+use crate::routing::matches_prefix_normalized;
+
+const HMAC_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+// Validates bearer tokens against either a shared HMAC secret or the keys published at a JWKS
+// URL. The JWKS is fetched by the background service and refreshed on an interval, so request
+// handling never waits on the identity provider.
+pub struct JwtValidator {
+    pub config: JwtConfig,
+    jwks: RwLock<JwkSet>,
+}
+
+impl JwtValidator {
+    pub fn new(config: JwtConfig) -> Self {
+        info!(
+            "🪪 JWT validation enabled ({}, issuer: {:?}, audience: {:?})",
+            if config.jwks_url.is_some() { "JWKS" } else { "shared secret" },
+            config.issuer,
+            config.audience
+        );
+        Self { config, jwks: RwLock::new(JwkSet { keys: Vec::new() }) }
+    }
+
+    pub fn is_bypassed(&self, path: &str) -> bool {
+        self.config.bypass_paths.iter().any(|prefix| matches_prefix_normalized(prefix, path))
+    }
+
+    // Returns the token's claims once signature, `exp`, `iss` and `aud` all check out.
+    pub fn verify(&self, authorization: Option<&str>) -> Result<Map<String, Value>, String> {
+        let token = authorization
+            .and_then(|value| value.trim().split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+            .map(|(_, token)| token.trim())
+            .ok_or("missing bearer token")?;
+        let header = decode_header(token).map_err(|e| e.to_string())?;
+
+        // The key decides the algorithm family, so an HMAC token can't be checked against a public
+        // key and vice versa.
+        let key = match &self.config.secret {
+            Some(secret) if HMAC_ALGORITHMS.contains(&header.alg) => DecodingKey::from_secret(secret.as_bytes()),
+            Some(_) => return Err(format!("unexpected algorithm {:?}", header.alg)),
+            None if HMAC_ALGORITHMS.contains(&header.alg) => return Err(format!("unexpected algorithm {:?}", header.alg)),
+            None => {
+                let jwks = self.jwks.read().unwrap();
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None if jwks.keys.len() == 1 => jwks.keys.first(),
+                    None => None,
+                }
+                .ok_or("no matching key in JWKS")?;
+                DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?
+            }
+        };
+
+        let mut validation = Validation::new(header.alg);
+        match &self.config.issuer {
+            Some(issuer) => validation.set_issuer(&[issuer]),
+            None => validation.iss = None,
+        }
+        match &self.config.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+
+        decode::<Map<String, Value>>(token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn refresh_jwks(&self, url: &str) {
+        let fetched = async {
+            reqwest::Client::new()
+                .get(url)
+                .timeout(Duration::from_secs(10))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<JwkSet>()
+                .await
+        };
+        match fetched.await {
+            Ok(jwks) => {
+                info!("🪪 Loaded {} key(s) from {}", jwks.keys.len(), url);
+                *self.jwks.write().unwrap() = jwks;
+            }
+            // Keep serving with the keys we already have.
+            Err(e) => warn!("⚠️ Failed to refresh JWKS from {}: {}", url, e),
+        }
+    }
+}
+
+// Renders a claim as a header value; objects and arrays aren't forwarded.
+pub fn claim_header_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        Value::Bool(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl BackgroundService for JwtValidator {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(url) = self.config.jwks_url.clone() else {
+            return;
+        };
+
+        let mut interval = tokio::time::interval(Duration::from_secs(self.config.jwks_refresh_secs.max(1)));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.refresh_jwks(&url).await,
+                _ = shutdown.changed() => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde_json::json;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn validator() -> JwtValidator {
+        JwtValidator::new(JwtConfig {
+            secret: Some("test-secret".to_string()),
+            jwks_url: None,
+            jwks_refresh_secs: 300,
+            issuer: Some("https://issuer.example.com".to_string()),
+            audience: None,
+            forward_claims: Vec::new(),
+            bypass_paths: vec!["/health".to_string()],
+        })
+    }
+
+    fn bearer(issuer: &str, expires_in: i64) -> String {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        let claims = json!({ "sub": "alice", "iss": issuer, "exp": now + expires_in });
+        let token = encode(&Header::new(Algorithm::HS256), &claims, &EncodingKey::from_secret(b"test-secret")).unwrap();
+        format!("Bearer {token}")
+    }
+
+    #[test]
+    fn accepts_valid_token() {
+        let claims = validator().verify(Some(&bearer("https://issuer.example.com", 300))).unwrap();
+        assert_eq!(claims["sub"], "alice");
+    }
+
+    #[test]
+    fn rejects_expired_token() {
+        assert!(validator().verify(Some(&bearer("https://issuer.example.com", -300))).is_err());
+    }
+
+    #[test]
+    fn rejects_wrong_issuer() {
+        assert!(validator().verify(Some(&bearer("https://evil.example.com", 300))).is_err());
+    }
+
+    #[test]
+    fn rejects_missing_token() {
+        assert!(validator().verify(None).is_err());
+    }
+
+    #[test]
+    fn bypass_ignores_dot_segments() {
+        let validator = validator();
+        assert!(validator.is_bypassed("/health/live"));
+        assert!(!validator.is_bypassed("/health/../admin"));
+        assert!(!validator.is_bypassed("/health/%2E%2E/admin"));
+    }
+}
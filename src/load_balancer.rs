@@ -1,6 +1,16 @@
 use crate::backend::Backend;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::cooldown::ConnectCooldown;
+use crate::config::{HashAlgo, StickyFailover, StickyMode};
+use crate::outlier::OutlierDetector;
+use async_trait::async_trait;
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use log::{info, warn};
 use rand::Rng;
 use uuid::Uuid;
@@ -10,8 +20,13 @@ pub enum LoadBalanceStrategy {
     RoundRobin,
     Weighted,
     LeastConnections,
+    WeightedLeastConnections,
     StickySession,
     Random,
+    IpHash,
+    ConsistentHash,
+    P2C,
+    LeastResponseTime,
 }
 
 impl LoadBalanceStrategy {
@@ -20,54 +35,267 @@ impl LoadBalanceStrategy {
             "round_robin" | "round-robin" | "roundrobin" => Some(Self::RoundRobin),
             "weighted" => Some(Self::Weighted),
             "least_connections" | "least-connections" | "leastconnections" => Some(Self::LeastConnections),
+            "weighted_least_connections" | "weighted-least-connections" | "weightedleastconnections" => {
+                Some(Self::WeightedLeastConnections)
+            }
             "sticky_session" | "sticky-session" | "stickysession" => Some(Self::StickySession),
             "random" => Some(Self::Random),
+            "ip_hash" | "ip-hash" | "iphash" => Some(Self::IpHash),
+            "consistent_hash" | "consistent-hash" | "consistenthash" => Some(Self::ConsistentHash),
+            "p2c" | "power_of_two" | "power-of-two" | "poweroftwo" => Some(Self::P2C),
+            "least_response_time" | "least-response-time" | "leastresponsetime" => Some(Self::LeastResponseTime),
             _ => None,
         }
     }
 }
 
+const SESSION_PRUNE_THRESHOLD: usize = 10_000;
+const SESSION_STORE_INTERVAL_SECS: u64 = 30;
+// Share of least_response_time picks sent to a random backend, so a backend that was slow once
+// keeps getting fresh samples instead of being starved on a stale average.
+const LATENCY_EXPLORATION: f64 = 0.05;
+
+pub struct SessionEntry {
+    pub backend: String,
+    pub created_at: Instant,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedSession {
+    backend: String,
+    created_at_unix: u64,
+}
+
+// Maps a key (client IP, session id, ring member) to its position for the hash-based strategies.
+pub trait KeyHasher: Send + Sync {
+    fn hash(&self, key: &str) -> u64;
+}
+
+pub struct Fnv;
+
+impl KeyHasher for Fnv {
+    fn hash(&self, key: &str) -> u64 {
+        hash_key(key)
+    }
+}
+
+pub struct SipHash;
+
+impl KeyHasher for SipHash {
+    // The deprecated SipHasher is the one std hasher whose algorithm and keys are fixed.
+    #[allow(deprecated)]
+    fn hash(&self, key: &str) -> u64 {
+        use std::hash::Hasher;
+        let mut hasher = std::hash::SipHasher::new();
+        hasher.write(key.as_bytes());
+        hasher.finish()
+    }
+}
+
+pub struct Crc32;
+
+impl KeyHasher for Crc32 {
+    fn hash(&self, key: &str) -> u64 {
+        crc32fast::hash(key.as_bytes()) as u64
+    }
+}
+
+pub fn key_hasher(algo: HashAlgo) -> &'static dyn KeyHasher {
+    match algo {
+        HashAlgo::Fnv => &Fnv,
+        HashAlgo::SipHash => &SipHash,
+        HashAlgo::Crc32 => &Crc32,
+    }
+}
+
+pub struct ConsistentHashRing {
+    vnodes: usize,
+    hasher: &'static dyn KeyHasher,
+    members: Vec<String>,
+    ring: BTreeMap<u64, String>,
+}
+
+impl ConsistentHashRing {
+    pub fn new(backends: &[Backend], vnodes: usize, hasher: &'static dyn KeyHasher) -> Self {
+        let members: Vec<String> = backends.iter().map(|b| b.address()).collect();
+        let mut ring = BTreeMap::new();
+        for address in &members {
+            for vnode in 0..vnodes {
+                ring.insert(hasher.hash(&format!("{}#{}", address, vnode)), address.clone());
+            }
+        }
+        Self { vnodes, hasher, members, ring }
+    }
+
+    pub fn is_built_from(&self, backends: &[Backend]) -> bool {
+        self.members.len() == backends.len()
+            && self.members.iter().zip(backends).all(|(address, b)| *address == b.address())
+    }
+
+    // Walks clockwise from the key's position to the first eligible backend, so an unhealthy
+    // backend's keys spill onto its ring neighbours instead of reshuffling every key.
+    pub fn lookup(&self, key: &str, backends: &[Backend]) -> Option<Backend> {
+        let position = self.hasher.hash(key);
+        let mut first = None;
+        for (_, address) in self.ring.range(position..).chain(self.ring.range(..position)) {
+            if let Some(backend) = backends.iter().find(|b| b.address() == *address) {
+                if backend.healthy && !backend.is_draining() {
+                    return Some(backend.clone());
+                }
+                first.get_or_insert(backend);
+            }
+        }
+        first.cloned()
+    }
+}
+
 pub struct LoadBalancer {
-    pub strategy: LoadBalanceStrategy,
+    // Switchable at runtime from the admin listener.
+    strategy: std::sync::RwLock<LoadBalanceStrategy>,
     pub counter: AtomicUsize,
-    pub session_map: std::sync::RwLock<HashMap<String, usize>>,
+    pub session_map: std::sync::RwLock<HashMap<String, SessionEntry>>,
+    pub session_ttl: Duration,
+    session_prune_at: AtomicUsize,
+    pub session_store: Option<String>,
+    pub sticky_failover: StickyFailover,
+    pub sticky_mode: StickyMode,
+    pub ring: std::sync::RwLock<ConsistentHashRing>,
+    hasher: &'static dyn KeyHasher,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    pub outlier: Option<OutlierDetector>,
+    pub connect_cooldown: Option<ConnectCooldown>,
+    pub slow_start: Duration,
 }
 
 impl LoadBalancer {
-    pub fn new(strategy: LoadBalanceStrategy) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        strategy: LoadBalanceStrategy,
+        vnodes: usize,
+        hash_algo: HashAlgo,
+        session_ttl_secs: u64,
+        session_store: Option<String>,
+        sticky_failover: StickyFailover,
+        sticky_mode: StickyMode,
+        circuit_breaker: Option<CircuitBreaker>,
+        outlier: Option<OutlierDetector>,
+        connect_cooldown: Option<ConnectCooldown>,
+        slow_start: Duration,
+    ) -> Self {
         info!("⚖️ Load balancing strategy: {:?}", strategy);
-        Self {
-            strategy,
+        let hasher = key_hasher(hash_algo);
+        let load_balancer = Self {
+            strategy: std::sync::RwLock::new(strategy),
             counter: AtomicUsize::new(0),
             session_map: std::sync::RwLock::new(HashMap::new()),
-        }
+            session_ttl: Duration::from_secs(session_ttl_secs),
+            session_prune_at: AtomicUsize::new(SESSION_PRUNE_THRESHOLD),
+            session_store,
+            sticky_failover,
+            sticky_mode,
+            ring: std::sync::RwLock::new(ConsistentHashRing::new(&[], vnodes, hasher)),
+            hasher,
+            circuit_breaker,
+            outlier,
+            connect_cooldown,
+            slow_start,
+        };
+        load_balancer.load_sessions();
+        load_balancer
     }
     
-    pub fn select_backend(&self, backends: &[Backend], session_id: Option<&str>) -> Option<Backend> {
+    pub fn strategy(&self) -> LoadBalanceStrategy {
+        *self.strategy.read().unwrap()
+    }
+
+    pub fn set_strategy(&self, strategy: LoadBalanceStrategy) {
+        *self.strategy.write().unwrap() = strategy;
+        info!("⚖️ Load balancing strategy switched to {:?}", strategy);
+    }
+
+    pub fn select_backend(&self, backends: &[Backend], session_id: Option<&str>, client_ip: Option<&str>) -> Option<Backend> {
+        if self.circuit_breaker.is_none() && self.outlier.is_none() && self.connect_cooldown.is_none() {
+            return self.select_available(backends, session_id, client_ip);
+        }
+
+        // Backends with an open circuit, an active outlier ejection or a connect cooldown are hidden from every
+        // strategy the same way unhealthy ones are, so hash-based strategies spill over to their
+        // usual neighbours.
+        let blocked: Vec<String> = backends.iter().map(|b| b.address()).filter(|address| self.is_blocked(address)).collect();
+        let selected = if blocked.is_empty() {
+            self.select_available(backends, session_id, client_ip)
+        } else {
+            let view: Vec<Backend> = backends
+                .iter()
+                .cloned()
+                .map(|mut b| {
+                    if blocked.contains(&b.address()) {
+                        b.healthy = false;
+                    }
+                    b
+                })
+                .collect();
+            self.select_available(&view, session_id, client_ip)
+        };
+
+        if let (Some(circuit_breaker), Some(backend)) = (&self.circuit_breaker, &selected) {
+            circuit_breaker.on_selected(&backend.address());
+        }
+        selected
+    }
+
+    // Whether select_backend can find a backend, without the side effects of picking one.
+    pub fn has_available(&self, backends: &[Backend]) -> bool {
+        backends.iter().any(|b| b.healthy && !self.is_blocked(&b.address()))
+    }
+
+    fn is_blocked(&self, address: &str) -> bool {
+        self.circuit_breaker.as_ref().is_some_and(|cb| !cb.is_available(address))
+            || self.outlier.as_ref().is_some_and(|outlier| outlier.is_ejected(address))
+            || self.connect_cooldown.as_ref().is_some_and(|cooldown| cooldown.is_cooling(address))
+    }
+
+    // None once no backend is healthy; draining ones are still used when they're all that's left.
+    fn select_available(&self, backends: &[Backend], session_id: Option<&str>, client_ip: Option<&str>) -> Option<Backend> {
         let healthy_backends: Vec<&Backend> = backends.iter().filter(|b| b.healthy).collect();
+        let eligible_backends: Vec<&Backend> = healthy_backends.iter().copied().filter(|b| !b.is_draining()).collect();
         
         if healthy_backends.is_empty() {
-            warn!("⚠️ No healthy backends available, falling back to all backends");
-            return self.select_from_all(backends, session_id);
+            return None;
+        }
+        if eligible_backends.is_empty() {
+            warn!("⚠️ No non-draining backends available, falling back to all backends");
+            return self.select_from_all(backends, session_id, client_ip);
         }
         
-        match self.strategy {
-            LoadBalanceStrategy::RoundRobin => self.round_robin(&healthy_backends),
-            LoadBalanceStrategy::Weighted => self.weighted(&healthy_backends),
-            LoadBalanceStrategy::LeastConnections => self.least_connections(&healthy_backends),
-            LoadBalanceStrategy::StickySession => self.sticky_session(&healthy_backends, session_id),
-            LoadBalanceStrategy::Random => self.random(&healthy_backends),
+        match self.strategy() {
+            LoadBalanceStrategy::RoundRobin => self.round_robin(&eligible_backends),
+            LoadBalanceStrategy::Weighted => self.weighted(&eligible_backends),
+            LoadBalanceStrategy::LeastConnections => self.least_connections(&eligible_backends),
+            LoadBalanceStrategy::WeightedLeastConnections => self.weighted_least_connections(&eligible_backends),
+            LoadBalanceStrategy::StickySession => self.sticky_session(backends, &eligible_backends, &healthy_backends, session_id),
+            LoadBalanceStrategy::Random => self.random(&eligible_backends),
+            LoadBalanceStrategy::IpHash => self.ip_hash(backends, &eligible_backends, client_ip),
+            LoadBalanceStrategy::ConsistentHash => self.consistent_hash(backends, &eligible_backends, session_id.or(client_ip)),
+            LoadBalanceStrategy::P2C => self.p2c(&eligible_backends),
+            LoadBalanceStrategy::LeastResponseTime => self.least_response_time(&eligible_backends),
         }
     }
     
-    fn select_from_all(&self, backends: &[Backend], session_id: Option<&str>) -> Option<Backend> {
+    // Ignores health entirely; used as is when failing open.
+    pub fn select_from_all(&self, backends: &[Backend], session_id: Option<&str>, client_ip: Option<&str>) -> Option<Backend> {
         let all_backends: Vec<&Backend> = backends.iter().collect();
-        match self.strategy {
+        match self.strategy() {
             LoadBalanceStrategy::RoundRobin => self.round_robin(&all_backends),
             LoadBalanceStrategy::Weighted => self.weighted(&all_backends),
             LoadBalanceStrategy::LeastConnections => self.least_connections(&all_backends),
-            LoadBalanceStrategy::StickySession => self.sticky_session(&all_backends, session_id),
+            LoadBalanceStrategy::WeightedLeastConnections => self.weighted_least_connections(&all_backends),
+            LoadBalanceStrategy::StickySession => self.sticky_session(backends, &all_backends, &all_backends, session_id),
             LoadBalanceStrategy::Random => self.random(&all_backends),
+            LoadBalanceStrategy::IpHash => self.ip_hash(backends, &all_backends, client_ip),
+            LoadBalanceStrategy::ConsistentHash => self.consistent_hash(backends, &all_backends, session_id.or(client_ip)),
+            LoadBalanceStrategy::P2C => self.p2c(&all_backends),
+            LoadBalanceStrategy::LeastResponseTime => self.least_response_time(&all_backends),
         }
     }
     
@@ -84,16 +312,22 @@ impl LoadBalancer {
             return None;
         }
         
-        let total_weight: usize = backends.iter().map(|b| b.weight).sum();
-        if total_weight == 0 {
+        // Recovering backends count with their slow-start weight, so the share they give up is
+        // spread over the others in proportion to their own weights. Weights are relative and
+        // divided by their common factor, so 1,1,2 cycles through 4 slots for exactly 25/25/50.
+        let weights: Vec<usize> = backends.iter().map(|b| b.effective_weight(self.slow_start)).collect();
+        let divisor = weights.iter().fold(0, |acc, &w| gcd(acc, w));
+        if divisor == 0 {
             return self.round_robin(backends);
         }
+        let weights: Vec<usize> = weights.into_iter().map(|w| w / divisor).collect();
+        let total_weight: usize = weights.iter().sum();
         
-        let choice = (self.counter.fetch_add(1, Ordering::Relaxed) % 100) as usize;
+        let choice = self.counter.fetch_add(1, Ordering::Relaxed) % total_weight;
         let mut acc = 0;
         
-        for b in backends {
-            acc += b.weight;
+        for (b, weight) in backends.iter().zip(weights) {
+            acc += weight;
             if choice < acc {
                 return Some((*b).clone());
             }
@@ -103,30 +337,215 @@ impl LoadBalancer {
     }
     
     fn least_connections(&self, backends: &[&Backend]) -> Option<Backend> {
-        self.round_robin(backends)
+        if backends.is_empty() {
+            return None;
+        }
+        
+        // Start the scan at a rotating offset so ties don't always land on the first backend.
+        let start = self.counter.fetch_add(1, Ordering::Relaxed) % backends.len();
+        (0..backends.len())
+            .map(|offset| backends[(start + offset) % backends.len()])
+            .min_by_key(|b| b.active_connections())
+            .cloned()
+    }
+    
+    // Lowest active connections per unit of weight, so a backend with 7x the weight carries about
+    // 7x the concurrent requests. Slow-start weights apply; weight 0 only shows up when every
+    // backend is draining and counts as the smallest possible weight.
+    fn weighted_least_connections(&self, backends: &[&Backend]) -> Option<Backend> {
+        if backends.is_empty() {
+            return None;
+        }
+
+        let load = |b: &Backend| (b.active_connections() as u128, b.effective_weight(self.slow_start).max(1) as u128);
+        let start = self.counter.fetch_add(1, Ordering::Relaxed) % backends.len();
+        (0..backends.len())
+            .map(|offset| backends[(start + offset) % backends.len()])
+            // a/wa < b/wb without floats: a*wb < b*wa.
+            .min_by(|a, b| {
+                let ((conns_a, weight_a), (conns_b, weight_b)) = (load(a), load(b));
+                (conns_a * weight_b).cmp(&(conns_b * weight_a))
+            })
+            .cloned()
+    }
+
+    fn p2c(&self, backends: &[&Backend]) -> Option<Backend> {
+        if backends.len() < 2 {
+            return backends.first().cloned().cloned();
+        }
+        
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(0..backends.len());
+        let second = (first + rng.gen_range(1..backends.len())) % backends.len();
+        
+        let (a, b) = (backends[first], backends[second]);
+        if b.active_connections() < a.active_connections() {
+            Some(b.clone())
+        } else {
+            Some(a.clone())
+        }
+    }
+    
+    // Backends without a latency sample yet average 0, so new backends are tried first.
+    fn least_response_time(&self, backends: &[&Backend]) -> Option<Backend> {
+        if backends.is_empty() {
+            return None;
+        }
+        
+        let mut rng = rand::thread_rng();
+        if backends.len() > 1 && rng.gen_bool(LATENCY_EXPLORATION) {
+            return self.random(backends);
+        }
+        
+        let start = self.counter.fetch_add(1, Ordering::Relaxed) % backends.len();
+        (0..backends.len())
+            .map(|offset| backends[(start + offset) % backends.len()])
+            .min_by(|a, b| a.latency_ewma().total_cmp(&b.latency_ewma()))
+            .cloned()
     }
     
-    fn sticky_session(&self, backends: &[&Backend], session_id: Option<&str>) -> Option<Backend> {
+    // `pinnable` may include draining backends so established sessions keep their backend,
+    // while new sessions are only assigned from `backends`.
+    fn sticky_session(&self, all: &[Backend], backends: &[&Backend], pinnable: &[&Backend], session_id: Option<&str>) -> Option<Backend> {
         if backends.is_empty() {
             return None;
         }
+        // Nothing is pinned, so the ring walk is the whole story: an unhealthy backend's sessions
+        // spill onto its neighbours and come back once it recovers.
+        if self.sticky_mode == StickyMode::Hashed {
+            return self.consistent_hash(all, backends, session_id);
+        }
         
+        let mut previous = None;
         if let Some(session_id) = session_id {
             let session_map = self.session_map.read().unwrap();
-            if let Some(&backend_index) = session_map.get(session_id) {
-                if let Some(backend) = backends.get(backend_index) {
-                    return Some((*backend).clone());
+            if let Some(entry) = session_map.get(session_id) {
+                if entry.created_at.elapsed() < self.session_ttl {
+                    if let Some(backend) = pinnable.iter().find(|b| b.address() == entry.backend) {
+                        return Some((*backend).clone());
+                    }
+                    // A backend that was removed from the pool is never coming back, so only one
+                    // that is merely unhealthy keeps its sessions under the 503 policy.
+                    if self.sticky_failover == StickyFailover::Unavailable && all.iter().any(|b| b.address() == entry.backend) {
+                        warn!("📌 Pinned backend {} is unhealthy, answering 503 (STICKY_FAILOVER=503)", entry.backend);
+                        return None;
+                    }
+                    previous = Some(entry.backend.clone());
                 }
             }
         }
         
+        // Either a new session, or its backend is no longer among the candidates (unhealthy or
+        // removed): pin it to a fresh pick.
         let backend_index = self.counter.fetch_add(1, Ordering::Relaxed) % backends.len();
-        if let Some(session_id) = session_id {
+        let backend = backends.get(backend_index).cloned().cloned();
+        if let (Some(session_id), Some(backend)) = (session_id, &backend) {
+            if let Some(previous) = previous {
+                info!("📌 Sticky session moved from {} to {}", previous, backend.address());
+            }
             let mut session_map = self.session_map.write().unwrap();
-            session_map.insert(session_id.to_string(), backend_index);
+            session_map.insert(session_id.to_string(), SessionEntry { backend: backend.address(), created_at: Instant::now() });
+            if session_map.len() >= self.session_prune_at.load(Ordering::Relaxed) {
+                self.prune_sessions(&mut session_map);
+            }
         }
         
-        backends.get(backend_index).cloned().cloned()
+        backend
+    }
+    
+    // The cookie is issued with Max-Age = TTL and never refreshed, so an entry older than the TTL
+    // can no longer be presented by any client. The next prune is scheduled at twice the
+    // surviving size to keep the cost amortized when most sessions are still live.
+    fn prune_sessions(&self, session_map: &mut HashMap<String, SessionEntry>) {
+        let before = session_map.len();
+        session_map.retain(|_, entry| entry.created_at.elapsed() < self.session_ttl);
+        self.session_prune_at.store((session_map.len() * 2).max(SESSION_PRUNE_THRESHOLD), Ordering::Relaxed);
+        info!("🧹 Pruned {} expired sticky sessions ({} remaining)", before - session_map.len(), session_map.len());
+    }
+    
+    // Unexpired sticky sessions still pinned to `address`.
+    pub fn live_sessions(&self, address: &str) -> usize {
+        let session_map = self.session_map.read().unwrap();
+        session_map.values().filter(|entry| entry.backend == address && entry.created_at.elapsed() < self.session_ttl).count()
+    }
+
+    // Drops sticky sessions pinned to backends that are no longer configured.
+    pub fn retain_sessions(&self, backends: &[Backend]) {
+        let mut session_map = self.session_map.write().unwrap();
+        session_map.retain(|_, entry| backends.iter().any(|b| b.address() == entry.backend));
+    }
+
+    fn load_sessions(&self) {
+        let path = match &self.session_store {
+            Some(path) => path,
+            None => return,
+        };
+
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) => {
+                info!("💾 No sticky session store loaded from {}: {}", path, e);
+                return;
+            }
+        };
+
+        let persisted: HashMap<String, PersistedSession> = match serde_json::from_str(&data) {
+            Ok(persisted) => persisted,
+            Err(e) => {
+                warn!("⚠️ Failed to parse sticky session store {}: {}", path, e);
+                return;
+            }
+        };
+
+        let now = unix_now();
+        let mut session_map = self.session_map.write().unwrap();
+        for (session_id, session) in persisted {
+            let age = Duration::from_secs(now.saturating_sub(session.created_at_unix));
+            if age >= self.session_ttl {
+                continue;
+            }
+            if let Some(created_at) = Instant::now().checked_sub(age) {
+                session_map.insert(session_id, SessionEntry { backend: session.backend, created_at });
+            }
+        }
+        info!("💾 Restored {} sticky sessions from {}", session_map.len(), path);
+    }
+
+    pub fn save_sessions(&self) {
+        let path = match &self.session_store {
+            Some(path) => path,
+            None => return,
+        };
+
+        let now = unix_now();
+        let session_map = self.session_map.read().unwrap();
+        let persisted: HashMap<&String, PersistedSession> = session_map
+            .iter()
+            .filter(|(_, entry)| entry.created_at.elapsed() < self.session_ttl)
+            .map(|(session_id, entry)| {
+                (session_id, PersistedSession {
+                    backend: entry.backend.clone(),
+                    created_at_unix: now.saturating_sub(entry.created_at.elapsed().as_secs()),
+                })
+            })
+            .collect();
+
+        let serialized = serde_json::to_string(&persisted);
+        drop(session_map);
+
+        let data = match serialized {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("⚠️ Failed to serialize sticky sessions: {}", e);
+                return;
+            }
+        };
+
+        // Write to a sibling file and rename so a crash mid-write never leaves a truncated store.
+        let tmp_path = format!("{}.tmp", path);
+        if let Err(e) = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, path)) {
+            warn!("⚠️ Failed to write sticky session store {}: {}", path, e);
+        }
     }
     
     fn random(&self, backends: &[&Backend]) -> Option<Backend> {
@@ -139,7 +558,357 @@ impl LoadBalancer {
         backends.get(index).cloned().cloned()
     }
     
+    // Hashes over the full backend list rather than the healthy subset, so a client only moves
+    // when its own backend goes down: the walk continues to the next healthy index after it and
+    // returns to the original backend once it recovers. When every healthy backend is draining the
+    // walk settles for a draining one; only with none healthy at all (failing open) does the hashed
+    // backend come back regardless. Clients without an IP fall back to round-robin over the candidates.
+    fn ip_hash(&self, backends: &[Backend], candidates: &[&Backend], client_ip: Option<&str>) -> Option<Backend> {
+        if backends.is_empty() {
+            return None;
+        }
+
+        let client_ip = match client_ip {
+            Some(ip) => ip,
+            None => return self.round_robin(candidates),
+        };
+
+        let start = (self.hasher.hash(client_ip) % backends.len() as u64) as usize;
+        for offset in 0..backends.len() {
+            let backend = &backends[(start + offset) % backends.len()];
+            if backend.healthy && !backend.is_draining() {
+                return Some(backend.clone());
+            }
+        }
+        for offset in 0..backends.len() {
+            let backend = &backends[(start + offset) % backends.len()];
+            if backend.healthy {
+                return Some(backend.clone());
+            }
+        }
+
+        backends.get(start).cloned()
+    }
+
+    fn consistent_hash(&self, backends: &[Backend], candidates: &[&Backend], key: Option<&str>) -> Option<Backend> {
+        let key = match key {
+            Some(key) => key,
+            None => return self.round_robin(candidates),
+        };
+
+        {
+            let ring = self.ring.read().unwrap();
+            if ring.is_built_from(backends) {
+                return ring.lookup(key, backends);
+            }
+        }
+
+        let mut ring = self.ring.write().unwrap();
+        if !ring.is_built_from(backends) {
+            *ring = ConsistentHashRing::new(backends, ring.vnodes, self.hasher);
+        }
+        ring.lookup(key, backends)
+    }
+
     pub fn generate_session_id() -> String {
         Uuid::new_v4().to_string()
     }
-}
\ No newline at end of file
+}
+
+#[async_trait]
+impl BackgroundService for LoadBalancer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if self.session_store.is_none() {
+            return;
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(SESSION_STORE_INTERVAL_SECS));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => self.save_sessions(),
+                _ = shutdown.changed() => {
+                    self.save_sessions();
+                    info!("💾 Sticky sessions saved on shutdown");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// FNV-1a: stable across builds and platforms, unlike std's randomly seeded hasher.
+pub fn hash_key(key: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn backends(count: u16) -> Vec<Backend> {
+        (0..count).map(|i| Backend::new("10.0.0.1".to_string(), 8000 + i, 1)).collect()
+    }
+
+    fn balancer(strategy: LoadBalanceStrategy) -> LoadBalancer {
+        sticky_balancer(HashAlgo::Fnv, StickyFailover::Reassign, StickyMode::Stateful, strategy)
+    }
+
+    fn sticky_balancer(hash_algo: HashAlgo, failover: StickyFailover, mode: StickyMode, strategy: LoadBalanceStrategy) -> LoadBalancer {
+        LoadBalancer::new(
+            strategy,
+            160,
+            hash_algo,
+            3600,
+            None,
+            failover,
+            mode,
+            None,
+            None,
+            None,
+            Duration::ZERO,
+        )
+    }
+
+    fn pick(lb: &LoadBalancer, backends: &[Backend], key: &str) -> String {
+        lb.select_backend(backends, Some(key), None).unwrap().address()
+    }
+
+    #[test]
+    fn consistent_hash_spreads_keys_over_every_backend() {
+        let lb = balancer(LoadBalanceStrategy::ConsistentHash);
+        let backends = backends(4);
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for i in 0..4000 {
+            *counts.entry(pick(&lb, &backends, &format!("key-{}", i))).or_default() += 1;
+        }
+        assert_eq!(counts.len(), 4);
+        // 1000 each on a perfect ring; vnodes keep every backend well within a factor of two.
+        assert!(counts.values().all(|&n| (500..=1500).contains(&n)), "{:?}", counts);
+    }
+
+    #[test]
+    fn consistent_hash_only_moves_keys_of_a_removed_backend() {
+        let lb = balancer(LoadBalanceStrategy::ConsistentHash);
+        let all = backends(4);
+        let removed = all[3].address();
+        let before: Vec<String> = (0..1000).map(|i| pick(&lb, &all, &format!("key-{}", i))).collect();
+        let after: Vec<String> = (0..1000).map(|i| pick(&lb, &all[..3], &format!("key-{}", i))).collect();
+        for (old, new) in before.iter().zip(&after) {
+            if *old != removed {
+                assert_eq!(old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn consistent_hash_spills_an_unhealthy_backends_keys_to_neighbours() {
+        let lb = balancer(LoadBalanceStrategy::ConsistentHash);
+        let mut backends = backends(3);
+        let before: Vec<String> = (0..500).map(|i| pick(&lb, &backends, &format!("key-{}", i))).collect();
+        backends[1].healthy = false;
+        let down = backends[1].address();
+        for (i, old) in before.iter().enumerate() {
+            let new = pick(&lb, &backends, &format!("key-{}", i));
+            assert_ne!(new, down);
+            if *old != down {
+                assert_eq!(*old, new);
+            }
+        }
+    }
+
+    #[test]
+    fn p2c_prefers_the_less_loaded_of_two_backends() {
+        let lb = balancer(LoadBalanceStrategy::P2C);
+        let backends = backends(2);
+        let _busy: Vec<_> = (0..5).map(|_| backends[0].track_connection()).collect();
+        // With two backends every draw compares both of them.
+        for _ in 0..100 {
+            assert_eq!(lb.select_backend(&backends, None, None).unwrap().address(), backends[1].address());
+        }
+    }
+
+    #[test]
+    fn p2c_skips_unhealthy_backends() {
+        let lb = balancer(LoadBalanceStrategy::P2C);
+        let mut backends = backends(3);
+        backends[0].healthy = false;
+        for _ in 0..100 {
+            assert_ne!(lb.select_backend(&backends, None, None).unwrap().address(), backends[0].address());
+        }
+    }
+
+    #[test]
+    fn weighted_splits_traffic_in_the_ratio_of_the_weights() {
+        let lb = balancer(LoadBalanceStrategy::Weighted);
+        let mut backends = backends(2);
+        backends[0].weight = 3;
+        backends[1].weight = 1;
+        // 3,1 reduces to a 4-slot cycle, so every 4 requests split exactly 3 to 1.
+        let picks: Vec<String> = (0..400).map(|_| lb.select_backend(&backends, None, None).unwrap().address()).collect();
+        for cycle in picks.chunks(4) {
+            assert_eq!(cycle.iter().filter(|&a| *a == backends[0].address()).count(), 3);
+        }
+    }
+
+    #[test]
+    fn ip_hash_falls_through_an_unhealthy_backend_and_returns_to_it() {
+        let lb = balancer(LoadBalanceStrategy::IpHash);
+        let mut backends = backends(3);
+        let home = lb.select_backend(&backends, None, Some("198.51.100.7")).unwrap().address();
+        let index = backends.iter().position(|b| b.address() == home).unwrap();
+        backends[index].healthy = false;
+        let next = backends[(index + 1) % 3].address();
+        assert_eq!(lb.select_backend(&backends, None, Some("198.51.100.7")).unwrap().address(), next);
+        backends[index].healthy = true;
+        assert_eq!(lb.select_backend(&backends, None, Some("198.51.100.7")).unwrap().address(), home);
+    }
+
+    #[test]
+    fn ip_hash_never_picks_an_unhealthy_backend_while_the_rest_drain() {
+        let lb = balancer(LoadBalanceStrategy::IpHash);
+        let mut backends = backends(3);
+        for b in backends.iter_mut() {
+            b.weight = 0;
+        }
+        for i in 0..3 {
+            backends[i].healthy = false;
+            for client in 0..50 {
+                let ip = format!("198.51.100.{}", client);
+                let picked = lb.select_backend(&backends, None, Some(&ip)).unwrap();
+                assert!(picked.healthy, "{} picked {}", ip, picked.address());
+            }
+            backends[i].healthy = true;
+        }
+    }
+
+    #[test]
+    fn sticky_session_moves_off_an_unhealthy_backend_and_stays_moved() {
+        let lb = balancer(LoadBalanceStrategy::StickySession);
+        let mut backends = backends(3);
+        let first = pick(&lb, &backends, "session");
+        assert_eq!(pick(&lb, &backends, "session"), first);
+
+        let index = backends.iter().position(|b| b.address() == first).unwrap();
+        backends[index].healthy = false;
+        let moved = pick(&lb, &backends, "session");
+        assert_ne!(moved, first);
+
+        // The session is re-pinned, so the original backend recovering doesn't move it back.
+        backends[index].healthy = true;
+        assert_eq!(pick(&lb, &backends, "session"), moved);
+    }
+
+    #[test]
+    fn weight_zero_backend_gets_no_new_round_robin_picks() {
+        let lb = balancer(LoadBalanceStrategy::RoundRobin);
+        let mut backends = backends(3);
+        backends[0].weight = 0;
+        for _ in 0..30 {
+            assert_ne!(lb.select_backend(&backends, None, None).unwrap().address(), backends[0].address());
+        }
+    }
+
+    #[test]
+    fn weight_zero_backend_keeps_its_sticky_sessions() {
+        let lb = balancer(LoadBalanceStrategy::StickySession);
+        let mut backends = backends(2);
+        let pinned = pick(&lb, &backends, "session");
+        let index = backends.iter().position(|b| b.address() == pinned).unwrap();
+        backends[index].weight = 0;
+        assert_eq!(pick(&lb, &backends, "session"), pinned);
+        // New sessions only go to the other backend.
+        for i in 0..10 {
+            assert_ne!(pick(&lb, &backends, &format!("new-{}", i)), pinned);
+        }
+    }
+
+    #[test]
+    fn reassign_failover_moves_the_session_to_a_healthy_backend() {
+        let lb = sticky_balancer(HashAlgo::Fnv, StickyFailover::Reassign, StickyMode::Stateful, LoadBalanceStrategy::StickySession);
+        let mut backends = backends(2);
+        let pinned = pick(&lb, &backends, "s1");
+        backends.iter_mut().find(|b| b.address() == pinned).unwrap().healthy = false;
+
+        let moved = pick(&lb, &backends, "s1");
+        assert_ne!(moved, pinned);
+        assert_eq!(lb.live_sessions(&moved), 1);
+    }
+
+    #[test]
+    fn unavailable_failover_answers_503_instead_of_moving_the_session() {
+        let lb = sticky_balancer(HashAlgo::Fnv, StickyFailover::Unavailable, StickyMode::Stateful, LoadBalanceStrategy::StickySession);
+        let mut backends = backends(2);
+        let pinned = pick(&lb, &backends, "s1");
+        backends.iter_mut().find(|b| b.address() == pinned).unwrap().healthy = false;
+
+        assert!(lb.select_backend(&backends, Some("s1"), None).is_none());
+        assert_eq!(lb.live_sessions(&pinned), 1);
+        assert_ne!(pick(&lb, &backends, "s2"), pinned);
+    }
+
+    // Hashed mode keeps no session table, so separate proxy instances must agree on their own.
+    #[test]
+    fn hashed_sticky_sessions_agree_across_instances() {
+        let first = sticky_balancer(HashAlgo::Fnv, StickyFailover::Reassign, StickyMode::Hashed, LoadBalanceStrategy::StickySession);
+        let second = sticky_balancer(HashAlgo::Fnv, StickyFailover::Reassign, StickyMode::Hashed, LoadBalanceStrategy::StickySession);
+        let backends = backends(5);
+        let mut used = std::collections::HashSet::new();
+        for i in 0..50 {
+            let cookie = format!("session-{}", i);
+            let address = pick(&first, &backends, &cookie);
+            assert_eq!(address, pick(&second, &backends, &cookie));
+            used.insert(address);
+        }
+        assert!(used.len() > 1);
+        assert_eq!(first.live_sessions(&backends[0].address()), 0);
+    }
+
+    // Two rings built separately from the same backends must place every key identically, or
+    // consistent hashing would differ between restarts and proxy instances.
+    fn assert_rings_agree(algo: HashAlgo) {
+        let backends = backends(4);
+        let first = ConsistentHashRing::new(&backends, 160, key_hasher(algo));
+        let second = ConsistentHashRing::new(&backends, 160, key_hasher(algo));
+        let mut used = std::collections::HashSet::new();
+        for i in 0..1000 {
+            let key = format!("key-{}", i);
+            let address = first.lookup(&key, &backends).unwrap().address();
+            assert_eq!(address, second.lookup(&key, &backends).unwrap().address());
+            used.insert(address);
+        }
+        assert!(used.len() > 1);
+    }
+
+    #[test]
+    fn fnv_ring_is_deterministic() {
+        assert_rings_agree(HashAlgo::Fnv);
+    }
+
+    #[test]
+    fn siphash_ring_is_deterministic() {
+        assert_eq!(key_hasher(HashAlgo::SipHash).hash("key"), SipHash.hash("key"));
+        assert_rings_agree(HashAlgo::SipHash);
+    }
+
+    #[test]
+    fn crc32_ring_is_deterministic() {
+        assert_eq!(key_hasher(HashAlgo::Crc32).hash("123456789"), 0xCBF4_3926);
+        assert_rings_agree(HashAlgo::Crc32);
+    }
+}
@@ -1,25 +1,93 @@
-use log::{info, warn};
+use log::{error, info, warn};
 use pingora_core::server::configuration::Opt;
 use pingora_core::server::Server;
+use pingora_core::services::background::GenBackgroundService;
+use pingora_core::services::listening::Service;
 use pingora_proxy::http_proxy_service;
 use pingora_core::listeners::tls::TlsSettings;
-use std::sync::{Arc, Mutex, RwLock};
+use pingora_core::listeners::TcpSocketOptions;
+use pingora_core::protocols::TcpKeepalive;
+use pingora_core::tls::ssl::{AlpnError, SslVersion};
+use hickory_resolver::TokioAsyncResolver;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, RwLock};
 use std::{process, thread};
 use std::time::Duration;
 use structopt::StructOpt;
 
+mod access_log;
+mod acme;
+mod adaptive;
+mod admin;
+mod auth;
 mod backend;
+mod cache;
+mod circuit_breaker;
+mod compression;
 mod config;
+mod connection_limit;
+mod cooldown;
+mod cors;
+mod dns;
+mod error_pages;
+mod geoip;
+mod header_rewrite;
 mod health_check;
+mod ip_filter;
+mod jwt;
 mod load_balancer;
+mod maintenance;
+mod metrics;
+mod mirror;
+mod mtls;
+mod ocsp;
+mod outlier;
+mod path_rules;
 mod proxy;
+mod proxy_protocol;
+mod routing;
+mod security_headers;
+mod session_signing;
+mod shutdown;
+mod sni;
+mod srv;
 mod ssl_watcher;
 mod generate_ssl;
 
+use access_log::AccessLog;
+use acme::{AcmeChallenges, AcmeClient};
+use admin::AdminService;
+use auth::BasicAuth;
+use cache::ResponseCache;
+use circuit_breaker::CircuitBreaker;
 use config::*;
+use connection_limit::ConnectionLimiter;
+use cooldown::ConnectCooldown;
+use cors::Cors;
+use error_pages::ErrorPages;
+use geoip::GeoIp;
+use header_rewrite::HeaderRewriter;
 use health_check::HealthChecker;
+use ip_filter::{IpFilter, TrustedProxies};
+use jwt::JwtValidator;
 use load_balancer::LoadBalancer;
+use maintenance::Maintenance;
+use metrics::Metrics;
+use mirror::Mirror;
+use mtls::ClientCerts;
+use ocsp::OcspStapler;
+use outlier::OutlierDetector;
+use path_rules::PathRules;
 use proxy::MyProxy;
+use proxy_protocol::{ClientAddrs, ProxyProtocolFront};
+use routing::{ConcurrencyLimit, Pool, Router, DEFAULT_POOL};
+use security_headers::SecurityHeaders;
+use session_signing::SessionSigner;
+use shutdown::{DrainMonitor, ShutdownSignal};
+use sni::SniResolver;
 use ssl_watcher::check_cert;
 use generate_ssl::generate_cert;
 
@@ -31,155 +99,602 @@ struct Args {
 
     #[structopt(short = "c", long = "conf", help = "Path to configuration file")]
     conf: Option<String>,
+
+    #[structopt(long = "app-conf", help = "Path to application TOML configuration (overrides env)")]
+    app_conf: Option<String>,
+}
+
+const ACME_STARTUP_DELAY_SECS: u64 = 5;
+// A failed renewal (ACME order or self-signed regeneration) is retried this much sooner than usual.
+const CERT_RETRY_SECS: u64 = 60 * 60;
+// sysexits' EX_CONFIG, so supervisors can tell a bad configuration from a crash.
+const CONFIG_ERROR_EXIT_CODE: i32 = 78;
+// How often the SIGHUP handlers look for a pending signal and for shutdown.
+const SIGNAL_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+// A bad setting stops startup with one line naming it, rather than a panic and a backtrace.
+fn or_exit<T>(result: Result<T, ConfigError>) -> T {
+    result.unwrap_or_else(|e| {
+        error!("❌ {}", e);
+        process::exit(CONFIG_ERROR_EXIT_CODE);
+    })
+}
+
+// TlsSettings starts from Mozilla's intermediate profile, which already refuses TLS 1.0 and 1.1;
+// modern only raises the floor to 1.3, whose cipher suites are all strong.
+fn apply_tls_policy(settings: &mut TlsSettings, policy: TlsPolicy) -> Result<(), ConfigError> {
+    let (min_version, name) = match policy.min_version {
+        TlsVersion::Tls12 => (SslVersion::TLS1_2, "1.2"),
+        TlsVersion::Tls13 => (SslVersion::TLS1_3, "1.3"),
+    };
+    settings.set_min_proto_version(Some(min_version)).map_err(|e| ConfigError::Invalid {
+        name: "TLS_MIN_VERSION".to_string(),
+        value: name.to_string(),
+        expected: format!("a version this OpenSSL build supports ({})", e),
+    })
+}
+
+// Answers with the first configured protocol the client also offers, so the configured order is
+// the server's preference. No overlap falls back to HTTP/1.1 without ALPN, except for an h2-only
+// list, which refuses the handshake as Pingora's own h2-only mode does.
+fn apply_alpn(settings: &mut TlsSettings, protocols: Vec<String>) {
+    let h2_only = protocols == ["h2"];
+    settings.set_alpn_select_callback(move |_, offered| {
+        let mut entries = Vec::new();
+        let mut rest = offered;
+        while let Some((&len, tail)) = rest.split_first() {
+            let Some(entry) = tail.get(..len as usize) else { break };
+            entries.push(entry);
+            rest = &tail[len as usize..];
+        }
+        protocols
+            .iter()
+            .find_map(|protocol| entries.iter().find(|entry| **entry == protocol.as_bytes()).copied())
+            .ok_or(if h2_only { AlpnError::ALERT_FATAL } else { AlpnError::NOACK })
+    });
+}
+
+fn tcp_keepalive(config: TcpKeepaliveConfig) -> TcpKeepalive {
+    TcpKeepalive {
+        idle: config.idle,
+        interval: config.interval,
+        count: config.probes,
+        #[cfg(target_os = "linux")]
+        user_timeout: Duration::ZERO,
+    }
+}
+
+// Keepalive is set on the listening socket and inherited by every connection accepted on it.
+fn add_tcp<A>(service: &mut Service<A>, addr: &str, options: &Option<TcpSocketOptions>) {
+    match options {
+        Some(options) => service.add_tcp_with_settings(addr, options.clone()),
+        None => service.add_tcp(addr),
+    }
+}
+
+// Pingora unlinks whatever is at the path before binding, so only a dead socket is cleared here:
+// a regular file or a socket another process still answers on is left alone.
+fn remove_stale_socket(path: &str) -> Result<(), String> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("Cannot inspect {}: {}", path, e)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and is not a socket", path));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(format!("{} is already in use by another process", path));
+    }
+    std::fs::remove_file(path).map_err(|e| format!("Cannot remove stale socket {}: {}", path, e))?;
+    info!("🧹 Removed stale socket {}", path);
+    Ok(())
+}
+
+// Tries each resolved address in turn and names what went wrong: a refusal means the host is up
+// but nothing listens, a timeout usually a firewall dropping packets or a dead host.
+fn probe_backend(address: &str, timeout: Duration) -> Result<(), String> {
+    let addrs: Vec<SocketAddr> = address
+        .to_socket_addrs()
+        .map_err(|e| format!("DNS lookup failed: {}", e))?
+        .collect();
+    let mut failure = format!("DNS lookup returned no addresses for {}", address);
+    for addr in addrs {
+        match std::net::TcpStream::connect_timeout(&addr, timeout) {
+            Ok(_) => return Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => failure = format!("connection refused by {}", addr),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => failure = format!("connect to {} timed out after {}ms", addr, timeout.as_millis()),
+            Err(e) => failure = format!("connect to {} failed: {}", addr, e),
+        }
+    }
+    Err(failure)
 }
 
-fn load_tls_settings(cert_path: &str, key_path: &str) -> TlsSettings {
-    if !std::path::Path::new(cert_path).exists() {
-        panic!("SSL certificate not found: {}", cert_path);
+// One pass of the certificate watcher. Nothing here stops the server: the resolver only swaps in
+// certificates that load, so new handshakes get the renewed one while open connections carry on.
+fn renew_certificate(resolver: &SniResolver, acme: Option<&AcmeClient>, autogen: bool) -> Result<(), String> {
+    let day_cert = check_cert();
+    if !day_cert.is_good {
+        return Err(day_cert.error);
+    }
+    if let Some(acme) = acme {
+        if !acme.has_certificate() || day_cert.day_left <= acme.config.renew_before_days {
+            acme.provision()?;
+            resolver.reload()?;
+        }
+    } else if day_cert.day_left <= 1 {
+        if !autogen {
+            return Err(format!("certificate expires in {} days and SSL_AUTOGEN=off, replace it", day_cert.day_left));
+        }
+        warn!("⚠️ Cert about to expire, regenerating...");
+        let gen_ssl = generate_cert();
+        if gen_ssl.status != "Success" {
+            return Err(gen_ssl.error);
+        }
+        resolver.reload()?;
     }
-    if !std::path::Path::new(key_path).exists() {
-        panic!("SSL private key not found: {}", key_path);
+    Ok(())
+}
+
+// The listener asks this resolver for a certificate on every handshake, so reloading it (SIGHUP,
+// renewal) takes effect for new connections without a restart.
+fn load_cert_resolver(cert_path: &str, key_path: &str, certificates: &[SniCertConfig], autogen: bool) -> Result<SniResolver, ConfigError> {
+    let file_error = |path: &str, reason: String| ConfigError::File { path: path.to_string(), reason };
+    for path in [cert_path, key_path] {
+        if !std::path::Path::new(path).exists() {
+            return Err(file_error(path, "not found".to_string()));
+        }
     }
     
-    match TlsSettings::intermediate(cert_path, key_path) {
-        Ok(settings) => settings,
+    match SniResolver::new(cert_path, key_path, certificates) {
+        Ok(resolver) => Ok(resolver),
+        Err(e) if !autogen => Err(file_error(cert_path, format!("{} (SSL_AUTOGEN=off, not regenerating)", e))),
         Err(e) => {
-            warn!("Failed to load TLS settings: {}, regenerating SSL...", e);
+            warn!("Failed to load TLS certificates: {}, regenerating SSL...", e);
             
             let gen_ssl = generate_cert();
             if gen_ssl.status != "Success" {
-                panic!("Failed to regenerate SSL: {}", gen_ssl.error);
+                return Err(file_error(cert_path, format!("{}, and regenerating it failed: {}", e, gen_ssl.error)));
             }
             
-            TlsSettings::intermediate(cert_path, key_path)
-                .expect("Failed to create TlsSettings even after SSL regeneration")
+            SniResolver::new(cert_path, key_path, certificates)
+                .map_err(|e| file_error(cert_path, format!("{} even after regenerating it", e)))
         }
     }
 }
 
 fn main() {
-    dotenvy::dotenv().ok();
+    // Loaded before the logger so .env can set RUST_LOG; the outcome is logged once it's up.
+    let dotenv = dotenvy::dotenv();
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    match dotenv {
+        Ok(path) => info!("📄 Loaded settings from {} (variables already set in the environment win)", path.display()),
+        // Containers and systemd units usually inject everything directly.
+        Err(e) if e.not_found() => info!("📄 No .env file, using the process environment only"),
+        Err(e) => {
+            error!("❌ Failed to read .env: {}", e);
+            process::exit(CONFIG_ERROR_EXIT_CODE);
+        }
+    }
 
     let args = Args::from_args();
 
-    let proxy_port = get_proxy_port(args.proxy_port);
+    let errors = validate(args.app_conf.as_deref());
+    if !errors.is_empty() {
+        for e in &errors {
+            error!("❌ {}", e);
+        }
+        error!("❌ Not starting: {} configuration error(s)", errors.len());
+        process::exit(CONFIG_ERROR_EXIT_CODE);
+    }
+
+    let bind_address = or_exit(get_bind_address());
     let ssl = is_ssl_enabled();
 
+    let app_config = or_exit(match &args.app_conf {
+        Some(path) => load_from_toml(path),
+        None => load_from_env(),
+    });
+    let AppConfig {
+        backends,
+        pools,
+        unmatched_host,
+        split,
+        geoip,
+        load_balance_strategy,
+        consistent_hash_vnodes,
+        hash_algo,
+        health_check: health_check_config,
+        sticky_cookie_name,
+        sticky_header,
+        sticky_cookie,
+        sticky_session_ttl,
+        sticky_session_store,
+        sticky_failover,
+        sticky_mode,
+        custom_headers,
+        remove_headers,
+        custom_request_headers,
+        remove_request_headers,
+        header_rewrites,
+        path_rules,
+        cors,
+        error_pages,
+        certificates,
+        listeners,
+    } = app_config;
+
+    // -p replaces PROXY_PORT / [[listeners]] with a single port, as it always has.
+    let listeners: Vec<(u16, bool)> = match args.proxy_port {
+        Some(port) => vec![ListenerConfig { port, tls: None }],
+        None => listeners,
+    }
+    .into_iter()
+    .map(|listener| (listener.port, listener.tls.unwrap_or(ssl.status)))
+    .collect();
+    if let Some((port, _)) = listeners.iter().find(|(_, tls)| *tls && !ssl.status) {
+        error!("❌ Port {} is set to tls but SSL is off", port);
+        process::exit(CONFIG_ERROR_EXIT_CODE);
+    }
+    let proxy_ports: Vec<u16> = listeners.iter().map(|(port, _)| *port).collect();
+    // Where plain requests are redirected to: the first TLS port.
+    let https_port = listeners.iter().find(|(_, tls)| *tls).map_or(proxy_ports[0], |(port, _)| *port);
+
     let cert_path = ssl.cert_loc.clone();
     let key_path = ssl.key_loc.clone();
 
-    let shared_tls = if ssl.status {
-        if !std::path::Path::new(&ssl.cert_loc).exists() {
-            panic!("SSL certificate not found: {}", ssl.cert_loc);
-        }
-        if !std::path::Path::new(&ssl.key_loc).exists() {
-            panic!("SSL private key not found: {}", ssl.key_loc);
-        }
-        Some(Arc::new(Mutex::new(load_tls_settings(&ssl.cert_loc, &ssl.key_loc))))
+    let shutdown = Arc::new(ShutdownSignal::default());
+
+    let cert_resolver = if ssl.status {
+        Some(or_exit(load_cert_resolver(&cert_path, &key_path, &certificates, ssl.autogen)))
     } else {
         None
     };
+    let ocsp_refresh = or_exit(load_ocsp_refresh_interval());
+    if ocsp_refresh.is_some() && !ssl.status {
+        warn!("⚠️ OCSP_STAPLING is set but SSL is off, nothing to staple");
+    }
+    let ocsp_stapler = ocsp_refresh.filter(|_| ssl.status).map(|_| Arc::new(OcspStapler::default()));
 
-    if let Some(tls_arc) = shared_tls.clone() {
-        let cert_path = cert_path.clone();
-        let key_path = key_path.clone();
+    if let Some(resolver) = cert_resolver.clone() {
+        let ocsp_stapler = ocsp_stapler.clone();
+        let worker = shutdown.register();
         thread::spawn(move || {
             let mut signals =
-                signal_hook::iterator::Signals::new(&[signal_hook::consts::signal::SIGHUP])
+                signal_hook::iterator::Signals::new([signal_hook::consts::signal::SIGHUP])
                     .expect("Failed to bind signals");
-            for _ in signals.forever() {
-                info!("SIGHUP received: reloading TLS cert...");
-                let new_settings = load_tls_settings(&cert_path, &key_path);
-                *tls_arc.lock().unwrap() = new_settings;
+            while !worker.signal().sleep(SIGNAL_POLL_INTERVAL) {
+                for _ in signals.pending() {
+                    info!("SIGHUP received: reloading TLS cert...");
+                    if let Err(e) = resolver.reload() {
+                        error!("❌ TLS reload failed, still serving the previous certificates: {}", e);
+                    }
+                    if let Some(stapler) = &ocsp_stapler {
+                        stapler.refresh(&resolver.leaf_and_issuers());
+                    }
+                }
             }
         });
     }
 
-    if let Some(tls_arc) = shared_tls.clone() {
-        let cert_path = cert_path.clone();
-        let key_path = key_path.clone();
+    let acme = or_exit(load_acme_config()).map(|config| {
+        Arc::new(AcmeClient { config, challenges: Arc::new(AcmeChallenges::default()) })
+    });
+    if acme.is_some() && !ssl.status {
+        warn!("⚠️ ACME_ENABLED is set but SSL is off, not requesting certificates");
+    }
+
+    if let Some(resolver) = cert_resolver.clone() {
+        let acme = acme.clone();
+        let autogen = ssl.autogen;
+        let worker = shutdown.register();
+        let ocsp_stapler = ocsp_stapler.clone();
+        // The loop also refreshes the OCSP staples, so it wakes at least every OCSP_REFRESH_INTERVAL.
+        let watch_interval = ocsp_refresh.map_or(Duration::from_secs(60 * 60 * 24), |interval| {
+            interval.min(Duration::from_secs(60 * 60 * 24))
+        });
         thread::spawn(move || {
+            let shutdown = worker.signal();
+            // HTTP-01 validation needs the proxy listener up to answer the challenge.
+            if acme.is_some() && shutdown.sleep(Duration::from_secs(ACME_STARTUP_DELAY_SECS)) {
+                return;
+            }
             loop {
-                let day_cert = check_cert();
-                if !day_cert.is_good {
-                    warn!("{}", day_cert.error);
-                    process::exit(1);
-                }
-                if day_cert.day_left <= 1 {
-                    warn!("⚠️ Cert about to expire, reloading...");
-                    let gen_ssl = generate_cert();
-
-                    if gen_ssl.status != "Success".to_string() {
-                        warn!("{}", gen_ssl.error);
-                        process::exit(1);
+                let wait = match renew_certificate(&resolver, acme.as_deref(), autogen) {
+                    Ok(()) => watch_interval,
+                    Err(e) => {
+                        error!("❌ Certificate renewal failed, still serving the previous certificate: {}", e);
+                        watch_interval.min(Duration::from_secs(CERT_RETRY_SECS))
                     }
-                    
-                    let new_settings = load_tls_settings(&cert_path, &key_path);
-                    *tls_arc.lock().unwrap() = new_settings;
+                };
+                if let Some(stapler) = &ocsp_stapler {
+                    stapler.refresh(&resolver.leaf_and_issuers());
+                }
+                if shutdown.sleep(wait) {
+                    break;
                 }
-                thread::sleep(Duration::from_secs(60 * 60 * 24));
             }
+            info!("🛑 Certificate watcher stopped");
         });
     }
 
-    let backends = load_backends();
-    let custom_headers = load_custom_headers();
-    let remove_headers = load_remove_headers();
-    let health_check_config = load_health_check_config();
-    let load_balance_strategy = load_balance_strategy();
-    let sticky_cookie_name = load_sticky_cookie_name();
-    let sticky_session_ttl = config::load_sticky_session_ttl();
+    let circuit_breaker_config = or_exit(load_circuit_breaker_config());
+    let outlier_config = or_exit(load_outlier_config());
+    // Only without active checks, which otherwise decide when an unreachable backend is back.
+    let connect_cooldown = Some(or_exit(load_connect_cooldown())).filter(|d| !health_check_config.enabled && !d.is_zero());
 
-    let shared_backends_std = Arc::new(RwLock::new(backends));
-    let load_balancer = Arc::new(LoadBalancer::new(load_balance_strategy));
+    // BACKENDS_SRV replaces the default pool's static list; the first answer is needed to start.
+    let backends_srv = load_backends_srv();
+    let mut srv_valid_for = Duration::ZERO;
+    let backends = match &backends_srv {
+        Some(name) => {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let resolved = rt.block_on(async {
+                let resolver = TokioAsyncResolver::tokio_from_system_conf().map_err(|e| e.to_string())?;
+                srv::resolve(&resolver, name).await
+            });
+            match resolved {
+                Ok((backends, valid_for)) => {
+                    info!("🧭 SRV {} lists {} backends", name, backends.len());
+                    srv_valid_for = valid_for;
+                    backends
+                }
+                Err(e) => {
+                    error!("❌ SRV lookup of BACKENDS_SRV {} failed: {}", name, e);
+                    process::exit(CONFIG_ERROR_EXIT_CODE);
+                }
+            }
+        }
+        None => backends,
+    };
+    let shared_backends = Arc::new(RwLock::new(backends));
+    let load_balancer = Arc::new(LoadBalancer::new(
+        load_balance_strategy,
+        consistent_hash_vnodes,
+        hash_algo,
+        sticky_session_ttl,
+        // Hashed sessions have nothing to persist.
+        sticky_session_store.filter(|_| sticky_mode == StickyMode::Stateful),
+        sticky_failover,
+        sticky_mode,
+        circuit_breaker_config.clone().map(CircuitBreaker::new),
+        outlier_config.clone().map(OutlierDetector::new),
+        connect_cooldown.map(ConnectCooldown::new),
+        load_slow_start(),
+    ));
 
     info!("🔍 Testing initial connection to upstreams...");
-    let shared_backends_std_clone = shared_backends_std.clone();
+    let initial_backends = shared_backends.clone();
+    // Without active checks nothing would ever bring an unreachable backend back, so it stays in
+    // rotation and only gets logged.
+    let mark_unhealthy = health_check_config.enabled;
+    let probe_timeout = or_exit(load_startup_probe_timeout());
     let health_check_handle = thread::spawn(move || {
-        let backends_guard = shared_backends_std_clone.read().unwrap();
+        let backends_guard = initial_backends.read().unwrap();
         let mut unhealthy_backends = Vec::new();
         
-        for b in backends_guard.iter() {
-            match std::net::TcpStream::connect(format!("{}:{}", b.host, b.port)) {
-                Ok(_) => info!("✅ {}:{} is reachable", b.host, b.port),
-                Err(e) => {
+        // Probed side by side, so a black-holed backend costs the boot one timeout, not one each.
+        let results: Vec<Result<(), String>> = thread::scope(|scope| {
+            let probes: Vec<_> = backends_guard.iter().map(|b| scope.spawn(move || probe_backend(&b.address(), probe_timeout))).collect();
+            probes.into_iter().map(|probe| probe.join().unwrap()).collect()
+        });
+        for (b, result) in backends_guard.iter().zip(results) {
+            match result {
+                Ok(()) => info!("✅ {} is reachable", b.address()),
+                Err(e) if mark_unhealthy => {
                     warn!(
-                        "⚠️ Cannot connect to upstream {}:{}: {} (will be marked unhealthy)",
-                        b.host, b.port, e
+                        "⚠️ Cannot connect to upstream {}: {} (will be marked unhealthy)",
+                        b.address(), e
                     );
-                    unhealthy_backends.push((b.host.clone(), b.port));
+                    unhealthy_backends.push((b.host.clone(), b.port, e));
                 }
+                Err(e) => warn!("⚠️ Cannot connect to upstream {}: {}", b.address(), e),
             }
         }
         
         drop(backends_guard);
         
         if !unhealthy_backends.is_empty() {
-            let mut backends_write = shared_backends_std_clone.write().unwrap();
-            for (host, port) in unhealthy_backends {
+            let mut backends_write = initial_backends.write().unwrap();
+            for (host, port, error) in unhealthy_backends {
                 if let Some(backend) = backends_write.iter_mut().find(|be| be.host == host && be.port == port) {
                     backend.healthy = false;
+                    backend.last_error = Some(format!("startup connect failed: {}", error));
                 }
             }
         }
-        
-        shared_backends_std_clone.read().unwrap().clone()
     });
 
-    let initial_backends = health_check_handle.join().unwrap();
+    health_check_handle.join().unwrap();
 
-    let shared_backends = Arc::new(RwLock::new(initial_backends));
+    let router = Arc::new(Router {
+        default_pool: Arc::new(Pool {
+            name: DEFAULT_POOL.to_string(),
+            hosts: Vec::new(),
+            path_prefixes: Vec::new(),
+            path_rewrite: None,
+            backends: shared_backends.clone(),
+            load_balancer: load_balancer.clone(),
+            upstream_timeouts: None,
+            upstream_host: or_exit(load_upstream_host()),
+            concurrency_limit: or_exit(load_backend_max_concurrency()).map(ConcurrencyLimit::new),
+        }),
+        // Extra pools keep sticky sessions in memory only; the session store belongs to the default pool.
+        pools: pools
+            .into_iter()
+            .map(|pool| {
+                info!(
+                    "🧭 Pool '{}' serves hosts {:?} and paths {:?} with {} backends",
+                    pool.name, pool.hosts, pool.path_prefixes, pool.backends.len()
+                );
+                Arc::new(Pool {
+                    name: pool.name,
+                    hosts: pool.hosts,
+                    path_prefixes: pool.path_prefixes,
+                    path_rewrite: pool.path_rewrite,
+                    backends: Arc::new(RwLock::new(pool.backends)),
+                    load_balancer: Arc::new(LoadBalancer::new(
+                        pool.load_balance_strategy,
+                        consistent_hash_vnodes,
+                        hash_algo,
+                        sticky_session_ttl,
+                        None,
+                        sticky_failover,
+                        sticky_mode,
+                        circuit_breaker_config.clone().map(CircuitBreaker::new),
+                        outlier_config.clone().map(OutlierDetector::new),
+                        connect_cooldown.map(ConnectCooldown::new),
+                        load_slow_start(),
+                    )),
+                    upstream_timeouts: pool.upstream_timeouts,
+                    upstream_host: pool.upstream_host,
+                    concurrency_limit: pool.max_concurrency.map(ConcurrencyLimit::new),
+                })
+            })
+            .collect(),
+        unmatched: unmatched_host,
+        split,
+        geoip: geoip.map(|config| or_exit(GeoIp::open(config))),
+    });
+    if !router.split.is_empty() {
+        let arms: Vec<String> = router.split.iter().map(|arm| format!("{} {}%", arm.pool, arm.percent)).collect();
+        info!("🧪 Splitting traffic between {}", arms.join(", "));
+    }
+    let canary_override = or_exit(load_canary_override());
+    if let Some(canary) = &canary_override {
+        if !router.split.iter().any(|arm| arm.pool == canary.pool) {
+            error!("❌ CANARY_OVERRIDE_POOL is '{}', but that pool isn't part of the traffic split", canary.pool);
+            process::exit(CONFIG_ERROR_EXIT_CODE);
+        }
+        info!("🧪 Cookie {} with the override secret forces pool '{}'", canary.cookie, canary.pool);
+    }
 
-    let health_backends = shared_backends.clone();
+    {
+        let reload_router = router.clone();
+        let srv_reload = backends_srv.clone();
+        let app_conf = args.app_conf.clone();
+        let worker = shutdown.register();
+        thread::spawn(move || {
+            let mut signals =
+                signal_hook::iterator::Signals::new([signal_hook::consts::signal::SIGHUP])
+                    .expect("Failed to bind signals");
+            while !worker.signal().sleep(SIGNAL_POLL_INTERVAL) {
+                for _ in signals.pending() {
+                    info!("SIGHUP received: reloading backends...");
+                    let mut reloaded = match reload_backends(app_conf.as_deref()) {
+                        Ok(pools) => pools,
+                        Err(e) => {
+                            warn!("⚠️ Backend reload failed, keeping the current backends: {}", e);
+                            continue;
+                        }
+                    };
+
+                    for pool in reload_router.all_pools() {
+                        let fresh = match reloaded.remove(&pool.name) {
+                            Some(fresh) => fresh,
+                            None => {
+                                warn!("⚠️ Pool '{}' is missing from the reloaded config, keeping its backends", pool.name);
+                                continue;
+                            }
+                        };
+                        // Its backends come from SRV, which the refresher keeps current.
+                        if srv_reload.is_some() && pool.name == DEFAULT_POOL {
+                            continue;
+                        }
+
+                        let count = fresh.len();
+                        pool.replace_backends(fresh);
+                        info!("🔄 Reloaded {} backends for pool '{}'", count, pool.name);
+                    }
+
+                    for name in reloaded.keys() {
+                        warn!("⚠️ New pool '{}' needs a restart to take effect", name);
+                    }
+                }
+            }
+        });
+    }
+
+    if let Some(name) = backends_srv {
+        let pool = router.default_pool.clone();
+        let srv_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async {
+                let resolver = match TokioAsyncResolver::tokio_from_system_conf() {
+                    Ok(resolver) => resolver,
+                    Err(e) => {
+                        warn!("⚠️ No resolver for refreshing BACKENDS_SRV, keeping the first answer: {}", e);
+                        return;
+                    }
+                };
+                tokio::select! {
+                    _ = srv::refresh_loop(pool, resolver, name, srv_valid_for) => {}
+                    _ = srv_shutdown.stopped() => {}
+                }
+            });
+        });
+    }
+
+    let dns_refresh_interval = or_exit(load_dns_refresh_interval());
+    let hostname_backends = router
+        .all_pools()
+        .map(|pool| pool.backends.read().unwrap().iter().filter(|b| b.is_hostname()).count())
+        .sum::<usize>();
+    // Runs even without hostname backends at startup, since reloads and the admin API can add some.
+    if dns_refresh_interval > 0 {
+        if hostname_backends > 0 {
+            info!("🌐 Re-resolving {} hostname backends every {}s", hostname_backends, dns_refresh_interval);
+        }
+        let pools = router.all_pools().map(|pool| pool.backends.clone()).collect();
+        let dns_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async {
+                tokio::select! {
+                    _ = dns::refresh_loop(pools, Duration::from_secs(dns_refresh_interval)) => {}
+                    _ = dns_shutdown.stopped() => {}
+                }
+            });
+        });
+    }
+
+    if let Some(adaptive) = or_exit(load_adaptive_weights()) {
+        info!(
+            "📈 Adapting weights to latency every {}s ({}%-{}% of the configured weight)",
+            adaptive.interval_secs, adaptive.min_percent, adaptive.max_percent
+        );
+        let pools = router.all_pools().map(|pool| pool.backends.clone()).collect();
+        let adaptive_shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let rt = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            rt.block_on(async {
+                tokio::select! {
+                    _ = adaptive::adjust_loop(pools, adaptive) => {}
+                    _ = adaptive_shutdown.stopped() => {}
+                }
+            });
+        });
+    }
+
+    let backend_tls = or_exit(load_backend_tls());
+    let health_router = router.clone();
     let health_config = health_check_config.clone();
+    let health_backend_tls = backend_tls.clone();
+    let health_worker = shutdown.register();
     thread::spawn(move || {
         let rt = tokio::runtime::Builder::new_current_thread()
             .enable_all()
             .build()
             .unwrap();
         rt.block_on(async {
-            HealthChecker::health_check_loop(health_backends, health_config).await;
+            let loops = health_router
+                .all_pools()
+                .map(|pool| HealthChecker::health_check_loop(pool.backends.clone(), health_config.clone(), health_backend_tls.clone()));
+            tokio::select! {
+                _ = futures::future::join_all(loops) => {}
+                _ = health_worker.signal().stopped() => info!("🛑 Health checks stopped"),
+            }
         });
     });
 
@@ -192,33 +707,236 @@ fn main() {
     });
 
     let mut my_server = Server::new(server_opt).unwrap();
+    let grace_secs = or_exit(load_shutdown_grace_secs());
+    let upstream_pool_size = or_exit(load_upstream_pool_size());
+    // Nothing else holds the configuration before bootstrap.
+    if let Some(conf) = Arc::get_mut(&mut my_server.configuration) {
+        if let Some(grace_secs) = grace_secs {
+            conf.grace_period_seconds = Some(grace_secs);
+        }
+        if let Some(size) = upstream_pool_size {
+            conf.upstream_keepalive_pool_size = size;
+        }
+    }
     my_server.bootstrap();
 
+    if load_balancer.session_store.is_some() {
+        my_server.add_service(GenBackgroundService::new(
+            "sticky session store".to_string(),
+            load_balancer.clone(),
+        ));
+    }
+
+    let mtls = or_exit(load_mtls_config());
+    if mtls.is_some() && !ssl.status {
+        warn!("⚠️ MTLS_MODE is set but SSL is off, client certificates are not checked");
+    }
+    let client_certs = mtls.as_ref().filter(|_| ssl.status).map(|config| Arc::new(ClientCerts::new(config.mode)));
+    let jwt = or_exit(load_jwt_config()).map(|config| Arc::new(JwtValidator::new(config)));
+    if let Some(jwt) = jwt.as_ref().filter(|jwt| jwt.config.jwks_url.is_some()) {
+        my_server.add_service(GenBackgroundService::new("jwks refresh".to_string(), jwt.clone()));
+    }
+
+    let access_log = or_exit(load_access_log_config()).map(|config| {
+        let path = config.path.clone();
+        Arc::new(or_exit(AccessLog::new(config).map_err(|e| ConfigError::File { path, reason: e.to_string() })))
+    });
+    if let Some(access_log) = &access_log {
+        my_server.add_service(GenBackgroundService::new("access log".to_string(), access_log.clone()));
+    }
+
+    let metrics = Arc::new(Metrics::new());
+    my_server.add_service(GenBackgroundService::new(
+        "shutdown drain".to_string(),
+        Arc::new(DrainMonitor { signal: shutdown.clone(), in_flight: metrics.requests_in_flight.clone(), access_log: access_log.clone(), thread_timeout: or_exit(load_shutdown_thread_timeout()) }),
+    ));
+
+    let maintenance = Arc::new(Maintenance::new(or_exit(load_maintenance_config())));
+
+    if let Some(admin_addr) = or_exit(get_admin_addr()) {
+        info!("🛠️ Starting admin listener on {}", admin_addr);
+        let drain_timeout_secs = or_exit(load_drain_timeout());
+        // Only the admin API drains backends, so the reaper only runs alongside it.
+        let reap_router = router.clone();
+        let reap_worker = shutdown.register();
+        thread::spawn(move || {
+            while !reap_worker.signal().sleep(Duration::from_secs(1)) {
+                for pool in reap_router.all_pools() {
+                    pool.reap_drained(Duration::from_secs(drain_timeout_secs));
+                }
+            }
+        });
+        let mut admin_service = Service::new(
+            "admin".to_string(),
+            AdminService {
+                router: router.clone(),
+                metrics: metrics.clone(),
+                maintenance: maintenance.clone(),
+                token: get_admin_token(),
+                drain_timeout_secs,
+                ssl_enabled: ssl.status,
+                health_check: health_check_config.clone(),
+            },
+        );
+        admin_service.add_tcp(&admin_addr);
+        my_server.add_service(admin_service);
+    }
+
+    let redirect = or_exit(load_redirect_config());
+    if redirect.is_some() && !ssl.status {
+        warn!("⚠️ REDIRECT_HTTP_TO_HTTPS is set but SSL is off, not redirecting");
+    }
+
+    let proxy_protocol_ports = or_exit(load_proxy_protocol_ports(&proxy_ports));
+    let client_addrs = proxy_protocol_ports.as_ref().map(|_| Arc::new(ClientAddrs::default()));
+
+    let tcp_keepalive_config = or_exit(load_tcp_keepalive());
+    if let Some(config) = &tcp_keepalive_config {
+        info!("💓 TCP keepalive after {:?} idle, every {:?}, {} probes", config.idle, config.interval, config.probes);
+    }
     let proxy = MyProxy {
-        backends: shared_backends.clone(),
-        load_balancer,
+        router,
         ssl_enabled: ssl.status,
         custom_headers,
         remove_headers,
+        custom_request_headers,
+        remove_request_headers,
+        header_rewriter: Some(header_rewrites).filter(|rules| !rules.is_empty()).map(HeaderRewriter::new),
+        security_headers: or_exit(load_security_headers_config()).map(|config| SecurityHeaders::new(config, ssl.status)),
+        path_rules: Some(path_rules).filter(|rules| !rules.is_empty()).map(PathRules::new),
         sticky_cookie_name,
+        sticky_header,
+        session_signer: or_exit(load_sticky_cookie_secret()).map(|secret| SessionSigner::new(&secret)),
+        sticky_cookie,
         sticky_session_ttl,
+        canary_override,
+        // Same reasoning as the startup probe: an ejected backend could only come back through the checker.
+        passive_failure_threshold: if health_check_config.enabled { health_check_config.passive_failure_threshold } else { 0 },
+        metrics,
+        max_retries: load_max_retries(),
+        request_deadline: or_exit(load_request_deadline()),
+        concurrency_queue_timeout: or_exit(load_concurrency_queue_timeout()),
+        fail_open: load_fail_open(),
+        unavailable_retry_after: or_exit(load_unavailable_retry_after()),
+        retry_non_idempotent: load_retry_non_idempotent(),
+        retry_methods: or_exit(load_retry_methods()),
+        retry_buffer_bytes: or_exit(load_retry_buffer_bytes()),
+        expose_upstream: load_expose_upstream(),
+        emit_forwarded: load_emit_forwarded(),
+        strip_fingerprint_headers: load_strip_fingerprint_headers(),
+        forwarded_by: or_exit(load_forwarded_by()),
+        via: or_exit(load_via()),
+        connection_limiter: or_exit(load_connection_limits()).map(|config| Arc::new(ConnectionLimiter::new(config))),
+        max_header_bytes: or_exit(load_max_header_bytes()),
+        max_body_bytes: or_exit(load_max_body_bytes()),
+        max_response_bytes: or_exit(load_max_response_bytes()),
+        allowed_methods: or_exit(load_allowed_methods()),
+        decompress_request: load_decompress_request(),
+        upstream_timeouts: load_upstream_timeouts(),
+        tcp_keepalive: tcp_keepalive_config.map(tcp_keepalive),
+        upstream_tcp_nodelay: load_upstream_tcp_nodelay(),
+        backend_tls,
+        cache: load_cache_config().map(ResponseCache::new),
+        compression: load_compression_config(),
+        redirect: redirect.clone(),
+        cors: cors.map(Cors::new),
+        basic_auth: or_exit(load_basic_auth_config()).map(BasicAuth::new),
+        jwt,
+        ip_filter: or_exit(load_ip_filter_config()).map(IpFilter::new),
+        trusted_proxies: TrustedProxies::new(or_exit(load_trusted_proxies())),
+        debug_upstream_ips: or_exit(load_debug_upstream_ips()),
+        client_addrs: client_addrs.clone(),
+        access_log,
+        error_pages: ErrorPages::new(&error_pages, or_exit(load_error_format())),
+        client_certs: client_certs.clone(),
+        acme_challenges: acme.as_ref().filter(|_| ssl.status).map(|acme| acme.challenges.clone()),
+        mirror: or_exit(load_mirror_config()).map(Mirror::new),
+        maintenance,
+        https_port,
     };
 
     let mut proxy_service = http_proxy_service(&my_server.configuration, proxy);
+    let listener_options = tcp_keepalive_config.map(|config| {
+        let mut options = TcpSocketOptions::default();
+        options.tcp_keepalive = Some(tcp_keepalive(config));
+        options
+    });
+    let alpn = or_exit(load_tls_alpn());
+    let tls_policy = or_exit(load_tls_policy());
+    if ssl.status {
+        info!("🔒 TLS policy: {:?}, minimum {:?}", tls_policy.profile, tls_policy.min_version);
+    }
+
+    let uds = or_exit(load_uds_listener());
+    if let Some(uds) = &uds {
+        if let Err(e) = remove_stale_socket(&uds.path) {
+            error!("❌ {}", e);
+            process::exit(CONFIG_ERROR_EXIT_CODE);
+        }
+        info!("🔌 Starting plain HTTP listener on unix:{} (mode {:o})", uds.path, uds.mode);
+        proxy_service.add_uds(&uds.path, Some(std::fs::Permissions::from_mode(uds.mode)));
+    }
+
+    let serve_tcp = !uds.as_ref().is_some_and(|uds| uds.only);
+    for (i, (port, tls)) in listeners.iter().enumerate().filter(|_| serve_tcp) {
+        // Behind the PROXY protocol relay, the proxy's own listener moves to loopback.
+        let proxy_addr = match (&proxy_protocol_ports, &client_addrs) {
+            (Some(internal_ports), Some(clients)) => {
+                let internal_addr = format!("127.0.0.1:{}", internal_ports[i]);
+                info!("🧾 Accepting PROXY protocol on {}, relaying to {}", port, internal_addr);
+                let mut front = Service::new(
+                    format!("proxy protocol {}", port),
+                    ProxyProtocolFront { internal_addr: internal_addr.clone(), clients: clients.clone() },
+                );
+                add_tcp(&mut front, &listen_addr(bind_address, *port), &listener_options);
+                my_server.add_service(front);
+                internal_addr
+            }
+            _ => listen_addr(bind_address, *port),
+        };
+
+        match cert_resolver.clone().filter(|_| *tls) {
+            Some(resolver) => {
+                let alpn_note = if alpn.is_empty() { String::new() } else { format!(" ({})", alpn.join(", ")) };
+                info!("🔒 Starting TLS listener on {}{}", proxy_addr, alpn_note);
+
+                let mut tls_settings = TlsSettings::with_callbacks(Box::new(resolver)).expect("Failed to create TlsSettings");
+                or_exit(apply_tls_policy(&mut tls_settings, tls_policy));
+                if !alpn.is_empty() {
+                    apply_alpn(&mut tls_settings, alpn.clone());
+                }
+                if let (Some(config), Some(certs)) = (&mtls, &client_certs) {
+                    mtls::configure(&mut tls_settings, config, certs.clone());
+                }
+                if let Some(stapler) = &ocsp_stapler {
+                    ocsp::configure(&mut tls_settings, stapler.clone());
+                }
+
+                proxy_service.add_tls_with_settings(
+                    &proxy_addr,
+                    listener_options.clone(),
+                    tls_settings,
+                );
+            }
+            None => {
+                info!("🔓 Starting plain TCP listener on {}", proxy_addr);
+                add_tcp(&mut proxy_service, &proxy_addr, &listener_options);
+            }
+        }
+    }
 
     if ssl.status {
-        info!("🔒 Starting TLS listener on {}", proxy_port);
-        
-        let tls_settings = load_tls_settings(&cert_path, &key_path);
-        
-        proxy_service.add_tls_with_settings(
-            &format!("0.0.0.0:{}", proxy_port),
-            None,
-            tls_settings,
-        );
-    } else {
-        info!("🔓 Starting plain TCP listener on {}", proxy_port);
-        proxy_service.add_tcp(&format!("0.0.0.0:{}", proxy_port));
+        // Skipped when PROXY_PORT already has a plain listener on the same port.
+        let mut bound_ports = proxy_ports.clone();
+        if let Some(http_port) = redirect.as_ref().and_then(|r| r.http_port).filter(|port| !bound_ports.contains(port)) {
+            info!("↪️ Starting plain TCP listener on {} redirecting to https", http_port);
+            add_tcp(&mut proxy_service, &listen_addr(bind_address, http_port), &listener_options);
+            bound_ports.push(http_port);
+        }
+        if let Some(acme_port) = acme.as_ref().map(|acme| acme.config.http_port).filter(|port| !bound_ports.contains(port)) {
+            info!("🔏 Starting plain TCP listener on {} for ACME challenges", acme_port);
+            add_tcp(&mut proxy_service, &listen_addr(bind_address, acme_port), &listener_options);
+        }
     }
 
     my_server.add_service(proxy_service);
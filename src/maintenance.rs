@@ -0,0 +1,80 @@
+use bytes::Bytes;
+use ipnet::IpNet;
+use log::{info, warn};
+use pingora_core::Result;
+use pingora_http::{Method, ResponseHeader};
+use pingora_proxy::Session;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use crate::config::MaintenanceConfig;
+use crate::error_pages::ErrorPages;
+use crate::routing::matches_prefix;
+
+// While enabled, every request that isn't from an allowlisted client IP or under a bypass path is
+// answered with the maintenance page (or the usual 503 body) and never reaches a backend. The flag can be flipped at
+// runtime from the admin listener.
+pub struct Maintenance {
+    enabled: AtomicBool,
+    page: Option<Bytes>,
+    retry_after_secs: u64,
+    allow_ips: Vec<IpNet>,
+    bypass_paths: Vec<String>,
+}
+
+impl Maintenance {
+    pub fn new(config: MaintenanceConfig) -> Self {
+        let page = config.page_path.as_ref().map(|path| {
+            fs::read(path).unwrap_or_else(|e| panic!("❌ Failed to read MAINTENANCE_PAGE {}: {}", path, e))
+        });
+        let maintenance = Self {
+            enabled: AtomicBool::new(false),
+            page: page.map(Bytes::from),
+            retry_after_secs: config.retry_after_secs,
+            allow_ips: config.allow_ips,
+            bypass_paths: config.bypass_paths,
+        };
+        maintenance.set_enabled(config.enabled);
+        maintenance
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn set_enabled(&self, enabled: bool) {
+        if self.enabled.swap(enabled, Ordering::Relaxed) == enabled {
+            return;
+        }
+        if enabled {
+            warn!("🚧 Maintenance mode on (allowed: {:?}, bypassed: {:?})", self.allow_ips, self.bypass_paths);
+        } else {
+            info!("🚧 Maintenance mode off");
+        }
+    }
+
+    pub fn is_exempt(&self, client_ip: Option<IpAddr>, path: &str) -> bool {
+        let allowed = client_ip.is_some_and(|ip| {
+            let ip = ip.to_canonical();
+            self.allow_ips.iter().any(|net| net.contains(&ip))
+        });
+        allowed || self.bypass_paths.iter().any(|prefix| matches_prefix(prefix, path))
+    }
+
+    pub async fn respond(&self, session: &mut Session, error_pages: &ErrorPages) -> Result<()> {
+        let Some(page) = &self.page else {
+            return error_pages.respond_unavailable(session, "down for maintenance", self.retry_after_secs).await;
+        };
+        let mut header = ResponseHeader::build(503, Some(4))?;
+        header.insert_header("Content-Type", "text/html; charset=utf-8")?;
+        header.insert_header("Content-Length", page.len().to_string())?;
+        header.insert_header("Retry-After", self.retry_after_secs.to_string())?;
+        header.insert_header("Cache-Control", "no-store")?;
+        if session.req_header().method == Method::HEAD {
+            session.write_response_header(Box::new(header), true).await
+        } else {
+            session.write_response_header(Box::new(header), false).await?;
+            session.write_response_body(Some(page.clone()), true).await
+        }
+    }
+}
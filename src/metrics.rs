@@ -0,0 +1,114 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+// App-level series served at /metrics on the admin listener:
+//   proxy_requests_total                                    every request accepted by the proxy
+//   proxy_requests_in_flight                                requests currently being handled
+//   proxy_backend_requests_total{backend, status_class}     responses relayed from each backend
+//   proxy_responses_total{backend, status_class}            final status sent to the client, including
+//                                                           the proxy's own errors for a failed backend;
+//                                                           backend "-" when none was picked
+//   proxy_backend_errors_total{backend}                     connect and proxy errors per backend
+//   proxy_backend_connect_errors_total{backend, reason}     failed connects, by refused, timeout, tls,
+//                                                           no_route or other
+//   proxy_backend_active_connections{pool, backend}         requests in flight to each backend, read
+//                                                           from the load balancer's counters per scrape
+//   proxy_request_duration_seconds{backend, status_class}   request_filter -> response_filter latency
+//   proxy_request_total_duration_seconds{status_class}      arrival -> logging, for every request,
+//                                                           including ones the proxy answered or failed
+pub struct Metrics {
+    registry: Registry,
+    pub requests_total: IntCounter,
+    pub requests_in_flight: IntGauge,
+    pub backend_requests_total: IntCounterVec,
+    pub responses_total: IntCounterVec,
+    pub backend_errors_total: IntCounterVec,
+    pub backend_connect_errors_total: IntCounterVec,
+    pub backend_active_connections: IntGaugeVec,
+    pub request_duration_seconds: HistogramVec,
+    pub request_total_duration_seconds: HistogramVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+
+        let requests_total = IntCounter::new("proxy_requests_total", "Total requests received by the proxy").unwrap();
+        let requests_in_flight = IntGauge::new("proxy_requests_in_flight", "Requests currently being handled by the proxy").unwrap();
+        let backend_requests_total = IntCounterVec::new(
+            Opts::new("proxy_backend_requests_total", "Requests routed to each backend by response status class"),
+            &["backend", "status_class"],
+        )
+        .unwrap();
+        let responses_total = IntCounterVec::new(
+            Opts::new("proxy_responses_total", "Final responses sent to clients per backend and status class"),
+            &["backend", "status_class"],
+        )
+        .unwrap();
+        let backend_errors_total = IntCounterVec::new(
+            Opts::new("proxy_backend_errors_total", "Upstream connect and proxy errors per backend"),
+            &["backend"],
+        )
+        .unwrap();
+        let backend_connect_errors_total = IntCounterVec::new(
+            Opts::new("proxy_backend_connect_errors_total", "Failed upstream connects per backend and reason"),
+            &["backend", "reason"],
+        )
+        .unwrap();
+        let backend_active_connections = IntGaugeVec::new(
+            Opts::new("proxy_backend_active_connections", "Requests currently in flight to each backend"),
+            &["pool", "backend"],
+        )
+        .unwrap();
+        let request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("proxy_request_duration_seconds", "Time from receiving a request to the upstream response header"),
+            &["backend", "status_class"],
+        )
+        .unwrap();
+        let request_total_duration_seconds = HistogramVec::new(
+            HistogramOpts::new("proxy_request_total_duration_seconds", "Time from receiving a request to finishing it, by the status sent to the client"),
+            &["status_class"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(requests_total.clone())).unwrap();
+        registry.register(Box::new(requests_in_flight.clone())).unwrap();
+        registry.register(Box::new(backend_requests_total.clone())).unwrap();
+        registry.register(Box::new(responses_total.clone())).unwrap();
+        registry.register(Box::new(backend_errors_total.clone())).unwrap();
+        registry.register(Box::new(backend_connect_errors_total.clone())).unwrap();
+        registry.register(Box::new(backend_active_connections.clone())).unwrap();
+        registry.register(Box::new(request_duration_seconds.clone())).unwrap();
+        registry.register(Box::new(request_total_duration_seconds.clone())).unwrap();
+
+        Metrics {
+            registry,
+            requests_total,
+            requests_in_flight,
+            backend_requests_total,
+            responses_total,
+            backend_errors_total,
+            backend_connect_errors_total,
+            backend_active_connections,
+            request_duration_seconds,
+            request_total_duration_seconds,
+        }
+    }
+
+    pub fn status_class(status: u16) -> &'static str {
+        match status {
+            100..=199 => "1xx",
+            200..=299 => "2xx",
+            300..=399 => "3xx",
+            400..=499 => "4xx",
+            _ => "5xx",
+        }
+    }
+
+    pub fn render(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer).unwrap();
+        buffer
+    }
+}
@@ -0,0 +1,120 @@
+use bytes::Bytes;
+use log::{debug, info, warn};
+use pingora_http::RequestHeader;
+use rand::Rng;
+use reqwest::Client;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::backend::Backend;
+use crate::config::MirrorConfig;
+
+// Bodies larger than this are not mirrored at all rather than sent truncated.
+const MAX_MIRROR_BODY_BYTES: usize = 1024 * 1024;
+// Mirrored requests still waiting on the shadow backend; further samples are dropped, so a slow
+// shadow can't pile up memory behind live traffic.
+const MAX_IN_FLIGHT: usize = 256;
+const MIRROR_TIMEOUT: Duration = Duration::from_secs(10);
+// Set by the proxy itself on the upstream connection, not part of the mirrored request.
+const HOP_BY_HOP_HEADERS: [&str; 6] = ["connection", "keep-alive", "transfer-encoding", "upgrade", "content-length", "te"];
+
+// A sampled request being buffered, dispatched once its body has been read in full.
+pub struct MirrorRequest {
+    pub url: String,
+    pub method: reqwest::Method,
+    pub headers: Vec<(String, Vec<u8>)>,
+    pub body: Vec<u8>,
+    pub oversized: bool,
+}
+
+impl MirrorRequest {
+    pub fn push_body(&mut self, chunk: &Bytes) {
+        if self.oversized {
+            return;
+        }
+        if self.body.len() + chunk.len() > MAX_MIRROR_BODY_BYTES {
+            self.oversized = true;
+            self.body = Vec::new();
+            return;
+        }
+        self.body.extend_from_slice(chunk);
+    }
+}
+
+// Copies a sample of live requests to shadow backends, fire-and-forget: the shadow's responses
+// and errors are only logged, and the client is always answered by the primary pool.
+pub struct Mirror {
+    backends: Vec<Backend>,
+    percent: f64,
+    client: Client,
+    counter: AtomicUsize,
+    in_flight: Arc<AtomicUsize>,
+}
+
+impl Mirror {
+    pub fn new(config: MirrorConfig) -> Self {
+        let addresses: Vec<String> = config.backends.iter().map(|b| b.address()).collect();
+        info!("🪞 Mirroring {}% of requests to {}", config.percent, addresses.join(", "));
+        let client = Client::builder()
+            .timeout(MIRROR_TIMEOUT)
+            .build()
+            .expect("Failed to build mirror HTTP client");
+        Self {
+            backends: config.backends,
+            percent: config.percent,
+            client,
+            counter: AtomicUsize::new(0),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    pub fn sample(&self, req: &RequestHeader) -> Option<MirrorRequest> {
+        if self.backends.is_empty() || !rand::thread_rng().gen_bool(self.percent / 100.0) {
+            return None;
+        }
+        let method = reqwest::Method::from_bytes(req.method.as_str().as_bytes()).ok()?;
+        let backend = &self.backends[self.counter.fetch_add(1, Ordering::Relaxed) % self.backends.len()];
+        let scheme = if backend.tls { "https" } else { "http" };
+        let path = req.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+        let headers = req
+            .headers
+            .iter()
+            .filter(|(name, _)| !HOP_BY_HOP_HEADERS.contains(&name.as_str()))
+            .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+            .collect();
+
+        Some(MirrorRequest {
+            url: format!("{}://{}{}", scheme, backend.address(), path),
+            method,
+            headers,
+            body: Vec::new(),
+            oversized: false,
+        })
+    }
+
+    pub fn send(&self, request: MirrorRequest) {
+        if request.oversized {
+            debug!("🪞 Not mirroring {} {}: body over {} bytes", request.method, request.url, MAX_MIRROR_BODY_BYTES);
+            return;
+        }
+        if self.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_IN_FLIGHT {
+            self.in_flight.fetch_sub(1, Ordering::Relaxed);
+            warn!("🪞 Dropping mirrored request to {}: {} already in flight", request.url, MAX_IN_FLIGHT);
+            return;
+        }
+
+        let mut builder = self.client.request(request.method.clone(), &request.url);
+        for (name, value) in request.headers {
+            builder = builder.header(name, value);
+        }
+        let builder = builder.body(request.body);
+        let in_flight = self.in_flight.clone();
+        tokio::spawn(async move {
+            match builder.send().await {
+                Ok(resp) => debug!("🪞 Mirror {} {} -> {}", request.method, request.url, resp.status()),
+                Err(e) => warn!("🪞 Mirror {} {} failed: {}", request.method, request.url, e),
+            }
+            in_flight.fetch_sub(1, Ordering::Relaxed);
+        });
+    }
+}
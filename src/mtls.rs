@@ -0,0 +1,103 @@
+use log::info;
+use pingora_core::listeners::tls::TlsSettings;
+use pingora_core::protocols::Digest;
+use pingora_core::tls::hash::MessageDigest;
+use pingora_core::tls::nid::Nid;
+use pingora_core::tls::ssl::SslVerifyMode;
+use pingora_core::tls::x509::{X509Name, X509Ref};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use crate::config::{MtlsConfig, MtlsMode};
+
+pub const CN_HEADER: &str = "X-Client-Cert-CN";
+pub const SAN_HEADER: &str = "X-Client-Cert-SAN";
+// Distinct client certificates remembered at once; the cache is simply dropped when it fills up.
+const MAX_IDENTITIES: usize = 10_000;
+
+#[derive(Clone)]
+pub struct ClientIdentity {
+    pub common_name: Option<String>,
+    pub sans: Vec<String>,
+}
+
+// Pingora's SslDigest only keeps a SHA-256 of the peer certificate, so the subject and SANs of
+// every verified leaf certificate are recorded here during the handshake, keyed by that digest.
+pub struct ClientCerts {
+    pub mode: MtlsMode,
+    identities: Mutex<HashMap<Vec<u8>, ClientIdentity>>,
+}
+
+impl ClientCerts {
+    pub fn new(mode: MtlsMode) -> Self {
+        Self { mode, identities: Mutex::new(HashMap::new()) }
+    }
+
+    // A TLS connection only carries a peer certificate digest once the handshake verified it.
+    pub fn is_verified(digest: Option<&Digest>) -> bool {
+        digest.and_then(|d| d.ssl_digest.as_ref()).is_some_and(|ssl| !ssl.cert_digest.is_empty())
+    }
+
+    pub fn identity(&self, digest: Option<&Digest>) -> Option<ClientIdentity> {
+        let ssl = digest?.ssl_digest.as_ref()?;
+        if ssl.cert_digest.is_empty() {
+            return None;
+        }
+        self.identities.lock().unwrap().get(&ssl.cert_digest).cloned()
+    }
+
+    fn record(&self, cert: &X509Ref) {
+        let Ok(digest) = cert.digest(MessageDigest::sha256()) else {
+            return;
+        };
+        let common_name = cert
+            .subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .and_then(|entry| entry.data().as_utf8().ok())
+            .map(|cn| cn.to_string());
+        let sans = cert
+            .subject_alt_names()
+            .map(|names| {
+                names
+                    .iter()
+                    .filter_map(|name| {
+                        name.dnsname()
+                            .map(|dns| format!("DNS:{}", dns))
+                            .or_else(|| name.email().map(|email| format!("email:{}", email)))
+                            .or_else(|| name.uri().map(|uri| format!("URI:{}", uri)))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let mut identities = self.identities.lock().unwrap();
+        if identities.len() >= MAX_IDENTITIES {
+            identities.clear();
+        }
+        identities.insert(digest.to_vec(), ClientIdentity { common_name, sans });
+    }
+}
+
+pub fn configure(settings: &mut TlsSettings, config: &MtlsConfig, certs: Arc<ClientCerts>) {
+    settings
+        .set_ca_file(&config.ca_path)
+        .unwrap_or_else(|e| panic!("❌ Failed to load MTLS_CA {}: {}", config.ca_path, e));
+    // Advertised in the CertificateRequest so clients know which certificate to pick.
+    let ca_names = X509Name::load_client_ca_file(&config.ca_path)
+        .unwrap_or_else(|e| panic!("❌ Failed to read CA names from {}: {}", config.ca_path, e));
+    settings.set_client_ca_list(ca_names);
+
+    let mode = match config.mode {
+        MtlsMode::Optional => SslVerifyMode::PEER,
+        MtlsMode::Require => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+    };
+    settings.set_verify_callback(mode, move |verified, store| {
+        if verified && store.error_depth() == 0 {
+            if let Some(cert) = store.current_cert() {
+                certs.record(cert);
+            }
+        }
+        verified
+    });
+    info!("🪪 Client certificates {} (CA {})", if config.mode == MtlsMode::Require { "required" } else { "optional" }, config.ca_path);
+}
@@ -0,0 +1,155 @@
+use chrono::{DateTime, NaiveDateTime, Utc};
+use log::{info, warn};
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspRequest, OcspResponse, OcspResponseStatus};
+use pingora_core::listeners::tls::TlsSettings;
+use pingora_core::tls::hash::MessageDigest;
+use pingora_core::tls::nid::Nid;
+use pingora_core::tls::x509::{X509Ref, X509};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+// Clock skew allowed on thisUpdate/nextUpdate when checking a fetched response.
+const VALIDITY_LEEWAY_SECS: u32 = 300;
+
+struct Staple {
+    der: Vec<u8>,
+    // nextUpdate of the response; it's no longer stapled after this.
+    expires: Option<DateTime<Utc>>,
+}
+
+// OCSP responses for the served certificates, keyed by the SHA-256 of the leaf. The certificate
+// watcher refreshes them from each leaf's AIA responder; a failed fetch keeps the previous response
+// until its nextUpdate passes, after which the handshake simply goes out without a staple.
+#[derive(Default)]
+pub struct OcspStapler {
+    staples: RwLock<HashMap<Vec<u8>, Staple>>,
+}
+
+impl OcspStapler {
+    // Fetches a fresh response for every (leaf, issuer) pair and forgets certificates no longer served.
+    pub fn refresh(&self, chains: &[(X509, X509)]) {
+        let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime,
+            Err(e) => {
+                warn!("⚠️ Failed to start OCSP runtime: {}", e);
+                return;
+            }
+        };
+        let mut served = Vec::new();
+        for (leaf, issuer) in chains {
+            let Some(key) = fingerprint(leaf).filter(|key| !served.contains(key)) else { continue };
+            served.push(key.clone());
+            let subject = subject_name(leaf);
+            match runtime.block_on(fetch(leaf, issuer)) {
+                Ok(staple) => {
+                    info!("📎 Stapling OCSP response for {} (next update {})", subject, describe_expiry(staple.expires));
+                    self.staples.write().unwrap().insert(key, staple);
+                }
+                Err(e) => match self.staples.read().unwrap().get(&key).filter(|staple| is_current(staple)) {
+                    Some(staple) => warn!(
+                        "⚠️ OCSP refresh for {} failed, keeping the response valid until {}: {}",
+                        subject,
+                        describe_expiry(staple.expires),
+                        e
+                    ),
+                    None => warn!("⚠️ OCSP refresh for {} failed, handshakes go out without a staple: {}", subject, e),
+                },
+            }
+        }
+        self.staples.write().unwrap().retain(|key, _| served.contains(key));
+    }
+
+    fn staple_for(&self, leaf: &X509Ref) -> Option<Vec<u8>> {
+        let key = fingerprint(leaf)?;
+        self.staples.read().unwrap().get(&key).filter(|staple| is_current(staple)).map(|staple| staple.der.clone())
+    }
+}
+
+// Staples the cached response for whichever certificate the SNI callback installed on the connection.
+pub fn configure(settings: &mut TlsSettings, stapler: Arc<OcspStapler>) {
+    let result = settings.set_status_callback(move |ssl| {
+        let Some(der) = ssl.certificate().and_then(|leaf| stapler.staple_for(leaf)) else {
+            return Ok(false);
+        };
+        ssl.set_ocsp_status(&der)?;
+        Ok(true)
+    });
+    if let Err(e) = result {
+        warn!("⚠️ Failed to enable OCSP stapling: {}", e);
+    }
+}
+
+async fn fetch(leaf: &X509, issuer: &X509) -> Result<Staple, String> {
+    let responders = leaf.ocsp_responders().map_err(|e| format!("unreadable AIA extension: {}", e))?;
+    let url = responders
+        .iter()
+        .map(|url| url.to_string())
+        .find(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .ok_or("the certificate names no OCSP responder")?;
+
+    let cert_id = || OcspCertId::from_cert(MessageDigest::sha1(), leaf, issuer).map_err(|e| e.to_string());
+    let mut request = OcspRequest::new().map_err(|e| e.to_string())?;
+    request.add_id(cert_id()?).map_err(|e| e.to_string())?;
+    let body = request.to_der().map_err(|e| e.to_string())?;
+
+    let response = reqwest::Client::new()
+        .post(&url)
+        .header("Content-Type", "application/ocsp-request")
+        .body(body)
+        .timeout(FETCH_TIMEOUT)
+        .send()
+        .await
+        .map_err(|e| format!("{} unreachable: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("{} answered {}", url, response.status()));
+    }
+    let der = response.bytes().await.map_err(|e| format!("{} read failed: {}", url, e))?.to_vec();
+
+    // The signature is left to the client, which has to verify it against the issuer anyway;
+    // here the response only has to be a current answer for this certificate.
+    let parsed = OcspResponse::from_der(&der).map_err(|e| format!("{} sent an invalid response: {}", url, e))?;
+    if parsed.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(format!("{} answered with status {:?}", url, parsed.status()));
+    }
+    let basic = parsed.basic().map_err(|e| format!("{} sent an invalid response: {}", url, e))?;
+    let id = cert_id()?;
+    let status = basic.find_status(&id).ok_or_else(|| format!("{} did not answer for this certificate", url))?;
+    // A revoked answer is stapled all the same: it's the CA's current word on the certificate.
+    if status.status == OcspCertStatus::REVOKED {
+        warn!("⚠️ {} reports {} as revoked", url, subject_name(leaf));
+    } else if status.status != OcspCertStatus::GOOD {
+        return Err(format!("{} does not know the certificate", url));
+    }
+    status
+        .check_validity(VALIDITY_LEEWAY_SECS, None)
+        .map_err(|e| format!("{} sent an out-of-date response: {}", url, e))?;
+    let expires = parse_time(&status.next_update.to_string());
+    Ok(Staple { der, expires })
+}
+
+fn is_current(staple: &Staple) -> bool {
+    staple.expires.is_none_or(|expires| expires > Utc::now())
+}
+
+fn fingerprint(cert: &X509Ref) -> Option<Vec<u8>> {
+    cert.digest(MessageDigest::sha256()).ok().map(|digest| digest.to_vec())
+}
+
+fn subject_name(cert: &X509Ref) -> String {
+    cert.subject_name()
+        .entries_by_nid(Nid::COMMONNAME)
+        .next()
+        .and_then(|entry| entry.data().as_utf8().ok())
+        .map_or_else(|| "the certificate".to_string(), |cn| cn.to_string())
+}
+
+// OpenSSL prints generalized times as "Jan  2 15:04:05 2026 GMT".
+fn parse_time(value: &str) -> Option<DateTime<Utc>> {
+    NaiveDateTime::parse_from_str(value.trim_end_matches(" GMT"), "%b %e %H:%M:%S %Y").ok().map(|time| time.and_utc())
+}
+
+fn describe_expiry(expires: Option<DateTime<Utc>>) -> String {
+    expires.map_or_else(|| "unspecified".to_string(), |expires| expires.to_rfc3339())
+}
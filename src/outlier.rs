@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::{info, warn};
+use crate::config::OutlierConfig;
+
+// Longest a backend stays ejected, however often it has been ejected before.
+const MAX_EJECTION_SECS: u64 = 300;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    requests: u32,
+    failures: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct OutlierState {
+    window_start: Instant,
+    current: Bucket,
+    previous: Bucket,
+    // Consecutive ejections; each one lasts base_ejection_secs times this count.
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+// Per-backend outlier detection keyed by host:port, fed by real traffic rather than probes, so a
+// backend whose /health passes while it fails requests still gets taken out of rotation. The error
+// rate is estimated over a sliding window (the previous bucket weighted by how much of it still
+// overlaps). An ejected backend is re-admitted once its ejection expires with a fresh window; if it
+// crosses the threshold again it is ejected for longer, and every clean window after re-admission
+// shortens the next ejection again.
+pub struct OutlierDetector {
+    config: OutlierConfig,
+    states: Mutex<HashMap<String, OutlierState>>,
+}
+
+impl OutlierDetector {
+    pub fn new(config: OutlierConfig) -> Self {
+        info!(
+            "🚨 Outlier detection enabled (error threshold: {:.0}% over {}s, base ejection: {}s)",
+            config.error_pct, config.window_secs, config.base_ejection_secs
+        );
+        Self { config, states: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn is_ejected(&self, address: &str) -> bool {
+        self.states
+            .lock()
+            .unwrap()
+            .get(address)
+            .and_then(|state| state.ejected_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    pub fn record_success(&self, address: &str) {
+        self.record(address, false);
+    }
+
+    pub fn record_failure(&self, address: &str) {
+        self.record(address, true);
+    }
+
+    fn record(&self, address: &str, failed: bool) {
+        let window = Duration::from_secs(self.config.window_secs.max(1));
+        let now = Instant::now();
+        let mut states = self.states.lock().unwrap();
+        let state = states.entry(address.to_string()).or_insert_with(|| OutlierState {
+            window_start: now,
+            current: Bucket { requests: 0, failures: 0 },
+            previous: Bucket { requests: 0, failures: 0 },
+            ejections: 0,
+            ejected_until: None,
+        });
+
+        match state.ejected_until {
+            // Stragglers from requests that started before the ejection don't extend it.
+            Some(until) if now < until => return,
+            Some(_) => {
+                info!("🚨 {} re-admitted after ejection", address);
+                state.ejected_until = None;
+                state.window_start = now;
+                state.current = Bucket { requests: 0, failures: 0 };
+                state.previous = Bucket { requests: 0, failures: 0 };
+            }
+            None => {}
+        }
+
+        let elapsed = now.duration_since(state.window_start);
+        if elapsed >= window {
+            // A window that ended under the threshold counts as a clean run.
+            state.ejections = state.ejections.saturating_sub(1);
+            state.previous = if elapsed >= window * 2 { Bucket { requests: 0, failures: 0 } } else { state.current };
+            state.current = Bucket { requests: 0, failures: 0 };
+            state.window_start = now;
+        }
+        state.current.requests += 1;
+        state.current.failures += failed as u32;
+
+        let overlap = 1.0 - now.duration_since(state.window_start).as_secs_f64() / window.as_secs_f64();
+        let requests = state.current.requests as f64 + state.previous.requests as f64 * overlap;
+        let failures = state.current.failures as f64 + state.previous.failures as f64 * overlap;
+        if requests < self.config.min_requests as f64 || failures * 100.0 / requests < self.config.error_pct {
+            return;
+        }
+
+        state.ejections = state.ejections.saturating_add(1);
+        let ejection_secs = (self.config.base_ejection_secs * state.ejections as u64).min(MAX_EJECTION_SECS);
+        warn!(
+            "🚨 {} ejected for {}s after {:.0}/{:.0} failed requests (ejection #{})",
+            address, ejection_secs, failures, requests, state.ejections
+        );
+        state.ejected_until = Some(now + Duration::from_secs(ejection_secs));
+    }
+}
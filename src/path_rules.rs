@@ -0,0 +1,25 @@
+use crate::config::{PathAction, PathRuleConfig};
+
+// Regex allow/deny rules on the request path. The first matching rule decides; a path no rule
+// matches is let through unless there are allow rules, in which case only what they match is.
+pub struct PathRules {
+    rules: Vec<PathRuleConfig>,
+    allowlist: bool,
+}
+
+impl PathRules {
+    pub fn new(rules: Vec<PathRuleConfig>) -> Self {
+        let allowlist = rules.iter().any(|rule| rule.action == PathAction::Allow);
+        Self { rules, allowlist }
+    }
+
+    // The status to answer with when the path is blocked.
+    pub fn blocked(&self, path: &str) -> Option<u16> {
+        match self.rules.iter().find(|rule| rule.pattern.is_match(path)) {
+            Some(rule) if rule.action == PathAction::Deny => Some(rule.status),
+            Some(_) => None,
+            None if self.allowlist => Some(403),
+            None => None,
+        }
+    }
+}
@@ -1,138 +1,1666 @@
 use async_trait::async_trait;
-use log::{info, error};
+use bytes::Bytes;
+use log::{debug, info, error, warn};
+use pingora_core::protocols::{Digest, TcpKeepalive};
 use pingora_core::upstreams::peer::HttpPeer;
-use pingora_core::Result;
-use pingora_http::{ResponseHeader, RequestHeader};
-use pingora_proxy::{ProxyHttp, Session};
+use pingora_core::{Error, ErrorSource, ErrorType, Result};
+use pingora_http::{Method, ResponseHeader, RequestHeader};
+use pingora_proxy::{FailToProxy, ProxyHttp, Session};
 use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, SocketAddr};
+use ipnet::IpNet;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::OwnedSemaphorePermit;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
 
-use crate::backend::Backend;
+use crate::access_log::AccessLog;
+use crate::acme::{self, AcmeChallenges};
+use crate::auth::BasicAuth;
+use crate::backend::{Backend, ConnectionGuard};
+use crate::cache::{CacheFill, ResponseCache};
+use crate::compression::{self, Compressor, Decompressor, Encoding};
+use crate::config::{BackendTlsConfig, CanaryOverrideConfig, CompressionConfig, CookieConfig, MtlsMode, RedirectConfig, SameSite, UnmatchedHost, UpstreamTimeouts};
+use crate::connection_limit::{ConnectionLimiter, ConnectionSlot};
+use crate::cors::Cors;
+use crate::error_pages::ErrorPages;
+use crate::header_rewrite::HeaderRewriter;
+use crate::ip_filter::{IpFilter, TrustedProxies};
+use crate::jwt::{self, JwtValidator};
 use crate::load_balancer::{LoadBalancer, LoadBalanceStrategy};
+use crate::maintenance::Maintenance;
+use crate::metrics::Metrics;
+use crate::mirror::{Mirror, MirrorRequest};
+use crate::mtls::{self, ClientCerts};
+use crate::path_rules::PathRules;
+use crate::proxy_protocol::ClientAddrs;
+use crate::security_headers::SecurityHeaders;
+use crate::routing::{normalized_path, Pool, Router, VARIANT_COOKIE, VARIANT_HEADER};
+use crate::session_signing::SessionSigner;
+
+const REQUEST_ID_HEADER: &str = "X-Request-ID";
+const UPSTREAM_HEADER: &str = "X-Upstream";
+// `host:port` of a backend to pin a single request to, honoured from DEBUG_UPSTREAM_ALLOW_IPS only.
+const DEBUG_UPSTREAM_HEADER: &str = "X-Debug-Upstream";
+// Longer incoming IDs are replaced rather than forwarded into backend and access logs.
+const MAX_REQUEST_ID_LEN: usize = 128;
+// Response headers that give away the backend's software and version, dropped unless
+// STRIP_FINGERPRINT_HEADERS=false.
+const FINGERPRINT_HEADERS: [&str; 6] =
+    ["Server", "X-Powered-By", "X-AspNet-Version", "X-AspNetMvc-Version", "X-Runtime", "X-Generator"];
 
 pub struct MyProxy {
-    pub backends: Arc<std::sync::RwLock<Vec<Backend>>>,
-    pub load_balancer: Arc<LoadBalancer>,
+    pub router: Arc<Router>,
     pub ssl_enabled: bool,
     pub custom_headers: HashMap<String, String>,
     pub remove_headers: Vec<String>,
+    pub custom_request_headers: HashMap<String, String>,
+    pub remove_request_headers: Vec<String>,
+    pub header_rewriter: Option<HeaderRewriter>,
+    pub path_rules: Option<PathRules>,
+    pub security_headers: Option<SecurityHeaders>,
     pub sticky_cookie_name: String,
+    pub sticky_header: Option<String>,
+    pub session_signer: Option<SessionSigner>,
+    pub sticky_cookie: CookieConfig,
+    pub canary_override: Option<CanaryOverrideConfig>,
     pub sticky_session_ttl: u64,
+    pub passive_failure_threshold: u32,
+    pub metrics: Arc<Metrics>,
+    pub max_retries: usize,
+    pub request_deadline: Option<Duration>,
+    pub concurrency_queue_timeout: Duration,
+    pub fail_open: bool,
+    pub unavailable_retry_after: u64,
+    pub retry_non_idempotent: bool,
+    pub retry_methods: Vec<Method>,
+    pub retry_buffer_bytes: Option<usize>,
+    pub expose_upstream: bool,
+    // Add an RFC 7239 Forwarded element to upstream requests besides the X-Forwarded-* headers.
+    pub emit_forwarded: bool,
+    pub strip_fingerprint_headers: bool,
+    // Name and value of the marker added to upstream requests; None adds nothing.
+    pub forwarded_by: Option<(String, String)>,
+    pub via: Option<String>,
+    pub connection_limiter: Option<Arc<ConnectionLimiter>>,
+    pub max_header_bytes: Option<usize>,
+    pub max_body_bytes: Option<usize>,
+    pub max_response_bytes: Option<usize>,
+    pub decompress_request: bool,
+    pub allowed_methods: Option<Vec<Method>>,
+    pub upstream_timeouts: UpstreamTimeouts,
+    pub tcp_keepalive: Option<TcpKeepalive>,
+    pub upstream_tcp_nodelay: bool,
+    pub backend_tls: BackendTlsConfig,
+    pub cache: Option<ResponseCache>,
+    pub compression: Option<CompressionConfig>,
+    pub redirect: Option<RedirectConfig>,
+    pub https_port: u16,
+    pub cors: Option<Cors>,
+    pub basic_auth: Option<BasicAuth>,
+    pub jwt: Option<Arc<JwtValidator>>,
+    pub ip_filter: Option<IpFilter>,
+    pub trusted_proxies: TrustedProxies,
+    pub debug_upstream_ips: Vec<IpNet>,
+    pub client_addrs: Option<Arc<ClientAddrs>>,
+    pub access_log: Option<Arc<AccessLog>>,
+    pub error_pages: ErrorPages,
+    pub client_certs: Option<Arc<ClientCerts>>,
+    pub acme_challenges: Option<Arc<AcmeChallenges>>,
+    pub mirror: Option<Mirror>,
+    pub maintenance: Arc<Maintenance>,
+}
+
+pub struct RequestCtx {
+    pub pool: Option<Arc<Pool>>,
+    pub session_id: Option<String>,
+    pub chosen_backend: Option<Backend>,
+    pub connection: Option<ConnectionGuard>,
+    // The chosen backend's slot, when its pool has a concurrency limit.
+    pub concurrency_permit: Option<OwnedSemaphorePermit>,
+    // Held until the request is logged, when MAX_CONNECTIONS or MAX_CONNECTIONS_PER_IP is set.
+    pub connection_slot: Option<ConnectionSlot>,
+    pub start: Instant,
+    pub retries: usize,
+    pub failed_backends: Vec<String>,
+    pub cache_key: Option<String>,
+    pub cache_fill: Option<CacheFill>,
+    pub accept_encoding: Option<Encoding>,
+    pub compressor: Option<Compressor>,
+    pub upgrade: bool,
+    // The backend answered with text/event-stream.
+    pub event_stream: bool,
+    pub cors_origin: Option<String>,
+    pub request_body_bytes: usize,
+    pub response_body_bytes: usize,
+    // Raw body bytes sent on the current attempt, to tell whether a retry can replay all of them.
+    pub request_body_sent: usize,
+    // The client's body was refused mid-stream (too large or undecodable), which isn't the backend's fault.
+    pub body_rejected: bool,
+    pub decompressor: Option<Decompressor>,
+    pub mirror_request: Option<MirrorRequest>,
+    pub variant: Option<String>,
+    // Set by the canary override, which mustn't pin the tester to the variant with a cookie.
+    pub variant_forced: bool,
+    // Why request_filter answered the request itself, for the access log.
+    pub short_circuit: Option<&'static str>,
+    // Sent upstream and echoed to the client, so one ID follows the request through every log.
+    pub request_id: String,
+    // Backend named by a trusted X-Debug-Upstream; bypasses load balancing, health and retries.
+    pub debug_upstream: Option<String>,
 }
 
 impl MyProxy {
+    // Passive health: live connection errors and 5xx responses count against the backend, and it
+    // is ejected once the consecutive count reaches the threshold. The active checker revives it
+    // once its health probe passes `healthy_threshold` times in a row.
+    fn record_passive_failure(&self, pool: &Pool, backend: &Backend) {
+        if let Some(circuit_breaker) = &pool.load_balancer.circuit_breaker {
+            circuit_breaker.record_failure(&backend.address());
+        }
+        if let Some(outlier) = &pool.load_balancer.outlier {
+            outlier.record_failure(&backend.address());
+        }
+
+        if self.passive_failure_threshold == 0 {
+            return;
+        }
+
+        let failures = backend.passive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < self.passive_failure_threshold {
+            return;
+        }
+
+        let mut backends = pool.backends.write().unwrap();
+        if let Some(b) = backends.iter_mut().find(|b| b.address() == backend.address()) {
+            if b.healthy {
+                b.healthy = false;
+                b.consecutive_successes = 0;
+                b.last_error = Some(format!("{} consecutive proxy failures", failures));
+                warn!("⚠️ {} marked unhealthy after {} consecutive proxy failures", b.address(), failures);
+            }
+        }
+    }
+
+    fn record_passive_success(&self, pool: &Pool, backend: &Backend) {
+        if let Some(circuit_breaker) = &pool.load_balancer.circuit_breaker {
+            circuit_breaker.record_success(&backend.address());
+        }
+        if let Some(outlier) = &pool.load_balancer.outlier {
+            outlier.record_success(&backend.address());
+        }
+        backend.passive_failures.store(0, Ordering::Relaxed);
+    }
+
+    // The sticky header wins when both are sent, so a client that can't drop a stale cookie can
+    // still pick its session explicitly. With STICKY_COOKIE_SECRET a tampered value counts as no
+    // session, so the client is issued a fresh one.
     fn get_session_id(&self, req_header: &RequestHeader) -> Option<String> {
-        if let Some(cookie_header) = req_header.headers.get("Cookie") {
-            if let Ok(cookie_str) = cookie_header.to_str() {
-                for cookie in cookie_str.split(';') {
-                    let cookie = cookie.trim();
-                    if let Some((name, value)) = cookie.split_once('=') {
-                        if name.trim() == self.sticky_cookie_name {
-                            return Some(value.trim().to_string());
-                        }
+        let value = self
+            .sticky_header
+            .as_ref()
+            .and_then(|name| req_header.headers.get(name.as_str()))
+            .and_then(|v| v.to_str().ok())
+            .map(str::trim)
+            .filter(|id| !id.is_empty())
+            .map(str::to_string)
+            .or_else(|| cookie_value(req_header, &self.sticky_cookie_name))?;
+        let Some(signer) = &self.session_signer else {
+            return Some(value);
+        };
+        let session_id = signer.verify(&value).map(str::to_string);
+        if session_id.is_none() {
+            warn!("🔏 Ignored sticky session id with an invalid signature");
+        }
+        session_id
+    }
+
+    // A request pinned for debugging is never moved elsewhere.
+    fn is_retryable(&self, req: &RequestHeader, ctx: &RequestCtx) -> bool {
+        ctx.debug_upstream.is_none() && is_safe_to_repeat(req, &self.retry_methods, self.retry_non_idempotent)
+    }
+
+    // The connecting client's address, as recovered from the PROXY header when one was required.
+    fn client_addr(&self, session: &Session) -> Option<SocketAddr> {
+        let peer = *session.client_addr()?.as_inet()?;
+        Some(match &self.client_addrs {
+            Some(clients) => clients.resolve(peer),
+            None => peer,
+        })
+    }
+
+    // A trusted proxy in front may have terminated TLS itself, so the scheme it reports wins over
+    // the one of our own listener; anyone else's claim is ignored.
+    fn client_scheme(&self, session: &Session) -> &'static str {
+        let trusted_peer = self.client_addr(session).is_some_and(|addr| self.trusted_proxies.contains(addr.ip()));
+        Some(session.req_header())
+            .filter(|_| trusted_peer)
+            .and_then(forwarded_proto)
+            .unwrap_or(if session.digest().is_some_and(|d| d.ssl_digest.is_some()) { "https" } else { "http" })
+    }
+
+    // The client as reported by trusted proxies in front, through X-Forwarded-For or Forwarded.
+    fn real_client_ip(&self, session: &Session) -> Option<IpAddr> {
+        let peer = self.client_addr(session)?.ip();
+        Some(self.trusted_proxies.real_client_ip(peer, forwarded_for(session.req_header()).as_deref()))
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty() && id.len() <= MAX_REQUEST_ID_LEN && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+// Size of the header block as sent: `METHOD URI VERSION\r\n` plus `name: value\r\n` per header.
+fn header_bytes(req: &RequestHeader) -> usize {
+    let request_line = req.method.as_str().len() + req.uri.to_string().len() + 12;
+    request_line + req.headers.iter().map(|(name, value)| name.as_str().len() + value.len() + 4).sum::<usize>()
+}
+
+fn cookie_value(req_header: &RequestHeader, cookie_name: &str) -> Option<String> {
+    let cookie_str = req_header.headers.get("Cookie")?.to_str().ok()?;
+    cookie_str
+        .split(';')
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| name.trim() == cookie_name)
+        .map(|(_, value)| value.trim().to_string())
+}
+
+// Hop-by-hop headers (RFC 7230 §6.1) describe one connection and aren't forwarded. Transfer-Encoding
+// is left alone: Pingora frames the body from it and sets it afresh for the next hop.
+const HOP_BY_HOP_HEADERS: [&str; 8] =
+    ["connection", "keep-alive", "proxy-authenticate", "proxy-authorization", "proxy-connection", "te", "trailer", "upgrade"];
+
+// The standard set plus whatever the message's own Connection header names. A real upgrade keeps
+// Connection and Upgrade, which the backend (or client, for the 101) needs to switch protocols.
+fn hop_by_hop_headers(headers: &http::HeaderMap, upgrade: bool) -> Vec<String> {
+    let listed = headers
+        .get_all("Connection")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|token| token.trim().to_ascii_lowercase())
+        .filter(|token| !token.is_empty() && !matches!(token.as_str(), "transfer-encoding" | "content-length" | "host"));
+    HOP_BY_HOP_HEADERS
+        .iter()
+        .map(|name| name.to_string())
+        .chain(listed)
+        .filter(|name| !(upgrade && (name == "connection" || name == "upgrade")))
+        .collect()
+}
+
+// The client's Host header, or the authority of an HTTP/2 request that carries none.
+fn original_host(req: &RequestHeader) -> Option<&str> {
+    req.headers.get("Host").and_then(|h| h.to_str().ok()).or_else(|| req.uri.authority().map(|a| a.as_str()))
+}
+
+// The `name=` parameter of each RFC 7239 Forwarded element, nearest the client first, unquoted;
+// None for an element that doesn't carry it.
+fn forwarded_params<'a>(req: &'a RequestHeader, name: &'a str) -> impl Iterator<Item = Option<String>> + 'a {
+    req.headers
+        .get_all("Forwarded")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(forwarded_elements)
+        .map(move |pairs| pairs.into_iter().find(|(param, _)| param.eq_ignore_ascii_case(name)).map(|(_, value)| value))
+}
+
+// Splits a Forwarded value into elements of `(name, value)` pairs (RFC 7239 §4). `,` and `;` only
+// separate outside quoted-strings, so `for="_a,b";host="x;y"` is one element of two pairs.
+fn forwarded_elements(value: &str) -> Vec<Vec<(String, String)>> {
+    let mut elements = Vec::new();
+    let mut pairs = Vec::new();
+    // Text that isn't a pair still makes an element, so a malformed hop isn't silently skipped.
+    let mut blank = true;
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+    for (i, c) in value.char_indices().chain([(value.len(), ',')]) {
+        let end = i == value.len();
+        match c {
+            _ if escaped && !end => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ';' | ',' if !quoted || end => {
+                let pair = value[start..i].trim();
+                blank &= pair.is_empty();
+                if let Some((name, raw)) = pair.split_once('=') {
+                    pairs.push((name.trim().to_string(), forwarded_unquote(raw.trim())));
+                }
+                start = i + 1;
+                if c == ',' {
+                    if !blank {
+                        elements.push(std::mem::take(&mut pairs));
                     }
+                    blank = true;
                 }
             }
+            _ => {}
         }
+    }
+    elements
+}
+
+// A token as is, or a quoted-string without its quotes and backslash escapes.
+fn forwarded_unquote(raw: &str) -> String {
+    let Some(inner) = raw.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return raw.to_string();
+    };
+    let mut value = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        value.push(if c == '\\' { chars.next().unwrap_or(c) } else { c });
+    }
+    value
+}
+
+// Shared by the sticky and variant cookies. SameSite=None without Secure is rejected by browsers.
+fn cookie_attributes(cookie: &CookieConfig, ssl_enabled: bool) -> String {
+    let mut attributes = format!("Path={}", cookie.path);
+    if let Some(domain) = &cookie.domain {
+        attributes.push_str(&format!("; Domain={}", domain));
+    }
+    attributes.push_str(&format!("; HttpOnly; SameSite={}", cookie.same_site.as_str()));
+    if cookie.same_site == SameSite::None || cookie.secure.unwrap_or(ssl_enabled) {
+        attributes.push_str("; Secure");
+    }
+    attributes
+}
+
+// The address of a Forwarded node: `1.2.3.4`, `1.2.3.4:80`, `[2001:db8::1]` or `[2001:db8::1]:80`.
+// Obfuscated names and `unknown` are returned as they are.
+fn forwarded_node_ip(node: &str) -> &str {
+    if let Some(bracketed) = node.strip_prefix('[') {
+        return bracketed.split(']').next().unwrap_or(bracketed);
+    }
+    match node.split_once(':') {
+        Some((ip, port)) if !port.contains(':') => ip,
+        _ => node,
+    }
+}
+
+// The forwarding chain as a comma-separated list of addresses: X-Forwarded-For, else the `for=` of
+// every Forwarded element, so the client is derived the same way from either. A node that isn't an
+// address (obfuscated, `unknown` or missing) becomes `unknown`, which ends the trusted chain; an
+// obfuscated name may itself contain commas, so it can't be passed on as it is.
+fn forwarded_for(req: &RequestHeader) -> Option<String> {
+    if let Some(chain) = req.headers.get("X-Forwarded-For").and_then(|v| v.to_str().ok()) {
+        return Some(chain.to_string());
+    }
+    let hops: Vec<String> = forwarded_params(req, "for")
+        .map(|node| {
+            node.as_deref()
+                .map(forwarded_node_ip)
+                .filter(|ip| ip.parse::<IpAddr>().is_ok())
+                .unwrap_or("unknown")
+                .to_string()
+        })
+        .collect();
+    (!hops.is_empty()).then(|| hops.join(", "))
+}
+
+// Forwarded, X-Forwarded-For and X-Real-IP for the upstream request; returns the real client IP.
+// Only a trusted proxy's forwarding chain is kept, since a direct client could put anything there,
+// and one given only as Forwarded is carried on in X-Forwarded-For too. The chain is read before
+// this hop's own Forwarded `element` is appended, or the peer would be counted twice.
+fn rewrite_forwarding_headers(
+    req: &mut RequestHeader,
+    peer: Option<IpAddr>,
+    trusted_proxies: &TrustedProxies,
+    element: Option<String>,
+) -> Result<Option<IpAddr>> {
+    let trusted_peer = peer.is_some_and(|ip| trusted_proxies.contains(ip));
+    let existing = forwarded_for(req).filter(|_| trusted_peer);
+
+    let mut forwarded = req
+        .headers
+        .get_all("Forwarded")
+        .iter()
+        .filter(|_| trusted_peer)
+        .filter_map(|v| v.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(", ");
+    if let Some(element) = element {
+        forwarded = if forwarded.is_empty() { element } else { format!("{}, {}", forwarded, element) };
+    }
+    if forwarded.is_empty() {
+        req.remove_header("Forwarded");
+    } else {
+        req.insert_header("Forwarded", forwarded)?;
+    }
+
+    let Some(peer) = peer else {
+        req.remove_header("X-Forwarded-For");
+        req.remove_header("X-Real-IP");
+        return Ok(None);
+    };
+    let real_ip = trusted_proxies.real_client_ip(peer, existing.as_deref());
+    let chain = match existing {
+        Some(existing) => format!("{}, {}", existing, peer),
+        None => peer.to_string(),
+    };
+    req.insert_header("X-Forwarded-For", chain)?;
+    req.insert_header("X-Real-IP", real_ip.to_string())?;
+    Ok(Some(real_ip))
+}
+
+// A Forwarded parameter value, quoted unless it is a plain token (IPv6 addresses and host:port are not).
+fn forwarded_value(value: &str) -> String {
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)) {
+        value.to_string()
+    } else {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+// The client-facing scheme from X-Forwarded-Proto, else the `proto=` of the first Forwarded element.
+// Both list the hop nearest the client first.
+fn forwarded_proto(req: &RequestHeader) -> Option<&'static str> {
+    let proto = req
+        .headers
+        .get("X-Forwarded-Proto")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|value| value.split(',').next())
+        .map(str::to_string)
+        .or_else(|| forwarded_params(req, "proto").next().flatten())?;
+    let proto = proto.trim();
+    if proto.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if proto.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
         None
     }
 }
 
+// Rebuilds the request URL on the https scheme, keeping the path and query. The Host's own port
+// belongs to the plain listener, so it's swapped for the TLS one (omitted when it's 443).
+fn https_location(host: &str, path_and_query: &str, https_port: u16) -> String {
+    let name = if host.starts_with('[') {
+        host.split_inclusive(']').next().unwrap_or(host)
+    } else {
+        host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host)
+    };
+    if https_port == 443 {
+        format!("https://{}{}", name, path_and_query)
+    } else {
+        format!("https://{}:{}{}", name, https_port, path_and_query)
+    }
+}
+
+// A retry sends the request a second time, so only requests that are safe to repeat qualify:
+// the RETRY_METHODS, anything carrying an Idempotency-Key (the backend dedupes those), or every
+// method with RETRY_NON_IDEMPOTENT.
+fn is_safe_to_repeat(req: &RequestHeader, retry_methods: &[Method], retry_non_idempotent: bool) -> bool {
+    retry_non_idempotent || retry_methods.contains(&req.method) || req.headers.contains_key("Idempotency-Key")
+}
+
+// The Allow header for a 405 when ALLOWED_METHODS is set and doesn't list the method, None when
+// the request may go on.
+fn refused_method(allowed: Option<&[Method]>, method: &Method) -> Option<String> {
+    let allowed = allowed.filter(|allowed| !allowed.contains(method))?;
+    Some(allowed.iter().map(Method::as_str).collect::<Vec<_>>().join(", "))
+}
+
+// Server-sent events stay open and deliver each event as it's written, so they must reach the
+// client chunk by chunk: never compressed, cached or held to the response size limit.
+fn is_event_stream(resp: &ResponseHeader) -> bool {
+    resp.headers
+        .get("Content-Type")
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.trim_start().to_lowercase().starts_with("text/event-stream"))
+}
+
+// Label for proxy_backend_connect_errors_total and the connect failure log line.
+fn connect_failure_reason(etype: &ErrorType) -> &'static str {
+    match etype {
+        ErrorType::ConnectRefused => "refused",
+        ErrorType::ConnectTimedout => "timeout",
+        ErrorType::TLSHandshakeFailure | ErrorType::TLSHandshakeTimedout | ErrorType::InvalidCert | ErrorType::HandshakeError => "tls",
+        ErrorType::ConnectNoRoute => "no_route",
+        _ => "other",
+    }
+}
+
+// Pingora sets TCP_NODELAY on every connection it opens and keeps owning the descriptor, so the
+// socket is only borrowed here.
+fn enable_nagle(fd: RawFd) {
+    // SAFETY: the fd is a live TCP socket for the duration of the call, and ManuallyDrop keeps
+    // this handle from closing it.
+    let stream = ManuallyDrop::new(unsafe { std::net::TcpStream::from_raw_fd(fd) });
+    if let Err(e) = stream.set_nodelay(false) {
+        debug!("Could not turn Nagle's algorithm back on for an upstream socket: {}", e);
+    }
+}
+
 #[async_trait]
 impl ProxyHttp for MyProxy {
-    type CTX = Option<String>;
+    type CTX = RequestCtx;
 
     fn new_ctx(&self) -> Self::CTX {
-        None
+        RequestCtx {
+            pool: None,
+            session_id: None,
+            chosen_backend: None,
+            connection: None,
+            concurrency_permit: None,
+            connection_slot: None,
+            start: Instant::now(),
+            retries: 0,
+            failed_backends: Vec::new(),
+            cache_key: None,
+            cache_fill: None,
+            accept_encoding: None,
+            compressor: None,
+            upgrade: false,
+            cors_origin: None,
+            request_body_bytes: 0,
+            response_body_bytes: 0,
+            event_stream: false,
+            request_body_sent: 0,
+            body_rejected: false,
+            decompressor: None,
+            mirror_request: None,
+            variant: None,
+            variant_forced: false,
+            short_circuit: None,
+            request_id: Uuid::new_v4().to_string(),
+            debug_upstream: None,
+        }
     }
 
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
+        ctx.start = Instant::now();
+        self.metrics.requests_total.inc();
+        self.metrics.requests_in_flight.inc();
+
+        // Checked before anything below adds headers of its own.
+        if let Some(limit) = self.max_header_bytes {
+            let size = header_bytes(session.req_header());
+            if size > limit {
+                warn!("📦 Refused {} {}: headers of {} bytes exceed {}", session.req_header().method, session.req_header().uri, size, limit);
+                session.set_keepalive(None);
+                self.error_pages.respond_with(session, 431, "request headers too large", &[]).await?;
+                ctx.short_circuit = Some("headers_too_large");
+                return Ok(true);
+            }
+        }
+
+        // Only a trusted proxy's ID is kept; anyone else gets a fresh one.
+        let peer_trusted = self.client_addr(session).is_some_and(|addr| self.trusted_proxies.contains(addr.ip()));
+        if let Some(id) = session
+            .req_header()
+            .headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|id| peer_trusted && is_valid_request_id(id))
+        {
+            ctx.request_id = id.to_string();
+        }
+        session.req_header_mut().insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+
+        // Capacity protection comes first, so an overloaded proxy does as little as possible.
+        if let Some(limiter) = &self.connection_limiter {
+            let client_ip = self.real_client_ip(session);
+            ctx.connection_slot = limiter.acquire(client_ip);
+            if ctx.connection_slot.is_none() {
+                warn!("🚧 Refused {} {} from {:?}: connection limit reached", session.req_header().method, session.req_header().uri, client_ip);
+                // Closing the connection is what actually frees capacity.
+                session.set_keepalive(None);
+                self.error_pages.respond_unavailable(session, "connection limit reached", self.unavailable_retry_after).await?;
+                ctx.short_circuit = Some("connection_limit");
+                return Ok(true);
+            }
+        }
+
+        // ACME validation has to succeed regardless of IP filters, auth or https redirects.
+        if let Some(challenges) = &self.acme_challenges {
+            if let Some(token) = session.req_header().uri.path().strip_prefix(acme::CHALLENGE_PATH) {
+                let Some(key_authorization) = challenges.key_authorization(token) else {
+                    self.error_pages.respond(session, 404).await?;
+                    ctx.short_circuit = Some("acme");
+                    return Ok(true);
+                };
+                let mut header = ResponseHeader::build(200, Some(2))?;
+                header.insert_header("Content-Type", "application/octet-stream")?;
+                header.insert_header("Content-Length", key_authorization.len().to_string())?;
+                session.write_response_header(Box::new(header), false).await?;
+                session.write_response_body(Some(Bytes::from(key_authorization)), true).await?;
+                ctx.short_circuit = Some("acme");
+                return Ok(true);
+            }
+        }
+
+        // Checked against the socket peer; X-Forwarded-For is client-controlled.
+        if let Some(filter) = &self.ip_filter {
+            let peer_ip = self.client_addr(session).map(|addr| addr.ip());
+            if !peer_ip.is_some_and(|ip| filter.is_allowed(ip)) {
+                warn!("🛡️ Refused {} {} from {:?}", session.req_header().method, session.req_header().uri, peer_ip);
+                self.error_pages.respond_with(session, 403, "client address not allowed", &[]).await?;
+                ctx.short_circuit = Some("ip_filter");
+                return Ok(true);
+            }
+        }
+
+        // `/public/../admin` would pass path rules and auth bypass lists as `/public/...` while the
+        // backend serves `/admin`, so such paths are refused outright.
+        if normalized_path(session.req_header().uri.path()).is_none() {
+            warn!("🚫 Refused {} {}: dot-segments in path", session.req_header().method, session.req_header().uri);
+            self.error_pages.respond_with(session, 400, "dot-segments in path", &[]).await?;
+            ctx.short_circuit = Some("dot_segments");
+            return Ok(true);
+        }
+
+        if let Some(status) = self.path_rules.as_ref().and_then(|rules| rules.blocked(session.req_header().uri.path())) {
+            warn!("🚫 Refused {} {}: blocked by path rules", session.req_header().method, session.req_header().uri);
+            self.error_pages.respond_with(session, status, "blocked by path rules", &[]).await?;
+            ctx.short_circuit = Some("path_rule");
+            return Ok(true);
+        }
+
+        if self.maintenance.is_enabled() {
+            let client_ip = self.real_client_ip(session);
+            if !self.maintenance.is_exempt(client_ip, session.req_header().uri.path()) {
+                self.maintenance.respond(session, &self.error_pages).await?;
+                ctx.short_circuit = Some("maintenance");
+                return Ok(true);
+            }
+        }
+
+        if let Some(redirect) = self.redirect.as_ref().filter(|_| self.ssl_enabled) {
+            let plain = self.client_scheme(session) == "http";
+            let req = session.req_header();
+            let host = req.headers.get("Host").and_then(|h| h.to_str().ok()).or_else(|| req.uri.host());
+
+            if let Some(host) = host.filter(|_| plain) {
+                let path = req.uri.path_and_query().map(|pq| pq.as_str()).unwrap_or("/");
+                let location = https_location(host, path, self.https_port);
+                let mut header = ResponseHeader::build(redirect.status, Some(2))?;
+                header.insert_header("Location", location)?;
+                header.insert_header("Content-Length", "0")?;
+                session.write_response_header(Box::new(header), true).await?;
+                ctx.short_circuit = Some("https_redirect");
+                return Ok(true);
+            }
+        }
+
+        if let Some(certs) = &self.client_certs {
+            // Identity headers only ever come from a verified certificate, never from the client.
+            session.req_header_mut().remove_header(mtls::CN_HEADER);
+            session.req_header_mut().remove_header(mtls::SAN_HEADER);
+
+            // The handshake already enforces this on the TLS listener; plain listeners don't.
+            if certs.mode == MtlsMode::Require && !ClientCerts::is_verified(session.digest()) {
+                warn!("🪪 Rejected {} {}: no verified client certificate", session.req_header().method, session.req_header().uri);
+                self.error_pages.respond_with(session, 403, "client certificate required", &[]).await?;
+                ctx.short_circuit = Some("mtls");
+                return Ok(true);
+            }
+            if let Some(identity) = certs.identity(session.digest()) {
+                if let Some(cn) = identity.common_name {
+                    session.req_header_mut().insert_header(mtls::CN_HEADER, cn)?;
+                }
+                if !identity.sans.is_empty() {
+                    session.req_header_mut().insert_header(mtls::SAN_HEADER, identity.sans.join(", "))?;
+                }
+            }
+        }
+
+        if let Some(cors) = &self.cors {
+            let origin = session.req_header().headers.get("Origin").and_then(|v| v.to_str().ok()).map(str::to_string);
+            ctx.cors_origin = origin.filter(|origin| cors.is_allowed(origin));
+
+            if Cors::is_preflight(session.req_header()) {
+                match &ctx.cors_origin {
+                    Some(origin) => {
+                        let header = cors.preflight_response(session.req_header(), origin)?;
+                        session.write_response_header(Box::new(header), true).await?;
+                    }
+                    None => self.error_pages.respond_with(session, 403, "origin not allowed", &[]).await?,
+                }
+                ctx.short_circuit = Some("cors_preflight");
+                return Ok(true);
+            }
+        }
+
+        if let Some(allow) = refused_method(self.allowed_methods.as_deref(), &session.req_header().method) {
+            warn!("🚫 Refused {} {}: method not allowed", session.req_header().method, session.req_header().uri);
+            self.error_pages.respond_with(session, 405, "method not allowed", &[("Allow", allow)]).await?;
+            ctx.short_circuit = Some("method_not_allowed");
+            return Ok(true);
+        }
+
+        if let Some(auth) = &self.basic_auth {
+            let req = session.req_header();
+            if !auth.is_bypassed(req.uri.path()) {
+                let authorization = req.headers.get("Authorization").and_then(|v| v.to_str().ok());
+                if !auth.verify(authorization).await {
+                    let challenge = "Basic realm=\"Restricted\", charset=\"UTF-8\"".to_string();
+                    self.error_pages.respond_with(session, 401, "authentication required", &[("WWW-Authenticate", challenge)]).await?;
+                    ctx.short_circuit = Some("basic_auth");
+                    return Ok(true);
+                }
+            }
+        }
+
+        if let Some(validator) = &self.jwt {
+            // Claim headers only ever come from a verified token, never from the client.
+            for (_, header) in &validator.config.forward_claims {
+                session.req_header_mut().remove_header(header.as_str());
+            }
+
+            if !validator.is_bypassed(session.req_header().uri.path()) {
+                let authorization = session.req_header().headers.get("Authorization").and_then(|v| v.to_str().ok());
+                match validator.verify(authorization) {
+                    Ok(claims) => {
+                        for (claim, header) in &validator.config.forward_claims {
+                            if let Some(value) = claims.get(claim).and_then(jwt::claim_header_value) {
+                                session.req_header_mut().insert_header(header.clone(), value)?;
+                            }
+                        }
+                    }
+                    Err(reason) => {
+                        warn!("🪪 Rejected {} {}: {}", session.req_header().method, session.req_header().uri, reason);
+                        self.error_pages.respond_with(session, 401, "invalid or missing token", &[("WWW-Authenticate", "Bearer".to_string())]).await?;
+                        ctx.short_circuit = Some("jwt");
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+
+        if let Some(limit) = self.max_body_bytes {
+            let content_length = session
+                .req_header()
+                .headers
+                .get("Content-Length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<usize>().ok());
+            if content_length.is_some_and(|len| len > limit) {
+                warn!("📦 Refused {} {}: body of {} bytes exceeds {}", session.req_header().method, session.req_header().uri, content_length.unwrap_or(0), limit);
+                // The body is never read, so the connection can't be reused.
+                session.set_keepalive(None);
+                self.error_pages.respond_with(session, 413, "request body too large", &[]).await?;
+                ctx.short_circuit = Some("body_too_large");
+                return Ok(true);
+            }
+        }
+
+        // WebSocket and other upgrades are tunnelled by Pingora once the backend answers 101; they
+        // are routed like any other request but skip caching and compression.
+        ctx.upgrade = session.is_upgrade_req();
+
+        let host = session
+            .req_header()
+            .headers
+            .get("Host")
+            .and_then(|h| h.to_str().ok())
+            .or_else(|| session.req_header().uri.host());
+        ctx.pool = self.router.route(host, session.req_header().uri.path());
+        if ctx.pool.is_none() {
+            let code = if self.router.unmatched == UnmatchedHost::NotFound { 404 } else { 502 };
+            warn!("🧭 No pool matches host {:?} and path {}, answering {}", host, session.req_header().uri.path(), code);
+            self.error_pages.respond_with(session, code, "no route for this host and path", &[]).await?;
+            ctx.short_circuit = Some("no_pool");
+            return Ok(true);
+        }
+
+        let client_ip = self.real_client_ip(session);
+        if let Some(nearest) = ctx.pool.as_ref().and_then(|pool| self.router.nearest(pool, client_ip)) {
+            ctx.pool = Some(nearest);
+        }
+
+        if let Some(pool) = ctx.pool.as_ref().filter(|_| !self.router.split.is_empty()) {
+            let forced = self.canary_override.as_ref().filter(|canary| {
+                cookie_value(session.req_header(), &canary.cookie)
+                    .is_some_and(|value| bool::from(value.as_bytes().ct_eq(canary.secret.as_bytes())))
+            });
+            if let Some(variant) = forced.and_then(|canary| self.router.forced(pool, &canary.pool)) {
+                debug!("🧪 Canary override sends {} to pool '{}'", session.req_header().uri.path(), variant.name);
+                ctx.variant = Some(variant.name.clone());
+                ctx.variant_forced = true;
+                ctx.pool = Some(variant);
+            } else {
+                // A returning client is pinned by its variant cookie; a new one is bucketed by IP.
+                let pinned = cookie_value(session.req_header(), VARIANT_COOKIE);
+                let client_ip = client_ip.map(|ip| ip.to_string());
+                if let Some(variant) = self.router.split(pool, pinned.as_deref(), client_ip.as_deref()) {
+                    ctx.variant = Some(variant.name.clone());
+                    ctx.pool = Some(variant);
+                }
+            }
+        }
+
+        if !self.debug_upstream_ips.is_empty() {
+            if let Some(requested) = session.req_header_mut().remove_header(DEBUG_UPSTREAM_HEADER) {
+                let requested = requested.to_str().unwrap_or_default().trim().to_string();
+                let trusted = client_ip.is_some_and(|ip| self.debug_upstream_ips.iter().any(|net| net.contains(&ip.to_canonical())));
+                let known = ctx.pool.as_ref().is_some_and(|pool| pool.backends.read().unwrap().iter().any(|b| b.address() == requested));
+                if !trusted {
+                    warn!("🐞 Ignored {} from untrusted client {:?}", DEBUG_UPSTREAM_HEADER, client_ip);
+                } else if !known {
+                    warn!("🐞 Ignored {}: {} is not a backend of the selected pool", DEBUG_UPSTREAM_HEADER, requested);
+                } else {
+                    info!("🐞 {} {} pinned to {} by {}", session.req_header().method, session.req_header().uri, requested, DEBUG_UPSTREAM_HEADER);
+                    ctx.debug_upstream = Some(requested);
+                }
+            }
+        }
+
+        // A pinned debug request should see the backend's own response, not a cached one.
+        if let Some(cache) = self.cache.as_ref().filter(|_| !ctx.upgrade && ctx.debug_upstream.is_none()) {
+            if let Some(key) = ResponseCache::key(session.req_header()) {
+                // Each variant caches its own responses.
+                let key = match &ctx.variant {
+                    Some(variant) => format!("{} #{}", key, variant),
+                    None => key,
+                };
+                if let Some(cached) = cache.get(&key) {
+                    let mut header = cached.header;
+                    header.insert_header("X-Cache", "HIT")?;
+                    header.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+                    if let Some(variant) = &ctx.variant {
+                        header.insert_header(VARIANT_HEADER, variant.as_str())?;
+                    }
+                    if let (Some(cors), Some(origin)) = (&self.cors, &ctx.cors_origin) {
+                        cors.apply(&mut header, origin)?;
+                    }
+                    session.write_response_header(Box::new(header), false).await?;
+                    session.write_response_body(Some(cached.body), true).await?;
+                    ctx.short_circuit = Some("cache_hit");
+                    return Ok(true);
+                }
+                ctx.cache_key = Some(key);
+            }
+        }
+
+        // No healthy backend is a temporary condition, so it gets a 503 the client can retry
+        // instead of an attempt that fails as a 502. A cache hit above is still served.
+        if let Some(pool) = ctx.pool.as_ref().filter(|_| !self.fail_open && ctx.debug_upstream.is_none()) {
+            let available = pool.load_balancer.has_available(&pool.backends.read().unwrap());
+            if !available {
+                warn!("🚨 No healthy backends in pool '{}', answering 503", pool.name);
+                self.error_pages.respond_unavailable(session, "no healthy backend", self.unavailable_retry_after).await?;
+                ctx.short_circuit = Some("no_healthy_backend");
+                return Ok(true);
+            }
+        }
+
+        if self.compression.is_some() && !ctx.upgrade && session.req_header().method != Method::HEAD {
+            ctx.accept_encoding = session
+                .req_header()
+                .headers
+                .get("Accept-Encoding")
+                .and_then(|v| v.to_str().ok())
+                .and_then(Encoding::negotiate);
+        }
+
         let existing_session_id = self.get_session_id(session.req_header());
         
-        if self.load_balancer.strategy == LoadBalanceStrategy::StickySession && existing_session_id.is_none() {
-            *ctx = Some(LoadBalancer::generate_session_id());
+        let strategy = ctx.pool.as_ref().map(|pool| pool.load_balancer.strategy());
+        if strategy == Some(LoadBalanceStrategy::StickySession) && existing_session_id.is_none() {
+            ctx.session_id = Some(LoadBalancer::generate_session_id());
         }
         
-        session.req_header_mut().insert_header("X-Forwarded-By", "Pingora-Proxy")?;
+        if let Some((name, value)) = &self.forwarded_by {
+            session.req_header_mut().insert_header(name.clone(), value.as_str())?;
+        }
+        if let Some(via) = &self.via {
+            session.req_header_mut().append_header("Via", via.as_str())?;
+        }
         
-        let proto = if self.ssl_enabled { "https" } else { "http" };
+        let trusted_peer = self.client_addr(session).is_some_and(|addr| self.trusted_proxies.contains(addr.ip()));
+        let proto = self.client_scheme(session);
         session.req_header_mut().insert_header("X-Forwarded-Proto", proto)?;
 
-        if let Some(client_addr) = session.client_addr() {
-            let addr_string = client_addr.to_string();
-            let client_ip = addr_string.split(':').next().unwrap_or("unknown");
-            
-            if let Some(existing_forwarded) = session.req_header().headers.get("X-Forwarded-For") {
-                if let Ok(existing_str) = existing_forwarded.to_str() {
-                    let new_value = format!("{}, {}", existing_str, client_ip);
-                    session.req_header_mut().insert_header("X-Forwarded-For", new_value)?;
-                }
-            } else {
-                session.req_header_mut().insert_header("X-Forwarded-For", client_ip)?;
+        // The Host the client asked for, before any rewrite; a trusted proxy's value is passed on.
+        let forwarded_host = Some(session.req_header())
+            .filter(|_| trusted_peer)
+            .and_then(|req| {
+                req.headers
+                    .get("X-Forwarded-Host")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string)
+                    .or_else(|| forwarded_params(req, "host").next().flatten())
+            })
+            .or_else(|| original_host(session.req_header()).map(str::to_string));
+        if let Some(host) = &forwarded_host {
+            session.req_header_mut().insert_header("X-Forwarded-Host", host.as_str())?;
+        } else {
+            session.req_header_mut().remove_header("X-Forwarded-Host");
+        }
+
+        // With EMIT_FORWARDED this hop adds its own element describing the client connection.
+        let peer = self.client_addr(session).map(|addr| addr.ip());
+        let element = peer.filter(|_| self.emit_forwarded).map(|ip| {
+            let node = match ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{}]", ip),
+            };
+            let mut element = format!("for={};proto={}", forwarded_value(&node), proto);
+            if let Some(host) = &forwarded_host {
+                element.push_str(&format!(";host={}", forwarded_value(host)));
             }
-            
-            info!("{} {} {}", session.req_header().method, client_ip, session.req_header().uri);
+            element
+        });
+        if let Some(real_ip) = rewrite_forwarding_headers(session.req_header_mut(), peer, &self.trusted_proxies, element)? {
+            info!("{} {} {}", session.req_header().method, real_ip, session.req_header().uri);
+        }
+
+        if let Some(mirror) = self.mirror.as_ref().filter(|_| !ctx.upgrade) {
+            ctx.mirror_request = mirror.sample(session.req_header());
         }
 
         Ok(false)
     }
 
+    // Prefix rewriting and per-backend headers only touch the upstream request, so caching and
+    // logging keep the request the client sent. Runs for every attempt, after the backend is picked.
+    async fn upstream_request_filter(&self, session: &mut Session, upstream_request: &mut RequestHeader, ctx: &mut Self::CTX) -> Result<()> {
+        for name in hop_by_hop_headers(&upstream_request.headers, ctx.upgrade) {
+            upstream_request.remove_header(name.as_str());
+        }
+        if let Some(rewriter) = &self.header_rewriter {
+            rewriter.rewrite_request(upstream_request)?;
+        }
+        for key in &self.remove_request_headers {
+            upstream_request.remove_header(key.as_str());
+        }
+        for (key, value) in &self.custom_request_headers {
+            upstream_request.insert_header(key.clone(), value.as_str())?;
+        }
+        // Most specific last, so a backend's own headers win over the global ones.
+        if let Some(backend) = &ctx.chosen_backend {
+            for (name, value) in &backend.request_headers {
+                upstream_request.insert_header(name.clone(), value.as_str())?;
+            }
+        }
+
+        // Set up afresh on every attempt, since a retry replays the body from the start. The decoded
+        // length isn't known up front, so the body goes out chunked.
+        ctx.decompressor = None;
+        ctx.request_body_bytes = 0;
+        ctx.request_body_sent = 0;
+        if self.decompress_request {
+            let encoding = upstream_request.headers.get("Content-Encoding").and_then(|v| v.to_str().ok());
+            if let Some(decompressor) = encoding.and_then(Decompressor::for_encoding) {
+                upstream_request.remove_header("Content-Encoding");
+                upstream_request.remove_header("Content-Length");
+                upstream_request.insert_header("Transfer-Encoding", "chunked")?;
+                ctx.decompressor = Some(decompressor);
+            }
+        }
+
+        if let Some(uri) = ctx.pool.as_ref().and_then(|pool| pool.rewrite_uri(&upstream_request.uri)) {
+            let uri = uri
+                .parse()
+                .map_err(|e| Error::because(ErrorType::InternalError, "invalid rewritten request path", e))?;
+            upstream_request.set_uri(uri);
+        }
+
+        // Applied last so the pool's policy wins over header rewrites and custom request headers.
+        let original = original_host(session.req_header());
+        if let Some(host) = ctx.pool.as_ref().and_then(|pool| pool.upstream_host(original, ctx.chosen_backend.as_ref())) {
+            upstream_request.insert_header("Host", host)?;
+        }
+        Ok(())
+    }
+
     async fn upstream_peer(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<Box<HttpPeer>> {
-        let backends = self.backends.read().unwrap();
-        
-        let session_id = if self.load_balancer.strategy == LoadBalanceStrategy::StickySession {
-            self.get_session_id(session.req_header()).or_else(|| ctx.clone())
-        } else {
-            None
+        let pool = match &ctx.pool {
+            Some(pool) => pool.clone(),
+            None => return Err(Error::new_str("No pool selected for request")),
+        };
+        // Every attempt draws on one budget from the request's arrival, so retries can't stack
+        // their full timeouts; a retry that finds it spent answers 504 instead of trying again.
+        let remaining = self.request_deadline.map(|deadline| deadline.saturating_sub(ctx.start.elapsed()));
+        if remaining.is_some_and(|remaining| remaining.is_zero()) {
+            warn!("⏱️ Request deadline passed after {} attempt(s) in pool '{}', answering 504", ctx.retries, pool.name);
+            return Error::e_explain(ErrorType::HTTPStatus(504), "request deadline exceeded");
+        }
+        let within_budget = |timeout: Duration| remaining.map_or(timeout, |remaining| timeout.min(remaining));
+        // The read guard is released before a possible DNS lookup, which would otherwise hold up
+        // config reloads.
+        let backend = {
+            let shared_backends = pool.backends.read().unwrap();
+            // On a retry, backends that already failed this request are treated as unhealthy so every
+            // strategy moves on to the next candidate.
+            let retry_view: Vec<Backend>;
+            let backends: &[Backend] = if ctx.failed_backends.is_empty() {
+                &shared_backends
+            } else {
+                retry_view = shared_backends
+                    .iter()
+                    .cloned()
+                    .map(|mut b| {
+                        if ctx.failed_backends.contains(&b.address()) {
+                            b.healthy = false;
+                        }
+                        b
+                    })
+                    .collect();
+                &retry_view
+            };
+            // Backends at the pool's concurrency limit are passed over while another can take the
+            // request; once all of them are full it queues at the one the strategy picks anyway.
+            let open_view: Vec<Backend>;
+            let open: &[Backend] = match &pool.concurrency_limit {
+                Some(limit) => {
+                    open_view = backends
+                        .iter()
+                        .cloned()
+                        .map(|mut b| {
+                            if limit.is_full(&b.address()) {
+                                b.healthy = false;
+                            }
+                            b
+                        })
+                        .collect();
+                    &open_view
+                }
+                None => backends,
+            };
+
+            let session_id = match pool.load_balancer.strategy() {
+                LoadBalanceStrategy::StickySession => self.get_session_id(session.req_header()).or_else(|| ctx.session_id.clone()),
+                LoadBalanceStrategy::ConsistentHash => self.get_session_id(session.req_header()),
+                _ => None,
+            };
+
+            let client_ip = self.client_addr(session).map(|addr| addr.ip().to_string());
+
+            let pinned = ctx.debug_upstream.as_ref().and_then(|address| backends.iter().find(|b| b.address() == *address));
+            if let Some(backend) = pinned.filter(|b| !b.healthy) {
+                warn!("🐞 {} targets {}, which is marked unhealthy", DEBUG_UPSTREAM_HEADER, backend.address());
+            }
+            pinned
+                .cloned()
+                .or_else(|| pool.load_balancer.select_backend(open, session_id.as_deref(), client_ip.as_deref()))
+                .or_else(|| {
+                    pool.concurrency_limit.as_ref()?;
+                    pool.load_balancer.select_backend(backends, session_id.as_deref(), client_ip.as_deref())
+                })
+                .or_else(|| {
+                    if !self.fail_open {
+                        return None;
+                    }
+                    warn!("⚠️ No healthy backends in pool '{}', failing open to all of them", pool.name);
+                    pool.load_balancer.select_from_all(backends, session_id.as_deref(), client_ip.as_deref())
+                })
         };
 
-        let backend = self.load_balancer.select_backend(&backends, session_id.as_deref());
-        
         match backend {
             Some(backend) => {
-                let peer = Box::new(HttpPeer::new(
-                    format!("{}:{}", backend.host, backend.port),
-                    false,
-                    "".to_string(),
-                ));
+                // With every slot taken the request queues up to CONCURRENCY_QUEUE_TIMEOUT_MS (and
+                // the deadline), then gets a 503. A retry gives up its failed backend's slot first.
+                ctx.concurrency_permit = None;
+                if let Some(limit) = &pool.concurrency_limit {
+                    let Some(permit) = limit.acquire(&backend.address(), within_budget(self.concurrency_queue_timeout)).await else {
+                        warn!("🚦 {} has {} requests in flight, answering 503 [{}]", backend.address(), limit.max, ctx.request_id);
+                        ctx.short_circuit = Some("concurrency_limit");
+                        return Error::e_explain(ErrorType::HTTPStatus(503), format!("backend {} at its concurrency limit", backend.address()));
+                    };
+                    ctx.concurrency_permit = Some(permit);
+                }
+                let Some(addr) = backend.resolve().await else {
+                    warn!("⚠️ Cannot resolve backend {}", backend.address());
+                    return Error::e_explain(ErrorType::HTTPStatus(502), format!("Cannot resolve backend {}", backend.address()));
+                };
+                let mut peer = Box::new(HttpPeer::new(addr, backend.tls, backend.sni.clone()));
+                let timeouts = pool.upstream_timeouts.as_ref().unwrap_or(&self.upstream_timeouts);
+                peer.options.connection_timeout = Some(within_budget(timeouts.connect));
+                // A tunnelled connection can sit idle legitimately, so it isn't subject to the
+                // read timeout.
+                peer.options.read_timeout = if ctx.upgrade { None } else { Some(within_budget(timeouts.read)) };
+                peer.options.write_timeout = Some(within_budget(timeouts.write));
+                peer.options.idle_timeout = timeouts.idle;
+                peer.options.tcp_keepalive = self.tcp_keepalive.clone();
+                if backend.tls {
+                    // Pingora checks the chain and the SNI (or verify_name) against the system roots
+                    // unless a CA bundle is given; a backend without an SNI isn't verified at all.
+                    let verify = backend.tls_verify.unwrap_or(self.backend_tls.verify);
+                    peer.options.verify_cert = verify;
+                    peer.options.verify_hostname = verify;
+                    peer.options.alternative_cn = backend.verify_name.clone();
+                    peer.options.ca = backend.ca_bundle.as_ref().or(self.backend_tls.ca_bundle.as_ref()).map(|ca| ca.certs.clone());
+                }
+                ctx.connection = Some(backend.track_connection());
+                ctx.chosen_backend = Some(backend);
                 Ok(peer)
             }
+            // Every other backend is down too, so the client gets the failed attempt's 502, not a 503.
+            None if !ctx.failed_backends.is_empty() => {
+                warn!("🔁 No other backend left to retry in pool '{}'", pool.name);
+                Error::e_explain(ErrorType::HTTPStatus(502), format!("No other backend left to retry in pool '{}'", pool.name))
+            }
             None => {
-                error!("🚨 No backends available for routing");
-                Err(pingora_core::Error::new_str("No backends available"))
+                error!("🚨 No backends available for routing in pool '{}'", pool.name);
+                Error::e_explain(ErrorType::HTTPStatus(503), format!("No backends available in pool '{}'", pool.name))
             }
         }
     }
 
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        fd: RawFd,
+        _digest: Option<&Digest>,
+        _ctx: &mut Self::CTX,
+    ) -> Result<()> {
+        // A reused connection already had it applied.
+        if !self.upstream_tcp_nodelay && !reused {
+            enable_nagle(fd);
+        }
+        Ok(())
+    }
+
+    fn fail_to_connect(&self, session: &mut Session, _peer: &HttpPeer, ctx: &mut Self::CTX, mut e: Box<Error>) -> Box<Error> {
+        let reason = connect_failure_reason(e.etype());
+        if let (Some(pool), Some(backend)) = (&ctx.pool, &ctx.chosen_backend) {
+            self.metrics.backend_errors_total.with_label_values(&[&backend.address()]).inc();
+            self.metrics.backend_connect_errors_total.with_label_values(&[&backend.address(), reason]).inc();
+            self.record_passive_failure(pool, backend);
+            if let Some(cooldown) = &pool.load_balancer.connect_cooldown {
+                cooldown.start(&backend.address());
+            }
+        }
+
+        // Nothing reached the backend, so the request can go to another one if it is retryable at all.
+        let retryable = self.is_retryable(session.req_header(), ctx);
+        let retry = retryable && ctx.retries < self.max_retries;
+        let address = ctx.chosen_backend.as_ref().map_or_else(|| "-".to_string(), |b| b.address());
+        warn!(
+            "🔌 Connect to {} failed ({}): {}; {} [{}]",
+            address,
+            reason,
+            e,
+            if retry { "retrying on another backend" } else { "not retrying" },
+            ctx.request_id
+        );
+        if retry {
+            if ctx.chosen_backend.is_some() {
+                ctx.failed_backends.push(address);
+            }
+            ctx.retries += 1;
+        }
+        e.set_retry(retry);
+        e
+    }
+
+    // Chunked bodies carry no length up front, so the limit is also enforced as bytes stream through.
+    async fn request_body_filter(&self, session: &mut Session, body: &mut Option<Bytes>, end_of_stream: bool, ctx: &mut Self::CTX) -> Result<()> {
+        ctx.request_body_sent += body.as_ref().map_or(0, |chunk| chunk.len());
+
+        // The mirror copied the client's headers, so it gets the body as the client sent it too.
+        if let Some(mirror_request) = ctx.mirror_request.as_mut() {
+            if let Some(chunk) = body.as_ref() {
+                mirror_request.push_body(chunk);
+            }
+            if end_of_stream {
+                if let (Some(mirror), Some(mirror_request)) = (&self.mirror, ctx.mirror_request.take()) {
+                    mirror.send(mirror_request);
+                }
+            }
+        }
+
+        // Decoded before the size check, so the limit also catches bodies that only blow up once inflated.
+        if let Some(decompressor) = &mut ctx.decompressor {
+            let decoded = decompressor.decompress(body.as_deref().unwrap_or_default()).and_then(|mut output| {
+                if end_of_stream {
+                    if let Some(decompressor) = ctx.decompressor.take() {
+                        output.extend_from_slice(&decompressor.finish()?);
+                    }
+                }
+                Ok(output)
+            });
+            match decoded {
+                Ok(output) => *body = Some(Bytes::from(output)),
+                Err(e) => {
+                    ctx.body_rejected = true;
+                    warn!("📦 Aborted {} {}: undecodable request body: {}", session.req_header().method, session.req_header().uri, e);
+                    return Error::e_explain(ErrorType::HTTPStatus(400), "request body doesn't match its Content-Encoding");
+                }
+            }
+        }
+
+        if let (Some(limit), Some(chunk)) = (self.max_body_bytes, body.as_ref()) {
+            ctx.request_body_bytes += chunk.len();
+            if ctx.request_body_bytes > limit {
+                ctx.body_rejected = true;
+                warn!("📦 Aborted {} {}: body exceeded {} bytes", session.req_header().method, session.req_header().uri, limit);
+                return Error::e_explain(ErrorType::HTTPStatus(413), format!("request body exceeds {} bytes", limit));
+            }
+        }
+        Ok(())
+    }
+
+    fn error_while_proxy(&self, peer: &HttpPeer, session: &mut Session, e: Box<Error>, ctx: &mut Self::CTX, client_reused: bool) -> Box<Error> {
+        // An oversized or undecodable upload is the client's fault, not the backend's.
+        if let (Some(pool), Some(backend), false) = (&ctx.pool, &ctx.chosen_backend, ctx.body_rejected) {
+            self.metrics.backend_errors_total.with_label_values(&[&backend.address()]).inc();
+            self.record_passive_failure(pool, backend);
+        }
+
+        let mut e = e.more_context(format!("Peer: {}", peer));
+        // With RETRY_BUFFER_BYTES, a backend failing before it answered can be retried elsewhere
+        // as long as every body byte sent so far is still buffered for the replay.
+        let replayable = self
+            .retry_buffer_bytes
+            .is_some_and(|cap| ctx.request_body_sent <= cap && !session.as_ref().retry_buffer_truncated());
+        let retryable = self.is_retryable(session.req_header(), ctx);
+        if replayable
+            && retryable
+            && !ctx.body_rejected
+            && session.response_written().is_none()
+            && *e.esource() == ErrorSource::Upstream
+            && ctx.retries < self.max_retries
+        {
+            if let Some(backend) = &ctx.chosen_backend {
+                warn!("🔁 {} failed mid-request, replaying {} body bytes on another backend", backend.address(), ctx.request_body_sent);
+                ctx.failed_backends.push(backend.address());
+            }
+            // The mirror has already seen part of the body, which the replay would repeat.
+            ctx.mirror_request = None;
+            ctx.retries += 1;
+            e.set_retry(true);
+            return e;
+        }
+        e.retry.decide_reuse(client_reused && !session.as_ref().retry_buffer_truncated());
+        e
+    }
+
+    // Same as the default mapping, except upstream timeouts become 504 Gateway Timeout rather than
+    // a generic 502.
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> FailToProxy {
+        let code = match e.etype() {
+            ErrorType::HTTPStatus(code) => *code,
+            ErrorType::ConnectTimedout | ErrorType::ReadTimedout | ErrorType::WriteTimedout
+                if *e.esource() == ErrorSource::Upstream => 504,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // the client connection is already gone
+                    ErrorType::WriteError | ErrorType::ReadError | ErrorType::ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+
+        // A 503 here means the pool lost its last healthy backend after request_filter checked.
+        let responded = match code {
+            0 => Ok(()),
+            // Failed mid-response (e.g. cut off at MAX_RESPONSE_BYTES): the client already has a
+            // status line, all that's left is to close the connection.
+            _ if session.response_written().is_some() => Ok(()),
+            503 if ctx.short_circuit == Some("concurrency_limit") => {
+                self.error_pages.respond_unavailable(session, "backends at their concurrency limit", self.unavailable_retry_after).await
+            }
+            503 => self.error_pages.respond_unavailable(session, "no healthy backend", self.unavailable_retry_after).await,
+            504 => self.error_pages.respond_with(session, 504, "backend timed out", &[]).await,
+            _ => self.error_pages.respond(session, code).await,
+        };
+        responded.unwrap_or_else(|e| {
+            error!("failed to send error response to downstream: {}", e);
+        });
+
+        FailToProxy {
+            error_code: code,
+            can_reuse_downstream: false,
+        }
+    }
+
     async fn response_filter(&self, _session: &mut Session, upstream_response: &mut ResponseHeader, ctx: &mut Self::CTX, ) -> Result<()> {
-        if let Some(session_id) = ctx.take() {
+        for name in hop_by_hop_headers(&upstream_response.headers, upstream_response.status == 101) {
+            upstream_response.remove_header(name.as_str());
+        }
+        ctx.event_stream = is_event_stream(upstream_response);
+        // Nothing has been sent yet, so a body announced as too large can still become a 502.
+        if let Some(limit) = self.max_response_bytes.filter(|_| !ctx.upgrade && !ctx.event_stream) {
+            let content_length = upstream_response
+                .headers
+                .get("Content-Length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<usize>().ok());
+            if content_length.is_some_and(|len| len > limit) {
+                warn!("📦 Refused response of {} bytes from {:?}: exceeds {}", content_length.unwrap_or(0), ctx.chosen_backend.as_ref().map(|b| b.address()), limit);
+                return Error::e_explain(ErrorType::HTTPStatus(502), format!("response body exceeds {} bytes", limit));
+            }
+        }
+        // Decide cacheability on the backend's own headers, before the sticky cookie is added.
+        let cache_ttl = match (&self.cache, &ctx.cache_key) {
+            (Some(cache), Some(_)) if !ctx.event_stream => ResponseCache::ttl(upstream_response).filter(|_| {
+                upstream_response.headers.get("Content-Length")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .is_none_or(|len| len <= cache.max_body_bytes)
+            }),
+            _ => None,
+        };
+
+        if let (Some(pool), Some(backend)) = (&ctx.pool, &ctx.chosen_backend) {
+            if upstream_response.status.is_server_error() {
+                self.record_passive_failure(pool, backend);
+            } else {
+                self.record_passive_success(pool, backend);
+            }
+            backend.record_latency(ctx.start.elapsed());
+
+            let address = backend.address();
+            let status_class = Metrics::status_class(upstream_response.status.as_u16());
+            self.metrics.backend_requests_total.with_label_values(&[&address, status_class]).inc();
+            self.metrics
+                .request_duration_seconds
+                .with_label_values(&[&address, status_class])
+                .observe(ctx.start.elapsed().as_secs_f64());
+        }
+
+        if let Some(session_id) = ctx.session_id.take() {
             use chrono::{Utc, Duration};
+            let session_id = match &self.session_signer {
+                Some(signer) => signer.sign(&session_id),
+                None => session_id,
+            };
             let expire_time = Utc::now() + Duration::seconds(self.sticky_session_ttl as i64);
             let expires_str = expire_time.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
 
-            let mut cookie_value = format!(
-                "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}; Expires={}",
+            let cookie_value = format!(
+                "{}={}; {}; Max-Age={}; Expires={}",
                 self.sticky_cookie_name,
                 session_id,
+                cookie_attributes(&self.sticky_cookie, self.ssl_enabled),
                 self.sticky_session_ttl,
                 expires_str
             );
 
-            if self.ssl_enabled {
-                cookie_value.push_str("; Secure");
+            upstream_response.insert_header("Set-Cookie", cookie_value)?;
+            // Clients without a cookie jar read the new id here and send it back in the same header.
+            if let Some(header) = &self.sticky_header {
+                upstream_response.insert_header(header.clone(), session_id)?;
+            }
+        }
+
+        if let Some(variant) = &ctx.variant {
+            upstream_response.insert_header(VARIANT_HEADER, variant.as_str())?;
+            if !ctx.variant_forced {
+                upstream_response.append_header(
+                    "Set-Cookie",
+                    format!("{}={}; {}; Max-Age={}", VARIANT_COOKIE, variant, cookie_attributes(&self.sticky_cookie, self.ssl_enabled), self.sticky_session_ttl),
+                )?;
             }
+        }
 
-            upstream_response.insert_header("Set-Cookie", cookie_value)?;
+        upstream_response.insert_header(REQUEST_ID_HEADER, ctx.request_id.as_str())?;
+
+        if let Some(rewriter) = &self.header_rewriter {
+            rewriter.rewrite_response(upstream_response)?;
         }
 
+        if self.strip_fingerprint_headers {
+            for name in FINGERPRINT_HEADERS {
+                upstream_response.remove_header(name);
+            }
+        }
         for key in &self.remove_headers {
             upstream_response.remove_header(key.as_str());
         }
+        // Replaces the backend's own Via, which can name internal hops; without VIA_HEADER it is
+        // passed through untouched.
+        if let Some(via) = &self.via {
+            upstream_response.insert_header("Via", via.as_str())?;
+        }
+
+        // Between the two, so REMOVE_HEADER can drop a backend's value for these to replace and
+        // CUSTOM_HEADER still has the last word.
+        if let Some(security_headers) = &self.security_headers {
+            security_headers.apply(upstream_response)?;
+        }
 
         for (key, value) in &self.custom_headers {
             upstream_response.insert_header(key.clone(), value.clone())?;
         }
 
+        if let Some(key) = ctx.cache_key.take() {
+            upstream_response.insert_header("X-Cache", "MISS")?;
+            if let Some(ttl) = cache_ttl {
+                ctx.cache_fill = Some(CacheFill { key, header: upstream_response.clone(), ttl, body: Vec::new() });
+            }
+        }
+
+        // Added after the cache snapshot, since the allowed origin differs per client.
+        if let (Some(cors), Some(origin)) = (&self.cors, &ctx.cors_origin) {
+            cors.apply(upstream_response, origin)?;
+        }
+
+        // Also after the snapshot: a cache hit wasn't served by any backend. After retries this is
+        // the backend that finally answered.
+        if let Some(backend) = ctx.chosen_backend.as_ref().filter(|_| self.expose_upstream) {
+            upstream_response.insert_header(UPSTREAM_HEADER, backend.address())?;
+        }
+
+        // Compression comes after the cache snapshot so cached entries stay uncompressed and can
+        // be replayed to any client.
+        if let (Some(config), Some(encoding), false) = (&self.compression, ctx.accept_encoding, ctx.event_stream) {
+            if compression::should_compress(upstream_response, config.min_size) {
+                upstream_response.remove_header("Content-Length");
+                upstream_response.insert_header("Transfer-Encoding", "chunked")?;
+                upstream_response.insert_header("Content-Encoding", encoding.as_str())?;
+                upstream_response.append_header("Vary", "Accept-Encoding")?;
+                ctx.compressor = Some(Compressor::new(encoding));
+            }
+        }
+
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn response_body_filter(&self, session: &mut Session, body: &mut Option<Bytes>, end_of_stream: bool, ctx: &mut Self::CTX) -> Result<Option<Duration>> {
+        // Chunked or lying backends only show their size as it streams: the response is cut off
+        // where it crosses the limit, before any of it is cached.
+        if let (Some(limit), Some(chunk), false) = (self.max_response_bytes, body.as_ref(), ctx.upgrade || ctx.event_stream) {
+            ctx.response_body_bytes += chunk.len();
+            if ctx.response_body_bytes > limit {
+                ctx.cache_fill = None;
+                warn!("📦 Cut off response to {} {}: body exceeded {} bytes", session.req_header().method, session.req_header().uri, limit);
+                return Error::e_explain(ErrorType::HTTPStatus(502), format!("response body exceeds {} bytes", limit));
+            }
+        }
+
+        if let (Some(cache), Some(fill)) = (&self.cache, &mut ctx.cache_fill) {
+            if let Some(chunk) = body {
+                fill.body.extend_from_slice(chunk);
+            }
+            if fill.body.len() > cache.max_body_bytes {
+                ctx.cache_fill = None;
+            } else if end_of_stream {
+                if let Some(fill) = ctx.cache_fill.take() {
+                    cache.insert(fill);
+                }
+            }
+        }
+
+        if let Some(compressor) = &mut ctx.compressor {
+            let mut output = compressor
+                .compress(body.as_deref().unwrap_or_default())
+                .map_err(|e| Error::because(ErrorType::InternalError, "failed to compress response body", e))?;
+            if end_of_stream {
+                if let Some(compressor) = ctx.compressor.take() {
+                    let tail = compressor
+                        .finish()
+                        .map_err(|e| Error::because(ErrorType::InternalError, "failed to finish compressed body", e))?;
+                    output.extend_from_slice(&tail);
+                }
+            }
+            *body = Some(Bytes::from(output));
+        }
+        Ok(None)
+    }
+
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX) {
+        self.metrics.requests_in_flight.dec();
+        let backend = match (&ctx.chosen_backend, ctx.short_circuit) {
+            (Some(backend), _) => backend.address(),
+            (None, Some(reason)) => format!("({})", reason),
+            (None, None) => "-".to_string(),
+        };
+        let status = session.response_written().map(|resp| resp.status.as_u16()).unwrap_or(0);
+        let elapsed = ctx.start.elapsed();
+        let elapsed_ms = elapsed.as_millis();
+        // Status 0 (nothing sent, e.g. the client went away) is counted as 5xx.
+        let status_class = Metrics::status_class(status);
+        self.metrics
+            .request_total_duration_seconds
+            .with_label_values(&[status_class])
+            .observe(elapsed.as_secs_f64());
+        // Unlike proxy_backend_requests_total, this also sees the 502/504 a backend caused without
+        // answering, and a response cut off after its status went out.
+        let backend_label = ctx.chosen_backend.as_ref().map_or_else(|| "-".to_string(), |b| b.address());
+        self.metrics.responses_total.with_label_values(&[&backend_label, status_class]).inc();
+
+        match e {
+            Some(e) => warn!(
+                "{} {} -> {} {} failed after {}ms: {} [{}]",
+                session.req_header().method, session.req_header().uri, backend, status, elapsed_ms, e, ctx.request_id
+            ),
+            None => info!(
+                "{} {} -> {} {} in {}ms [{}]",
+                session.req_header().method, session.req_header().uri, backend, status, elapsed_ms, ctx.request_id
+            ),
+        }
+
+        if let Some(access_log) = &self.access_log {
+            let req = session.req_header();
+            access_log.write(&serde_json::json!({
+                "time": chrono::Utc::now().to_rfc3339(),
+                "request_id": ctx.request_id,
+                "client": self.client_addr(session).map(|addr| addr.ip().to_string()),
+                "method": req.method.as_str(),
+                "host": req.headers.get("Host").and_then(|h| h.to_str().ok()),
+                "uri": req.uri.to_string(),
+                "status": status,
+                "status_class": status_class,
+                "bytes": session.body_bytes_sent(),
+                "backend": ctx.chosen_backend.as_ref().map(|b| b.address()),
+                "short_circuit": ctx.short_circuit,
+                "duration_ms": elapsed_ms,
+                "error": e.map(|e| e.etype().as_str()),
+            }));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(forwarded: &[&str]) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        for value in forwarded {
+            req.append_header("Forwarded", *value).unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn forwarded_reads_client_and_scheme() {
+        let req = request(&["for=1.2.3.4;proto=https"]);
+        assert_eq!(forwarded_for(&req).as_deref(), Some("1.2.3.4"));
+        assert_eq!(forwarded_proto(&req), Some("https"));
+    }
+
+    #[test]
+    fn forwarded_keeps_separators_inside_quotes() {
+        let req = request(&[r#"for="_a,b";host="x;y", for=5.6.7.8"#]);
+        let hosts: Vec<Option<String>> = forwarded_params(&req, "host").collect();
+        assert_eq!(hosts, vec![Some("x;y".to_string()), None]);
+        assert_eq!(forwarded_for(&req).as_deref(), Some("unknown, 5.6.7.8"));
+    }
+
+    #[test]
+    fn forwarded_unescapes_quoted_values() {
+        let req = request(&[r#"host="a\"b\\c""#]);
+        assert_eq!(forwarded_params(&req, "host").next().flatten().as_deref(), Some(r#"a"b\c"#));
+    }
+
+    #[test]
+    fn forwarded_chain_spans_headers_and_elements() {
+        let req = request(&[r#"for="[2001:db8::1]:4711", for=10.0.0.1:80"#, "for=unknown;by=10.0.0.2, proto=http"]);
+        assert_eq!(forwarded_for(&req).as_deref(), Some("2001:db8::1, 10.0.0.1, unknown, unknown"));
+    }
+
+    #[test]
+    fn forwarded_value_quotes_non_tokens() {
+        assert_eq!(forwarded_value("1.2.3.4"), "1.2.3.4");
+        assert_eq!(forwarded_value("[2001:db8::1]"), "\"[2001:db8::1]\"");
+        assert_eq!(forwarded_value("a\"b"), "\"a\\\"b\"");
+    }
+
+    fn cookie(secure: Option<bool>, same_site: SameSite, domain: Option<&str>) -> CookieConfig {
+        CookieConfig { secure, same_site, domain: domain.map(str::to_string), path: "/".to_string() }
+    }
+
+    #[test]
+    fn cookie_attributes_follow_the_config() {
+        let cookie = cookie(None, SameSite::Lax, Some("example.com"));
+        assert_eq!(cookie_attributes(&cookie, false), "Path=/; Domain=example.com; HttpOnly; SameSite=Lax");
+        assert_eq!(cookie_attributes(&cookie, true), "Path=/; Domain=example.com; HttpOnly; SameSite=Lax; Secure");
+
+        let cookie = CookieConfig { path: "/app".to_string(), ..self::cookie(Some(true), SameSite::Strict, None) };
+        assert_eq!(cookie_attributes(&cookie, false), "Path=/app; HttpOnly; SameSite=Strict; Secure");
+    }
+
+    #[test]
+    fn same_site_none_is_always_secure() {
+        assert_eq!(cookie_attributes(&cookie(Some(false), SameSite::None, None), false), "Path=/; HttpOnly; SameSite=None; Secure");
+    }
+
+    #[test]
+    fn unlisted_method_gets_405_with_allow_header() {
+        let allowed = [Method::GET, Method::HEAD, Method::POST];
+        assert_eq!(refused_method(Some(&allowed), &Method::DELETE).as_deref(), Some("GET, HEAD, POST"));
+        assert_eq!(refused_method(Some(&allowed), &Method::POST), None);
+        assert_eq!(refused_method(None, &Method::DELETE), None);
+    }
+
+    #[test]
+    fn only_idempotent_methods_are_retried_by_default() {
+        let retry_methods = [Method::GET, Method::HEAD, Method::OPTIONS];
+        let get = RequestHeader::build("GET", b"/", None).unwrap();
+        let mut post = RequestHeader::build("POST", b"/", None).unwrap();
+        assert!(is_safe_to_repeat(&get, &retry_methods, false));
+        assert!(!is_safe_to_repeat(&post, &retry_methods, false));
+        assert!(is_safe_to_repeat(&post, &retry_methods, true));
+
+        post.insert_header("Idempotency-Key", "abc").unwrap();
+        assert!(is_safe_to_repeat(&post, &retry_methods, false));
+    }
+
+    #[test]
+    fn trusted_forwarded_only_chain_counts_the_peer_once() {
+        let trusted = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let peer: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut req = request(&["for=1.2.3.4"]);
+        let element = Some("for=10.0.0.2;proto=http".to_string());
+
+        let real_ip = rewrite_forwarding_headers(&mut req, Some(peer), &trusted, element).unwrap();
+        assert_eq!(real_ip, Some("1.2.3.4".parse().unwrap()));
+        assert_eq!(req.headers.get("X-Forwarded-For").unwrap(), "1.2.3.4, 10.0.0.2");
+        assert_eq!(req.headers.get("X-Real-IP").unwrap(), "1.2.3.4");
+        assert_eq!(req.headers.get("Forwarded").unwrap(), "for=1.2.3.4, for=10.0.0.2;proto=http");
+    }
+
+    #[test]
+    fn untrusted_peer_forwarding_chain_is_replaced() {
+        let trusted = TrustedProxies::new(vec!["10.0.0.0/8".parse().unwrap()]);
+        let peer: IpAddr = "192.0.2.7".parse().unwrap();
+        let mut req = request(&["for=1.2.3.4"]);
+        req.insert_header("X-Forwarded-For", "1.2.3.4").unwrap();
+
+        let real_ip = rewrite_forwarding_headers(&mut req, Some(peer), &trusted, None).unwrap();
+        assert_eq!(real_ip, Some(peer));
+        assert_eq!(req.headers.get("X-Forwarded-For").unwrap(), "192.0.2.7");
+        assert!(req.headers.get("Forwarded").is_none());
+    }
+}
@@ -0,0 +1,204 @@
+use async_trait::async_trait;
+use log::warn;
+use pingora_core::apps::ServerApp;
+use pingora_core::protocols::Stream;
+use pingora_core::server::ShutdownWatch;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// "PROXY TCP6 <39> <39> <5> <5>\r\n" is the longest legal v1 line.
+const V1_MAX_LEN: usize = 107;
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Real client addresses of connections relayed by the front listener, keyed by the relay's own
+// loopback address as the proxy sees it.
+#[derive(Default)]
+pub struct ClientAddrs {
+    addrs: Mutex<HashMap<SocketAddr, SocketAddr>>,
+}
+
+impl ClientAddrs {
+    pub fn resolve(&self, peer: SocketAddr) -> SocketAddr {
+        self.addrs.lock().unwrap().get(&peer).copied().unwrap_or(peer)
+    }
+}
+
+// Pingora gives applications no way to consume bytes ahead of the HTTP parser, so the public port
+// is served by this relay: it strips the PROXY header, then splices the rest of the connection (TLS
+// included) to the proxy's internal loopback listener, recording who the client really was.
+pub struct ProxyProtocolFront {
+    pub internal_addr: String,
+    pub clients: Arc<ClientAddrs>,
+}
+
+#[async_trait]
+impl ServerApp for ProxyProtocolFront {
+    async fn process_new(self: &Arc<Self>, mut stream: Stream, _shutdown: &ShutdownWatch) -> Option<Stream> {
+        let peer = stream
+            .get_socket_digest()
+            .and_then(|digest| digest.peer_addr().and_then(|addr| addr.as_inet()).copied());
+
+        let source = match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)).await {
+            Ok(Ok(source)) => source,
+            Ok(Err(e)) => {
+                warn!("🧾 Rejected connection from {:?}: malformed PROXY header ({})", peer, e);
+                return None;
+            }
+            Err(_) => {
+                warn!("🧾 Rejected connection from {:?}: no PROXY header within {:?}", peer, HEADER_TIMEOUT);
+                return None;
+            }
+        };
+        // LOCAL / UNKNOWN headers (e.g. the balancer's own health checks) keep the balancer's address.
+        let client = match source.or(peer) {
+            Some(client) => client,
+            None => return None,
+        };
+
+        let mut internal = match TcpStream::connect(&self.internal_addr).await {
+            Ok(internal) => internal,
+            Err(e) => {
+                warn!("🧾 Failed to reach internal listener {}: {}", self.internal_addr, e);
+                return None;
+            }
+        };
+        let relay_addr = internal.local_addr().ok();
+        if let Some(relay_addr) = relay_addr {
+            self.clients.addrs.lock().unwrap().insert(relay_addr, client);
+        }
+
+        let _ = tokio::io::copy_bidirectional(&mut stream, &mut internal).await;
+
+        if let Some(relay_addr) = relay_addr {
+            self.clients.addrs.lock().unwrap().remove(&relay_addr);
+        }
+        None
+    }
+}
+
+// Reads exactly the PROXY header (v1 or v2) off the stream, leaving the payload untouched.
+// Ok(None) means the header carried no client address.
+async fn read_header(stream: &mut Stream) -> Result<Option<SocketAddr>, String> {
+    let mut start = [0u8; 6];
+    stream.read_exact(&mut start).await.map_err(|e| e.to_string())?;
+
+    if start == V1_PREFIX {
+        let mut line = start.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                return Err("v1 header too long".to_string());
+            }
+            line.push(stream.read_u8().await.map_err(|e| e.to_string())?);
+        }
+        let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| "v1 header is not text")?;
+        parse_v1(line)
+    } else if start == V2_SIGNATURE[..6] {
+        let mut rest = [0u8; 10];
+        stream.read_exact(&mut rest).await.map_err(|e| e.to_string())?;
+        if rest[..6] != V2_SIGNATURE[6..] {
+            return Err("bad v2 signature".to_string());
+        }
+        let len = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+        let mut body = vec![0u8; len];
+        stream.read_exact(&mut body).await.map_err(|e| e.to_string())?;
+        parse_v2(rest[6], rest[7], &body)
+    } else {
+        Err("missing PROXY header".to_string())
+    }
+}
+
+// `PROXY TCP4 192.0.2.1 198.51.100.1 56324 443`, or `PROXY UNKNOWN ...`.
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, String> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| format!("bad source address '{}'", src))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(format!("{} address in a {} header", src, family));
+            }
+            let port: u16 = src_port.parse().map_err(|_| format!("bad source port '{}'", src_port))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(format!("unrecognised v1 header '{}'", line)),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, body: &[u8]) -> Result<Option<SocketAddr>, String> {
+    if version_command >> 4 != 2 {
+        return Err(format!("unsupported version {}", version_command >> 4));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("unknown command {}", command)),
+    }
+
+    match family >> 4 {
+        // AF_INET: src(4) dst(4) src_port(2) dst_port(2)
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            Ok(Some(SocketAddr::new(ip.into(), u16::from_be_bytes([body[8], body[9]]))))
+        }
+        // AF_INET6: src(16) dst(16) src_port(2) dst_port(2)
+        2 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), u16::from_be_bytes([body[32], body[33]]))))
+        }
+        1 | 2 => Err("truncated v2 address block".to_string()),
+        // AF_UNSPEC / AF_UNIX carry nothing usable as a client IP.
+        0 | 3 => Ok(None),
+        other => Err(format!("unknown address family {}", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> Option<SocketAddr> {
+        Some(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn parses_v1_headers() {
+        assert_eq!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 56324 443"), Ok(addr("192.0.2.1:56324")));
+        assert_eq!(parse_v1("PROXY TCP6 2001:db8::1 2001:db8::2 4000 443"), Ok(addr("[2001:db8::1]:4000")));
+        assert_eq!(parse_v1("PROXY UNKNOWN"), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_v1_headers() {
+        assert!(parse_v1("PROXY TCP4 2001:db8::1 198.51.100.1 56324 443").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1 198.51.100.1 70000 443").is_err());
+        assert!(parse_v1("PROXY TCP4 192.0.2.1").is_err());
+    }
+
+    #[test]
+    fn parses_v2_headers() {
+        let v4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(parse_v2(0x21, 0x11, &v4), Ok(addr("192.0.2.1:56324")));
+
+        let mut v6 = [0u8; 36];
+        v6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        v6[32..34].copy_from_slice(&4000u16.to_be_bytes());
+        assert_eq!(parse_v2(0x21, 0x21, &v6), Ok(addr("[2001:db8::1]:4000")));
+
+        // LOCAL connections carry no client address.
+        assert_eq!(parse_v2(0x20, 0x00, &[]), Ok(None));
+    }
+
+    #[test]
+    fn rejects_malformed_v2_headers() {
+        assert!(parse_v2(0x11, 0x11, &[0; 12]).is_err());
+        assert!(parse_v2(0x21, 0x11, &[0; 8]).is_err());
+        assert!(parse_v2(0x23, 0x11, &[0; 12]).is_err());
+    }
+}
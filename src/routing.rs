@@ -0,0 +1,521 @@
+use log::info;
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use crate::backend::Backend;
+use crate::config::{SplitConfig, UnmatchedHost, UpstreamTimeouts};
+use crate::geoip::GeoIp;
+use crate::load_balancer::{self, LoadBalancer};
+
+pub const DEFAULT_POOL: &str = "default";
+pub const VARIANT_HEADER: &str = "X-Variant";
+pub const VARIANT_COOKIE: &str = "X_VARIANT";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathRewrite {
+    Strip,
+    Replace(String),
+}
+
+// The Host header sent upstream. Preserve restores the client's Host after header rewrites,
+// Backend uses the chosen backend's host:port and Fixed always sends the given name.
+#[derive(Debug, Clone, PartialEq)]
+pub enum UpstreamHost {
+    Preserve,
+    Backend,
+    Fixed(String),
+}
+
+// Caps requests in flight to each backend of a pool at `max`. Slots are keyed by host:port, so a
+// backend keeps its count across reloads; a request holds its permit until it is logged.
+pub struct ConcurrencyLimit {
+    pub max: usize,
+    slots: Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConcurrencyLimit {
+    pub fn new(max: usize) -> Self {
+        Self { max, slots: Mutex::new(HashMap::new()) }
+    }
+
+    fn slots(&self, address: &str) -> Arc<Semaphore> {
+        self.slots
+            .lock()
+            .unwrap()
+            .entry(address.to_string())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max)))
+            .clone()
+    }
+
+    pub fn is_full(&self, address: &str) -> bool {
+        self.slots(address).available_permits() == 0
+    }
+
+    // Takes a slot on the backend, queueing for up to `wait` behind the requests holding them.
+    pub async fn acquire(&self, address: &str, wait: Duration) -> Option<OwnedSemaphorePermit> {
+        tokio::time::timeout(wait, self.slots(address).acquire_owned()).await.ok()?.ok()
+    }
+}
+
+pub struct Pool {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub path_prefixes: Vec<String>,
+    pub path_rewrite: Option<PathRewrite>,
+    // A std lock on purpose: it's also taken from sync code (load balancer, admin, SIGHUP thread),
+    // every critical section is a short copy or update, and no guard is ever held across `.await`.
+    pub backends: Arc<RwLock<Vec<Backend>>>,
+    pub load_balancer: Arc<LoadBalancer>,
+    // None uses the proxy-wide upstream timeouts.
+    pub upstream_timeouts: Option<UpstreamTimeouts>,
+    // None leaves Host as the request and header rewrites made it.
+    pub upstream_host: Option<UpstreamHost>,
+    // Cap on requests in flight to each of its backends; None is unlimited.
+    pub concurrency_limit: Option<ConcurrencyLimit>,
+}
+
+impl Pool {
+    pub fn matched_prefix(&self, path: &str) -> Option<&str> {
+        self.path_prefixes
+            .iter()
+            .filter(|prefix| matches_prefix(prefix, path))
+            .max_by_key(|prefix| prefix.len())
+            .map(String::as_str)
+    }
+
+    // Swaps in a new backend list (SIGHUP reload, SRV refresh). Backends that stay keep their health,
+    // connection counts and latency; sticky sessions pinned to ones that left are dropped.
+    pub fn replace_backends(&self, mut fresh: Vec<Backend>) {
+        let mut backends = self.backends.write().unwrap();
+        for backend in fresh.iter_mut() {
+            if let Some(previous) = backends.iter().find(|b| b.address() == backend.address()) {
+                backend.inherit_state(previous);
+            }
+        }
+        *backends = fresh;
+        self.load_balancer.retain_sessions(&backends);
+    }
+
+    // Drops backends being removed once nothing needs them any more: no live sticky session pinned
+    // to them and no request in flight, or once they have drained for `timeout`.
+    pub fn reap_drained(&self, timeout: Duration) {
+        let done = |b: &Backend| {
+            b.drain_started.is_some_and(|since| {
+                since.elapsed() >= timeout
+                    || (b.active_connections() == 0 && self.load_balancer.live_sessions(&b.address()) == 0)
+            })
+        };
+        if !self.backends.read().unwrap().iter().any(done) {
+            return;
+        }
+        let mut backends = self.backends.write().unwrap();
+        backends.retain(|b| {
+            if !done(b) {
+                return true;
+            }
+            info!("🛠️ Backend {} drained, removed from pool '{}'", b.address(), self.name);
+            false
+        });
+        self.load_balancer.retain_sessions(&backends);
+    }
+
+    // The path the backend should see, e.g. `/api/users` -> `/users` (strip) or `/v2/users`
+    // (replace with `/v2`). None when the pool doesn't rewrite or no prefix matched.
+    pub fn rewrite_path(&self, path: &str) -> Option<String> {
+        let rewrite = self.path_rewrite.as_ref()?;
+        let prefix = self.matched_prefix(path)?;
+        let rest = &path[prefix.trim_end_matches('/').len()..];
+        let base = match rewrite {
+            PathRewrite::Strip => "",
+            PathRewrite::Replace(replacement) => replacement.trim_end_matches('/'),
+        };
+        let rewritten = format!("{}{}", base, rest);
+        Some(if rewritten.starts_with('/') { rewritten } else { format!("/{}", rewritten) })
+    }
+
+    // rewrite_path for a whole request target, keeping the query string as it was.
+    pub fn rewrite_uri(&self, uri: &http::Uri) -> Option<String> {
+        let path = self.rewrite_path(uri.path())?;
+        Some(match uri.query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        })
+    }
+
+    // The Host the backend should see under this pool's policy, given the client's original one.
+    pub fn upstream_host(&self, original: Option<&str>, backend: Option<&Backend>) -> Option<String> {
+        match self.upstream_host.as_ref()? {
+            UpstreamHost::Preserve => original.map(str::to_string),
+            UpstreamHost::Backend => backend.map(Backend::address),
+            UpstreamHost::Fixed(host) => Some(host.clone()),
+        }
+    }
+}
+
+// Maps the request Host and path to a backend pool. A pool matches when its host patterns (if any)
+// match and one of its path prefixes (if any) does. The most specific match wins: exact hosts beat
+// `*.suffix` wildcards, which beat host-agnostic pools, then the longest path prefix breaks ties.
+// Anything unmatched goes to the default pool or is refused, per `unmatched`.
+pub struct Router {
+    pub default_pool: Arc<Pool>,
+    pub pools: Vec<Arc<Pool>>,
+    pub unmatched: UnmatchedHost,
+    pub split: Vec<SplitConfig>,
+    pub geoip: Option<GeoIp>,
+}
+
+impl Router {
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<Arc<Pool>> {
+        let host = host.map(normalize_host);
+        let best = self
+            .pools
+            .iter()
+            .filter_map(|pool| {
+                let host_rank = match &host {
+                    _ if pool.hosts.is_empty() => 0,
+                    Some(host) if pool.hosts.contains(host) => 2,
+                    Some(host) if pool.hosts.iter().any(|pattern| matches_wildcard(pattern, host)) => 1,
+                    _ => return None,
+                };
+                let prefix_len = if pool.path_prefixes.is_empty() {
+                    0
+                } else {
+                    pool.matched_prefix(path)?.len()
+                };
+                Some(((host_rank, prefix_len), pool))
+            })
+            // max_by_key keeps the last maximum; reversing keeps config order as the tie-breaker.
+            .rev()
+            .max_by_key(|(rank, _)| *rank);
+
+        if let Some((_, pool)) = best {
+            return Some(pool.clone());
+        }
+
+        match self.unmatched {
+            UnmatchedHost::Default => Some(self.default_pool.clone()),
+            UnmatchedHost::NotFound | UnmatchedHost::BadGateway => None,
+        }
+    }
+
+    // Requests that fell through to the default pool go to the pool mapped to the client's region
+    // instead; host and path matches are left alone.
+    pub fn nearest(&self, routed: &Pool, client_ip: Option<IpAddr>) -> Option<Arc<Pool>> {
+        if routed.name != DEFAULT_POOL {
+            return None;
+        }
+        let name = self.geoip.as_ref()?.pool_for(client_ip?)?;
+        self.all_pools().find(|pool| pool.name == name).cloned()
+    }
+
+    // A request routed to any pool taking part in the split is reassigned to one of its arms: the
+    // arm named by the client's variant cookie, else the one its IP hashes into (0..100), so the
+    // same client keeps landing on the same variant. Clients without either get a random bucket.
+    pub fn split(&self, routed: &Pool, pinned: Option<&str>, client_ip: Option<&str>) -> Option<Arc<Pool>> {
+        if !self.split.iter().any(|arm| arm.pool == routed.name) {
+            return None;
+        }
+        let arm = match pinned.and_then(|name| self.split.iter().find(|arm| arm.pool == name && arm.percent > 0)) {
+            Some(arm) => arm,
+            None => {
+                let bucket = match client_ip {
+                    Some(ip) => (load_balancer::hash_key(ip) % 100) as u8,
+                    None => rand::thread_rng().gen_range(0..100),
+                };
+                let mut upper = 0;
+                self.split.iter().find(|arm| {
+                    upper += arm.percent;
+                    bucket < upper
+                })?
+            }
+        };
+        self.all_pools().find(|pool| pool.name == arm.pool).cloned()
+    }
+
+    // The arm a tester forced with the canary override, for requests routed to a pool in the split.
+    pub fn forced(&self, routed: &Pool, name: &str) -> Option<Arc<Pool>> {
+        if !self.split.iter().any(|arm| arm.pool == routed.name) {
+            return None;
+        }
+        self.all_pools().find(|pool| pool.name == name).cloned()
+    }
+
+    pub fn all_pools(&self) -> impl Iterator<Item = &Arc<Pool>> {
+        std::iter::once(&self.default_pool).chain(self.pools.iter())
+    }
+}
+
+// `/api` matches `/api` and `/api/...` but not `/apis`.
+pub fn matches_prefix(prefix: &str, path: &str) -> bool {
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'))
+}
+
+// The path with its percent-escapes decoded, or None when it has `.` or `..` segments, written
+// out or escaped (`%2e%2e`, `..%2f`). Backends disagree on how to resolve those, so prefix checks
+// that grant access (auth bypass lists) can't be done on them at all.
+pub fn normalized_path(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| bytes.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    let decoded = String::from_utf8_lossy(&decoded).into_owned();
+    // Some backends also take a backslash as a separator.
+    if decoded.split(['/', '\\']).any(|segment| segment == "." || segment == "..") {
+        return None;
+    }
+    Some(decoded)
+}
+
+// matches_prefix on the decoded path; never true for a path with dot-segments.
+pub fn matches_prefix_normalized(prefix: &str, path: &str) -> bool {
+    normalized_path(path).is_some_and(|path| matches_prefix(prefix, &path))
+}
+
+// `*.example.com` matches any subdomain of example.com, but not example.com itself.
+fn matches_wildcard(pattern: &str, host: &str) -> bool {
+    pattern
+        .strip_prefix("*.")
+        .and_then(|suffix| host.strip_suffix(suffix))
+        .is_some_and(|rest| rest.len() > 1 && rest.ends_with('.'))
+}
+
+// Host header values may carry a port and differ in case; patterns are matched on the bare name.
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let name = if host.starts_with('[') {
+        host.split(']').next().map(|h| format!("{}]", h)).unwrap_or_default()
+    } else {
+        host.rsplit_once(':').map(|(name, _)| name).unwrap_or(host).to_string()
+    };
+    name.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{HashAlgo, StickyFailover, StickyMode};
+    use crate::load_balancer::LoadBalanceStrategy;
+
+    fn pool(name: &str, hosts: &[&str], path_prefixes: &[&str]) -> Arc<Pool> {
+        Arc::new(Pool {
+            name: name.to_string(),
+            hosts: hosts.iter().map(|h| h.to_string()).collect(),
+            path_prefixes: path_prefixes.iter().map(|p| p.to_string()).collect(),
+            path_rewrite: None,
+            backends: Arc::new(RwLock::new(Vec::new())),
+            load_balancer: Arc::new(LoadBalancer::new(
+                LoadBalanceStrategy::RoundRobin,
+                100,
+                HashAlgo::Fnv,
+                3600,
+                None,
+                StickyFailover::Reassign,
+                StickyMode::Stateful,
+                None,
+                None,
+                None,
+                Duration::ZERO,
+            )),
+            upstream_timeouts: None,
+            upstream_host: None,
+            concurrency_limit: None,
+        })
+    }
+
+    fn router(pools: Vec<Arc<Pool>>, unmatched: UnmatchedHost) -> Router {
+        Router { default_pool: pool(DEFAULT_POOL, &[], &[]), pools, unmatched, split: Vec::new(), geoip: None }
+    }
+
+    fn routed(router: &Router, host: &str, path: &str) -> Option<String> {
+        router.route(Some(host), path).map(|pool| pool.name.clone())
+    }
+
+    #[test]
+    fn exact_host_beats_wildcard() {
+        let router = router(
+            vec![pool("wild", &["*.example.com"], &[]), pool("api", &["api.example.com"], &[])],
+            UnmatchedHost::Default,
+        );
+        assert_eq!(routed(&router, "API.example.com:8443", "/").as_deref(), Some("api"));
+        assert_eq!(routed(&router, "www.example.com", "/").as_deref(), Some("wild"));
+        // The wildcard doesn't cover the bare domain.
+        assert_eq!(routed(&router, "example.com", "/").as_deref(), Some(DEFAULT_POOL));
+    }
+
+    #[test]
+    fn unmatched_hosts_follow_the_policy() {
+        let router = router(vec![pool("api", &["api.example.com"], &[])], UnmatchedHost::NotFound);
+        assert_eq!(routed(&router, "other.example.com", "/"), None);
+    }
+
+    #[test]
+    fn longest_path_prefix_wins() {
+        let router = router(
+            vec![pool("api", &[], &["/api"]), pool("v2", &[], &["/api/v2/"])],
+            UnmatchedHost::Default,
+        );
+        assert_eq!(routed(&router, "example.com", "/api/v2/users").as_deref(), Some("v2"));
+        assert_eq!(routed(&router, "example.com", "/api").as_deref(), Some("api"));
+        assert_eq!(routed(&router, "example.com", "/apis").as_deref(), Some(DEFAULT_POOL));
+    }
+
+    #[test]
+    fn host_and_prefix_must_both_match() {
+        let router = router(vec![pool("admin", &["admin.example.com"], &["/admin"])], UnmatchedHost::Default);
+        assert_eq!(routed(&router, "admin.example.com", "/admin/users").as_deref(), Some("admin"));
+        assert_eq!(routed(&router, "admin.example.com", "/").as_deref(), Some(DEFAULT_POOL));
+        assert_eq!(routed(&router, "www.example.com", "/admin").as_deref(), Some(DEFAULT_POOL));
+    }
+
+    #[test]
+    fn normalized_path_decodes_escapes() {
+        assert_eq!(normalized_path("/health%2Fz").as_deref(), Some("/health/z"));
+        assert_eq!(normalized_path("/a%zz").as_deref(), Some("/a%zz"));
+        assert_eq!(normalized_path("/.well-known/acme").as_deref(), Some("/.well-known/acme"));
+    }
+
+    #[test]
+    fn normalized_path_rejects_dot_segments() {
+        for path in ["/public/../admin", "/public/./x", "/public/%2e%2e/admin", "/public/..%2fadmin", "/public\\..\\admin", "/.."] {
+            assert_eq!(normalized_path(path), None, "{path}");
+            assert!(!matches_prefix_normalized("/public", path), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_turns_away_the_request_over_the_limit() {
+        let limit = ConcurrencyLimit::new(2);
+        let _first = limit.acquire("10.0.0.1:80", Duration::ZERO).await.unwrap();
+        let _second = limit.acquire("10.0.0.1:80", Duration::ZERO).await.unwrap();
+        assert!(limit.is_full("10.0.0.1:80"));
+        assert!(limit.acquire("10.0.0.1:80", Duration::from_millis(10)).await.is_none());
+        // Every backend has slots of its own.
+        assert!(limit.acquire("10.0.0.2:80", Duration::ZERO).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_queues_until_a_slot_is_released() {
+        let limit = Arc::new(ConcurrencyLimit::new(1));
+        let held = limit.acquire("10.0.0.1:80", Duration::ZERO).await.unwrap();
+        let queued = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire("10.0.0.1:80", Duration::from_secs(5)).await.is_some() }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        drop(held);
+        assert!(queued.await.unwrap());
+    }
+
+    fn rewriting(rewrite: PathRewrite) -> Pool {
+        let mut pool = Arc::into_inner(pool("api", &[], &["/api"])).unwrap();
+        pool.path_rewrite = Some(rewrite);
+        pool
+    }
+
+    #[test]
+    fn strip_rewrite_keeps_the_query() {
+        let pool = rewriting(PathRewrite::Strip);
+        assert_eq!(pool.rewrite_uri(&"/api/users?id=1".parse().unwrap()).as_deref(), Some("/users?id=1"));
+        assert_eq!(pool.rewrite_uri(&"/api".parse().unwrap()).as_deref(), Some("/"));
+    }
+
+    #[test]
+    fn replace_rewrite_keeps_the_query() {
+        let pool = rewriting(PathRewrite::Replace("/v2".to_string()));
+        assert_eq!(pool.rewrite_uri(&"/api/users?id=1".parse().unwrap()).as_deref(), Some("/v2/users?id=1"));
+        assert_eq!(pool.rewrite_uri(&"/other?id=1".parse().unwrap()), None);
+    }
+
+    // tokio::spawn only takes a Send future, so this compiles only while the health checker holds
+    // no std guard across an `.await`; the pool then sees its results through the same lock.
+    #[tokio::test]
+    async fn health_checker_shares_the_pools_backend_lock() {
+        use crate::config::{load_health_check_config, BackendTlsConfig, HealthCheckConfig};
+        use crate::health_check::{HealthCheckMode, HealthChecker};
+        use std::time::Instant;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let pool = pool("default", &[], &[]);
+        pool.backends.write().unwrap().push(Backend::new("127.0.0.1".to_string(), listener.local_addr().unwrap().port(), 1));
+        let config = HealthCheckConfig { enabled: true, mode: HealthCheckMode::Tcp, ..load_health_check_config().unwrap() };
+        let tls = BackendTlsConfig { verify: true, ca_bundle: None };
+        let checker = tokio::spawn(HealthChecker::health_check_loop(pool.backends.clone(), config, tls));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pool.backends.read().unwrap()[0].last_checked.is_none() {
+            assert!(Instant::now() < deadline, "the health checker never probed the backend");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        checker.abort();
+        assert!(pool.backends.read().unwrap()[0].healthy);
+    }
+
+    // Two backends behind sticky sessions; session "s1" is pinned to the first, which then starts
+    // draining the way the admin API's DELETE does.
+    fn draining_pool() -> (Arc<Pool>, String) {
+        let pool = pool("default", &[], &[]);
+        pool.load_balancer.set_strategy(LoadBalanceStrategy::StickySession);
+        *pool.backends.write().unwrap() = vec![Backend::new("10.0.0.1".to_string(), 80, 1), Backend::new("10.0.0.2".to_string(), 80, 1)];
+        let pinned = pool.load_balancer.select_backend(&pool.backends.read().unwrap(), Some("s1"), None).unwrap().address();
+        let mut backends = pool.backends.write().unwrap();
+        backends.iter_mut().find(|b| b.address() == pinned).unwrap().drain_started = Some(std::time::Instant::now());
+        drop(backends);
+        (pool, pinned)
+    }
+
+    #[test]
+    fn draining_backend_keeps_its_sessions_but_gets_no_new_ones() {
+        let (pool, pinned) = draining_pool();
+        let backends = pool.backends.read().unwrap().clone();
+        assert_eq!(pool.load_balancer.select_backend(&backends, Some("s1"), None).unwrap().address(), pinned);
+        for session in ["s2", "s3", "s4"] {
+            assert_ne!(pool.load_balancer.select_backend(&backends, Some(session), None).unwrap().address(), pinned);
+        }
+        assert_eq!(pool.load_balancer.live_sessions(&pinned), 1);
+    }
+
+    #[test]
+    fn draining_backend_stays_while_a_session_or_request_needs_it() {
+        let (pool, pinned) = draining_pool();
+        pool.reap_drained(Duration::from_secs(3600));
+        assert_eq!(pool.backends.read().unwrap().len(), 2);
+
+        pool.load_balancer.retain_sessions(&[]);
+        let in_flight = pool.backends.read().unwrap().iter().find(|b| b.address() == pinned).unwrap().track_connection();
+        pool.reap_drained(Duration::from_secs(3600));
+        assert_eq!(pool.backends.read().unwrap().len(), 2);
+
+        drop(in_flight);
+        pool.reap_drained(Duration::from_secs(3600));
+        let backends = pool.backends.read().unwrap();
+        assert_eq!(backends.len(), 1);
+        assert_ne!(backends[0].address(), pinned);
+    }
+
+    #[test]
+    fn drain_timeout_removes_the_backend_and_its_sessions() {
+        let (pool, pinned) = draining_pool();
+        pool.reap_drained(Duration::ZERO);
+        assert!(pool.backends.read().unwrap().iter().all(|b| b.address() != pinned));
+        assert_eq!(pool.load_balancer.live_sessions(&pinned), 0);
+    }
+}
@@ -0,0 +1,50 @@
+use log::info;
+use pingora_core::Result;
+use pingora_http::ResponseHeader;
+use crate::config::SecurityHeadersConfig;
+
+// Standard hardening headers added to every proxied response. Values the backend already sent
+// are kept unless the config says to override them.
+pub struct SecurityHeaders {
+    headers: Vec<(&'static str, String)>,
+    override_backend: bool,
+}
+
+impl SecurityHeaders {
+    pub fn new(config: SecurityHeadersConfig, ssl_enabled: bool) -> Self {
+        let mut headers = Vec::new();
+        // Browsers ignore HSTS over plain HTTP, and it would pin clients to an https that isn't there.
+        if let Some(hsts) = config.hsts.filter(|_| ssl_enabled) {
+            let mut value = format!("max-age={}", hsts.max_age_secs);
+            if hsts.include_subdomains {
+                value.push_str("; includeSubDomains");
+            }
+            if hsts.preload {
+                value.push_str("; preload");
+            }
+            headers.push(("Strict-Transport-Security", value));
+        }
+        if config.content_type_options {
+            headers.push(("X-Content-Type-Options", "nosniff".to_string()));
+        }
+        if let Some(frame_options) = config.frame_options {
+            headers.push(("X-Frame-Options", frame_options));
+        }
+        if let Some(referrer_policy) = config.referrer_policy {
+            headers.push(("Referrer-Policy", referrer_policy));
+        }
+
+        let names: Vec<&str> = headers.iter().map(|(name, _)| *name).collect();
+        info!("🛡️ Adding security headers: {}", names.join(", "));
+        Self { headers, override_backend: config.override_backend }
+    }
+
+    pub fn apply(&self, resp: &mut ResponseHeader) -> Result<()> {
+        for (name, value) in &self.headers {
+            if self.override_backend || !resp.headers.contains_key(*name) {
+                resp.insert_header(*name, value.as_str())?;
+            }
+        }
+        Ok(())
+    }
+}
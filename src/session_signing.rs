@@ -0,0 +1,61 @@
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::info;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{PKey, Private};
+use openssl::sign::Signer;
+use subtle::ConstantTimeEq;
+
+// HMAC-SHA256 over sticky session ids, so a client can't forge one or pick another client's: the
+// value handed out is `<id>.<mac>`, and one whose MAC doesn't match is treated as no session.
+pub struct SessionSigner {
+    key: PKey<Private>,
+}
+
+impl SessionSigner {
+    pub fn new(secret: &str) -> Self {
+        info!("🔏 Signing sticky session ids");
+        Self { key: PKey::hmac(secret.as_bytes()).expect("HMAC key from STICKY_COOKIE_SECRET") }
+    }
+
+    pub fn sign(&self, session_id: &str) -> String {
+        format!("{}.{}", session_id, URL_SAFE_NO_PAD.encode(self.mac(session_id)))
+    }
+
+    // The bare session id when the MAC checks out.
+    pub fn verify<'a>(&self, value: &'a str) -> Option<&'a str> {
+        let (session_id, mac) = value.rsplit_once('.')?;
+        let presented = URL_SAFE_NO_PAD.decode(mac).ok()?;
+        bool::from(presented.ct_eq(&self.mac(session_id))).then_some(session_id)
+    }
+
+    fn mac(&self, session_id: &str) -> Vec<u8> {
+        let mut signer = Signer::new(MessageDigest::sha256(), &self.key).expect("HMAC-SHA256 signer");
+        signer.update(session_id.as_bytes()).expect("HMAC update");
+        signer.sign_to_vec().expect("HMAC finish")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_session_id_round_trips() {
+        let signer = SessionSigner::new("secret");
+        let cookie = signer.sign("abc-123");
+        assert!(cookie.starts_with("abc-123."));
+        assert_eq!(signer.verify(&cookie), Some("abc-123"));
+    }
+
+    #[test]
+    fn tampered_session_id_is_rejected() {
+        let signer = SessionSigner::new("secret");
+        let cookie = signer.sign("abc-123");
+        let (_, mac) = cookie.rsplit_once('.').unwrap();
+        assert_eq!(signer.verify(&format!("abc-124.{}", mac)), None);
+        assert_eq!(signer.verify(&format!("{}A", cookie)), None);
+        assert_eq!(signer.verify("abc-123"), None);
+        assert_eq!(SessionSigner::new("other").verify(&cookie), None);
+    }
+}
@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora_core::server::ShutdownWatch;
+use pingora_core::services::background::BackgroundService;
+use prometheus::IntGauge;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use crate::access_log::AccessLog;
+
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Raised once the server begins a graceful shutdown, so the proxy's own threads (health checks,
+// cert watcher) stop instead of running until the process is torn down.
+#[derive(Default)]
+pub struct ShutdownSignal {
+    stopping: Mutex<bool>,
+    condvar: Condvar,
+    notify: Notify,
+    workers: AtomicUsize,
+}
+
+// Held by a background thread for as long as it runs, so the exit can wait for it to wind down.
+pub struct WorkerGuard(Arc<ShutdownSignal>);
+
+impl WorkerGuard {
+    pub fn signal(&self) -> &ShutdownSignal {
+        &self.0
+    }
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.0.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl ShutdownSignal {
+    pub fn trigger(&self) {
+        *self.stopping.lock().unwrap() = true;
+        self.condvar.notify_all();
+        self.notify.notify_waiters();
+    }
+
+    pub fn is_stopping(&self) -> bool {
+        *self.stopping.lock().unwrap()
+    }
+
+    // Blocking sleep for std threads; returns true (early) once shutdown has started.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let stopping = self.stopping.lock().unwrap();
+        let (stopping, _) = self.condvar.wait_timeout_while(stopping, duration, |stopping| !*stopping).unwrap();
+        *stopping
+    }
+
+    pub async fn stopped(&self) {
+        let notified = self.notify.notified();
+        if self.is_stopping() {
+            return;
+        }
+        notified.await;
+    }
+
+    pub fn register(self: &Arc<Self>) -> WorkerGuard {
+        self.workers.fetch_add(1, Ordering::SeqCst);
+        WorkerGuard(self.clone())
+    }
+
+    pub fn running_workers(&self) -> usize {
+        self.workers.load(Ordering::SeqCst)
+    }
+}
+
+// On SIGTERM Pingora stops accepting connections and then waits out the whole grace period
+// before exiting. This exits as soon as the last in-flight request has finished instead.
+pub struct DrainMonitor {
+    pub signal: Arc<ShutdownSignal>,
+    pub in_flight: IntGauge,
+    pub access_log: Option<Arc<AccessLog>>,
+    // How long the background threads get to stop once the requests have drained.
+    pub thread_timeout: Duration,
+}
+
+#[async_trait]
+impl BackgroundService for DrainMonitor {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        if shutdown.changed().await.is_err() {
+            return;
+        }
+        self.signal.trigger();
+        info!("🛑 Shutting down, draining {} in-flight requests", self.in_flight.get());
+
+        while self.in_flight.get() > 0 {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        info!("🛑 All requests drained, stopping background threads");
+        let deadline = Instant::now() + self.thread_timeout;
+        while self.signal.running_workers() > 0 && Instant::now() < deadline {
+            tokio::time::sleep(DRAIN_POLL_INTERVAL).await;
+        }
+        let running = self.signal.running_workers();
+        if running > 0 {
+            warn!("⚠️ {} background threads still running after {:?}, exiting anyway", running, self.thread_timeout);
+        }
+        info!("🛑 Exiting");
+        if let Some(access_log) = &self.access_log {
+            access_log.flush();
+        }
+        process::exit(0);
+    }
+}
@@ -0,0 +1,118 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use pingora_core::listeners::TlsAccept;
+use pingora_core::protocols::tls::TlsRef;
+use pingora_core::tls::ext;
+use pingora_core::tls::pkey::{PKey, Private};
+use pingora_core::tls::ssl::NameType;
+use pingora_core::tls::x509::X509;
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{Arc, RwLock};
+use crate::config::SniCertConfig;
+
+struct CertifiedKey {
+    // Leaf first, then any intermediates from the same PEM file.
+    chain: Vec<X509>,
+    key: PKey<Private>,
+}
+
+impl CertifiedKey {
+    fn load(cert_path: &str, key_path: &str) -> Result<Self, String> {
+        let pem = fs::read(cert_path).map_err(|e| format!("Failed to read {}: {}", cert_path, e))?;
+        let chain = X509::stack_from_pem(&pem).map_err(|e| format!("Invalid certificate {}: {}", cert_path, e))?;
+        if chain.is_empty() {
+            return Err(format!("No certificate found in {}", cert_path));
+        }
+        let pem = fs::read(key_path).map_err(|e| format!("Failed to read {}: {}", key_path, e))?;
+        let key = PKey::private_key_from_pem(&pem).map_err(|e| format!("Invalid private key {}: {}", key_path, e))?;
+        Ok(Self { chain, key })
+    }
+}
+
+struct Certs {
+    default: Arc<CertifiedKey>,
+    by_domain: HashMap<String, Arc<CertifiedKey>>,
+}
+
+// Picks the certificate for each handshake from the SNI hostname: an exact match first, then a
+// `*.parent` wildcard, then the default certificate (also used when the client sends no SNI).
+// Clones share the loaded certificates, so a `reload` is seen by the listener on its next handshake.
+#[derive(Clone)]
+pub struct SniResolver {
+    default_cert: String,
+    default_key: String,
+    entries: Vec<SniCertConfig>,
+    certs: Arc<RwLock<Certs>>,
+}
+
+impl SniResolver {
+    pub fn new(default_cert: &str, default_key: &str, entries: &[SniCertConfig]) -> Result<Self, String> {
+        let certs = load_certs(default_cert, default_key, entries)?;
+        for entry in entries {
+            info!("🔒 Serving {} for SNI {}", entry.cert_path, entry.domain);
+        }
+        Ok(Self {
+            default_cert: default_cert.to_string(),
+            default_key: default_key.to_string(),
+            entries: entries.to_vec(),
+            certs: Arc::new(RwLock::new(certs)),
+        })
+    }
+
+    // Re-reads every certificate and key from disk. Nothing is swapped unless all of them load,
+    // so a half-written rotation keeps the previous certificates in service.
+    pub fn reload(&self) -> Result<(), String> {
+        let certs = load_certs(&self.default_cert, &self.default_key, &self.entries)?;
+        *self.certs.write().unwrap() = certs;
+        info!("🔒 Reloaded TLS certificates from {}", self.default_cert);
+        Ok(())
+    }
+
+    // Each served leaf with the certificate that issued it, as needed for OCSP. Files holding only
+    // the leaf are skipped since the issuer isn't known.
+    pub fn leaf_and_issuers(&self) -> Vec<(X509, X509)> {
+        let certs = self.certs.read().unwrap();
+        std::iter::once(&certs.default)
+            .chain(certs.by_domain.values())
+            .filter(|cert| cert.chain.len() > 1)
+            .map(|cert| (cert.chain[0].clone(), cert.chain[1].clone()))
+            .collect()
+    }
+
+    fn resolve(&self, server_name: Option<&str>) -> Arc<CertifiedKey> {
+        let certs = self.certs.read().unwrap();
+        let Some(name) = server_name.map(|name| name.trim_end_matches('.').to_lowercase()) else {
+            return certs.default.clone();
+        };
+        if let Some(cert) = certs.by_domain.get(&name) {
+            return cert.clone();
+        }
+        name.split_once('.')
+            .and_then(|(_, parent)| certs.by_domain.get(&format!("*.{}", parent)))
+            .unwrap_or(&certs.default)
+            .clone()
+    }
+}
+
+fn load_certs(default_cert: &str, default_key: &str, entries: &[SniCertConfig]) -> Result<Certs, String> {
+    let mut by_domain = HashMap::new();
+    for entry in entries {
+        let cert = CertifiedKey::load(&entry.cert_path, &entry.key_path)?;
+        by_domain.insert(entry.domain.to_lowercase(), Arc::new(cert));
+    }
+    Ok(Certs { default: Arc::new(CertifiedKey::load(default_cert, default_key)?), by_domain })
+}
+
+#[async_trait]
+impl TlsAccept for SniResolver {
+    async fn certificate_callback(&self, ssl: &mut TlsRef) -> () {
+        let cert = self.resolve(ssl.servername(NameType::HOST_NAME));
+        let result = ext::ssl_use_certificate(ssl, &cert.chain[0])
+            .and_then(|_| cert.chain[1..].iter().try_for_each(|intermediate| ext::ssl_add_chain_cert(ssl, intermediate)))
+            .and_then(|_| ext::ssl_use_private_key(ssl, &cert.key));
+        if let Err(e) = result {
+            warn!("⚠️ Failed to install certificate for SNI {:?}: {}", ssl.servername(NameType::HOST_NAME), e);
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use hickory_resolver::TokioAsyncResolver;
+use log::{info, warn};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::backend::Backend;
+use crate::routing::Pool;
+
+// Bounds on how long an SRV answer is trusted, whatever TTL it carries: a TTL of 0 would otherwise
+// mean querying in a tight loop, and a day-long one would miss scale-ups.
+const MIN_REFRESH: Duration = Duration::from_secs(5);
+const MAX_REFRESH: Duration = Duration::from_secs(300);
+
+// Backends from the SRV record `name`, e.g. `_http._tcp.service.consul`, and how long the answer
+// is valid. Only the lowest priority present is used; the others are fallbacks the record owner
+// promotes by republishing. SRV weights become backend weights (0, "rarely", becomes 1, since a
+// backend weight of 0 drains).
+pub async fn resolve(resolver: &TokioAsyncResolver, name: &str) -> Result<(Vec<Backend>, Duration), String> {
+    let lookup = resolver.srv_lookup(name).await.map_err(|e| e.to_string())?;
+    let valid_for = lookup.as_lookup().valid_until().saturating_duration_since(Instant::now());
+    let Some(priority) = lookup.iter().map(|srv| srv.priority()).min() else {
+        return Err("no SRV records".to_string());
+    };
+    let mut backends: Vec<Backend> = lookup
+        .iter()
+        .filter(|srv| srv.priority() == priority)
+        .map(|srv| {
+            let host = srv.target().to_utf8().trim_end_matches('.').to_string();
+            Backend::new(host, srv.port(), (srv.weight() as usize).max(1))
+        })
+        .collect();
+    backends.sort_by_key(|b| b.address());
+    backends.dedup_by_key(|b| b.address());
+    Ok((backends, valid_for))
+}
+
+// Re-queries the record as its TTL runs out and swaps the pool's backends when the set changes,
+// keeping health and connection state for every host:port still present. A failed or empty
+// answer keeps the current backends.
+pub async fn refresh_loop(pool: Arc<Pool>, resolver: TokioAsyncResolver, name: String, mut valid_for: Duration) {
+    loop {
+        tokio::time::sleep(valid_for.clamp(MIN_REFRESH, MAX_REFRESH)).await;
+        let fresh = match resolve(&resolver, &name).await {
+            Ok((fresh, ttl)) => {
+                valid_for = ttl;
+                fresh
+            }
+            Err(e) => {
+                warn!("⚠️ SRV lookup of {} failed, keeping the current backends: {}", name, e);
+                valid_for = MIN_REFRESH;
+                continue;
+            }
+        };
+
+        let unchanged = {
+            let current = pool.backends.read().unwrap();
+            current.len() == fresh.len()
+                && current.iter().zip(&fresh).all(|(a, b)| a.address() == b.address() && a.weight == b.weight)
+        };
+        if !unchanged {
+            let addresses: Vec<String> = fresh.iter().map(|b| format!("{} (weight {})", b.address(), b.weight)).collect();
+            info!("🧭 SRV {} now lists {}", name, addresses.join(", "));
+            pool.replace_backends(fresh);
+        }
+    }
+}